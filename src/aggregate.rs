@@ -0,0 +1,925 @@
+//! Aggregate call trees across requests, e.g. to get a periodic overview
+//! of where a service spends its time instead of one table per request, or
+//! to keep the slowest requests around for inspection.
+//!
+//! With the `persist` feature, the aggregates of an [AggregatingProcessor]
+//! survive restarts, see [AggregatingProcessor::save_state].
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tracing::callsite::Identifier;
+
+use crate::{
+    clock::Instant,
+    sync::{self, lock},
+    BusyHistogram, CallPathPool, FinishedCallTreeProcessor,
+};
+
+/// Merges finished call trees with the same root call site into long
+/// running aggregates and hands them to a callback every
+/// [interval](Self::with_interval) or every [max_trees](Self::with_max_trees)
+/// call trees, whichever comes first.
+///
+/// The aggregates are reset after every summary. Summaries are only
+/// handed over when a call tree is processed, use [flush](Self::flush) to
+/// force one, e.g. on shutdown.
+///
+/// Call trees are merged while holding a lock, which summaries and
+/// [handles](Self::handle) take as well. So every call tree is part of
+/// exactly one summary and no one sees a partially merged call tree, also
+/// if call trees finish on many threads at once. The callback is called
+/// after the lock is released, so summaries of concurrent flushes can
+/// arrive in any order.
+///
+/// ```
+/// # #[cfg(feature = "display")] {
+/// use std::time::Duration;
+///
+/// use reqray::{aggregate::AggregatingProcessor, CallTreeCollectorBuilder};
+///
+/// let collector = CallTreeCollectorBuilder::default().build_with_collector(
+///     AggregatingProcessor::logging()
+///         .with_interval(Duration::from_secs(300))
+///         .with_max_trees(10_000),
+/// );
+/// # }
+/// ```
+pub struct AggregatingProcessor {
+    state: sync::Arc<sync::Mutex<State>>,
+    interval: Option<Duration>,
+    max_trees: Option<usize>,
+    on_summary: OnSummary,
+}
+
+type OnSummary = Box<dyn Fn(&[Aggregate]) + Send + Sync>;
+
+/// The merged call trees of all requests with the same root call site
+/// since the last summary of an [AggregatingProcessor].
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Aggregate {
+    call_tree: CallPathPool,
+    trees: usize,
+    tree_sizes: TreeSizes,
+    /// Missing in states saved by older versions.
+    #[cfg_attr(feature = "serde", serde(default))]
+    root_busy: BusyHistogram,
+}
+
+/// The distributions of the sizes of call trees, e.g. to tune
+/// [max_call_depth](crate::CallTreeCollectorBuilder::max_call_depth) or
+/// [max_spans_per_tree](crate::CallTreeCollectorBuilder::max_spans_per_tree)
+/// with data about the actual workload.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TreeSizes {
+    call_paths: SizeHistogram,
+    spans: SizeHistogram,
+}
+
+impl TreeSizes {
+    fn record(&mut self, pool: &CallPathPool) {
+        self.call_paths.record(pool.call_path_count());
+        self.spans.record(pool.span_count());
+    }
+
+    /// The numbers of call paths per call tree, see
+    /// [CallPathPool::call_path_count].
+    pub fn call_paths(&self) -> &SizeHistogram {
+        &self.call_paths
+    }
+
+    /// The numbers of spans per call tree, see [CallPathPool::span_count].
+    pub fn spans(&self) -> &SizeHistogram {
+        &self.spans
+    }
+}
+
+/// A histogram of sizes with a bucket per power of two, so percentiles are
+/// accurate to a factor of two.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SizeHistogram {
+    /// The number of sizes up to `2^idx`.
+    counts: Vec<u64>,
+    total: u64,
+    max: usize,
+}
+
+impl SizeHistogram {
+    fn record(&mut self, size: usize) {
+        let bucket = size.next_power_of_two().trailing_zeros() as usize;
+        if self.counts.len() <= bucket {
+            self.counts.resize(bucket + 1, 0);
+        }
+        self.counts[bucket] += 1;
+        self.total += 1;
+        self.max = core::cmp::max(self.max, size);
+    }
+
+    /// The number of recorded sizes.
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    /// The largest recorded size.
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// An upper bound of the size below or at which `percentile` percent
+    /// of the recorded sizes are, e.g. `percentile(99.0)`.
+    ///
+    /// Returns `None` if nothing was recorded.
+    pub fn percentile(&self, percentile: f64) -> Option<usize> {
+        if self.total == 0 {
+            return None;
+        }
+        let fraction = (percentile / 100.0).clamp(0.0, 1.0);
+        let rank = core::cmp::max(1, (fraction * self.total as f64).ceil() as u64);
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(core::cmp::min(1 << bucket, self.max));
+            }
+        }
+        Some(self.max)
+    }
+
+    /// The upper bounds of the buckets with the number of recorded sizes
+    /// in them, e.g. `(8, 3)` for three sizes between 5 and 8.
+    pub fn buckets(&self) -> impl Iterator<Item = (usize, u64)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .map(|(bucket, count)| (1 << bucket, *count))
+    }
+}
+
+impl Aggregate {
+    /// The merged call tree, e.g. the call count of its root is the number
+    /// of merged requests.
+    pub fn call_tree(&self) -> &CallPathPool {
+        &self.call_tree
+    }
+
+    /// The number of call trees merged into this aggregate.
+    pub fn trees(&self) -> usize {
+        self.trees
+    }
+
+    /// The sizes of the merged call trees.
+    pub fn tree_sizes(&self) -> &TreeSizes {
+        &self.tree_sizes
+    }
+
+    /// The busy times of the roots of the merged call trees, e.g. to tell
+    /// whether a single request was unusually slow.
+    pub fn root_busy(&self) -> &BusyHistogram {
+        &self.root_busy
+    }
+}
+
+/// Identifies the root call site, deserialized call trees have no
+/// [Identifier] and fall back to the name and location.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum AggregateKey {
+    Callsite(Identifier),
+    Location(String, Option<String>, Option<u32>),
+}
+
+impl AggregateKey {
+    fn of(pool: &CallPathPool) -> AggregateKey {
+        match pool.root().try_static_span_meta() {
+            Some(meta) => AggregateKey::Callsite(meta.callsite()),
+            None => AggregateKey::location(pool),
+        }
+    }
+
+    fn location(pool: &CallPathPool) -> AggregateKey {
+        let root = pool.root();
+        let meta = root.span_metadata();
+        AggregateKey::Location(
+            root.name().to_string(),
+            meta.file().map(str::to_string),
+            meta.line(),
+        )
+    }
+}
+
+/// The aggregates of an [AggregatingProcessor] since its last summary and
+/// the sizes of all call trees, e.g. to restore them after a restart with
+/// [with_state](AggregatingProcessor::with_state).
+///
+/// With the `serde` feature, the state can be serialized as well.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AggregatorState {
+    aggregates: Vec<Aggregate>,
+    tree_sizes: TreeSizes,
+}
+
+impl AggregatorState {
+    /// One aggregate per root call site, e.g. as the baseline of a
+    /// [CallTreeDiff](crate::diff::CallTreeDiff) right after a restart.
+    pub fn aggregates(&self) -> &[Aggregate] {
+        &self.aggregates
+    }
+
+    /// The sizes of all call trees, see
+    /// [AggregatingProcessor::tree_sizes].
+    pub fn tree_sizes(&self) -> &TreeSizes {
+        &self.tree_sizes
+    }
+}
+
+struct State {
+    aggregates: Vec<Aggregate>,
+    index: HashMap<AggregateKey, usize>,
+    trees: usize,
+    since: Instant,
+    /// Of all call trees, not reset by summaries.
+    tree_sizes: TreeSizes,
+}
+
+impl State {
+    fn new() -> State {
+        State {
+            aggregates: Vec::new(),
+            index: HashMap::new(),
+            trees: 0,
+            since: Instant::now(),
+            tree_sizes: TreeSizes::default(),
+        }
+    }
+}
+
+impl State {
+    /// Merges `pool` into the aggregate of its root call site.
+    fn record(&mut self, pool: Cow<'_, CallPathPool>) {
+        let key = AggregateKey::of(&pool);
+        self.tree_sizes.record(&pool);
+        if !self.index.contains_key(&key) {
+            // Restored aggregates have no callsites.
+            if let Some(index) = self.index.remove(&AggregateKey::location(&pool)) {
+                self.index.insert(key.clone(), index);
+            }
+        }
+        match self.index.get(&key) {
+            Some(&index) => {
+                let aggregate = &mut self.aggregates[index];
+                aggregate.call_tree.merge(&pool);
+                aggregate.trees += 1;
+                aggregate.tree_sizes.record(&pool);
+                aggregate.root_busy.record(pool.root().sum_with_children());
+            }
+            None => {
+                let index = self.aggregates.len();
+                let mut tree_sizes = TreeSizes::default();
+                tree_sizes.record(&pool);
+                let mut root_busy = BusyHistogram::default();
+                root_busy.record(pool.root().sum_with_children());
+                self.aggregates.push(Aggregate {
+                    call_tree: pool.into_owned(),
+                    trees: 1,
+                    tree_sizes,
+                    root_busy,
+                });
+                self.index.insert(key, index);
+            }
+        }
+        self.trees += 1;
+    }
+
+    fn to_aggregator_state(&self) -> AggregatorState {
+        AggregatorState {
+            aggregates: self.aggregates.clone(),
+            tree_sizes: self.tree_sizes.clone(),
+        }
+    }
+}
+
+/// A cheaply clonable handle to the aggregates of an
+/// [AggregatingProcessor], see [AggregatingProcessor::handle].
+#[derive(Clone)]
+pub struct AggregatorHandle {
+    state: sync::Arc<sync::Mutex<State>>,
+}
+
+impl fmt::Debug for AggregatorHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AggregatorHandle").finish_non_exhaustive()
+    }
+}
+
+impl AggregatorHandle {
+    /// See [AggregatingProcessor::state].
+    pub fn state(&self) -> AggregatorState {
+        lock(&self.state).to_aggregator_state()
+    }
+
+    /// See [AggregatingProcessor::tree_sizes].
+    pub fn tree_sizes(&self) -> TreeSizes {
+        lock(&self.state).tree_sizes.clone()
+    }
+
+    /// Calls `f` with the aggregate of the root call site of `pool`, if
+    /// there is one.
+    #[cfg(feature = "display")]
+    pub(crate) fn with_aggregate_of<R>(
+        &self,
+        pool: &CallPathPool,
+        f: impl FnOnce(&Aggregate) -> R,
+    ) -> Option<R> {
+        let state = lock(&self.state);
+        let index = state
+            .index
+            .get(&AggregateKey::of(pool))
+            .or_else(|| state.index.get(&AggregateKey::location(pool)))?;
+        Some(f(&state.aggregates[*index]))
+    }
+
+    /// The aggregates since the last summary in the OpenMetrics text
+    /// format, e.g. as the response of a scrape endpoint:
+    ///
+    /// ```
+    /// use reqray::{aggregate::AggregatingProcessor, openmetrics::OpenMetricsEncoder};
+    ///
+    /// let processor = AggregatingProcessor::new(|_| {}).with_interval(None);
+    /// let handle = processor.handle();
+    /// // ... move the processor into a collector, and on every scrape:
+    /// let body = handle.open_metrics(&OpenMetricsEncoder::default());
+    /// ```
+    #[cfg(feature = "openmetrics")]
+    pub fn open_metrics(&self, encoder: &crate::openmetrics::OpenMetricsEncoder) -> String {
+        encoder.encode(&lock(&self.state).aggregates)
+    }
+}
+
+impl fmt::Debug for AggregatingProcessor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AggregatingProcessor")
+            .field("interval", &self.interval)
+            .field("max_trees", &self.max_trees)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AggregatingProcessor {
+    /// Hands every summary to `on_summary`, one [Aggregate] per root call
+    /// site in the order they were first seen.
+    ///
+    /// By default, summaries are due every 60 seconds.
+    pub fn new(on_summary: impl Fn(&[Aggregate]) + Send + Sync + 'static) -> Self {
+        AggregatingProcessor {
+            state: sync::Arc::new(sync::Mutex::new(State::new())),
+            interval: Some(Duration::from_secs(60)),
+            max_trees: None,
+            on_summary: Box::new(on_summary),
+        }
+    }
+
+    /// Logs every summary at INFO level with one table per root call site,
+    /// showing the call counts, summed and mean busy times of every call
+    /// path.
+    #[cfg(feature = "display")]
+    pub fn logging() -> Self {
+        use crate::display::{Column, LoggingCallTreeCollectorBuilder};
+
+        let table = LoggingCallTreeCollectorBuilder::default()
+            .columns(vec![
+                Column::Calls,
+                Column::BusySum,
+                Column::MeanBusy,
+                Column::OwnBusy,
+            ])
+            .build();
+        Self::new(move |aggregates| {
+            for aggregate in aggregates {
+                let pool = aggregate.call_tree();
+                let sizes = aggregate.tree_sizes();
+                tracing::info!(
+                    "Aggregated call summary of {} over {} request{} \
+                     (call paths p50 {}, max {}; spans p50 {}, max {})\n\n{}",
+                    table.root_label(pool.root()),
+                    aggregate.trees(),
+                    if aggregate.trees() == 1 { "" } else { "s" },
+                    sizes.call_paths().percentile(50.0).unwrap_or_default(),
+                    sizes.call_paths().max(),
+                    sizes.spans().percentile(50.0).unwrap_or_default(),
+                    sizes.spans().max(),
+                    table.table(pool)
+                );
+            }
+        })
+    }
+
+    /// Hands over a summary at least this often while call trees are
+    /// processed, `None` disables time based summaries. The default is 60
+    /// seconds.
+    pub fn with_interval(mut self, interval: impl Into<Option<Duration>>) -> Self {
+        self.interval = interval.into();
+        self
+    }
+
+    /// Hands over a summary after this many call trees. There is no limit
+    /// by default.
+    pub fn with_max_trees(mut self, max_trees: impl Into<Option<usize>>) -> Self {
+        self.max_trees = max_trees.into();
+        self
+    }
+
+    /// The sizes of all call trees processed so far, also before the last
+    /// summary.
+    pub fn tree_sizes(&self) -> TreeSizes {
+        self.lock().tree_sizes.clone()
+    }
+
+    /// A copy of the aggregates since the last summary and of the tree
+    /// sizes.
+    pub fn state(&self) -> AggregatorState {
+        self.lock().to_aggregator_state()
+    }
+
+    /// A handle to read the aggregates after the processor has been moved
+    /// into a collector, e.g. from a metrics endpoint.
+    pub fn handle(&self) -> AggregatorHandle {
+        AggregatorHandle {
+            state: self.state.clone(),
+        }
+    }
+
+    /// Continues with the aggregates and tree sizes of `state`, e.g. of a
+    /// previous process. Replaces everything aggregated so far.
+    ///
+    /// Call trees are merged into the restored aggregates with the same
+    /// root name and location.
+    pub fn with_state(self, state: AggregatorState) -> Self {
+        {
+            let mut current = self.lock();
+            let mut restored = State::new();
+            for aggregate in state.aggregates {
+                let key = AggregateKey::of(&aggregate.call_tree);
+                restored.index.insert(key, restored.aggregates.len());
+                restored.trees += aggregate.trees;
+                restored.aggregates.push(aggregate);
+            }
+            restored.tree_sizes = state.tree_sizes;
+            *current = restored;
+        }
+        self
+    }
+
+    /// Writes the [state](Self::state) as JSON to `path`, e.g. on shutdown,
+    /// replacing the file atomically.
+    ///
+    /// Only the aggregates since the last summary are saved, so disable
+    /// summaries with [with_interval](Self::with_interval) for statistics
+    /// which cover the whole lifetime of a service:
+    ///
+    /// ```no_run
+    /// use reqray::aggregate::AggregatingProcessor;
+    ///
+    /// let processor = AggregatingProcessor::new(|_| {})
+    ///     .with_interval(None)
+    ///     .load_state("aggregates.json")
+    ///     .unwrap();
+    /// // ... on shutdown:
+    /// processor.save_state("aggregates.json").unwrap();
+    /// ```
+    #[cfg(feature = "persist")]
+    pub fn save_state(&self, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+        let path = path.as_ref();
+        let json = serde_json::to_vec(&self.state())?;
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, json)?;
+        std::fs::rename(&tmp, path)
+    }
+
+    /// Continues with the state saved by [save_state](Self::save_state) to
+    /// `path`, e.g. on startup. Starts from scratch if there is no such
+    /// file.
+    #[cfg(feature = "persist")]
+    pub fn load_state(self, path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        let json = match std::fs::read(path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(self),
+            Err(e) => return Err(e),
+        };
+        let state = serde_json::from_slice(&json)?;
+        Ok(self.with_state(state))
+    }
+
+    /// Hands over the aggregates collected so far, if any, and resets them.
+    pub fn flush(&self) {
+        let aggregates = {
+            let mut state = self.lock();
+            let next = State {
+                tree_sizes: std::mem::take(&mut state.tree_sizes),
+                ..State::new()
+            };
+            std::mem::replace(&mut *state, next).aggregates
+        };
+        if !aggregates.is_empty() {
+            (self.on_summary)(&aggregates);
+        }
+    }
+
+    fn lock(&self) -> sync::MutexGuard<'_, State> {
+        lock(&self.state)
+    }
+
+    fn aggregate(&self, pool: Cow<'_, CallPathPool>) {
+        let due = {
+            let mut state = self.lock();
+            state.record(pool);
+            matches!(self.max_trees, Some(max) if state.trees >= max)
+                || matches!(self.interval, Some(interval) if state.since.elapsed() >= interval)
+        };
+        if due {
+            self.flush();
+        }
+    }
+}
+
+impl FinishedCallTreeProcessor for AggregatingProcessor {
+    fn process_finished_call(&self, pool: CallPathPool) {
+        self.aggregate(Cow::Owned(pool))
+    }
+
+    fn process_shared_call(&self, pool: Arc<CallPathPool>) {
+        self.aggregate(Cow::Borrowed(&pool))
+    }
+}
+
+/// Keeps the `k` slowest call trees by the busy time of their root that
+/// finished within the last `window`, so that the worst requests can be
+/// dumped on demand, e.g. from a debug HTTP endpoint, without logging all
+/// of them.
+///
+/// Clones share the retained call trees, so keep a clone to read them:
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use reqray::{aggregate::SlowestRequestsProcessor, CallTreeCollectorBuilder};
+///
+/// let slowest = SlowestRequestsProcessor::new(10, Duration::from_secs(300));
+/// let collector = CallTreeCollectorBuilder::default().build_with_collector(slowest.clone());
+///
+/// // Later, e.g. in a debug endpoint:
+/// for request in slowest.slowest() {
+///     println!("{}: {:?}", request.call_tree().root().name(), request.age());
+/// }
+/// ```
+///
+/// Only the `k` slowest call trees are retained at any time. When they
+/// expire, faster call trees that finished during the window but were
+/// evicted by them are not recovered, so fewer than `k` call trees may be
+/// returned.
+#[derive(Debug, Clone)]
+pub struct SlowestRequestsProcessor {
+    k: usize,
+    window: Duration,
+    /// Slowest first.
+    retained: Arc<Mutex<Vec<SlowRequest>>>,
+}
+
+/// A call tree retained by a [SlowestRequestsProcessor].
+#[derive(Debug, Clone)]
+pub struct SlowRequest {
+    call_tree: Arc<CallPathPool>,
+    finished: Instant,
+}
+
+impl SlowRequest {
+    /// The finished call tree.
+    pub fn call_tree(&self) -> &Arc<CallPathPool> {
+        &self.call_tree
+    }
+
+    /// The time since the call tree was handed to the processor.
+    pub fn age(&self) -> Duration {
+        self.finished.elapsed()
+    }
+
+    fn busy(&self) -> Duration {
+        self.call_tree.root().sum_with_children()
+    }
+}
+
+impl SlowestRequestsProcessor {
+    /// Retains the `k` slowest call trees of the last `window`.
+    pub fn new(k: usize, window: Duration) -> Self {
+        SlowestRequestsProcessor {
+            k,
+            window,
+            retained: Arc::new(Mutex::new(Vec::with_capacity(k))),
+        }
+    }
+
+    /// The retained call trees which finished within the window, slowest
+    /// first.
+    pub fn slowest(&self) -> Vec<SlowRequest> {
+        let mut retained = self.lock();
+        self.expire(&mut retained);
+        retained.clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<SlowRequest>> {
+        match self.retained.lock() {
+            Ok(retained) => retained,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn expire(&self, retained: &mut Vec<SlowRequest>) {
+        retained.retain(|request| request.age() <= self.window);
+    }
+
+    /// Retains the call tree returned by `call_tree` if it is among the
+    /// `k` slowest.
+    fn retain(&self, busy: Duration, call_tree: impl FnOnce() -> Arc<CallPathPool>) {
+        if self.k == 0 {
+            return;
+        }
+        let mut retained = self.lock();
+        self.expire(&mut retained);
+        if retained.len() >= self.k && retained.last().is_some_and(|last| last.busy() >= busy) {
+            return;
+        }
+        retained.truncate(self.k - 1);
+        let idx = retained.partition_point(|request| request.busy() >= busy);
+        retained.insert(
+            idx,
+            SlowRequest {
+                call_tree: call_tree(),
+                finished: Instant::now(),
+            },
+        );
+    }
+}
+
+impl FinishedCallTreeProcessor for SlowestRequestsProcessor {
+    fn process_finished_call(&self, pool: CallPathPool) {
+        self.retain(pool.root().sum_with_children(), || Arc::new(pool))
+    }
+
+    fn process_shared_call(&self, pool: Arc<CallPathPool>) {
+        self.retain(pool.root().sum_with_children(), || pool)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::{AggregatingProcessor, SizeHistogram, SlowestRequestsProcessor};
+    use crate::{internal::test::collect_call_trees, FinishedCallTreeProcessor};
+
+    #[test]
+    fn aggregates_per_root() {
+        let call_trees = collect_call_trees(|mock| {
+            let request = |busy| {
+                tracing::info_span!("request").in_scope(|| {
+                    tracing::info_span!("query").in_scope(|| mock.increment(busy));
+                })
+            };
+            request(100);
+            tracing::info_span!("other").in_scope(|| mock.increment(5));
+            request(300);
+        });
+        let summaries = Arc::new(Mutex::new(Vec::new()));
+        let processor = AggregatingProcessor::new({
+            let summaries = summaries.clone();
+            move |aggregates| {
+                let summary = aggregates
+                    .iter()
+                    .flat_map(|aggregate| {
+                        aggregate.call_tree().flatten("/").map(move |(path, node)| {
+                            (
+                                path,
+                                aggregate.trees(),
+                                node.call_count(),
+                                node.mean_busy().as_nanos(),
+                            )
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                summaries.lock().unwrap().push(summary);
+            }
+        })
+        .with_interval(None)
+        .with_max_trees(3);
+        let mut call_trees = call_trees.into_iter();
+        processor.process_shared_call(Arc::new(call_trees.next().unwrap()));
+        processor.process_finished_call(call_trees.next().unwrap());
+        assert!(summaries.lock().unwrap().is_empty());
+        processor.process_finished_call(call_trees.next().unwrap());
+
+        assert_eq!(
+            *summaries.lock().unwrap(),
+            vec![vec![
+                ("request".to_string(), 2, 2, 200),
+                ("request/query".to_string(), 2, 2, 200),
+                ("other".to_string(), 1, 1, 5),
+            ]]
+        );
+        // Reset after the summary, except for the tree sizes.
+        processor.flush();
+        assert_eq!(summaries.lock().unwrap().len(), 1);
+        let tree_sizes = processor.tree_sizes();
+        assert_eq!(tree_sizes.call_paths().count(), 3);
+        assert_eq!(tree_sizes.call_paths().max(), 2);
+        assert_eq!(tree_sizes.spans().percentile(100.0), Some(2));
+    }
+
+    #[cfg(feature = "persist")]
+    #[test]
+    fn persists_state() {
+        let call_trees = collect_call_trees(|mock| {
+            for busy in [100, 300, 200] {
+                tracing::info_span!("request").in_scope(|| {
+                    tracing::info_span!("query").in_scope(|| mock.increment(busy));
+                });
+            }
+        });
+        let path =
+            std::env::temp_dir().join(format!("reqray-aggregates-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut call_trees = call_trees.into_iter();
+
+        let processor = AggregatingProcessor::new(|_| {})
+            .with_interval(None)
+            .load_state(&path)
+            .unwrap();
+        processor.process_finished_call(call_trees.next().unwrap());
+        processor.process_finished_call(call_trees.next().unwrap());
+        processor.save_state(&path).unwrap();
+
+        let summaries = Arc::new(Mutex::new(Vec::new()));
+        let restarted = AggregatingProcessor::new({
+            let summaries = summaries.clone();
+            move |aggregates| {
+                for aggregate in aggregates {
+                    let paths = aggregate
+                        .call_tree()
+                        .flatten("/")
+                        .map(|(path, node)| (path, node.call_count(), node.mean_busy().as_nanos()))
+                        .collect::<Vec<_>>();
+                    summaries.lock().unwrap().push((aggregate.trees(), paths));
+                }
+            }
+        })
+        .with_interval(None)
+        .load_state(&path)
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(restarted.tree_sizes().spans().count(), 2);
+        restarted.process_finished_call(call_trees.next().unwrap());
+        restarted.flush();
+
+        assert_eq!(
+            *summaries.lock().unwrap(),
+            vec![(
+                3,
+                vec![
+                    ("request".to_string(), 3, 200),
+                    ("request/query".to_string(), 3, 200),
+                ]
+            )]
+        );
+    }
+
+    #[test]
+    fn size_percentiles() {
+        let mut histogram = SizeHistogram::default();
+        assert_eq!(histogram.percentile(50.0), None);
+        for size in [1, 3, 4, 5, 100] {
+            histogram.record(size);
+        }
+        assert_eq!(histogram.percentile(20.0), Some(1));
+        assert_eq!(histogram.percentile(50.0), Some(4));
+        assert_eq!(histogram.percentile(80.0), Some(8));
+        assert_eq!(histogram.percentile(100.0), Some(100));
+        assert_eq!(
+            histogram.buckets().collect::<Vec<_>>(),
+            vec![
+                (1, 1),
+                (2, 0),
+                (4, 2),
+                (8, 1),
+                (16, 0),
+                (32, 0),
+                (64, 0),
+                (128, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn summarizes_after_interval() {
+        let call_trees = collect_call_trees(|_| tracing::info_span!("request").in_scope(|| {}));
+        let summaries = Arc::new(Mutex::new(0));
+        let processor = AggregatingProcessor::new({
+            let summaries = summaries.clone();
+            move |_| *summaries.lock().unwrap() += 1
+        })
+        .with_interval(Duration::ZERO);
+        processor.process_finished_call(call_trees[0].clone());
+        assert_eq!(*summaries.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn retains_slowest() {
+        let call_trees = collect_call_trees(|mock| {
+            for busy in [30, 10, 50, 20, 40] {
+                tracing::info_span!("request", busy).in_scope(|| mock.increment(busy));
+            }
+        });
+        let first = call_trees[0].clone();
+        let processor = SlowestRequestsProcessor::new(3, Duration::from_secs(3600));
+        for (idx, pool) in call_trees.into_iter().enumerate() {
+            if idx % 2 == 0 {
+                processor.process_finished_call(pool);
+            } else {
+                processor.process_shared_call(Arc::new(pool));
+            }
+        }
+        let slowest = processor
+            .slowest()
+            .iter()
+            .map(|request| request.call_tree().root().sum_with_children().as_nanos())
+            .collect::<Vec<_>>();
+        assert_eq!(slowest, vec![50, 40, 30]);
+
+        let processor = SlowestRequestsProcessor::new(3, Duration::from_millis(1));
+        processor.process_finished_call(first);
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(processor.slowest().is_empty());
+    }
+}
+
+#[cfg(all(test, reqray_loom))]
+mod loom_test {
+    use loom::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+    };
+
+    use super::AggregatingProcessor;
+    use crate::{
+        internal::test::{collect_call_trees, compound_call},
+        sync::Arc,
+        FinishedCallTreeProcessor,
+    };
+
+    #[test]
+    fn loom_concurrent_trees_are_summarized_once() {
+        let call_trees = collect_call_trees(|mock| {
+            compound_call(&mock);
+            compound_call(&mock);
+        });
+        loom::model(move || {
+            let summarized = Arc::new(AtomicUsize::new(0));
+            let processor = {
+                let summarized = summarized.clone();
+                let processor = AggregatingProcessor::new(move |aggregates| {
+                    for aggregate in aggregates {
+                        // No call tree is merged only partially.
+                        assert_eq!(aggregate.call_tree().root().call_count(), aggregate.trees());
+                        summarized.fetch_add(aggregate.trees(), Ordering::Relaxed);
+                    }
+                });
+                Arc::new(processor.with_interval(None).with_max_trees(1))
+            };
+            let threads = call_trees
+                .iter()
+                .cloned()
+                .map(|pool| {
+                    let processor = processor.clone();
+                    thread::spawn(move || processor.process_finished_call(pool))
+                })
+                .collect::<Vec<_>>();
+            for aggregate in processor.state().aggregates() {
+                assert_eq!(aggregate.call_tree().root().call_count(), aggregate.trees());
+            }
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            processor.flush();
+            assert_eq!(summarized.load(Ordering::Relaxed), 2);
+        });
+    }
+}
@@ -0,0 +1,176 @@
+//! [FinishedCallTreeProcessor]s which look for suspicious patterns in
+//! call trees and log targeted hints, automating what reviewers otherwise
+//! spot by scanning the call tree table.
+
+use std::{sync::Arc, time::Duration};
+
+use crate::{CallPathPool, FinishedCallTreeProcessor};
+
+/// Flags the classic N+1 pattern: a call path which is called many times
+/// for every call of its parent, e.g. a query per item of a list that
+/// was fetched before.
+///
+/// Every finding is logged at WARN level, e.g.
+///
+/// ```text
+/// Possible N+1 pattern in handle_request: handle_request/load_user was called
+/// 100 times in 1 call of handle_request (12.5ms busy), consider batching
+/// ```
+///
+/// Combine it with other processors with a
+/// [MultiProcessor](crate::processors::MultiProcessor):
+///
+/// ```
+/// # #[cfg(feature = "display")] {
+/// use reqray::{
+///     anomalies::NPlusOneDetector, display::LoggingCallTreeCollectorBuilder,
+///     processors::MultiProcessor, CallTreeCollectorBuilder,
+/// };
+///
+/// let collector = CallTreeCollectorBuilder::default().build_with_collector(
+///     MultiProcessor::default()
+///         .with(LoggingCallTreeCollectorBuilder::default().build())
+///         .with(NPlusOneDetector::default().with_min_calls(20)),
+/// );
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct NPlusOneDetector {
+    min_calls: usize,
+    min_calls_per_parent_call: usize,
+}
+
+/// A call path flagged by [NPlusOneDetector::detect].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NPlusOne {
+    /// The path of the repeated call, e.g. `request/list/load_item`.
+    pub call_path: String,
+    /// The path of its parent, e.g. `request/list`.
+    pub parent_path: String,
+    /// How often the call path was called in the call tree.
+    pub calls: usize,
+    /// How often the parent was called in the call tree.
+    pub parent_calls: usize,
+    /// The summed busy time of the repeated call.
+    pub busy: Duration,
+}
+
+impl Default for NPlusOneDetector {
+    fn default() -> Self {
+        NPlusOneDetector {
+            min_calls: 10,
+            min_calls_per_parent_call: 10,
+        }
+    }
+}
+
+impl NPlusOneDetector {
+    /// Only flag call paths called at least this often per call tree. The
+    /// default is `10`.
+    pub fn with_min_calls(mut self, min_calls: usize) -> Self {
+        self.min_calls = min_calls;
+        self
+    }
+
+    /// Only flag call paths called at least this often per call of their
+    /// parent. The default is `10`.
+    pub fn with_min_calls_per_parent_call(mut self, min_calls_per_parent_call: usize) -> Self {
+        self.min_calls_per_parent_call = min_calls_per_parent_call;
+        self
+    }
+
+    /// All call paths of `pool` which look like N+1 patterns, parents
+    /// before children.
+    pub fn detect(&self, pool: &CallPathPool) -> Vec<NPlusOne> {
+        pool.flatten("/")
+            .filter_map(|(call_path, timing)| {
+                let parent = &pool[timing.parent()?];
+                let calls = timing.call_count();
+                let parent_calls = parent.call_count();
+                let suspicious = !timing.is_event()
+                    && calls >= self.min_calls
+                    && calls >= parent_calls.saturating_mul(self.min_calls_per_parent_call);
+                suspicious.then(|| NPlusOne {
+                    parent_path: parent.path_string(pool, "/"),
+                    call_path,
+                    calls,
+                    parent_calls,
+                    busy: timing.sum_with_children(),
+                })
+            })
+            .collect()
+    }
+
+    fn log(&self, pool: &CallPathPool) {
+        for finding in self.detect(pool) {
+            tracing::warn!(
+                "Possible N+1 pattern in {}: {} was called {} times in {} call{} of {} ({:?} busy), \
+                 consider batching",
+                pool.root().name(),
+                finding.call_path,
+                finding.calls,
+                finding.parent_calls,
+                if finding.parent_calls == 1 { "" } else { "s" },
+                finding.parent_path,
+                finding.busy,
+            );
+        }
+    }
+}
+
+impl FinishedCallTreeProcessor for NPlusOneDetector {
+    fn process_finished_call(&self, pool: CallPathPool) {
+        self.log(&pool)
+    }
+
+    fn process_shared_call(&self, pool: Arc<CallPathPool>) {
+        self.log(&pool)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{NPlusOne, NPlusOneDetector};
+    use crate::internal::test::collect_call_trees;
+
+    #[test]
+    fn detects_calls_per_item() {
+        let call_trees = collect_call_trees(|mock| {
+            tracing::info_span!("request").in_scope(|| {
+                tracing::info_span!("list_items").in_scope(|| mock.increment(1_000));
+                for _ in 0..3 {
+                    // Only a few calls per parent call.
+                    tracing::info_span!("page").in_scope(|| {
+                        for _ in 0..4 {
+                            tracing::info_span!("load_item").in_scope(|| mock.increment(10));
+                        }
+                    });
+                }
+                for _ in 0..12 {
+                    tracing::info_span!("load_user").in_scope(|| mock.increment(100));
+                }
+            })
+        });
+        let detector = NPlusOneDetector::default();
+        assert_eq!(
+            detector.detect(&call_trees[0]),
+            vec![NPlusOne {
+                call_path: "request/load_user".to_string(),
+                parent_path: "request".to_string(),
+                calls: 12,
+                parent_calls: 1,
+                busy: Duration::from_nanos(1_200),
+            }]
+        );
+
+        let detector = detector.with_min_calls_per_parent_call(4);
+        let flagged = detector
+            .detect(&call_trees[0])
+            .into_iter()
+            .map(|finding| finding.call_path)
+            .collect::<Vec<_>>();
+        assert_eq!(flagged, vec!["request/page/load_item", "request/load_user"]);
+    }
+}
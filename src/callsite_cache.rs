@@ -0,0 +1,132 @@
+//! Representations of call paths which only depend on their callsite, e.g.
+//! escaped span names, kept for the lifetime of the process so that the
+//! exporters do not format the same metadata for every call tree.
+//!
+//! The exporters which key call paths by their path strings, `csv` and
+//! `metrics`, cannot use this cache: the same callsite has different path
+//! strings below different callers. Neither does `proto`, since its
+//! messages own their strings and a cached copy would have to be cloned
+//! just the same.
+//!
+//! Callsites are statics, so the caches only grow with the number of
+//! instrumented spans, not with the number of call trees.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, PoisonError, RwLock},
+};
+
+use tracing::callsite::Identifier;
+
+use crate::CallPathTiming;
+
+type Entries<T> = HashMap<Identifier, (Box<str>, Arc<T>)>;
+
+/// A cache of one exporter representation per callsite, meant for a
+/// `static`.
+pub(crate) struct CallsiteCache<T: ?Sized> {
+    /// The representations with the call path names they were made for.
+    entries: RwLock<Option<Entries<T>>>,
+}
+
+impl<T: ?Sized> CallsiteCache<T> {
+    pub(crate) const fn new() -> Self {
+        CallsiteCache {
+            entries: RwLock::new(None),
+        }
+    }
+
+    /// The representation of `node`, made by `make` for the first call
+    /// path of its callsite.
+    ///
+    /// Call paths without callsites, e.g. deserialized ones, are not
+    /// cached. Neither are call paths named differently than the cached
+    /// one, e.g. by the [span_name_mapper] of another collector.
+    ///
+    /// [span_name_mapper]: crate::CallTreeCollectorBuilder::span_name_mapper
+    pub(crate) fn get_or_insert_with(
+        &self,
+        node: &CallPathTiming,
+        make: impl FnOnce(&CallPathTiming) -> Arc<T>,
+    ) -> Arc<T> {
+        let callsite = match node.callsite() {
+            Some(callsite) => callsite,
+            None => return make(node),
+        };
+        {
+            let entries = self.entries.read().unwrap_or_else(PoisonError::into_inner);
+            if let Some((name, cached)) = entries.as_ref().and_then(|e| e.get(&callsite)) {
+                if **name == *node.name() {
+                    return Arc::clone(cached);
+                }
+                return make(node);
+            }
+        }
+        let made = make(node);
+        self.entries
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_or_insert_with(HashMap::new)
+            .entry(callsite)
+            .or_insert_with(|| (node.name().into(), Arc::clone(&made)));
+        made
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::Cell, sync::Arc};
+
+    use super::CallsiteCache;
+    use crate::{
+        internal::test::{collect_call_trees, collect_call_trees_with_builder},
+        CallTreeCollectorBuilder,
+    };
+
+    fn request() {
+        tracing::info_span!("request").in_scope(|| {
+            tracing::info_span!("step").in_scope(|| {});
+        });
+    }
+
+    #[test]
+    fn made_once_per_callsite() {
+        static CACHE: CallsiteCache<str> = CallsiteCache::new();
+        let call_trees = collect_call_trees(|_| {
+            request();
+            request();
+        });
+        let made = Cell::new(0);
+        let mut names = Vec::new();
+        for pool in &call_trees {
+            for (_, node) in pool.iter_depth_first() {
+                names.push(CACHE.get_or_insert_with(node, |node| {
+                    made.set(made.get() + 1);
+                    node.name().into()
+                }));
+            }
+        }
+        assert_eq!(made.get(), 2);
+        assert_eq!(
+            names.iter().map(|n| &**n).collect::<Vec<_>>(),
+            ["request", "step", "request", "step"]
+        );
+        assert!(Arc::ptr_eq(&names[1], &names[3]));
+    }
+
+    #[test]
+    fn differently_named() {
+        static CACHE: CallsiteCache<str> = CallsiteCache::new();
+        let plain = collect_call_trees(|_| request());
+        let mapped = collect_call_trees_with_builder(
+            CallTreeCollectorBuilder::default().span_name_mapper(|name| name.to_uppercase()),
+            |_| request(),
+        );
+        let step = |pool: &crate::CallPathPool| {
+            let (_, node) = pool.iter_depth_first().nth(1).unwrap();
+            CACHE.get_or_insert_with(node, |node| node.name().into())
+        };
+        assert!(Arc::ptr_eq(&step(&plain[0]), &step(&plain[0])));
+        assert!(!Arc::ptr_eq(&step(&plain[0]), &step(&mapped[0])));
+    }
+}
@@ -0,0 +1,94 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+/// The number of bits of the hash which select a register.
+const PRECISION: u32 = 8;
+const REGISTERS: usize = 1 << PRECISION;
+
+/// A HyperLogLog sketch estimating the number of distinct values of a
+/// captured field, see [FieldValues::distinct_count].
+///
+/// With 256 registers, the standard error is about 6.5%.
+///
+/// [FieldValues::distinct_count]: crate::FieldValues::distinct_count
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct DistinctValues {
+    registers: Vec<u8>,
+}
+
+impl Default for DistinctValues {
+    fn default() -> Self {
+        DistinctValues {
+            registers: vec![0; REGISTERS],
+        }
+    }
+}
+
+impl DistinctValues {
+    pub(crate) fn add(&mut self, value: &str) {
+        // Not randomly seeded, so that sketches stay comparable.
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+        let register = (hash >> (64 - PRECISION)) as usize;
+        let rank = ((hash << PRECISION).leading_zeros() + 1).min(64 - PRECISION + 1) as u8;
+        if self.registers[register] < rank {
+            self.registers[register] = rank;
+        }
+    }
+
+    /// Adds the values of `other`, as if they were added to this sketch.
+    pub(crate) fn merge(&mut self, other: &DistinctValues) {
+        for (rank, other_rank) in self.registers.iter_mut().zip(&other.registers) {
+            *rank = core::cmp::max(*rank, *other_rank);
+        }
+    }
+
+    pub(crate) fn estimate(&self) -> usize {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|rank| 2f64.powi(-i32::from(*rank)))
+            .sum();
+        let raw = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|rank| **rank == 0).count();
+        let estimate = if raw <= 2.5 * m && zeros > 0 {
+            // Linear counting is more accurate for small cardinalities.
+            m * (m / zeros as f64).ln()
+        } else {
+            raw
+        };
+        estimate.round() as usize
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DistinctValues;
+
+    #[test]
+    fn estimates() {
+        for distinct in [1, 10, 100, 1_000, 10_000] {
+            let mut sketch = DistinctValues::default();
+            // Repeated values do not count.
+            for _ in 0..3 {
+                for value in 0..distinct {
+                    sketch.add(&format!("value {}", value));
+                }
+            }
+            let estimate = sketch.estimate() as f64;
+            let error = (estimate - distinct as f64).abs() / distinct as f64;
+            assert!(
+                error < 0.2,
+                "estimated {} for {} distinct values",
+                estimate,
+                distinct
+            );
+        }
+    }
+}
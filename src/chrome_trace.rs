@@ -0,0 +1,202 @@
+//! Export finished call trees in the Chrome Trace Event format for
+//! [Perfetto](https://ui.perfetto.dev) or `chrome://tracing`.
+//!
+//! Call trees only contain aggregated timings per call path, not the
+//! individual spans. Therefore, every call path becomes a single complete
+//! (`"X"`) event lasting its summed busy time. The children of a call
+//! path are laid out one after another from the start of their parent,
+//! so the result looks like a flame graph in call order. The number of
+//! calls and the own busy time are included as event args.
+//!
+//! Every call tree is shown as its own track (`tid`), positioned at the
+//! time it finished.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    callsite_cache::CallsiteCache, clock::Instant, json::write_json_str, CallPathPool,
+    CallPathTiming, FinishedCallTreeProcessor,
+};
+
+/// The names of call paths as quoted JSON strings.
+static JSON_NAMES: CallsiteCache<[u8]> = CallsiteCache::new();
+
+/// A [FinishedCallTreeProcessor] which appends all call trees as trace
+/// events to a writer, e.g. a file.
+///
+/// ```no_run
+/// use reqray::{chrome_trace::ChromeTraceProcessor, CallTreeCollectorBuilder};
+///
+/// let collector = CallTreeCollectorBuilder::default()
+///     .build_with_collector(ChromeTraceProcessor::append_to_file("trace.json").unwrap());
+/// ```
+///
+/// The output is a JSON array without the closing `]`, which is
+/// explicitly allowed by the format so that files can be appended to.
+pub struct ChromeTraceProcessor<W: Write> {
+    started: Instant,
+    state: Mutex<State<W>>,
+}
+
+struct State<W> {
+    writer: W,
+    needs_header: bool,
+    trees: u64,
+}
+
+impl ChromeTraceProcessor<File> {
+    /// Appends to the file at `path`, creating it if necessary.
+    pub fn append_to_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let needs_header = file.metadata()?.len() == 0;
+        Ok(Self::with_header(file, needs_header))
+    }
+}
+
+impl<W: Write> ChromeTraceProcessor<W> {
+    /// Writes a new trace to `writer`.
+    pub fn new(writer: W) -> Self {
+        Self::with_header(writer, true)
+    }
+
+    fn with_header(writer: W, needs_header: bool) -> Self {
+        ChromeTraceProcessor {
+            started: Instant::now(),
+            state: Mutex::new(State {
+                writer,
+                needs_header,
+                trees: 0,
+            }),
+        }
+    }
+}
+
+impl<W: Write> FinishedCallTreeProcessor for ChromeTraceProcessor<W> {
+    fn process_finished_call(&self, pool: CallPathPool) {
+        self.write(&pool)
+    }
+
+    fn process_shared_call(&self, pool: Arc<CallPathPool>) {
+        self.write(&pool)
+    }
+}
+
+impl<W: Write> ChromeTraceProcessor<W> {
+    fn write(&self, pool: &CallPathPool) {
+        let finished = self.started.elapsed();
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        state.trees += 1;
+        let tid = state.trees;
+        let ts = finished.saturating_sub(pool.root().sum_with_children());
+        let result = (|| {
+            if state.needs_header {
+                state.writer.write_all(b"[\n")?;
+                state.needs_header = false;
+            }
+            write_trace_events(pool, tid, ts, &mut state.writer)?;
+            state.writer.flush()
+        })();
+        if let Err(e) = result {
+            tracing::warn!("Could not write trace events: {}", e);
+        }
+    }
+}
+
+/// Writes a trace event for every call path in `pool`, each followed by
+/// `,\n`. The root starts at `ts`.
+fn write_trace_events(
+    pool: &CallPathPool,
+    tid: u64,
+    ts: Duration,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    write_call_path(pool, pool.root(), tid, ts, out)
+}
+
+fn write_call_path(
+    pool: &CallPathPool,
+    node: &CallPathTiming,
+    tid: u64,
+    ts: Duration,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    if node.is_event() {
+        return Ok(());
+    }
+    let name = JSON_NAMES.get_or_insert_with(node, |node| {
+        let mut name = Vec::new();
+        // Writing to a Vec cannot fail.
+        let _ = write_json_str(node.name(), &mut name);
+        name.into()
+    });
+    out.write_all(b"{\"name\":")?;
+    out.write_all(&name)?;
+    writeln!(
+        out,
+        ",\"ph\":\"X\",\"pid\":1,\"tid\":{},\"ts\":{},\"dur\":{},\"args\":{{\"calls\":{},\"own_us\":{}}}}},",
+        tid,
+        ts.as_micros(),
+        node.sum_with_children().as_micros(),
+        node.call_count(),
+        node.sum_without_children().as_micros()
+    )?;
+
+    let mut children = node.children().copied().collect::<Vec<_>>();
+    children.sort();
+    let mut child_ts = ts;
+    for child_idx in children {
+        let child = &pool[child_idx];
+        write_call_path(pool, child, tid, child_ts, out)?;
+        child_ts += child.sum_with_children();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use quanta::Mock;
+
+    use crate::internal::test::collect_call_trees;
+
+    #[tracing::instrument]
+    fn leaf(mock: &Mock) {
+        mock.increment(2_000);
+    }
+
+    #[test]
+    fn trace_events() {
+        let call_trees = collect_call_trees(|mock| {
+            tracing::info_span!("request").in_scope(|| {
+                mock.increment(1_000);
+                tracing::info_span!("say \"hi\"").in_scope(|| {
+                    leaf(&mock);
+                    leaf(&mock);
+                });
+                leaf(&mock);
+            })
+        });
+
+        let mut out = Vec::new();
+        super::write_trace_events(&call_trees[0], 7, Duration::from_micros(100), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            indoc::indoc! {r#"
+                {"name":"request","ph":"X","pid":1,"tid":7,"ts":100,"dur":7,"args":{"calls":1,"own_us":1}},
+                {"name":"say \"hi\"","ph":"X","pid":1,"tid":7,"ts":100,"dur":4,"args":{"calls":1,"own_us":0}},
+                {"name":"leaf","ph":"X","pid":1,"tid":7,"ts":100,"dur":4,"args":{"calls":2,"own_us":4}},
+                {"name":"leaf","ph":"X","pid":1,"tid":7,"ts":104,"dur":2,"args":{"calls":1,"own_us":2}},
+            "#}
+        );
+    }
+}
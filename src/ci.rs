@@ -0,0 +1,311 @@
+//! Guard against performance regressions in `cargo test` by comparing the
+//! call tree of a workload with a committed baseline:
+//!
+//! ```no_run
+//! use reqray::ci::RegressionGate;
+//!
+//! #[tracing::instrument]
+//! fn handle_request() {
+//!     // ...
+//! }
+//!
+//! #[test]
+//! fn no_regressions() {
+//!     let report = RegressionGate::new("tests/baselines/handle_request.json")
+//!         .with_runs(20)
+//!         .with_threshold(25.0)
+//!         .check(handle_request)
+//!         .unwrap();
+//!     report.assert_passed();
+//! }
+//! ```
+//!
+//! The first check writes the baseline. Set `REQRAY_UPDATE_BASELINE=1` to
+//! replace it after an intended change, and commit the file.
+//!
+//! Durations are measured with the real clock, so choose thresholds well
+//! above the noise of the CI machines, e.g. with
+//! [with_min_delta](RegressionGate::with_min_delta).
+
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    diff::{CallPathDiff, CallTreeDiff},
+    test_util::capture_with,
+    CallPathPool, CallTreeCollectorBuilder,
+};
+
+/// Runs a workload several times and compares the merged call tree with a
+/// baseline file, see the [module docs](self).
+pub struct RegressionGate {
+    baseline: PathBuf,
+    runs: usize,
+    threshold_percent: f64,
+    min_delta: Duration,
+    update_baseline: bool,
+    builder: CallTreeCollectorBuilder,
+}
+
+/// The contents of a baseline file.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Baseline {
+    runs: usize,
+    call_tree: CallPathPool,
+}
+
+impl RegressionGate {
+    /// Compares with the baseline at `baseline`, which is written if it
+    /// does not exist yet or if the environment variable
+    /// `REQRAY_UPDATE_BASELINE` is `1` or `true`.
+    pub fn new(baseline: impl AsRef<Path>) -> Self {
+        let update_baseline = crate::env::var(
+            &crate::env::process_env,
+            "REQRAY_UPDATE_BASELINE",
+            "true or false",
+            crate::env::parse_bool,
+        );
+        RegressionGate {
+            baseline: baseline.as_ref().to_path_buf(),
+            runs: 10,
+            threshold_percent: 10.0,
+            min_delta: Duration::default(),
+            update_baseline: update_baseline.unwrap_or(false),
+            builder: CallTreeCollectorBuilder::default(),
+        }
+    }
+
+    /// How often to run the workload. The default is `10`. Baselines are
+    /// only comparable with the same number of runs.
+    pub fn with_runs(mut self, runs: usize) -> Self {
+        self.runs = runs.max(1);
+        self
+    }
+
+    /// The growth of the own busy time of a call path in percent from
+    /// which on it counts as a regression, see
+    /// [CallPathDiff::is_regression]. The default is `10`.
+    pub fn with_threshold(mut self, threshold_percent: f64) -> Self {
+        self.threshold_percent = threshold_percent;
+        self
+    }
+
+    /// Ignores regressions whose own busy time grew by less than
+    /// `min_delta` over all runs, e.g. of call paths which are so fast
+    /// that they are dominated by noise. Zero by default.
+    pub fn with_min_delta(mut self, min_delta: Duration) -> Self {
+        self.min_delta = min_delta;
+        self
+    }
+
+    /// Whether to replace the baseline with the current call tree instead
+    /// of comparing with it. Defaults to `REQRAY_UPDATE_BASELINE`.
+    pub fn update_baseline(mut self, update_baseline: bool) -> Self {
+        self.update_baseline = update_baseline;
+        self
+    }
+
+    /// Collects the call trees with this configuration, e.g. to exclude
+    /// spans or to collapse recursion.
+    pub fn with_builder(mut self, builder: CallTreeCollectorBuilder) -> Self {
+        self.builder = builder;
+        self
+    }
+
+    /// Runs `workload` the configured number of times, each time in a
+    /// `run` root span, and compares the merged call tree with the
+    /// baseline.
+    ///
+    /// Fails if the baseline cannot be read or written, or if it was
+    /// recorded with a different number of runs.
+    pub fn check(self, mut workload: impl FnMut()) -> io::Result<RegressionReport> {
+        let runs = self.runs;
+        let mut call_trees = capture_with(self.builder, || {
+            for _ in 0..runs {
+                tracing::info_span!("run").in_scope(&mut workload);
+            }
+        })
+        .into_iter();
+        let mut call_tree = call_trees
+            .next()
+            .ok_or_else(|| io::Error::other("the workload was not collected"))?;
+        for other in call_trees {
+            call_tree.merge(&other);
+        }
+        call_tree.compact();
+
+        let existing = match fs::read(&self.baseline) {
+            Ok(json) => Some(serde_json::from_slice::<Baseline>(&json)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let baseline = match existing {
+            Some(baseline) if !self.update_baseline => baseline,
+            _ => {
+                if let Some(dir) = self.baseline.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                let baseline = Baseline { runs, call_tree };
+                fs::write(&self.baseline, serde_json::to_vec_pretty(&baseline)?)?;
+                return Ok(RegressionReport {
+                    diff: baseline
+                        .call_tree
+                        .diff(&baseline.call_tree)
+                        .with_threshold(self.threshold_percent),
+                    threshold_percent: self.threshold_percent,
+                    min_delta: self.min_delta,
+                    baseline_written: true,
+                });
+            }
+        };
+        if baseline.runs != runs {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "the baseline {} was recorded with {} runs instead of {}",
+                    self.baseline.display(),
+                    baseline.runs,
+                    runs
+                ),
+            ));
+        }
+        Ok(RegressionReport {
+            diff: call_tree
+                .diff(&baseline.call_tree)
+                .with_threshold(self.threshold_percent),
+            threshold_percent: self.threshold_percent,
+            min_delta: self.min_delta,
+            baseline_written: false,
+        })
+    }
+}
+
+/// The result of [RegressionGate::check] with the differences per call
+/// path.
+///
+/// The [Display](fmt::Display) implementation writes the verdict and the
+/// [tree table](CallTreeDiff::tree_table) of the differences.
+#[derive(Debug, Clone)]
+pub struct RegressionReport {
+    diff: CallTreeDiff,
+    threshold_percent: f64,
+    min_delta: Duration,
+    baseline_written: bool,
+}
+
+impl RegressionReport {
+    /// True if no call path regressed.
+    pub fn passed(&self) -> bool {
+        self.regressions().next().is_none()
+    }
+
+    /// The call paths whose own busy time grew by more than the threshold
+    /// and the minimum delta.
+    pub fn regressions(&self) -> impl Iterator<Item = &CallPathDiff> {
+        let threshold_percent = self.threshold_percent;
+        let min_delta = self.min_delta;
+        self.diff.call_paths().iter().filter(move |diff| {
+            diff.is_regression(threshold_percent)
+                && diff.own_busy.saturating_sub(diff.baseline_own_busy) >= min_delta
+        })
+    }
+
+    /// The differences of all call paths.
+    pub fn diff(&self) -> &CallTreeDiff {
+        &self.diff
+    }
+
+    /// True if the baseline was written instead of compared with.
+    pub fn baseline_written(&self) -> bool {
+        self.baseline_written
+    }
+
+    /// Panics with the report unless it [passed](Self::passed).
+    #[track_caller]
+    pub fn assert_passed(&self) {
+        assert!(self.passed(), "{}", self);
+    }
+}
+
+impl fmt::Display for RegressionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let regressions = self.regressions().count();
+        if self.baseline_written {
+            writeln!(f, "Wrote a new baseline")?;
+        } else if regressions == 0 {
+            writeln!(f, "No regressions above {}%", self.threshold_percent)?;
+        } else {
+            writeln!(
+                f,
+                "{} call path{} regressed by more than {}%",
+                regressions,
+                if regressions == 1 { "" } else { "s" },
+                self.threshold_percent
+            )?;
+        }
+        write!(f, "\n{}", self.diff.tree_table())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use quanta::Clock;
+
+    use super::RegressionGate;
+    use crate::CallTreeCollectorBuilder;
+
+    #[test]
+    fn compares_with_baseline() {
+        let path =
+            std::env::temp_dir().join(format!("reqray-ci-{}/baseline.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let check = |query_busy: u64| {
+            let (clock, mock) = Clock::mock();
+            RegressionGate::new(&path)
+                .update_baseline(false)
+                .with_runs(3)
+                .with_min_delta(Duration::from_nanos(10))
+                .with_builder(CallTreeCollectorBuilder::default().clock(clock))
+                .check(|| {
+                    tracing::info_span!("query").in_scope(|| mock.increment(query_busy));
+                    tracing::info_span!("render").in_scope(|| mock.increment(100));
+                })
+                .unwrap()
+        };
+
+        let report = check(100);
+        assert!(report.baseline_written());
+        assert!(report.passed());
+
+        let report = check(105);
+        assert!(!report.baseline_written());
+        assert!(report.passed(), "{}", report);
+
+        let report = check(200);
+        assert!(!report.passed());
+        assert_eq!(
+            report
+                .regressions()
+                .map(|diff| diff.call_path.as_str())
+                .collect::<Vec<_>>(),
+            vec!["run/query"]
+        );
+        assert!(report
+            .to_string()
+            .starts_with("1 call path regressed by more than 10%\n"));
+
+        let error = RegressionGate::new(&path)
+            .update_baseline(false)
+            .with_runs(4)
+            .check(|| {})
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
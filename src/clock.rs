@@ -0,0 +1,252 @@
+//! The clock used to time spans and sanity checks for it.
+
+#[cfg(all(feature = "quanta", not(target_arch = "wasm32")))]
+use std::sync::OnceLock;
+use std::{sync::Arc, time::Duration};
+
+/// `std::time::Instant` panics in browsers, `web_time` uses
+/// `performance.now()` there instead.
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+pub(crate) use web_time::Instant;
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+pub(crate) use std::time::Instant;
+
+/// The current time as [std::time::SystemTime], whose `now` panics in
+/// browsers.
+#[cfg(all(
+    target_arch = "wasm32",
+    target_os = "unknown",
+    any(
+        feature = "csv",
+        feature = "display",
+        feature = "html",
+        feature = "otel"
+    )
+))]
+pub(crate) fn system_time_now() -> std::time::SystemTime {
+    use web_time::web::SystemTimeExt;
+
+    web_time::SystemTime::now().to_std()
+}
+
+/// The current time as [std::time::SystemTime].
+#[cfg(all(
+    not(all(target_arch = "wasm32", target_os = "unknown")),
+    any(
+        feature = "csv",
+        feature = "display",
+        feature = "html",
+        feature = "otel"
+    )
+))]
+pub(crate) fn system_time_now() -> std::time::SystemTime {
+    std::time::SystemTime::now()
+}
+
+/// A source of timestamps to time spans, see
+/// [CallTreeCollectorBuilder::clock](crate::CallTreeCollectorBuilder::clock).
+///
+/// Implemented for [InstantClock] and, with the `quanta` feature, for
+/// [quanta::Clock](https://docs.rs/quanta), which is the default. Timestamps are raw values which
+/// only need to be meaningful to [delta](Self::delta).
+pub trait ReqrayClock: Send + Sync + 'static {
+    /// A timestamp at the start of a measured period.
+    fn start(&self) -> u64;
+
+    /// A timestamp at the end of a measured period.
+    fn end(&self) -> u64 {
+        self.start()
+    }
+
+    /// The time between two timestamps, zero if `end` is before `start`.
+    fn delta(&self, start: u64, end: u64) -> Duration;
+}
+
+#[cfg(any(test, all(feature = "quanta", not(target_arch = "wasm32"))))]
+impl ReqrayClock for quanta::Clock {
+    fn start(&self) -> u64 {
+        quanta::Clock::start(self)
+    }
+
+    fn end(&self) -> u64 {
+        quanta::Clock::end(self)
+    }
+
+    fn delta(&self, start: u64, end: u64) -> Duration {
+        quanta::Clock::delta(self, start, end)
+    }
+}
+
+/// A [ReqrayClock] based on [std::time::Instant], or on
+/// `performance.now()` in browsers.
+///
+/// Slower than the TSC based [quanta](https://docs.rs/quanta) clock but
+/// without its calibration, which is unreliable on some platforms, e.g.
+/// some VMs and older ARM boards. It is the default without the `quanta`
+/// feature and on `wasm32` targets.
+#[derive(Debug, Clone, Copy)]
+pub struct InstantClock {
+    origin: Instant,
+}
+
+impl Default for InstantClock {
+    fn default() -> Self {
+        InstantClock {
+            origin: Instant::now(),
+        }
+    }
+}
+
+impl ReqrayClock for InstantClock {
+    /// The nanoseconds since the clock was created.
+    fn start(&self) -> u64 {
+        self.origin.elapsed().as_nanos() as u64
+    }
+
+    fn delta(&self, start: u64, end: u64) -> Duration {
+        Duration::from_nanos(end.saturating_sub(start))
+    }
+}
+
+/// The clock shared by the collector and its statistics.
+pub(crate) type SharedClock = Arc<dyn ReqrayClock>;
+
+/// The clock used if none was configured, together with the skew of
+/// quanta if it was replaced by an [InstantClock].
+///
+/// With `verify`, quanta is checked against [Instant] once per process.
+#[cfg(all(feature = "quanta", not(target_arch = "wasm32")))]
+pub(crate) fn default_clock(verify: bool) -> (SharedClock, Option<f64>) {
+    static QUANTA_SKEW: OnceLock<Option<f64>> = OnceLock::new();
+    select_clock(Arc::new(quanta::Clock::new()), verify, |clock| {
+        *QUANTA_SKEW.get_or_init(|| unreliable_clock_skew(clock))
+    })
+}
+
+/// `clock`, or an [InstantClock] if `verify` is set and `skew_check`
+/// reports a skew for `clock`.
+#[cfg(any(test, all(feature = "quanta", not(target_arch = "wasm32"))))]
+fn select_clock(
+    clock: SharedClock,
+    verify: bool,
+    skew_check: impl FnOnce(&dyn ReqrayClock) -> Option<f64>,
+) -> (SharedClock, Option<f64>) {
+    let skew = if verify { skew_check(&*clock) } else { None };
+    match skew {
+        Some(skew) => (Arc::new(InstantClock::default()), Some(skew)),
+        None => (clock, None),
+    }
+}
+
+/// The clock used if none was configured. [InstantClock] needs no
+/// verification.
+#[cfg(not(all(feature = "quanta", not(target_arch = "wasm32"))))]
+pub(crate) fn default_clock(_verify: bool) -> (SharedClock, Option<f64>) {
+    (Arc::new(InstantClock::default()), None)
+}
+
+/// How long to compare the clock against [Instant] per attempt.
+#[cfg(any(test, all(feature = "quanta", not(target_arch = "wasm32"))))]
+const VERIFICATION_PERIOD: Duration = Duration::from_millis(10);
+
+/// How often the comparison is repeated before the clock is considered
+/// unreliable, so that a single preemption does not cause a fallback.
+#[cfg(any(test, all(feature = "quanta", not(target_arch = "wasm32"))))]
+const VERIFICATION_ATTEMPTS: usize = 3;
+
+/// The maximum tolerated relative deviation from [Instant].
+#[cfg(any(test, all(feature = "quanta", not(target_arch = "wasm32"))))]
+const MAX_SKEW: f64 = 0.05;
+
+/// Compares `clock` against [Instant] for [VERIFICATION_PERIOD] and
+/// returns the relative deviation if it exceeds [MAX_SKEW] in all
+/// [VERIFICATION_ATTEMPTS].
+///
+/// On some platforms (e.g. some Windows VMs or ARM boards), the TSC
+/// calibration of `quanta` is unreliable and all measured durations are
+/// skewed.
+#[cfg(any(test, all(feature = "quanta", not(target_arch = "wasm32"))))]
+pub(crate) fn unreliable_clock_skew(clock: &dyn ReqrayClock) -> Option<f64> {
+    let mut min_skew = f64::INFINITY;
+    for _ in 0..VERIFICATION_ATTEMPTS {
+        let skew = clock_skew(clock);
+        if skew <= MAX_SKEW {
+            return None;
+        }
+        min_skew = min_skew.min(skew);
+    }
+    Some(min_skew)
+}
+
+/// The relative deviation of `clock` from [Instant] over
+/// [VERIFICATION_PERIOD].
+#[cfg(any(test, all(feature = "quanta", not(target_arch = "wasm32"))))]
+fn clock_skew(clock: &dyn ReqrayClock) -> f64 {
+    let instant_start = Instant::now();
+    let clock_start = clock.start();
+    std::thread::sleep(VERIFICATION_PERIOD);
+    let clock_end = clock.end();
+    let instant_elapsed = instant_start.elapsed().as_secs_f64();
+    let clock_elapsed = clock.delta(clock_start, clock_end).as_secs_f64();
+
+    (clock_elapsed - instant_elapsed).abs() / instant_elapsed
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use quanta::Clock;
+
+    use super::{InstantClock, ReqrayClock, SharedClock};
+
+    #[test]
+    #[ignore = "timing sensitive, fails on loaded machines"]
+    fn real_clock_is_reliable() {
+        assert_eq!(super::unreliable_clock_skew(&Clock::new()), None);
+        assert_eq!(super::unreliable_clock_skew(&InstantClock::default()), None);
+    }
+
+    #[test]
+    fn mock_clock_is_unreliable() {
+        let (clock, _mock) = Clock::mock();
+        assert_eq!(super::unreliable_clock_skew(&clock), Some(1.0));
+    }
+
+    /// Whether `clock` advances with the time, unlike a mock clock.
+    fn advances(clock: &SharedClock) -> bool {
+        let start = clock.start();
+        std::thread::sleep(Duration::from_millis(1));
+        clock.delta(start, clock.end()) >= Duration::from_millis(1)
+    }
+
+    #[test]
+    fn fall_back_to_instant_clock() {
+        let (mock_clock, _mock) = Clock::mock();
+        let (clock, skew) = super::select_clock(Arc::new(mock_clock), true, |_| Some(0.5));
+        assert_eq!(skew, Some(0.5));
+        assert!(advances(&clock));
+
+        let (mock_clock, _mock) = Clock::mock();
+        let (clock, skew) = super::select_clock(Arc::new(mock_clock), true, |_| None);
+        assert_eq!(skew, None);
+        assert!(!advances(&clock));
+
+        let (mock_clock, _mock) = Clock::mock();
+        let (clock, skew) =
+            super::select_clock(Arc::new(mock_clock), false, |_| panic!("not verified"));
+        assert_eq!(skew, None);
+        assert!(!advances(&clock));
+    }
+
+    #[test]
+    fn instant_clock() {
+        let clock = InstantClock::default();
+        let start = clock.start();
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        let end = clock.end();
+        assert!(clock.delta(start, end) >= std::time::Duration::from_millis(1));
+        assert_eq!(clock.delta(end, start), std::time::Duration::ZERO);
+    }
+}
@@ -0,0 +1,25 @@
+//! The CPU time of the current thread, see
+//! [CallTreeCollectorBuilder::cpu_time](crate::CallTreeCollectorBuilder::cpu_time).
+
+use std::time::Duration;
+
+/// The CPU time consumed by the current thread so far, `None` if the
+/// platform does not provide it.
+#[cfg(unix)]
+pub(crate) fn thread_cpu_time() -> Option<Duration> {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `time` is a valid pointer for the duration of the call.
+    let result = unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut time) };
+    if result != 0 {
+        return None;
+    }
+    Some(Duration::new(time.tv_sec as u64, time.tv_nsec as u32))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn thread_cpu_time() -> Option<Duration> {
+    None
+}
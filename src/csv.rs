@@ -0,0 +1,272 @@
+//! Export call paths as CSV or TSV rows for ad-hoc analysis, e.g. in a
+//! spreadsheet, pandas or DuckDB:
+//!
+//! ```text
+//! tree,path,retry,depth,calls,alive_ns,busy_ns,own_busy_ns
+//! 1644177717103-4242-0,request,false,0,1,12051230,12051230,119480
+//! 1644177717103-4242-0,request;nested,false,1,2,11931750,11931750,318410
+//! ```
+//!
+//! Every call tree gets an id which is unique across runs appending to the
+//! same file: the time the processor was created in milliseconds since the
+//! Unix epoch, the process id and the number of the call tree. The path
+//! contains the names of all spans from the root, separated by `;`. Retries
+//! of a span, see
+//! [CallPathTiming::is_retry](crate::CallPathTiming::is_retry), have the
+//! same path as the first attempt but `true` in the `retry` column.
+//! Durations are in nanoseconds.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::UNIX_EPOCH,
+};
+
+use crate::{clock::system_time_now, CallPathPool, FinishedCallTreeProcessor};
+
+/// A [FinishedCallTreeProcessor] which appends a row per call path to a
+/// writer, e.g. a file.
+///
+/// ```no_run
+/// use reqray::{csv::CsvProcessor, CallTreeCollectorBuilder};
+///
+/// let collector = CallTreeCollectorBuilder::default()
+///     .build_with_collector(CsvProcessor::append_to_file("call_paths.csv").unwrap());
+/// ```
+pub struct CsvProcessor<W: Write> {
+    separator: char,
+    /// Prefix of the tree ids, unique for this processor.
+    run: String,
+    state: Mutex<State<W>>,
+}
+
+struct State<W> {
+    writer: W,
+    needs_header: bool,
+    trees: u64,
+}
+
+impl CsvProcessor<File> {
+    /// Appends to the file at `path`, creating it if necessary. The header
+    /// row is only written to empty files.
+    pub fn append_to_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let needs_header = file.metadata()?.len() == 0;
+        Ok(Self::with_header(file, needs_header))
+    }
+}
+
+impl<W: Write> CsvProcessor<W> {
+    /// Writes a header row and then the call paths to `writer`.
+    pub fn new(writer: W) -> Self {
+        Self::with_header(writer, true)
+    }
+
+    fn with_header(writer: W, needs_header: bool) -> Self {
+        CsvProcessor {
+            separator: ',',
+            run: run_id(),
+            state: Mutex::new(State {
+                writer,
+                needs_header,
+                trees: 0,
+            }),
+        }
+    }
+
+    /// Separates fields with `separator` instead of `,`, e.g. `'\t'` for
+    /// TSV.
+    pub fn with_separator(mut self, separator: char) -> Self {
+        self.separator = separator;
+        self
+    }
+}
+
+impl<W: Write> FinishedCallTreeProcessor for CsvProcessor<W> {
+    fn process_finished_call(&self, pool: CallPathPool) {
+        self.write(&pool)
+    }
+
+    fn process_shared_call(&self, pool: Arc<CallPathPool>) {
+        self.write(&pool)
+    }
+}
+
+impl<W: Write> CsvProcessor<W> {
+    fn write(&self, pool: &CallPathPool) {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let tree = state.trees;
+        state.trees += 1;
+
+        // Formatted up front, so that rows of concurrently finished call
+        // trees are never interleaved.
+        let mut out = String::new();
+        if state.needs_header {
+            let header = [
+                "tree",
+                "path",
+                "retry",
+                "depth",
+                "calls",
+                "alive_ns",
+                "busy_ns",
+                "own_busy_ns",
+            ];
+            out.push_str(&header.join(&self.separator.to_string()));
+            out.push('\n');
+        }
+        let tree = format!("{}-{}", self.run, tree);
+        write_rows(pool, &tree, self.separator, &mut out);
+
+        let result = state
+            .writer
+            .write_all(out.as_bytes())
+            .and_then(|_| state.writer.flush());
+        match result {
+            Ok(()) => state.needs_header = false,
+            Err(e) => tracing::warn!("Could not write call path rows: {}", e),
+        }
+    }
+}
+
+/// The creation time in milliseconds since the Unix epoch and, where
+/// available, the process id.
+fn run_id() -> String {
+    let millis = system_time_now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    // Browsers have no processes.
+    if cfg!(target_arch = "wasm32") {
+        millis.to_string()
+    } else {
+        format!("{}-{}", millis, std::process::id())
+    }
+}
+
+/// Appends a row for every call path in `pool` to `out`, in depth first
+/// order.
+fn write_rows(pool: &CallPathPool, tree: &str, separator: char, out: &mut String) {
+    for (depth, node) in pool.iter_depth_first() {
+        let fields = [
+            quote(tree, separator),
+            quote(&node.path_string(pool, ";"), separator),
+            node.is_retry().to_string(),
+            depth.to_string(),
+            node.call_count().to_string(),
+            node.span_alive().as_nanos().to_string(),
+            node.sum_with_children().as_nanos().to_string(),
+            node.sum_without_children().as_nanos().to_string(),
+        ];
+        out.push_str(&fields.join(&separator.to_string()));
+        out.push('\n');
+    }
+}
+
+/// Quotes `field` as described in RFC 4180 if it contains the separator,
+/// quotes or line breaks.
+fn quote(field: &str, separator: char) -> String {
+    if field.contains([separator, '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use quanta::Mock;
+
+    use super::{quote, CsvProcessor};
+    use crate::{
+        internal::test::{collect_call_trees, fetch},
+        FinishedCallTreeProcessor,
+    };
+
+    #[tracing::instrument]
+    fn leaf(mock: &Mock) {
+        mock.increment(1_000);
+    }
+
+    #[test]
+    fn rows() {
+        let call_trees = collect_call_trees(|mock| {
+            for _ in 0..2 {
+                tracing::info_span!("request").in_scope(|| {
+                    mock.increment(10);
+                    tracing::info_span!("load, \"users\"").in_scope(|| {
+                        leaf(&mock);
+                        leaf(&mock);
+                    });
+                });
+            }
+        });
+        let mut processor = CsvProcessor::new(Vec::new());
+        processor.run = "run".to_string();
+        for call_tree in call_trees {
+            processor.process_finished_call(call_tree);
+        }
+        let out = processor.state.into_inner().unwrap().writer;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            indoc::indoc! {r#"
+                tree,path,retry,depth,calls,alive_ns,busy_ns,own_busy_ns
+                run-0,request,false,0,1,2010,2010,10
+                run-0,"request;load, ""users""",false,1,1,2000,2000,0
+                run-0,"request;load, ""users"";leaf",false,2,2,2000,2000,2000
+                run-1,request,false,0,1,2010,2010,10
+                run-1,"request;load, ""users""",false,1,1,2000,2000,0
+                run-1,"request;load, ""users"";leaf",false,2,2,2000,2000,2000
+            "#}
+        );
+    }
+
+    #[test]
+    fn retries() {
+        let mut processor = CsvProcessor::new(Vec::new()).with_separator('\t');
+        processor.run = "run".to_string();
+        for call_tree in collect_call_trees(|mock| fetch(&mock)) {
+            processor.process_finished_call(call_tree);
+        }
+        let out = processor.state.into_inner().unwrap().writer;
+        let rows = String::from_utf8(out).unwrap();
+        let rows = rows
+            .lines()
+            .skip(1)
+            .map(|row| row.split('\t').take(4).collect::<Vec<_>>().join(" "))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            vec![
+                "run-0 fetch false 0",
+                "run-0 fetch;attempt false 1",
+                "run-0 fetch;attempt;one_ns false 2",
+                "run-0 fetch;attempt true 1",
+                "run-0 fetch;attempt;one_ns false 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn unique_tree_ids() {
+        let first = CsvProcessor::new(Vec::new());
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let second = CsvProcessor::new(Vec::new());
+        assert_ne!(first.run, second.run);
+        assert!(first.run.ends_with(&format!("-{}", std::process::id())));
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote("request;load", ','), "request;load");
+        assert_eq!(quote("a,b", ','), "\"a,b\"");
+        assert_eq!(quote("a,b", '\t'), "a,b");
+        assert_eq!(quote("a\tb", '\t'), "\"a\tb\"");
+        assert_eq!(quote("say \"hi\"", ','), "\"say \"\"hi\"\"\"");
+    }
+}
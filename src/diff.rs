@@ -0,0 +1,487 @@
+//! Compare a call tree with a baseline, e.g. the x-ray of a slow request
+//! with the one of a recorded normal request, see [CallPathPool::diff].
+
+use std::{fmt, time::Duration};
+
+use crate::{CallPathPool, CallPathTiming};
+
+/// The per call path differences between a call tree and a baseline, see
+/// [CallPathPool::diff].
+///
+/// The [Display](fmt::Display) implementation writes a table of the
+/// differences and marks regressions with `▲` and improvements with `▼`:
+///
+/// ```text
+///   Δ calls │  Δ busy ms │ Δ own busy ms │  Δ own % │ call path
+/// ──────────┼────────────┼───────────────┼──────────┼───────────────────────
+///        +0 ┊     +2.350 ┊        +0.000 ┊    +0.0% ┊   request
+///        +0 ┊     +2.400 ┊        +2.400 ┊ +2400.0% ┊ ▲   query
+///        -1 ┊     -0.050 ┊        -0.050 ┊  removed ┊ ▼   cache_lookup
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallTreeDiff {
+    call_paths: Vec<CallPathDiff>,
+    threshold: f64,
+}
+
+/// The difference of a single call path, see [CallTreeDiff].
+///
+/// Call paths which are only in one of the call trees have zero calls and
+/// durations in the other.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallPathDiff {
+    /// The path of the call, e.g. `request/list/load_item`.
+    pub call_path: String,
+    /// The name of the call path.
+    pub name: String,
+    /// The depth of the call path, `0` for the root.
+    pub depth: usize,
+    pub calls: usize,
+    pub baseline_calls: usize,
+    /// The summed busy time including children, see
+    /// [CallPathTiming::sum_with_children].
+    pub busy: Duration,
+    pub baseline_busy: Duration,
+    /// The summed busy time excluding children, see
+    /// [CallPathTiming::sum_without_children].
+    pub own_busy: Duration,
+    pub baseline_own_busy: Duration,
+}
+
+impl CallPathDiff {
+    /// True if the call path is not in the baseline.
+    pub fn is_new(&self) -> bool {
+        self.baseline_calls == 0
+    }
+
+    /// True if the call path is only in the baseline.
+    pub fn is_removed(&self) -> bool {
+        self.calls == 0
+    }
+
+    /// The change of the own busy time in percent of the baseline, `None`
+    /// for new call paths.
+    pub fn own_busy_change_percent(&self) -> Option<f64> {
+        if self.is_new() {
+            return None;
+        }
+        let baseline = self.baseline_own_busy.as_secs_f64();
+        let delta = self.own_busy.as_secs_f64() - baseline;
+        if baseline == 0.0 {
+            return Some(if delta > 0.0 { f64::INFINITY } else { 0.0 });
+        }
+        Some(100.0 * delta / baseline)
+    }
+
+    /// True if the own busy time grew by more than `threshold_percent`,
+    /// or if the call path is new and was busy.
+    pub fn is_regression(&self, threshold_percent: f64) -> bool {
+        match self.own_busy_change_percent() {
+            Some(percent) => percent > threshold_percent,
+            None => self.own_busy > Duration::default(),
+        }
+    }
+
+    /// True if the own busy time shrank by more than `threshold_percent`.
+    pub fn is_improvement(&self, threshold_percent: f64) -> bool {
+        matches!(self.own_busy_change_percent(), Some(percent) if percent < -threshold_percent)
+    }
+}
+
+impl CallTreeDiff {
+    /// A table with the busy times of the baseline and the call tree side
+    /// by side and their differences, in the tree layout of the call tree
+    /// tables, e.g. to review regressions in a terminal or a CI log:
+    ///
+    /// ```text
+    ///  base busy ms │    busy ms │     Δ busy │  Δ busy % │ Δ own busy │ call tree
+    /// ──────────────┼────────────┼────────────┼───────────┼────────────┼───────────────────────
+    ///         1.150 ┊      3.500 ┊   +2.350ms ┊   +204.3% ┊   +0.000ms ┊   ┬ request
+    ///         0.100 ┊      2.500 ┊   +2.400ms ┊  +2400.0% ┊   +2.400ms ┊ ▲ ├─ query
+    ///         0.050 ┊            ┊   -0.050ms ┊   removed ┊   -0.050ms ┊ ▼ ╰─ cache_lookup
+    /// ```
+    ///
+    /// Regressions and improvements of the own busy time are marked like
+    /// in the [Display](fmt::Display) implementation.
+    pub fn tree_table(&self) -> DiffTreeTable<'_> {
+        DiffTreeTable { diff: self }
+    }
+
+    /// All call paths of both call trees, in depth-first order. Call paths
+    /// only in the baseline come after their siblings.
+    pub fn call_paths(&self) -> &[CallPathDiff] {
+        &self.call_paths
+    }
+
+    /// The change of the own busy time in percent from which on call paths
+    /// count as regressions or improvements. The default is `10`.
+    pub fn with_threshold(mut self, threshold_percent: f64) -> Self {
+        self.threshold = threshold_percent;
+        self
+    }
+
+    /// The call paths whose own busy time grew by more than the threshold,
+    /// see [CallPathDiff::is_regression].
+    pub fn regressions(&self) -> impl Iterator<Item = &CallPathDiff> {
+        let threshold = self.threshold;
+        self.call_paths
+            .iter()
+            .filter(move |diff| diff.is_regression(threshold))
+    }
+}
+
+impl CallPathPool {
+    /// Compares this call tree with `baseline` call path by call path.
+    ///
+    /// Call paths are matched like in [merge](Self::merge). Events are
+    /// ignored.
+    ///
+    /// ```
+    /// use reqray::CallPathPool;
+    ///
+    /// fn assert_no_regressions(call_tree: &CallPathPool, baseline: &CallPathPool) {
+    ///     let diff = call_tree.diff(baseline).with_threshold(50.0);
+    ///     assert!(diff.regressions().next().is_none(), "{}", diff);
+    /// }
+    /// ```
+    pub fn diff(&self, baseline: &CallPathPool) -> CallTreeDiff {
+        let mut call_paths = Vec::new();
+        diff_call_paths(
+            self,
+            Some(self.root()),
+            baseline,
+            Some(baseline.root()),
+            "",
+            0,
+            &mut call_paths,
+        );
+        CallTreeDiff {
+            call_paths,
+            threshold: 10.0,
+        }
+    }
+}
+
+/// Adds the difference of `node` and `baseline_node` and their children.
+fn diff_call_paths(
+    pool: &CallPathPool,
+    node: Option<&CallPathTiming>,
+    baseline: &CallPathPool,
+    baseline_node: Option<&CallPathTiming>,
+    parent_path: &str,
+    depth: usize,
+    call_paths: &mut Vec<CallPathDiff>,
+) {
+    let any_node = match node.or(baseline_node) {
+        Some(node) => node,
+        None => return,
+    };
+    let call_path = if parent_path.is_empty() {
+        any_node.name().to_string()
+    } else {
+        format!("{}/{}", parent_path, any_node.name())
+    };
+    call_paths.push(CallPathDiff {
+        name: any_node.name().to_string(),
+        depth,
+        calls: node.map_or(0, CallPathTiming::call_count),
+        baseline_calls: baseline_node.map_or(0, CallPathTiming::call_count),
+        busy: node
+            .map(CallPathTiming::sum_with_children)
+            .unwrap_or_default(),
+        baseline_busy: baseline_node
+            .map(CallPathTiming::sum_with_children)
+            .unwrap_or_default(),
+        own_busy: node
+            .map(CallPathTiming::sum_without_children)
+            .unwrap_or_default(),
+        baseline_own_busy: baseline_node
+            .map(CallPathTiming::sum_without_children)
+            .unwrap_or_default(),
+        call_path: call_path.clone(),
+    });
+
+    let sorted_children = |node: Option<&CallPathTiming>| {
+        let mut children = node
+            .map(|node| node.children().copied().collect::<Vec<_>>())
+            .unwrap_or_default();
+        children.sort();
+        children
+    };
+    let mut baseline_children = sorted_children(baseline_node)
+        .into_iter()
+        .map(|id| &baseline[id])
+        .filter(|child| !child.is_event())
+        .collect::<Vec<_>>();
+    for child_id in sorted_children(node) {
+        let child = &pool[child_id];
+        if child.is_event() {
+            continue;
+        }
+        let baseline_child = baseline_children
+            .iter()
+            .position(|baseline_child| child.looks_like(baseline_child))
+            .map(|idx| baseline_children.remove(idx));
+        diff_call_paths(
+            pool,
+            Some(child),
+            baseline,
+            baseline_child,
+            &call_path,
+            depth + 1,
+            call_paths,
+        );
+    }
+    for baseline_child in baseline_children {
+        diff_call_paths(
+            pool,
+            None,
+            baseline,
+            Some(baseline_child),
+            &call_path,
+            depth + 1,
+            call_paths,
+        );
+    }
+}
+
+/// Writes `nanos` as signed milliseconds with three decimals.
+fn signed_millis(nanos: i128) -> String {
+    let sign = if nanos < 0 { '-' } else { '+' };
+    let micros = nanos.unsigned_abs() / 1_000;
+    format!("{}{}.{:03}", sign, micros / 1_000, micros % 1_000)
+}
+
+fn delta(current: Duration, baseline: Duration) -> i128 {
+    current.as_nanos() as i128 - baseline.as_nanos() as i128
+}
+
+impl fmt::Display for CallTreeDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "  Δ calls │  Δ busy ms │ Δ own busy ms │  Δ own % │ call path"
+        )?;
+        writeln!(
+            f,
+            "──────────┼────────────┼───────────────┼──────────┼───────────────────────"
+        )?;
+        for diff in &self.call_paths {
+            let calls = diff.calls as i128 - diff.baseline_calls as i128;
+            let percent = if diff.is_removed() {
+                "removed".to_string()
+            } else {
+                match diff.own_busy_change_percent() {
+                    None => "new".to_string(),
+                    Some(percent) if percent.is_infinite() => "+∞".to_string(),
+                    Some(percent) => format!("{:+.1}%", percent),
+                }
+            };
+            let marker = if diff.is_regression(self.threshold) {
+                '▲'
+            } else if diff.is_improvement(self.threshold) {
+                '▼'
+            } else {
+                ' '
+            };
+            writeln!(
+                f,
+                "{:>9} ┊ {:>10} ┊ {:>13} ┊ {:>8} ┊ {} {:indent$}{}",
+                format!("{:+}", calls),
+                signed_millis(delta(diff.busy, diff.baseline_busy)),
+                signed_millis(delta(diff.own_busy, diff.baseline_own_busy)),
+                percent,
+                marker,
+                "",
+                diff.name,
+                indent = 2 * diff.depth
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats a [CallTreeDiff] as a tree table, see
+/// [CallTreeDiff::tree_table].
+#[derive(Debug, Clone, Copy)]
+pub struct DiffTreeTable<'a> {
+    diff: &'a CallTreeDiff,
+}
+
+/// Writes `nanos` as unsigned milliseconds with three decimals.
+fn millis(nanos: u128) -> String {
+    let micros = nanos / 1_000;
+    format!("{}.{:03}", micros / 1_000, micros % 1_000)
+}
+
+impl fmt::Display for DiffTreeTable<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            " base busy ms │    busy ms │     Δ busy │  Δ busy % │ Δ own busy │ call tree"
+        )?;
+        writeln!(
+            f,
+            "──────────────┼────────────┼────────────┼───────────┼────────────┼───────────────────────"
+        )?;
+        let call_paths = self.diff.call_paths();
+        // Whether the call path at every depth above the current row is
+        // the last of its siblings.
+        let mut last = Vec::new();
+        for (idx, diff) in call_paths.iter().enumerate() {
+            let later = &call_paths[idx + 1..];
+            let is_last = later
+                .iter()
+                .take_while(|later| later.depth >= diff.depth)
+                .all(|later| later.depth != diff.depth);
+            let has_children = later.first().is_some_and(|next| next.depth > diff.depth);
+            last.truncate(diff.depth);
+            last.push(is_last);
+
+            let busy_percent = if diff.is_removed() {
+                "removed".to_string()
+            } else if diff.is_new() {
+                "new".to_string()
+            } else if diff.baseline_busy == Duration::default() {
+                if diff.busy > Duration::default() {
+                    "+∞".to_string()
+                } else {
+                    "+0.0%".to_string()
+                }
+            } else {
+                let delta = delta(diff.busy, diff.baseline_busy) as f64;
+                format!(
+                    "{:+.1}%",
+                    100.0 * delta / diff.baseline_busy.as_nanos() as f64
+                )
+            };
+            let marker = if diff.is_regression(self.diff.threshold) {
+                '▲'
+            } else if diff.is_improvement(self.diff.threshold) {
+                '▼'
+            } else {
+                ' '
+            };
+            let side = |calls: usize, busy: Duration| {
+                if calls == 0 {
+                    String::new()
+                } else {
+                    millis(busy.as_nanos())
+                }
+            };
+            write!(
+                f,
+                "{:>13} ┊ {:>10} ┊ {:>10} ┊ {:>9} ┊ {:>10} ┊ {} ",
+                side(diff.baseline_calls, diff.baseline_busy),
+                side(diff.calls, diff.busy),
+                format!("{}ms", signed_millis(delta(diff.busy, diff.baseline_busy))),
+                busy_percent,
+                format!(
+                    "{}ms",
+                    signed_millis(delta(diff.own_busy, diff.baseline_own_busy))
+                ),
+                marker,
+            )?;
+            if diff.depth > 0 {
+                for is_last in &last[1..diff.depth] {
+                    f.write_str(if *is_last { " " } else { "┊" })?;
+                }
+                f.write_str(if is_last { "╰" } else { "├" })?;
+            }
+            writeln!(f, "{} {}", if has_children { "┬" } else { "─" }, diff.name)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::internal::test::collect_call_trees;
+
+    #[test]
+    fn diff_with_baseline() {
+        let call_trees = collect_call_trees(|mock| {
+            let request = |query: u64, cached: bool| {
+                tracing::info_span!("request").in_scope(|| {
+                    mock.increment(1_000_000);
+                    tracing::info_span!("query").in_scope(|| mock.increment(query));
+                    if cached {
+                        tracing::info_span!("cache_lookup").in_scope(|| mock.increment(50_000));
+                    }
+                })
+            };
+            request(100_000, true);
+            request(2_500_000, false);
+        });
+        let (baseline, slow) = (&call_trees[0], &call_trees[1]);
+        let diff = slow.diff(baseline);
+
+        let query = &diff.call_paths()[1];
+        assert_eq!(query.call_path, "request/query");
+        assert_eq!(query.busy, Duration::from_micros(2_500));
+        assert_eq!(query.baseline_busy, Duration::from_micros(100));
+        assert_eq!(
+            diff.regressions()
+                .map(|diff| diff.call_path.as_str())
+                .collect::<Vec<_>>(),
+            vec!["request/query"]
+        );
+        assert!(diff.call_paths()[2].is_removed());
+
+        assert_eq!(
+            diff.to_string(),
+            indoc::indoc! {r#"
+                  Δ calls │  Δ busy ms │ Δ own busy ms │  Δ own % │ call path
+                ──────────┼────────────┼───────────────┼──────────┼───────────────────────
+                       +0 ┊     +2.350 ┊        +0.000 ┊    +0.0% ┊   request
+                       +0 ┊     +2.400 ┊        +2.400 ┊ +2400.0% ┊ ▲   query
+                       -1 ┊     -0.050 ┊        -0.050 ┊  removed ┊ ▼   cache_lookup
+            "#}
+        );
+
+        assert_eq!(
+            diff.tree_table().to_string(),
+            indoc::indoc! {r#"
+                 base busy ms │    busy ms │     Δ busy │  Δ busy % │ Δ own busy │ call tree
+                ──────────────┼────────────┼────────────┼───────────┼────────────┼───────────────────────
+                        1.150 ┊      3.500 ┊   +2.350ms ┊   +204.3% ┊   +0.000ms ┊   ┬ request
+                        0.100 ┊      2.500 ┊   +2.400ms ┊  +2400.0% ┊   +2.400ms ┊ ▲ ├─ query
+                        0.050 ┊            ┊   -0.050ms ┊   removed ┊   -0.050ms ┊ ▼ ╰─ cache_lookup
+            "#}
+        );
+
+        // No differences to itself.
+        let diff = baseline.diff(baseline);
+        assert!(diff
+            .call_paths()
+            .iter()
+            .all(|diff| diff.calls == diff.baseline_calls
+                && diff.busy == diff.baseline_busy
+                && !diff.is_regression(0.0)));
+    }
+
+    #[test]
+    fn tree_table_layout() {
+        let call_trees = collect_call_trees(|mock| {
+            tracing::info_span!("request").in_scope(|| {
+                tracing::info_span!("a").in_scope(|| {
+                    tracing::info_span!("x").in_scope(|| mock.increment(1_000));
+                    tracing::info_span!("y").in_scope(|| mock.increment(1_000));
+                });
+                tracing::info_span!("b").in_scope(|| mock.increment(1_000));
+            })
+        });
+        let table = call_trees[0].diff(&call_trees[0]).tree_table().to_string();
+        let tree = table
+            .lines()
+            .skip(2)
+            .map(|line| line.rsplit(" ┊ ").next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tree,
+            vec!["  ┬ request", "  ├┬ a", "  ┊├─ x", "  ┊╰─ y", "  ╰─ b"]
+        );
+    }
+}
@@ -1,172 +1,4001 @@
 use core::fmt;
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
-use crate::{CallPathPool, CallPathTiming, FinishedCallTreeProcessor};
+use tracing::Level;
+
+#[cfg(feature = "aggregate")]
+use crate::aggregate::AggregatorHandle;
+use crate::{
+    clock::Instant,
+    dynamic_event::{dispatch_event, DynamicCallsite},
+    CallPathPool, CallPathPoolId, CallPathTiming, FinishedCallTreeProcessor, InFlightTree,
+};
 
 pub struct LoggingCallTreeCollector {
+    formatter: TextTableFormatter,
+    /// `min_root_busy`, `min_root_alive`, `warn_above_busy` and
+    /// `warn_above_alive`, which can be changed at runtime.
+    thresholds: LoggingThresholdsHandle,
+    level: Level,
+    target: &'static str,
+    /// `None` if disabled.
+    rate_limiter: Option<Mutex<RateLimiter>>,
+    /// `None` if disabled.
+    stderr_fallback: Option<Mutex<StderrFallback>>,
+    output_format: OutputFormat,
+    header_location: HeaderLocation,
+    clickable_location: bool,
+    time_split: bool,
+    #[cfg(feature = "aggregate")]
+    aggregates: Option<AggregatorHandle>,
+    /// The `call_tree` field of the next call tree in
+    /// [OutputFormat::Structured].
+    next_call_tree: AtomicU64,
+}
+
+/// Renders call trees as the table which [LoggingCallTreeCollector] logs,
+/// e.g. to show them on demand in a debug endpoint.
+///
+/// It has the same layout options as [LoggingCallTreeCollectorBuilder],
+/// which delegates them to its formatter:
+///
+/// ```
+/// use reqray::{display::TextTableFormatter, CallPathPool};
+///
+/// fn render(pool: &CallPathPool) -> String {
+///     let formatter = TextTableFormatter::new().left_margin(0).max_rows(20);
+///     let mut out = String::new();
+///     formatter.fmt(pool, &mut out).unwrap();
+///     out
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct TextTableFormatter {
     max_call_depth: usize,
     left_margin: usize,
+    duration_unit: DurationUnit,
+    duration_decimals: usize,
+    duration_rounding: DurationRounding,
+    number_format: NumberFormat,
+    sort_children: SortOrder,
+    /// `None` for the default columns.
+    columns: Option<Vec<Column>>,
+    highlight_own_busy: Option<Highlight>,
+    collapse_below: Option<CollapseBelow>,
+    max_rows: Option<usize>,
+    with_target: bool,
+    with_location: bool,
+    max_row_level: Option<Level>,
+    hidden_targets: Vec<String>,
+    target_rollup: Option<TargetRollup>,
+    thread_breakdown: bool,
+}
+
+/// The fields of the event with the table, see [OutputFormat::Table].
+const TABLE_FIELDS: &[&str] = &["message"];
+
+/// The fields of the first event of a call tree, see
+/// [OutputFormat::Structured].
+const STRUCTURED_HEADER_FIELDS: &[&str] = &[
+    "message",
+    "call_tree",
+    "root",
+    "file",
+    "line",
+    "skipped_spans",
+    "untracked_spans",
+];
+
+/// The fields of the event per call path, see [OutputFormat::Structured].
+const STRUCTURED_CALL_PATH_FIELDS: &[&str] = &[
+    "message",
+    "call_tree",
+    "call_path",
+    "calls",
+    "alive_ns",
+    "busy_ns",
+    "own_busy_ns",
+    "io_wait_ns",
+    "errors",
+    "warnings",
+    "retry",
+];
+
+/// The fields of the event, see [OutputFormat::StructuredSummary].
+const STRUCTURED_SUMMARY_FIELDS: &[&str] = &[
+    "message",
+    "root",
+    "file",
+    "line",
+    "total_alive_ms",
+    "total_busy_ms",
+    "own_busy_ms",
+    "call_paths",
+    "degraded",
+    "skipped_spans",
+    "untracked_spans",
+    "tree",
+];
+
+/// Write at most one call tree to stderr per this interval.
+const STDERR_FALLBACK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The rate limit state of the stderr fallback.
+#[derive(Debug, Default)]
+struct StderrFallback {
+    last_written: Option<Instant>,
+    /// The number of call trees not written since `last_written`.
+    suppressed: usize,
 }
 
 pub struct LoggingCallTreeCollectorBuilder {
-    max_call_depth: usize,
-    left_margin: usize,
+    formatter: TextTableFormatter,
+    min_root_busy: Option<Duration>,
+    min_root_alive: Option<Duration>,
+    level: Level,
+    target: &'static str,
+    warn_above_busy: Option<Duration>,
+    warn_above_alive: Option<Duration>,
+    rate_limit: Option<RateLimit>,
+    stderr_fallback: bool,
+    output_format: OutputFormat,
+    header_location: HeaderLocation,
+    clickable_location: bool,
+    time_split: bool,
+    #[cfg(feature = "aggregate")]
+    aggregates: Option<AggregatorHandle>,
+    /// Applied when building, once the target is known.
+    fmt_prefix: Option<FmtPrefix>,
 }
 
-impl LoggingCallTreeCollectorBuilder {
-    pub fn max_call_depth(mut self, max_call_depth: usize) -> Self {
-        self.max_call_depth = max_call_depth;
+/// Describes the prefix that the `tracing_subscriber::fmt` layer writes in
+/// front of every log message, to align the call tree table with the
+/// message with
+/// [align_with_fmt_prefix](LoggingCallTreeCollectorBuilder::align_with_fmt_prefix).
+///
+/// The default matches the default fmt layer: a RFC 3339 timestamp with
+/// microseconds, the level and the target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FmtPrefix {
+    time_width: usize,
+    level: bool,
+    target: bool,
+}
+
+impl Default for FmtPrefix {
+    fn default() -> Self {
+        FmtPrefix {
+            // e.g. `2022-02-06T20:01:57.103747Z`
+            time_width: 27,
+            level: true,
+            target: true,
+        }
+    }
+}
+
+impl FmtPrefix {
+    /// For fmt layers configured with `without_time()`.
+    pub fn without_time(self) -> Self {
+        self.with_time_width(0)
+    }
+
+    /// For fmt layers with a custom timer whose output has this many
+    /// characters.
+    pub fn with_time_width(mut self, time_width: usize) -> Self {
+        self.time_width = time_width;
         self
     }
 
-    pub fn left_margin(mut self, left_margin: usize) -> Self {
-        self.left_margin = left_margin;
+    /// For fmt layers configured with `with_level(level)`.
+    pub fn with_level(mut self, level: bool) -> Self {
+        self.level = level;
         self
     }
 
-    pub fn build(self) -> LoggingCallTreeCollector {
-        LoggingCallTreeCollector {
-            max_call_depth: self.max_call_depth,
-            left_margin: self.left_margin,
+    /// For fmt layers configured with `with_target(target)`.
+    pub fn with_target(mut self, target: bool) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// The number of characters in front of a message logged with
+    /// `target`.
+    fn width(&self, target: &str) -> usize {
+        let mut width = 0;
+        if self.time_width > 0 {
+            width += self.time_width + 1;
+        }
+        if self.level {
+            // right-aligned like ` INFO`
+            width += 5 + 1;
         }
+        if self.target {
+            // e.g. `reqray::display: `
+            width += target.len() + 2;
+        }
+        width
     }
 }
 
-impl Default for LoggingCallTreeCollectorBuilder {
-    fn default() -> Self {
-        LoggingCallTreeCollectorBuilder {
-            max_call_depth: 10,
-            left_margin: 20,
+/// When to color the own busy time of a call path green, yellow or red,
+/// see [highlight_own_busy](LoggingCallTreeCollectorBuilder::highlight_own_busy).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Highlight {
+    /// Yellow from `warm` on, red from `hot` on.
+    Durations { warm: Duration, hot: Duration },
+    /// Relative to the other call paths of the same call tree, e.g.
+    /// `warm: 0.75, hot: 0.95` to color the call paths with the top 25%
+    /// of own busy times yellow and the top 5% red.
+    Percentiles { warm: f64, hot: f64 },
+}
+
+impl Highlight {
+    /// The own busy times from which on call paths are yellow and red.
+    fn thresholds(self, pool: &CallPathPool) -> (Duration, Duration) {
+        match self {
+            Highlight::Durations { warm, hot } => (warm, hot),
+            Highlight::Percentiles { warm, hot } => {
+                let mut own_busy = pool
+                    .flatten("")
+                    .filter(|(_, node)| !node.is_event())
+                    .map(|(_, node)| node.sum_without_children())
+                    .collect::<Vec<_>>();
+                own_busy.sort();
+                let percentile = |p: f64| {
+                    let rank = (p.clamp(0.0, 1.0) * own_busy.len() as f64).ceil() as usize;
+                    own_busy
+                        .get(rank.saturating_sub(1))
+                        .copied()
+                        .unwrap_or_default()
+                };
+                (percentile(warm), percentile(hot))
+            }
         }
     }
 }
 
-impl FinishedCallTreeProcessor for LoggingCallTreeCollector {
-    fn process_finished_call(&self, pool: CallPathPool) {
-        let root = pool.root();
-        tracing::info!(
-            "Call summary of {}@{}:{}\n\n{}",
-            root.static_span_meta().name(),
-            root.static_span_meta().file().unwrap_or("unknown"),
-            root.static_span_meta().line().unwrap_or(0),
-            DisplayableCallPathTiming {
-                max_call_depth: self.max_call_depth,
-                left_margin: self.left_margin,
-                pool: &pool,
-                root
-            }
-        )
+/// A cheaply clonable handle to change the thresholds of a
+/// [LoggingCallTreeCollector] at runtime, see
+/// [LoggingCallTreeCollector::thresholds_handle]:
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use reqray::{display::LoggingCallTreeCollectorBuilder, CallTreeCollectorBuilder};
+///
+/// let logging = LoggingCallTreeCollectorBuilder::default()
+///     .min_root_busy(Duration::from_millis(100))
+///     .build();
+/// let thresholds = logging.thresholds_handle();
+/// let collector = CallTreeCollectorBuilder::default().build_with_collector(logging);
+///
+/// // Investigate a production issue.
+/// thresholds.set_min_root_busy(None);
+/// ```
+///
+/// Changes apply to the call trees logged afterwards.
+#[derive(Clone)]
+pub struct LoggingThresholdsHandle {
+    inner: Arc<ThresholdsInner>,
+}
+
+/// The thresholds in nanoseconds, `u64::MAX` if unset.
+struct ThresholdsInner {
+    min_root_busy: AtomicU64,
+    min_root_alive: AtomicU64,
+    warn_above_busy: AtomicU64,
+    warn_above_alive: AtomicU64,
+}
+
+impl LoggingThresholdsHandle {
+    fn new(
+        min_root_busy: Option<Duration>,
+        min_root_alive: Option<Duration>,
+        warn_above_busy: Option<Duration>,
+        warn_above_alive: Option<Duration>,
+    ) -> Self {
+        LoggingThresholdsHandle {
+            inner: Arc::new(ThresholdsInner {
+                min_root_busy: AtomicU64::new(to_nanos(min_root_busy)),
+                min_root_alive: AtomicU64::new(to_nanos(min_root_alive)),
+                warn_above_busy: AtomicU64::new(to_nanos(warn_above_busy)),
+                warn_above_alive: AtomicU64::new(to_nanos(warn_above_alive)),
+            }),
+        }
+    }
+
+    /// See [LoggingCallTreeCollectorBuilder::min_root_busy].
+    pub fn min_root_busy(&self) -> Option<Duration> {
+        from_nanos(&self.inner.min_root_busy)
+    }
+
+    /// Changes the minimum busy time of logged root spans, `None` to log
+    /// call trees independent of it.
+    pub fn set_min_root_busy(&self, min_root_busy: Option<Duration>) {
+        store_nanos(&self.inner.min_root_busy, min_root_busy);
+    }
+
+    /// See [LoggingCallTreeCollectorBuilder::min_root_alive].
+    pub fn min_root_alive(&self) -> Option<Duration> {
+        from_nanos(&self.inner.min_root_alive)
+    }
+
+    /// Changes the minimum time logged root spans were alive, `None` to
+    /// log call trees independent of it.
+    pub fn set_min_root_alive(&self, min_root_alive: Option<Duration>) {
+        store_nanos(&self.inner.min_root_alive, min_root_alive);
+    }
+
+    /// See [LoggingCallTreeCollectorBuilder::warn_above_busy].
+    pub fn warn_above_busy(&self) -> Option<Duration> {
+        from_nanos(&self.inner.warn_above_busy)
+    }
+
+    /// Changes the busy time of root spans from which on call trees are
+    /// logged at [Level::WARN], `None` to disable it.
+    pub fn set_warn_above_busy(&self, warn_above_busy: Option<Duration>) {
+        store_nanos(&self.inner.warn_above_busy, warn_above_busy);
+    }
+
+    /// See [LoggingCallTreeCollectorBuilder::warn_above_alive].
+    pub fn warn_above_alive(&self) -> Option<Duration> {
+        from_nanos(&self.inner.warn_above_alive)
+    }
+
+    /// Changes the time root spans were alive from which on call trees
+    /// are logged at [Level::WARN], `None` to disable it.
+    pub fn set_warn_above_alive(&self, warn_above_alive: Option<Duration>) {
+        store_nanos(&self.inner.warn_above_alive, warn_above_alive);
+    }
+}
+
+impl fmt::Debug for LoggingThresholdsHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LoggingThresholdsHandle")
+            .field("min_root_busy", &self.min_root_busy())
+            .field("min_root_alive", &self.min_root_alive())
+            .field("warn_above_busy", &self.warn_above_busy())
+            .field("warn_above_alive", &self.warn_above_alive())
+            .finish()
+    }
+}
+
+/// `u64::MAX` for `None`, durations beyond are capped just below.
+fn to_nanos(duration: Option<Duration>) -> u64 {
+    duration.map_or(u64::MAX, |duration| {
+        duration.as_nanos().min(u128::from(u64::MAX - 1)) as u64
+    })
+}
+
+fn store_nanos(threshold: &AtomicU64, duration: Option<Duration>) {
+    threshold.store(to_nanos(duration), Ordering::Relaxed);
+}
+
+fn from_nanos(threshold: &AtomicU64) -> Option<Duration> {
+    match threshold.load(Ordering::Relaxed) {
+        u64::MAX => None,
+        nanos => Some(Duration::from_nanos(nanos)),
+    }
+}
+
+/// Limits how many call trees are logged per root call site, see
+/// [rate_limit](LoggingCallTreeCollectorBuilder::rate_limit).
+///
+/// Every root call site has a token bucket which holds up to `burst`
+/// tokens and is refilled continuously. Logging a call tree takes a token.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    per_minute: f64,
+    burst: f64,
+    always_above_busy: Option<Duration>,
+}
+
+impl RateLimit {
+    /// At most `call_trees` per minute and root call site, which may all
+    /// be logged at once.
+    pub fn per_minute(call_trees: u32) -> Self {
+        RateLimit {
+            per_minute: f64::from(call_trees),
+            burst: f64::from(call_trees.max(1)),
+            always_above_busy: None,
+        }
+    }
+
+    /// At most `burst` call trees are logged at once, after a quiet period.
+    pub fn with_burst(mut self, burst: u32) -> Self {
+        self.burst = f64::from(burst.max(1));
+        self
+    }
+
+    /// Always log call trees whose root span was busy for at least
+    /// `busy`, without taking a token.
+    pub fn always_above_busy(mut self, busy: Duration) -> Self {
+        self.always_above_busy = Some(busy);
+        self
     }
 }
 
+/// The token buckets per root call site, see [RateLimit].
 #[derive(Debug)]
-struct DisplayableCallPathTiming<'a> {
-    max_call_depth: usize,
-    left_margin: usize,
+struct RateLimiter {
+    rate_limit: RateLimit,
+    /// By root call site, e.g. `my_service::request@src/main.rs:12`.
+    buckets: HashMap<String, TokenBucket>,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    refilled_at: Instant,
+    /// The number of call trees suppressed since the last logged one.
+    suppressed: usize,
+}
+
+impl RateLimiter {
+    fn new(rate_limit: RateLimit) -> Self {
+        RateLimiter {
+            rate_limit,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// `None` if the call tree of `root` should be suppressed, otherwise
+    /// the number of call trees suppressed before.
+    fn admit(&mut self, root: &CallPathTiming, now: Instant) -> Option<usize> {
+        let meta = root.span_metadata();
+        let key = format!(
+            "{}::{}@{}:{}",
+            meta.target(),
+            root.name(),
+            meta.file().unwrap_or("unknown"),
+            meta.line().unwrap_or(0)
+        );
+        let rate_limit = self.rate_limit;
+        let bucket = self.buckets.entry(key).or_insert(TokenBucket {
+            tokens: rate_limit.burst,
+            refilled_at: now,
+            suppressed: 0,
+        });
+        let elapsed = now.saturating_duration_since(bucket.refilled_at);
+        bucket.tokens = f64::min(
+            rate_limit.burst,
+            bucket.tokens + elapsed.as_secs_f64() * rate_limit.per_minute / 60.0,
+        );
+        bucket.refilled_at = now;
+
+        let slow =
+            matches!(rate_limit.always_above_busy, Some(busy) if root.sum_with_children() >= busy);
+        if slow {
+            // Slow call trees do not use up tokens.
+        } else if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+        } else {
+            bucket.suppressed += 1;
+            return None;
+        }
+        Some(std::mem::take(&mut bucket.suppressed))
+    }
+}
+
+/// Which children to collapse into a single row, see
+/// [collapse_below](LoggingCallTreeCollectorBuilder::collapse_below).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CollapseBelow {
+    /// Children which were busy for less than this, including their own
+    /// children.
+    Busy(Duration),
+    /// Children whose share in the busy time of the root is less than this
+    /// many percent, see [Column::PercentOfRoot].
+    PercentOfRoot(f64),
+}
+
+impl CollapseBelow {
+    /// The busy time below which children are collapsed.
+    fn threshold(self, root: &CallPathTiming) -> Duration {
+        match self {
+            CollapseBelow::Busy(busy) => busy,
+            CollapseBelow::PercentOfRoot(percent) => root
+                .sum_with_children()
+                .mul_f64(percent.clamp(0.0, 100.0) / 100.0),
+        }
+    }
+}
+
+/// How call paths are grouped in the rollup below the table, see
+/// [target_rollup](LoggingCallTreeCollectorBuilder::target_rollup).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetRollup {
+    /// By their full target, typically the module path.
+    Module,
+    /// By the first segment of their target, typically the crate name.
+    Crate,
+}
+
+impl TargetRollup {
+    fn group(self, target: &str) -> &str {
+        match self {
+            TargetRollup::Module => target,
+            TargetRollup::Crate => target.split("::").next().unwrap_or(target),
+        }
+    }
+}
+
+/// The busy times of all call paths of a target group, see [TargetRollup].
+struct TargetTotals<'a> {
+    group: &'a str,
+    /// The busy time including children, counting nested call paths of
+    /// the same group only once.
+    busy: Duration,
+    own_busy: Duration,
+}
+
+/// Sums up the busy times of `node` and its descendants per group.
+fn target_totals<'a>(
+    rollup: TargetRollup,
     pool: &'a CallPathPool,
-    root: &'a CallPathTiming,
+    node: &'a CallPathTiming,
+    ancestors: &mut Vec<&'a str>,
+    totals: &mut Vec<TargetTotals<'a>>,
+) {
+    if node.is_event() {
+        return;
+    }
+    let group = rollup.group(node.span_metadata().target());
+    let idx = match totals.iter().position(|totals| totals.group == group) {
+        Some(idx) => idx,
+        None => {
+            totals.push(TargetTotals {
+                group,
+                busy: Duration::default(),
+                own_busy: Duration::default(),
+            });
+            totals.len() - 1
+        }
+    };
+    if !ancestors.contains(&group) {
+        totals[idx].busy += node.sum_with_children();
+    }
+    totals[idx].own_busy += node.sum_without_children();
+    ancestors.push(group);
+    for child in node.children() {
+        target_totals(rollup, pool, &pool[*child], ancestors, totals);
+    }
+    ancestors.pop();
 }
 
-impl<'a> fmt::Display for DisplayableCallPathTiming<'a> {
+/// How the location of the root span is written in the header of a call
+/// tree, see [header_location](LoggingCallTreeCollectorBuilder::header_location).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderLocation {
+    /// The path as recorded by the compiler, e.g. `examples/nested.rs` for
+    /// the current crate or an absolute path for dependencies.
+    Full,
+    /// The path relative to the root of its crate, starting with the last
+    /// `src`, `examples`, `tests` or `benches` directory, e.g. `src/lib.rs`
+    /// for both `crates/api/src/lib.rs` and the absolute path of a
+    /// dependency.
+    CrateRelative,
+    /// Only the name of the root span.
+    Omitted,
+}
+
+impl HeaderLocation {
+    fn path(self, file: &str) -> Option<&str> {
+        match self {
+            HeaderLocation::Full => Some(file),
+            HeaderLocation::CrateRelative => {
+                const SEPARATORS: [char; 2] = ['/', '\\'];
+                let start = file
+                    .rmatch_indices(SEPARATORS)
+                    .map(|(idx, _)| idx + 1)
+                    .find(|&idx| {
+                        let dir = file[idx..].split(SEPARATORS).next();
+                        matches!(dir, Some("src" | "examples" | "tests" | "benches"))
+                    })
+                    .unwrap_or(0);
+                Some(&file[start..])
+            }
+            HeaderLocation::Omitted => None,
+        }
+    }
+}
+
+/// The unit of the durations in the call tree table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DurationUnit {
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+}
+
+impl DurationUnit {
+    fn symbol(self) -> &'static str {
+        match self {
+            DurationUnit::Nanoseconds => "ns",
+            DurationUnit::Microseconds => "µs",
+            DurationUnit::Milliseconds => "ms",
+            DurationUnit::Seconds => "s",
+        }
+    }
+
+    /// The number of decimal places down to nanoseconds.
+    fn max_decimals(self) -> usize {
+        match self {
+            DurationUnit::Nanoseconds => 0,
+            DurationUnit::Microseconds => 3,
+            DurationUnit::Milliseconds => 6,
+            DurationUnit::Seconds => 9,
+        }
+    }
+}
+
+/// How durations are rounded to the configured number of decimal places.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DurationRounding {
+    /// Cut off all digits that are not displayed, e.g. `1.2349 ms`
+    /// becomes `1.234`.
+    Truncate,
+    /// Round to the nearest displayed value, e.g. `1.2345 ms` becomes
+    /// `1.235`.
+    Nearest,
+}
+
+/// How numbers are written in the call tree table, see
+/// [number_format](LoggingCallTreeCollectorBuilder::number_format).
+///
+/// The default writes counts with spaces between groups of thousands and
+/// at least four digits, like `0 001`, and durations with a `.` as the
+/// decimal separator and without grouping, like `1234.567`.
+#[derive(Clone)]
+pub struct NumberFormat {
+    decimal_separator: char,
+    grouping_separator: Option<char>,
+    group_durations: bool,
+    custom: Option<Arc<dyn Fn(Number) -> String + Send + Sync>>,
+}
+
+/// A number in the call tree table, see [NumberFormat::with_custom].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Number {
+    /// A count, e.g. of calls.
+    Count(usize),
+    /// A duration, after rounding to the configured decimal places.
+    Duration(Duration),
+    /// A percentage, e.g. `12.5` for 12.5%.
+    Percent(f64),
+}
+
+impl Default for NumberFormat {
+    fn default() -> Self {
+        NumberFormat {
+            decimal_separator: '.',
+            grouping_separator: Some(' '),
+            group_durations: false,
+            custom: None,
+        }
+    }
+}
+
+impl fmt::Debug for NumberFormat {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        writeln!(
-            f,
-            "{:indent$}    # calls │   ∑ alive ms │    ∑ busy ms │ ∑ own busy ms │ span tree",
-            "",
-            indent = self.left_margin
-        )?;
-        writeln!(
-            f,
-            "{:indent$}────────────┼──────────────┼──────────────┼────────────-──┼───────────────────────",
-            "",
-            indent = self.left_margin
-        )?;
-        let mut last = Vec::with_capacity(self.max_call_depth);
-        last.push(true);
-        self.fmt(&mut last, self.root, f)
+        f.debug_struct("NumberFormat")
+            .field("decimal_separator", &self.decimal_separator)
+            .field("grouping_separator", &self.grouping_separator)
+            .field("group_durations", &self.group_durations)
+            .field("custom", &self.custom.is_some())
+            .finish()
     }
 }
 
-impl DisplayableCallPathTiming<'_> {
-    fn fmt(
-        &self,
-        // this is wasteful
-        last: &mut Vec<bool>,
-        node: &CallPathTiming,
-        f: &mut fmt::Formatter<'_>,
-    ) -> fmt::Result {
-        write!(
-            f,
-            "{:indent$}{: >7} {:0>3} ┊ {: >8}.{:0>3} ┊ {: >8}.{:0>3} ┊  {: >8}.{:0>3} ┊ ",
-            "",
-            node.call_count() / 1000,
-            node.call_count() % 1000,
-            node.span_alive().as_micros() / 1000,
-            node.span_alive().as_micros() % 1000,
-            node.sum_with_children().as_micros() / 1000,
-            node.sum_with_children().as_micros() % 1000,
-            node.sum_without_children().as_micros() / 1000,
-            node.sum_without_children().as_micros() % 1000,
-            indent = self.left_margin
-        )?;
+impl NumberFormat {
+    /// The separator between the integer and the fractional part, e.g.
+    /// `,` for many European locales.
+    pub fn with_decimal_separator(mut self, decimal_separator: char) -> Self {
+        self.decimal_separator = decimal_separator;
+        self
+    }
 
-        let child_connector = if node.children().next().is_none() {
-            "─"
-        } else {
-            "┬"
+    /// The separator between groups of thousands, `None` to write numbers
+    /// without grouping.
+    pub fn with_grouping_separator(mut self, grouping_separator: Option<char>) -> Self {
+        self.grouping_separator = grouping_separator;
+        self
+    }
+
+    /// Whether to group the integer part of durations as well.
+    pub fn with_group_durations(mut self, group_durations: bool) -> Self {
+        self.group_durations = group_durations;
+        self
+    }
+
+    /// Formats all numbers with `custom` instead. The result is still
+    /// right-aligned in its column.
+    ///
+    /// ```
+    /// use reqray::display::{LoggingCallTreeCollectorBuilder, Number, NumberFormat};
+    ///
+    /// let builder = LoggingCallTreeCollectorBuilder::default().number_format(
+    ///     NumberFormat::default().with_custom(|number| match number {
+    ///         Number::Count(count) => count.to_string(),
+    ///         Number::Duration(duration) => format!("{:?}", duration),
+    ///         Number::Percent(percent) => format!("{:.0}", percent),
+    ///     }),
+    /// );
+    /// ```
+    pub fn with_custom<F>(mut self, custom: F) -> Self
+    where
+        F: Fn(Number) -> String + Send + Sync + 'static,
+    {
+        self.custom = Some(Arc::new(custom));
+        self
+    }
+
+    /// Writes a count, zero-padded to four digits if grouped.
+    fn count(&self, count: usize) -> String {
+        if let Some(custom) = &self.custom {
+            return custom(Number::Count(count));
+        }
+        match self.grouping_separator {
+            Some(separator) => group_digits(&format!("{:0>4}", count), separator),
+            None => count.to_string(),
+        }
+    }
+
+    /// Writes a small count like the number of errors, without padding.
+    fn small_count(&self, count: usize) -> String {
+        if let Some(custom) = &self.custom {
+            return custom(Number::Count(count));
+        }
+        match self.grouping_separator {
+            Some(separator) => group_digits(&count.to_string(), separator),
+            None => count.to_string(),
+        }
+    }
+
+    /// Writes a number with an integer and a fractional part of
+    /// `fraction_digits` digits.
+    fn decimal(
+        &self,
+        integer: u128,
+        fraction: u128,
+        fraction_digits: usize,
+        group: bool,
+    ) -> String {
+        let mut out = match self.grouping_separator {
+            Some(separator) if group => group_digits(&integer.to_string(), separator),
+            _ => integer.to_string(),
         };
-        match last.len() {
-            1 => writeln!(f, "{} {}", child_connector, node.static_span_meta().name())?,
-            _ => {
-                if last.len() > 2 {
-                    for is_last in last.iter().skip(1).take(last.len() - 2) {
-                        f.write_str(if *is_last { " " } else { "┊" })?;
-                    }
-                }
+        if fraction_digits > 0 {
+            out.push(self.decimal_separator);
+            out.push_str(&format!("{:0>digits$}", fraction, digits = fraction_digits));
+        }
+        out
+    }
+}
+
+/// Inserts `separator` between groups of three digits.
+// `usize::is_multiple_of` needs Rust 1.87.
+#[allow(clippy::manual_is_multiple_of)]
+fn group_digits(digits: &str, separator: char) -> String {
+    let mut out = String::with_capacity(digits.len() + digits.len() / 3);
+    for (idx, digit) in digits.chars().enumerate() {
+        if idx > 0 && (digits.len() - idx) % 3 == 0 {
+            out.push(separator);
+        }
+        out.push(digit);
+    }
+    out
+}
+
+/// The order in which the children of a call path are displayed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    /// The order in which the call paths were first created.
+    FirstCreated,
+    /// The order in which spans of the call paths were first entered,
+    /// see [CallPathTiming::first_entered]. Call paths that were never
+    /// entered come last.
+    ///
+    /// This matches the timeline of sequential code better if spans are
+    /// created ahead of time, e.g. futures that are awaited later.
+    FirstEntered,
+    /// Descending by [CallPathTiming::sum_with_children], so that hot
+    /// paths come first.
+    Busy,
+    /// Descending by [CallPathTiming::call_count].
+    CallCount,
+    /// Alphabetically by [CallPathTiming::name].
+    Name,
+}
+
+/// How call trees are logged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// A table in the message of a single event, drawn with box-drawing
+    /// characters, for human readers.
+    Table,
+    /// A header event for the call tree, followed by one event per call
+    /// path with the aggregated values as separate fields, parents before
+    /// children.
+    ///
+    /// Use this with `tracing_subscriber::fmt().json()` or other machine
+    /// readable outputs which would escape the table into an unreadable
+    /// string. All events of a call tree have the same `call_tree` field,
+    /// call path events are identified by their `call_path` field like
+    /// `request/nested/repeated`. Durations are fields in nanoseconds like
+    /// `busy_ns`.
+    Structured,
+    /// A single event per call tree with the totals of the root as fields
+    /// like `total_busy_ms` and the whole call tree as JSON in the `tree`
+    /// field, e.g.
+    /// `{"name":"request","calls":1,"busy_ns":1200,…,"children":[…]}`.
+    ///
+    /// `tracing` only supports primitive field values, so `tree` is a
+    /// string which needs to be parsed once more. Unlike
+    /// [OutputFormat::Structured], the call tree stays in one record in
+    /// log pipelines.
+    StructuredSummary,
+}
+
+/// A column of the call tree table, see
+/// [columns](LoggingCallTreeCollectorBuilder::columns).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    /// The number of spans, see [CallPathTiming::call_count].
+    Calls,
+    /// The summed time the spans were alive, see
+    /// [CallPathTiming::span_alive].
+    Alive,
+    /// The summed time the spans were busy including their children, see
+    /// [CallPathTiming::sum_with_children].
+    BusySum,
+    /// The mean busy time of a span including its children, see
+    /// [CallPathTiming::mean_busy].
+    MeanBusy,
+    /// The summed time the spans were busy excluding their children, see
+    /// [CallPathTiming::sum_without_children].
+    OwnBusy,
+    /// The summed I/O wait time, see [CallPathTiming::sum_io_wait].
+    IoWait,
+    /// The summed CPU time including children, see
+    /// [CallPathTiming::sum_cpu].
+    Cpu,
+    /// The number of times the spans were entered, see
+    /// [CallPathTiming::poll_count].
+    Polls,
+    /// The summed time between exiting and entering the spans again, see
+    /// [CallPathTiming::idle_time].
+    Idle,
+    /// The number of `ERROR` events, see [CallPathTiming::error_count].
+    Errors,
+    /// The number of `WARN` events, see [CallPathTiming::warn_count].
+    Warnings,
+    /// The share of [Column::BusySum] in the busy time of the root, like
+    /// the inclusive time of profilers.
+    PercentOfRoot,
+    /// [Column::MeanBusy] as a multiple of the mean busy time of the same
+    /// call path in a baseline, e.g. `3.2×` for a step which took more
+    /// than three times as long as usual, see
+    /// [TextTableFormatter::display_compared]. Empty without a baseline.
+    VsAverage,
+}
+
+impl Column {
+    /// The header cell, without the leading space.
+    fn header(self, unit: DurationUnit) -> String {
+        let unit = unit.symbol();
+        match self {
+            Column::Calls => "    # calls │".to_string(),
+            Column::Alive => format!("{: >12} │", format!("∑ alive {}", unit)),
+            Column::BusySum => format!("{: >12} │", format!("∑ busy {}", unit)),
+            Column::MeanBusy => format!("{: >12} │", format!("⌀ busy {}", unit)),
+            Column::OwnBusy => format!("{: >13} │", format!("∑ own busy {}", unit)),
+            Column::IoWait => format!("{: >12} │", format!("∑ io wait {}", unit)),
+            Column::Cpu => format!("{: >12} │", format!("∑ cpu {}", unit)),
+            Column::Polls => "    # polls │".to_string(),
+            Column::Idle => format!("{: >12} │", format!("∑ idle {}", unit)),
+            Column::Errors => " #err │".to_string(),
+            Column::Warnings => " #warn │".to_string(),
+            Column::PercentOfRoot => "% of root busy │".to_string(),
+            Column::VsAverage => "× avg busy │".to_string(),
+        }
+    }
+
+    /// The width of a value cell, without the leading space and the
+    /// trailing ` ┊`.
+    fn width(self) -> usize {
+        match self {
+            Column::Calls | Column::Polls => 11,
+            Column::Alive
+            | Column::BusySum
+            | Column::MeanBusy
+            | Column::IoWait
+            | Column::Cpu
+            | Column::Idle => 12,
+            Column::OwnBusy => 13,
+            Column::Errors => 5,
+            Column::Warnings => 6,
+            Column::PercentOfRoot => 14,
+            Column::VsAverage => 10,
+        }
+    }
+
+    /// The separator cell below the header, without the leading line.
+    fn separator(self) -> &'static str {
+        match self {
+            Column::Calls | Column::Polls => "────────────┼",
+            Column::Alive
+            | Column::BusySum
+            | Column::MeanBusy
+            | Column::IoWait
+            | Column::Cpu
+            | Column::Idle => "─────────────┼",
+            Column::OwnBusy => "───────────-──┼",
+            Column::Errors => "──────┼",
+            Column::Warnings => "───────┼",
+            Column::PercentOfRoot => "───────────────┼",
+            Column::VsAverage => "───────────┼",
+        }
+    }
+}
+
+impl LoggingCallTreeCollectorBuilder {
+    /// The default configuration with the settings from these environment
+    /// variables if they are set, see
+    /// [CallTreeCollectorBuilder::from_env](crate::CallTreeCollectorBuilder::from_env):
+    ///
+    /// * `REQRAY_MAX_DEPTH`, e.g. `15`, see
+    ///   [max_call_depth](Self::max_call_depth).
+    /// * `REQRAY_MIN_ROOT_MS`, e.g. `2.5`, see
+    ///   [min_root_busy](Self::min_root_busy).
+    /// * `REQRAY_FORMAT`, `table`, `structured` or `json` for
+    ///   [OutputFormat::StructuredSummary], see
+    ///   [output_format](Self::output_format).
+    /// * `REQRAY_LEVEL`, e.g. `debug`, see [level](Self::level).
+    ///
+    /// Invalid values are ignored and reported on stderr.
+    pub fn from_env() -> Self {
+        Self::from_lookup(&crate::env::process_env)
+    }
+
+    pub(crate) fn from_lookup(lookup: crate::env::Lookup<'_>) -> Self {
+        use crate::env::{parse_millis, var};
+
+        let mut builder = Self::default();
+        if let Some(depth) = var(lookup, "REQRAY_MAX_DEPTH", "a number", |value| {
+            value.parse().ok()
+        }) {
+            builder = builder.max_call_depth(depth);
+        }
+        if let Some(min) = var(
+            lookup,
+            "REQRAY_MIN_ROOT_MS",
+            "a number of milliseconds",
+            parse_millis,
+        ) {
+            builder = builder.min_root_busy(min);
+        }
+        if let Some(format) = var(
+            lookup,
+            "REQRAY_FORMAT",
+            "table, structured or json",
+            |value| match value.to_ascii_lowercase().as_str() {
+                "table" => Some(OutputFormat::Table),
+                "structured" => Some(OutputFormat::Structured),
+                "json" => Some(OutputFormat::StructuredSummary),
+                _ => None,
+            },
+        ) {
+            builder = builder.output_format(format);
+        }
+        if let Some(level) = var(lookup, "REQRAY_LEVEL", "a level like info", |value| {
+            value.parse().ok()
+        }) {
+            builder = builder.level(level);
+        }
+        builder
+    }
+
+    pub fn max_call_depth(mut self, max_call_depth: usize) -> Self {
+        self.formatter = self.formatter.max_call_depth(max_call_depth);
+        self
+    }
+
+    pub fn left_margin(mut self, left_margin: usize) -> Self {
+        self.formatter = self.formatter.left_margin(left_margin);
+        self.fmt_prefix = None;
+        self
+    }
+
+    /// Sets the [left_margin](Self::left_margin) so that the table columns
+    /// start right below the log message of a `tracing_subscriber::fmt`
+    /// layer with the given prefix:
+    ///
+    /// ```
+    /// use reqray::display::{FmtPrefix, LoggingCallTreeCollectorBuilder};
+    ///
+    /// // for `fmt::layer().with_target(false)`
+    /// let builder = LoggingCallTreeCollectorBuilder::default()
+    ///     .align_with_fmt_prefix(FmtPrefix::default().with_target(false));
+    /// ```
+    ///
+    /// The width of the target in the prefix is the one configured with
+    /// [target](Self::target), regardless of the order of the calls.
+    pub fn align_with_fmt_prefix(mut self, prefix: FmtPrefix) -> Self {
+        self.fmt_prefix = Some(prefix);
+        self
+    }
+
+    /// The unit of durations. The default is
+    /// [DurationUnit::Milliseconds].
+    ///
+    /// Services with sub-millisecond calls are easier to read in
+    /// microseconds:
+    ///
+    /// ```
+    /// use reqray::display::{DurationUnit, LoggingCallTreeCollectorBuilder};
+    ///
+    /// let builder = LoggingCallTreeCollectorBuilder::default()
+    ///     .duration_unit(DurationUnit::Microseconds)
+    ///     .duration_decimals(1);
+    /// ```
+    pub fn duration_unit(mut self, duration_unit: DurationUnit) -> Self {
+        self.formatter = self.formatter.duration_unit(duration_unit);
+        self
+    }
+
+    /// The number of decimal places of durations, at most down to
+    /// nanoseconds, e.g. `6` for [DurationUnit::Milliseconds]. The default
+    /// is `3`.
+    pub fn duration_decimals(mut self, duration_decimals: usize) -> Self {
+        self.formatter = self.formatter.duration_decimals(duration_decimals);
+        self
+    }
+
+    /// How to round durations to the displayed decimal places. The
+    /// default is [DurationRounding::Truncate].
+    pub fn duration_rounding(mut self, duration_rounding: DurationRounding) -> Self {
+        self.formatter = self.formatter.duration_rounding(duration_rounding);
+        self
+    }
+
+    /// How to write counts and durations, e.g. with European decimal
+    /// separators. The default is [NumberFormat::default].
+    pub fn number_format(mut self, number_format: NumberFormat) -> Self {
+        self.formatter = self.formatter.number_format(number_format);
+        self
+    }
+
+    /// The order of the children of a call path. The default is
+    /// [SortOrder::FirstCreated].
+    pub fn sort_children(mut self, order: SortOrder) -> Self {
+        self.formatter = self.formatter.sort_children(order);
+        self
+    }
+
+    /// Only log call trees whose root span was busy for at least
+    /// `min_root_busy`, e.g. to skip fast health checks in production.
+    ///
+    /// This and the other thresholds can be changed at runtime, see
+    /// [LoggingCallTreeCollector::thresholds_handle].
+    pub fn min_root_busy(mut self, min_root_busy: Duration) -> Self {
+        self.min_root_busy = Some(min_root_busy);
+        self
+    }
+
+    /// Only log call trees whose root span was alive for at least
+    /// `min_root_alive`.
+    pub fn min_root_alive(mut self, min_root_alive: Duration) -> Self {
+        self.min_root_alive = Some(min_root_alive);
+        self
+    }
+
+    /// The level of the events with the call trees. The default is
+    /// [Level::INFO].
+    pub fn level(mut self, level: Level) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// The target of the events with the call trees, e.g. to enable them
+    /// separately with an env filter like `RUST_LOG=warn,xray=info`:
+    ///
+    /// ```
+    /// use reqray::display::LoggingCallTreeCollectorBuilder;
+    ///
+    /// let builder = LoggingCallTreeCollectorBuilder::default().target("xray");
+    /// ```
+    ///
+    /// The default is `reqray::display`.
+    pub fn target(mut self, target: &'static str) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// Log call trees whose root span was busy for at least
+    /// `warn_above_busy` at [Level::WARN], even if the configured
+    /// [level](Self::level) is less severe, e.g. to alert on slow
+    /// requests.
+    pub fn warn_above_busy(mut self, warn_above_busy: Duration) -> Self {
+        self.warn_above_busy = Some(warn_above_busy);
+        self
+    }
+
+    /// Log call trees whose root span was alive for at least
+    /// `warn_above_alive` at [Level::WARN], see
+    /// [warn_above_busy](Self::warn_above_busy).
+    pub fn warn_above_alive(mut self, warn_above_alive: Duration) -> Self {
+        self.warn_above_alive = Some(warn_above_alive);
+        self
+    }
+
+    /// Limits how many call trees are logged per root call site, e.g. to
+    /// keep a retry storm from flooding the logs:
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use reqray::display::{LoggingCallTreeCollectorBuilder, RateLimit};
+    ///
+    /// let builder = LoggingCallTreeCollectorBuilder::default().rate_limit(
+    ///     RateLimit::per_minute(10).always_above_busy(Duration::from_secs(1)),
+    /// );
+    /// ```
+    ///
+    /// The header of the next logged call tree of the same root call site
+    /// mentions how many were suppressed in between. Unlimited by default.
+    pub fn rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    /// Whether to write call trees directly to stderr if their events are
+    /// disabled, e.g. by a misconfigured filter. Enabled by default.
+    ///
+    /// At most one call tree is written every 10 seconds, the header
+    /// mentions how many were suppressed in between.
+    pub fn stderr_fallback(mut self, stderr_fallback: bool) -> Self {
+        self.stderr_fallback = stderr_fallback;
+        self
+    }
+
+    /// The [OutputFormat]. The default is [OutputFormat::Table].
+    ///
+    /// The format of the `tracing_subscriber::fmt` layer cannot be
+    /// detected from another layer, so configure
+    /// [OutputFormat::Structured] or [OutputFormat::StructuredSummary]
+    /// together with `fmt().json()`.
+    pub fn output_format(mut self, output_format: OutputFormat) -> Self {
+        self.output_format = output_format;
+        self
+    }
+
+    /// The columns of the table in this order, e.g. fewer columns for
+    /// narrow terminals:
+    ///
+    /// ```
+    /// use reqray::display::{Column, LoggingCallTreeCollectorBuilder};
+    ///
+    /// let builder = LoggingCallTreeCollectorBuilder::default()
+    ///     .columns([Column::Calls, Column::BusySum, Column::OwnBusy]);
+    /// ```
+    ///
+    /// Add [Column::PercentOfRoot] to see the share of every call path in
+    /// the request time at a glance.
+    ///
+    /// By default, the table shows [Column::Calls], [Column::Alive],
+    /// [Column::BusySum] and [Column::OwnBusy]. [Column::IoWait],
+    /// [Column::Cpu], [Column::Errors] and [Column::Warnings] are added if
+    /// any call path has values for them.
+    ///
+    /// For async code, add [Column::Polls] and [Column::Idle] to spot
+    /// futures which waited long to be polled again.
+    ///
+    /// Columns are widened if their largest values do not fit, e.g. the
+    /// durations of batch jobs which run for days.
+    pub fn columns(mut self, columns: impl IntoIterator<Item = Column>) -> Self {
+        self.formatter = self.formatter.columns(columns);
+        self
+    }
+
+    /// Colors the own busy times with ANSI escape codes, so that hot call
+    /// paths stand out in a terminal:
+    ///
+    /// ```
+    /// use reqray::display::{Highlight, LoggingCallTreeCollectorBuilder};
+    ///
+    /// let builder = LoggingCallTreeCollectorBuilder::default()
+    ///     .highlight_own_busy(Highlight::Percentiles { warm: 0.75, hot: 0.95 });
+    /// ```
+    ///
+    /// Disabled by default. Only enable it if the log output ends up in a
+    /// terminal or another viewer which understands ANSI colors.
+    pub fn highlight_own_busy(mut self, highlight: Highlight) -> Self {
+        self.formatter = self.formatter.highlight_own_busy(highlight);
+        self
+    }
+
+    /// Collapses the children of a call path which were hardly busy into a
+    /// single `… (k fast calls)` row, so that deep trees of trivially fast
+    /// helpers do not drown out the interesting rows:
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use reqray::display::{CollapseBelow, LoggingCallTreeCollectorBuilder};
+    ///
+    /// let builder = LoggingCallTreeCollectorBuilder::default()
+    ///     .collapse_below(CollapseBelow::Busy(Duration::from_micros(50)));
+    /// ```
+    ///
+    /// The row shows the summed calls and busy times of the collapsed
+    /// children, whose own children are not shown. Events are never
+    /// collapsed. Disabled by default.
+    pub fn collapse_below(mut self, collapse_below: CollapseBelow) -> Self {
+        self.formatter = self.formatter.collapse_below(collapse_below);
+        self
+    }
+
+    /// Only shows the `max_rows` call paths with the most own busy time
+    /// and their ancestors, e.g. to keep the tables of fan-out heavy
+    /// requests below the line limits of log pipelines.
+    ///
+    /// The number of call paths not shown is written below the table.
+    /// Unlimited by default.
+    pub fn max_rows(mut self, max_rows: usize) -> Self {
+        self.formatter = self.formatter.max_rows(max_rows);
+        self
+    }
+
+    /// Whether to show the target of every call path after its name, e.g.
+    /// to tell apart functions with the same name in different modules.
+    /// Disabled by default.
+    pub fn with_target(mut self, with_target: bool) -> Self {
+        self.formatter = self.formatter.with_target(with_target);
+        self
+    }
+
+    /// Whether to show the source location of every call path after its
+    /// name, like `src/worker.rs:42`. Disabled by default.
+    pub fn with_location(mut self, with_location: bool) -> Self {
+        self.formatter = self.formatter.with_location(with_location);
+        self
+    }
+
+    /// Hides the rows of call paths which are more verbose than
+    /// `max_row_level`, e.g. `Level::INFO` to hide `DEBUG` and `TRACE`
+    /// spans and events.
+    ///
+    /// Unlike a filter on the subscriber, this only affects the table: the
+    /// own busy time, I/O wait and event counts of hidden rows are added
+    /// to their closest shown ancestor and shown children of hidden rows
+    /// take their place. So one collected call tree can be logged at
+    /// different verbosities, e.g. by several processors. The root is
+    /// always shown.
+    pub fn max_row_level(mut self, max_row_level: Level) -> Self {
+        self.formatter = self.formatter.max_row_level(max_row_level);
+        self
+    }
+
+    /// Hides the rows of call paths whose target is `target` or inside of
+    /// it, e.g. `hyper` also hides `hyper::client`. Can be called several
+    /// times.
+    ///
+    /// Hidden rows are folded into their ancestors like with
+    /// [max_row_level](Self::max_row_level).
+    pub fn hide_target(mut self, target: impl Into<String>) -> Self {
+        self.formatter = self.formatter.hide_target(target);
+        self
+    }
+
+    /// How to write the location of the root span in the header. The
+    /// default is [HeaderLocation::Full].
+    pub fn header_location(mut self, header_location: HeaderLocation) -> Self {
+        self.header_location = header_location;
+        self
+    }
+
+    /// Whether to write the location in the header as `path:line:column`,
+    /// separated by spaces, so that terminal emulators and IDEs which
+    /// hyperlink such patterns make it clickable:
+    ///
+    /// ```text
+    /// Call summary of request at examples/nested.rs:51:1
+    /// ```
+    ///
+    /// `tracing` does not record columns, so the column is always `1`.
+    /// Disabled by default, which writes `request@examples/nested.rs:51`.
+    pub fn clickable_location(mut self, clickable_location: bool) -> Self {
+        self.clickable_location = clickable_location;
+        self
+    }
+
+    /// Compares every call tree with the aggregate of earlier call trees
+    /// with the same root call site, e.g. to see at a glance whether a
+    /// request was unusual. The header shows the distribution of the root
+    /// busy times since the last summary of the aggregating processor:
+    ///
+    /// ```text
+    /// Call summary of request@examples/nested.rs:51 (busy p50/p90/p99 1.000/2.250/8.000 ms over 120 calls)
+    /// ```
+    ///
+    /// The table gets a [Column::VsAverage] with the mean busy time of
+    /// every call path as a multiple of its mean busy time in the
+    /// aggregate, unless the [columns](Self::columns) are configured
+    /// without it.
+    ///
+    /// Call trees are aggregated when the [AggregatingProcessor] processes
+    /// them, so let it process call trees first:
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use reqray::{
+    ///     aggregate::AggregatingProcessor, display::LoggingCallTreeCollectorBuilder,
+    ///     CallTreeCollectorBuilder,
+    /// };
+    ///
+    /// let aggregating = AggregatingProcessor::new(|_| {}).with_interval(Duration::from_secs(600));
+    /// let logging = LoggingCallTreeCollectorBuilder::default()
+    ///     .compare_with_aggregates(aggregating.handle())
+    ///     .build();
+    /// let collector = CallTreeCollectorBuilder::default().build_with_collector((aggregating, logging));
+    /// ```
+    ///
+    /// [AggregatingProcessor]: crate::aggregate::AggregatingProcessor
+    #[cfg(feature = "aggregate")]
+    pub fn compare_with_aggregates(mut self, aggregates: AggregatorHandle) -> Self {
+        self.aggregates = Some(aggregates);
+        self
+    }
+
+    /// Whether to add the [time split](crate::CallPathPool::time_split)
+    /// of the call tree to the header, e.g. to tell at a glance whether a
+    /// slow request waited for its dependencies:
+    ///
+    /// ```text
+    /// Call summary of request@examples/nested.rs:51 (local 1.250 ms, downstream 8.000 ms, waiting 0.750 ms)
+    /// ```
+    ///
+    /// Disabled by default.
+    pub fn time_split(mut self, time_split: bool) -> Self {
+        self.time_split = time_split;
+        self
+    }
+
+    /// Adds a rollup below the table with the busy and own busy times per
+    /// target, e.g. to see which crate is eating the latency at a glance:
+    ///
+    /// ```text
+    ///    ∑ busy ms │ ∑ own busy ms │ target
+    /// ─────────────┼────────────-──┼───────────────────────
+    ///       12.500 ┊         9.000 ┊ sqlx
+    ///       15.000 ┊         3.000 ┊ my_service
+    ///        0.500 ┊         0.500 ┊ hyper
+    /// ```
+    ///
+    /// The own busy times add up to the busy time of the root. The busy
+    /// times include all children, also of other targets, so they overlap.
+    /// Groups are sorted by own busy time. Only shown in
+    /// [OutputFormat::Table]. Disabled by default.
+    pub fn target_rollup(mut self, target_rollup: TargetRollup) -> Self {
+        self.formatter = self.formatter.target_rollup(target_rollup);
+        self
+    }
+
+    /// Adds the busy time and polls per thread of every call path below
+    /// the table, e.g. to see whether a call path bounces between the
+    /// worker threads of a runtime or is stuck on a blocked one:
+    ///
+    /// ```text
+    ///    ∑ busy ms │     # polls │ thread per call path
+    /// ─────────────┼─────────────┼───────────────────────
+    ///        0.120 ┊       0 002 ┊ request on worker-1
+    ///        2.500 ┊       0 001 ┊ request on worker-2
+    /// ```
+    ///
+    /// Requires [CallTreeCollectorBuilder::per_thread_busy]. Only shown in
+    /// [OutputFormat::Table]. Disabled by default.
+    ///
+    /// [CallTreeCollectorBuilder::per_thread_busy]: crate::CallTreeCollectorBuilder::per_thread_busy
+    pub fn thread_breakdown(mut self, thread_breakdown: bool) -> Self {
+        self.formatter = self.formatter.thread_breakdown(thread_breakdown);
+        self
+    }
+
+    /// Build a [WriterCallTreeCollector] which writes the tables directly
+    /// to `writer` instead of logging them, e.g. to a dedicated file:
+    ///
+    /// ```no_run
+    /// use reqray::{
+    ///     display::LoggingCallTreeCollectorBuilder, writer::RotatingFile,
+    ///     CallTreeCollectorBuilder,
+    /// };
+    ///
+    /// let file = RotatingFile::open("xray.log", 10 * 1024 * 1024, 5).unwrap();
+    /// let collector = CallTreeCollectorBuilder::default().build_with_collector(
+    ///     LoggingCallTreeCollectorBuilder::default()
+    ///         .left_margin(0)
+    ///         .build_with_writer(file),
+    /// );
+    /// ```
+    ///
+    /// The level, target and output format are ignored.
+    ///
+    /// [WriterCallTreeCollector]: crate::writer::WriterCallTreeCollector
+    pub fn build_with_writer<W: Write>(
+        self,
+        writer: W,
+    ) -> crate::writer::WriterCallTreeCollector<W> {
+        crate::writer::WriterCallTreeCollector::new(self.build(), writer)
+    }
+
+    /// Replaces all layout options with those of `formatter`, e.g. to log
+    /// call trees like they are rendered elsewhere.
+    pub fn table_formatter(mut self, formatter: TextTableFormatter) -> Self {
+        self.formatter = formatter;
+        self
+    }
+
+    /// Builds only the table layout, e.g. to render call trees on demand
+    /// without logging them.
+    pub fn build_formatter(self) -> TextTableFormatter {
+        match self.fmt_prefix {
+            Some(prefix) => self.formatter.align_with_fmt_prefix(prefix, self.target),
+            None => self.formatter,
+        }
+    }
+
+    pub fn build(self) -> LoggingCallTreeCollector {
+        let formatter = match self.fmt_prefix {
+            Some(prefix) => self.formatter.align_with_fmt_prefix(prefix, self.target),
+            None => self.formatter,
+        };
+        LoggingCallTreeCollector {
+            formatter,
+            thresholds: LoggingThresholdsHandle::new(
+                self.min_root_busy,
+                self.min_root_alive,
+                self.warn_above_busy,
+                self.warn_above_alive,
+            ),
+            level: self.level,
+            target: self.target,
+            rate_limiter: self
+                .rate_limit
+                .map(|rate_limit| Mutex::new(RateLimiter::new(rate_limit))),
+            stderr_fallback: if self.stderr_fallback {
+                Some(Mutex::new(StderrFallback::default()))
+            } else {
+                None
+            },
+            output_format: self.output_format,
+            header_location: self.header_location,
+            clickable_location: self.clickable_location,
+            time_split: self.time_split,
+            #[cfg(feature = "aggregate")]
+            aggregates: self.aggregates,
+            next_call_tree: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Default for LoggingCallTreeCollectorBuilder {
+    fn default() -> Self {
+        LoggingCallTreeCollectorBuilder {
+            formatter: TextTableFormatter::default(),
+            min_root_busy: None,
+            min_root_alive: None,
+            level: Level::INFO,
+            target: module_path!(),
+            warn_above_busy: None,
+            warn_above_alive: None,
+            rate_limit: None,
+            stderr_fallback: true,
+            output_format: OutputFormat::Table,
+            header_location: HeaderLocation::Full,
+            clickable_location: false,
+            time_split: false,
+            #[cfg(feature = "aggregate")]
+            aggregates: None,
+            fmt_prefix: None,
+        }
+    }
+}
+
+impl LoggingCallTreeCollector {
+    /// A handle to change the thresholds which decide whether and at which
+    /// level call trees are logged while the collector is running.
+    pub fn thresholds_handle(&self) -> LoggingThresholdsHandle {
+        self.thresholds.clone()
+    }
+
+    /// The name and the location of `root` for headers, e.g.
+    /// `request@examples/nested.rs:51`.
+    pub(crate) fn root_label(&self, root: &CallPathTiming) -> String {
+        let meta = root.span_metadata();
+        let path = match self.header_location.path(meta.file().unwrap_or("unknown")) {
+            Some(path) => path,
+            None => return root.name().to_string(),
+        };
+        let line = meta.line().unwrap_or(0);
+        if self.clickable_location {
+            format!("{} at {}:{}:1", root.name(), path, line)
+        } else {
+            format!("{}@{}:{}", root.name(), path, line)
+        }
+    }
+
+    /// The [root label](Self::root_label) followed by the context fields of
+    /// `pool`, e.g. `request@examples/nested.rs:51 request_id=42`.
+    pub(crate) fn tree_label(&self, pool: &CallPathPool) -> String {
+        let mut label = self.root_label(pool.root());
+        for (field, value) in pool.context() {
+            label.push_str(&format!(" {}={}", field, value));
+        }
+        label
+    }
+
+    /// The level at which to log a call tree with `root`.
+    fn level(&self, root: &CallPathTiming) -> Level {
+        let thresholds = &self.thresholds;
+        let slow = matches!(thresholds.warn_above_busy(), Some(max) if root.sum_with_children() >= max)
+            || matches!(thresholds.warn_above_alive(), Some(max) if root.span_alive() >= max);
+        // More verbose levels are greater.
+        if slow && self.level > Level::WARN {
+            Level::WARN
+        } else {
+            self.level
+        }
+    }
+
+    /// The callsite for events with `fields` at `level`.
+    fn callsite(&self, level: Level, fields: &'static [&'static str]) -> &'static DynamicCallsite {
+        DynamicCallsite::get(self.target, level, fields)
+    }
+
+    /// Decides whether to log a call tree, `None` if it was too fast or
+    /// exceeds the [RateLimit]. Otherwise the number of call trees with the
+    /// same root call site which were suppressed before.
+    pub(crate) fn admit(&self, pool: &CallPathPool) -> Option<usize> {
+        let root = pool.root();
+        if !self.is_slow_enough(root) {
+            return None;
+        }
+        match &self.rate_limiter {
+            Some(limiter) => {
+                let mut limiter = match limiter.lock() {
+                    Ok(limiter) => limiter,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+                limiter.admit(root, Instant::now())
+            }
+            None => Some(0),
+        }
+    }
+
+    /// The line above the table, e.g.
+    /// `Call summary of request@examples/nested.rs:51`.
+    pub(crate) fn header(&self, pool: &CallPathPool, suppressed: usize) -> String {
+        let degraded = if pool.is_degraded() {
+            format!(" (degraded, {} spans skipped)", pool.skipped_spans())
+        } else {
+            String::new()
+        };
+        let heap = match pool.heap_summary() {
+            Some(heap) => format!(
+                " (heap: {} allocations, {} bytes allocated, {} bytes peak)",
+                heap.allocations(),
+                heap.allocated_bytes(),
+                heap.peak_bytes()
+            ),
+            None => String::new(),
+        };
+        let time_split = if self.time_split {
+            self.time_split_label(pool)
+        } else {
+            String::new()
+        };
+        let suppressed = if suppressed > 0 {
+            format!(" ({} call trees suppressed before)", suppressed)
+        } else {
+            String::new()
+        };
+        #[cfg(feature = "aggregate")]
+        let distribution = self.root_busy_distribution(pool).unwrap_or_default();
+        #[cfg(not(feature = "aggregate"))]
+        let distribution = "";
+        format!(
+            "Call summary of {}{}{}{}{}{}",
+            self.tree_label(pool),
+            distribution,
+            degraded,
+            heap,
+            time_split,
+            suppressed,
+        )
+    }
+
+    /// The time split of `pool` for the header, e.g.
+    /// ` (local 1.250 ms, downstream 8.000 ms, waiting 0.750 ms)`.
+    fn time_split_label(&self, pool: &CallPathPool) -> String {
+        let split = pool.time_split();
+        let unit = self.formatter.duration_unit.symbol();
+        format!(
+            " (local {} {}, downstream {} {}, waiting {} {})",
+            self.formatter.format_duration(split.local()),
+            unit,
+            self.formatter.format_duration(split.downstream()),
+            unit,
+            self.formatter.format_duration(split.waiting()),
+            unit,
+        )
+    }
+
+    /// The root busy time percentiles of the aggregate of `pool`, see
+    /// [LoggingCallTreeCollectorBuilder::compare_with_aggregates].
+    #[cfg(feature = "aggregate")]
+    fn root_busy_distribution(&self, pool: &CallPathPool) -> Option<String> {
+        let aggregates = self.aggregates.as_ref()?;
+        let (percentiles, count) = aggregates.with_aggregate_of(pool, |aggregate| {
+            let root_busy = aggregate.root_busy();
+            let percentiles = [50.0, 90.0, 99.0]
+                .iter()
+                .map(|p| root_busy.percentile(*p))
+                .collect::<Option<Vec<_>>>()?;
+            Some((percentiles, root_busy.count()))
+        })??;
+        let formatted = percentiles
+            .into_iter()
+            .map(|busy| self.formatter.format_duration(busy))
+            .collect::<Vec<_>>();
+        Some(format!(
+            " (busy p50/p90/p99 {} {} over {} call{})",
+            formatted.join("/"),
+            self.formatter.duration_unit.symbol(),
+            count,
+            if count == 1 { "" } else { "s" }
+        ))
+    }
+
+    /// False if the root of the call tree was too fast to be logged.
+    pub(crate) fn is_slow_enough(&self, root: &CallPathTiming) -> bool {
+        let thresholds = &self.thresholds;
+        !matches!(thresholds.min_root_busy(), Some(min) if root.sum_with_children() < min)
+            && !matches!(thresholds.min_root_alive(), Some(min) if root.span_alive() < min)
+    }
+}
+
+impl FinishedCallTreeProcessor for LoggingCallTreeCollector {
+    fn process_finished_call(&self, pool: CallPathPool) {
+        self.log(&pool)
+    }
+
+    fn process_shared_call(&self, pool: Arc<CallPathPool>) {
+        self.log(&pool)
+    }
+}
+
+impl LoggingCallTreeCollector {
+    fn log(&self, pool: &CallPathPool) {
+        let suppressed = match self.admit(pool) {
+            Some(suppressed) => suppressed,
+            None => return,
+        };
+        let root = pool.root();
+        let header = self.header(pool, suppressed);
+        let level = self.level(root);
+        // The stderr fallback is meant for humans, so it always uses the
+        // table.
+        let callsite = match self.output_format {
+            OutputFormat::Table => self.callsite(level, TABLE_FIELDS),
+            OutputFormat::Structured => self.callsite(level, STRUCTURED_HEADER_FIELDS),
+            OutputFormat::StructuredSummary => self.callsite(level, STRUCTURED_SUMMARY_FIELDS),
+        };
+        let enabled = callsite.is_enabled();
+        if self.output_format == OutputFormat::Structured && enabled {
+            self.log_structured(pool, &header, level);
+            return;
+        } else if self.output_format == OutputFormat::StructuredSummary && enabled {
+            self.log_structured_summary(pool, &header, level);
+            return;
+        }
+        let table = self.table(pool);
+        if enabled {
+            dispatch_event!(callsite, format_args!("{}\n\n{}", header, table));
+        } else if let Some(fallback) = &self.stderr_fallback {
+            let mut fallback = match fallback.lock() {
+                Ok(fallback) => fallback,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            let stderr = io::stderr();
+            let _ = fallback.write(Instant::now(), &header, &table, &mut stderr.lock());
+        }
+    }
+
+    /// Logs the call tree as events with one field per value, see
+    /// [OutputFormat::Structured].
+    fn log_structured(&self, pool: &CallPathPool, header: &str, level: Level) {
+        let call_tree = self.next_call_tree.fetch_add(1, Ordering::Relaxed);
+        let root = pool.root();
+        dispatch_event!(
+            self.callsite(level, STRUCTURED_HEADER_FIELDS),
+            format_args!("{}", header),
+            call_tree,
+            root.name(),
+            root.span_metadata().file(),
+            root.span_metadata().line(),
+            pool.skipped_spans(),
+            pool.untracked_spans(),
+        );
+        let callsite = self.callsite(level, STRUCTURED_CALL_PATH_FIELDS);
+        for (call_path, node) in pool.flatten("/") {
+            // Events have no durations.
+            let nanos =
+                |duration: Duration| (!node.is_event()).then_some(duration.as_nanos() as u64);
+            dispatch_event!(
+                callsite,
+                format_args!("call path"),
+                call_tree,
+                call_path.as_str(),
+                node.call_count(),
+                nanos(node.span_alive()),
+                nanos(node.sum_with_children()),
+                nanos(node.sum_without_children()),
+                nanos(node.sum_io_wait()),
+                node.error_count(),
+                node.warn_count(),
+                node.is_retry(),
+            );
+        }
+    }
+}
+
+impl LoggingCallTreeCollector {
+    /// Logs the call tree as a single event, see
+    /// [OutputFormat::StructuredSummary].
+    fn log_structured_summary(&self, pool: &CallPathPool, header: &str, level: Level) {
+        let root = pool.root();
+        let mut tree = Vec::new();
+        crate::json::write_call_tree(pool, root, &mut tree).expect("writing to a Vec");
+        let tree = String::from_utf8(tree).expect("JSON from strings is UTF-8");
+        let millis = |duration: Duration| duration.as_nanos() as f64 / 1_000_000.0;
+        dispatch_event!(
+            self.callsite(level, STRUCTURED_SUMMARY_FIELDS),
+            format_args!("{}", header),
+            root.name(),
+            root.span_metadata().file(),
+            root.span_metadata().line(),
+            millis(root.span_alive()),
+            millis(root.sum_with_children()),
+            millis(root.sum_without_children()),
+            pool.call_path_count(),
+            pool.is_degraded(),
+            pool.skipped_spans(),
+            pool.untracked_spans(),
+            tree.as_str(),
+        );
+    }
+}
+
+impl StderrFallback {
+    /// Writes the call tree to `out` unless it was written to too recently.
+    fn write(
+        &mut self,
+        now: Instant,
+        header: &str,
+        table: &dyn fmt::Display,
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        if matches!(self.last_written, Some(last) if now - last < STDERR_FALLBACK_INTERVAL) {
+            self.suppressed += 1;
+            return Ok(());
+        }
+        self.last_written = Some(now);
+        let suppressed = std::mem::take(&mut self.suppressed);
+        write!(out, "reqray: {}", header)?;
+        if suppressed > 0 {
+            write!(out, " ({} call trees suppressed before)", suppressed)?;
+        }
+        write!(out, "\n\n{}", table)
+    }
+}
+
+impl LoggingCallTreeCollector {
+    /// The table of `pool` in the configured format, compared with its
+    /// aggregate if configured with
+    /// [compare_with_aggregates](LoggingCallTreeCollectorBuilder::compare_with_aggregates).
+    pub(crate) fn table<'a>(&'a self, pool: &'a CallPathPool) -> impl fmt::Display + 'a {
+        CollectorTable {
+            collector: self,
+            pool,
+        }
+    }
+
+    /// The formatter of the tables, e.g. to render further call trees
+    /// like the logged ones.
+    pub fn formatter(&self) -> &TextTableFormatter {
+        &self.formatter
+    }
+
+    /// Logs the call tree collected so far for a call tree that is still in
+    /// flight, e.g. from a [Watchdog](crate::Watchdog).
+    ///
+    /// Uses the configured [level](LoggingCallTreeCollectorBuilder::level)
+    /// and [target](LoggingCallTreeCollectorBuilder::target), escalated to
+    /// WARN if the call tree has been alive for longer than
+    /// [warn_above_alive](LoggingCallTreeCollectorBuilder::warn_above_alive).
+    ///
+    /// Does nothing if the call tree has finished in the meantime, see
+    /// [InFlightTree::partial_call_tree].
+    pub fn log_in_flight(&self, tree: &InFlightTree) {
+        let slow = matches!(self.thresholds.warn_above_alive(), Some(max) if tree.alive() >= max);
+        // More verbose levels are greater.
+        let level = if slow && self.level > Level::WARN {
+            Level::WARN
+        } else {
+            self.level
+        };
+        let callsite = self.callsite(level, TABLE_FIELDS);
+        if !callsite.is_enabled() {
+            return;
+        }
+        let pool = match tree.partial_call_tree() {
+            Some(pool) => pool,
+            None => return,
+        };
+        dispatch_event!(
+            callsite,
+            format_args!(
+                "Call tree of {} still in flight after {:?}, {} spans so far\n\n{}",
+                self.tree_label(&pool),
+                tree.alive(),
+                tree.spans(),
+                self.table(&pool)
+            )
+        );
+    }
+}
+
+/// The table of a call tree as logged by a [LoggingCallTreeCollector].
+struct CollectorTable<'a> {
+    collector: &'a LoggingCallTreeCollector,
+    pool: &'a CallPathPool,
+}
+
+impl fmt::Display for CollectorTable<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let formatter = &self.collector.formatter;
+        // Copied, so that the aggregates are not locked while formatting.
+        #[cfg(feature = "aggregate")]
+        let baseline = self.collector.aggregates.as_ref().and_then(|aggregates| {
+            aggregates.with_aggregate_of(self.pool, |aggregate| aggregate.call_tree().clone())
+        });
+        #[cfg(not(feature = "aggregate"))]
+        let baseline: Option<CallPathPool> = None;
+        match &baseline {
+            Some(baseline) => write!(f, "{}", formatter.display_compared(self.pool, baseline)),
+            None => write!(f, "{}", formatter.display(self.pool)),
+        }
+    }
+}
+
+impl TextTableFormatter {
+    /// The layout of [LoggingCallTreeCollectorBuilder::default].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The maximum depth of call paths shown, see
+    /// [LoggingCallTreeCollectorBuilder::max_call_depth].
+    pub fn max_call_depth(mut self, max_call_depth: usize) -> Self {
+        self.max_call_depth = max_call_depth;
+        self
+    }
+
+    /// The number of spaces in front of every line, see
+    /// [LoggingCallTreeCollectorBuilder::left_margin].
+    pub fn left_margin(mut self, left_margin: usize) -> Self {
+        self.left_margin = left_margin;
+        self
+    }
+
+    /// See [LoggingCallTreeCollectorBuilder::align_with_fmt_prefix], for
+    /// messages logged with `target`, e.g. the one configured with
+    /// [LoggingCallTreeCollectorBuilder::target].
+    pub fn align_with_fmt_prefix(self, prefix: FmtPrefix, target: &str) -> Self {
+        self.left_margin(prefix.width(target))
+    }
+
+    /// See [LoggingCallTreeCollectorBuilder::duration_unit].
+    pub fn duration_unit(mut self, duration_unit: DurationUnit) -> Self {
+        self.duration_unit = duration_unit;
+        self
+    }
+
+    /// See [LoggingCallTreeCollectorBuilder::duration_decimals].
+    pub fn duration_decimals(mut self, duration_decimals: usize) -> Self {
+        self.duration_decimals = duration_decimals;
+        self
+    }
+
+    /// See [LoggingCallTreeCollectorBuilder::duration_rounding].
+    pub fn duration_rounding(mut self, duration_rounding: DurationRounding) -> Self {
+        self.duration_rounding = duration_rounding;
+        self
+    }
+
+    /// See [LoggingCallTreeCollectorBuilder::number_format].
+    pub fn number_format(mut self, number_format: NumberFormat) -> Self {
+        self.number_format = number_format;
+        self
+    }
+
+    /// See [LoggingCallTreeCollectorBuilder::sort_children].
+    pub fn sort_children(mut self, order: SortOrder) -> Self {
+        self.sort_children = order;
+        self
+    }
+
+    /// See [LoggingCallTreeCollectorBuilder::columns].
+    pub fn columns(mut self, columns: impl IntoIterator<Item = Column>) -> Self {
+        self.columns = Some(columns.into_iter().collect());
+        self
+    }
+
+    /// See [LoggingCallTreeCollectorBuilder::highlight_own_busy].
+    pub fn highlight_own_busy(mut self, highlight: Highlight) -> Self {
+        self.highlight_own_busy = Some(highlight);
+        self
+    }
+
+    /// See [LoggingCallTreeCollectorBuilder::collapse_below].
+    pub fn collapse_below(mut self, collapse_below: CollapseBelow) -> Self {
+        self.collapse_below = Some(collapse_below);
+        self
+    }
+
+    /// See [LoggingCallTreeCollectorBuilder::max_rows].
+    pub fn max_rows(mut self, max_rows: usize) -> Self {
+        self.max_rows = Some(max_rows);
+        self
+    }
+
+    /// See [LoggingCallTreeCollectorBuilder::with_target].
+    pub fn with_target(mut self, with_target: bool) -> Self {
+        self.with_target = with_target;
+        self
+    }
+
+    /// See [LoggingCallTreeCollectorBuilder::with_location].
+    pub fn with_location(mut self, with_location: bool) -> Self {
+        self.with_location = with_location;
+        self
+    }
+
+    /// See [LoggingCallTreeCollectorBuilder::max_row_level].
+    pub fn max_row_level(mut self, max_row_level: Level) -> Self {
+        self.max_row_level = Some(max_row_level);
+        self
+    }
+
+    /// See [LoggingCallTreeCollectorBuilder::hide_target].
+    pub fn hide_target(mut self, target: impl Into<String>) -> Self {
+        self.hidden_targets.push(target.into());
+        self
+    }
+
+    /// See [LoggingCallTreeCollectorBuilder::target_rollup].
+    pub fn target_rollup(mut self, target_rollup: TargetRollup) -> Self {
+        self.target_rollup = Some(target_rollup);
+        self
+    }
+
+    /// See [LoggingCallTreeCollectorBuilder::thread_breakdown].
+    pub fn thread_breakdown(mut self, thread_breakdown: bool) -> Self {
+        self.thread_breakdown = thread_breakdown;
+        self
+    }
+
+    /// Writes the table of the call tree in `pool` to `out`, without a
+    /// header and starting every line with the left margin.
+    pub fn fmt(&self, pool: &CallPathPool, out: &mut impl fmt::Write) -> fmt::Result {
+        write!(out, "{}", self.display(pool))
+    }
+
+    /// The table of the call tree in `pool`, e.g. for `format!`.
+    pub fn display<'a>(&'a self, pool: &'a CallPathPool) -> impl fmt::Display + 'a {
+        DisplayableCallPathTiming {
+            config: self,
+            pool,
+            root: pool.root(),
+            baseline: None,
+        }
+    }
+
+    /// Like [display](Self::display) but compares every call path with
+    /// the matching call path of `baseline` in [Column::VsAverage], e.g.
+    /// with the [aggregate](crate::aggregate::Aggregate) of earlier
+    /// requests. The column is shown by default.
+    pub fn display_compared<'a>(
+        &'a self,
+        pool: &'a CallPathPool,
+        baseline: &'a CallPathPool,
+    ) -> impl fmt::Display + 'a {
+        DisplayableCallPathTiming {
+            config: self,
+            pool,
+            root: pool.root(),
+            baseline: Some((baseline, pool.matching_call_paths(baseline))),
+        }
+    }
+
+    /// Writes `duration` in the configured unit.
+    pub(crate) fn format_duration(&self, duration: Duration) -> String {
+        let max_decimals = self.duration_unit.max_decimals();
+        let decimals = core::cmp::min(self.duration_decimals, max_decimals);
+        let unit = 10u128.pow((max_decimals - decimals) as u32);
+        let nanos = duration.as_nanos();
+        let units = match self.duration_rounding {
+            DurationRounding::Truncate => nanos / unit,
+            DurationRounding::Nearest => (nanos + unit / 2) / unit,
+        };
+        let number_format = &self.number_format;
+        match &number_format.custom {
+            Some(custom) => {
+                let rounded = Duration::from_nanos((units * unit) as u64);
+                custom(Number::Duration(rounded))
+            }
+            None => {
+                let scale = 10u128.pow(decimals as u32);
+                number_format.decimal(
+                    units / scale,
+                    units % scale,
+                    decimals,
+                    number_format.group_durations,
+                )
+            }
+        }
+    }
+}
+
+impl Default for TextTableFormatter {
+    fn default() -> Self {
+        TextTableFormatter {
+            max_call_depth: 10,
+            left_margin: 20,
+            duration_unit: DurationUnit::Milliseconds,
+            duration_decimals: 3,
+            duration_rounding: DurationRounding::Truncate,
+            number_format: NumberFormat::default(),
+            sort_children: SortOrder::FirstCreated,
+            columns: None,
+            highlight_own_busy: None,
+            collapse_below: None,
+            max_rows: None,
+            with_target: false,
+            with_location: false,
+            max_row_level: None,
+            hidden_targets: Vec::new(),
+            target_rollup: None,
+            thread_breakdown: false,
+        }
+    }
+}
+
+/// How the rows of a table are written, determined once per table.
+struct Layout {
+    columns: Vec<Column>,
+    /// The characters by which the columns are wider than their default
+    /// width, so that the largest values fit.
+    extra_widths: Vec<usize>,
+    /// The own busy times from which on call paths are yellow and red.
+    hot_thresholds: Option<(Duration, Duration)>,
+    /// Children which were busy for less than this are collapsed.
+    collapse_below: Option<Duration>,
+    /// The call paths below the root to show, `None` for all.
+    visible: Option<HashSet<CallPathPoolId>>,
+    /// The number of call paths not shown.
+    hidden: usize,
+}
+
+impl Layout {
+    /// The extra width of `column`, see [Layout::extra_widths].
+    fn extra_width(&self, column: Column) -> usize {
+        self.columns
+            .iter()
+            .position(|c| *c == column)
+            .map_or(0, |idx| self.extra_widths[idx])
+    }
+}
+
+const ANSI_GREEN: &str = "\x1b[32m";
+const ANSI_YELLOW: &str = "\x1b[33m";
+const ANSI_RED: &str = "\x1b[31m";
+const ANSI_RESET: &str = "\x1b[0m";
+
+/// The values of hidden rows which are added to the closest shown
+/// ancestor.
+#[derive(Debug, Default)]
+struct Folded {
+    own_busy: Duration,
+    io_wait: Duration,
+    errors: usize,
+    warnings: usize,
+}
+
+/// The `max_rows` call paths below the root of `pool` with the most own
+/// busy time and their ancestors, together with the number of the other
+/// call paths. `None` if all call paths fit.
+fn top_call_paths(
+    pool: &CallPathPool,
+    max_rows: usize,
+) -> Option<(HashSet<CallPathPoolId>, usize)> {
+    let mut ids = Vec::new();
+    let mut next = pool.root().children().copied().collect::<Vec<_>>();
+    while let Some(id) = next.pop() {
+        ids.push(id);
+        next.extend(pool[id].children().copied());
+    }
+    if ids.len() <= max_rows {
+        return None;
+    }
+    // Ties keep the order of creation.
+    ids.sort_by_key(|id| (std::cmp::Reverse(pool[*id].sum_without_children()), *id));
+    let call_paths = ids.len();
+    let mut visible = HashSet::new();
+    for id in ids.into_iter().take(max_rows) {
+        let mut ancestor = Some(id);
+        // The root has no parent.
+        while let Some(id) = ancestor.filter(|id| pool[*id].parent().is_some()) {
+            if !visible.insert(id) {
+                break;
+            }
+            ancestor = pool[id].parent();
+        }
+    }
+    let hidden = call_paths - visible.len();
+    Some((visible, hidden))
+}
+
+/// True if `predicate` holds for any call path in the tree below `node`.
+fn any_call_path(
+    pool: &CallPathPool,
+    node: &CallPathTiming,
+    predicate: &dyn Fn(&CallPathTiming) -> bool,
+) -> bool {
+    predicate(node)
+        || node
+            .children()
+            .any(|child_idx| any_call_path(pool, &pool[*child_idx], predicate))
+}
+
+struct DisplayableCallPathTiming<'a> {
+    config: &'a TextTableFormatter,
+    pool: &'a CallPathPool,
+    root: &'a CallPathTiming,
+    /// The baseline with the ids of its call paths by the ids in `pool`,
+    /// see [Column::VsAverage].
+    baseline: Option<(&'a CallPathPool, HashMap<CallPathPoolId, CallPathPoolId>)>,
+}
+
+impl<'a> fmt::Display for DisplayableCallPathTiming<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (visible, hidden) = match self
+            .config
+            .max_rows
+            .and_then(|max_rows| top_call_paths(self.pool, max_rows))
+        {
+            Some((visible, hidden)) => (Some(visible), hidden),
+            None => (None, 0),
+        };
+        let columns = match &self.config.columns {
+            Some(columns) => columns.clone(),
+            None => self.default_columns(),
+        };
+        let layout = Layout {
+            hidden,
+            extra_widths: columns
+                .iter()
+                .map(|column| self.extra_width(*column))
+                .collect(),
+            columns,
+            hot_thresholds: self
+                .config
+                .highlight_own_busy
+                .map(|highlight| highlight.thresholds(self.pool)),
+            collapse_below: self
+                .config
+                .collapse_below
+                .map(|collapse_below| collapse_below.threshold(self.root)),
+            visible,
+        };
+        let columns = &layout.columns;
+        write!(f, "{:indent$}", "", indent = self.config.left_margin)?;
+        for (idx, column) in columns.iter().enumerate() {
+            if idx > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{:extra$}", "", extra = layout.extra_widths[idx])?;
+            f.write_str(&column.header(self.config.duration_unit))?;
+        }
+        writeln!(f, " span tree")?;
+        write!(f, "{:indent$}", "", indent = self.config.left_margin)?;
+        for (idx, column) in columns.iter().enumerate() {
+            if idx > 0 {
+                f.write_str("─")?;
+            }
+            f.write_str(&"─".repeat(layout.extra_widths[idx]))?;
+            f.write_str(column.separator())?;
+        }
+        writeln!(f, "───────────────────────")?;
+        let mut last = Vec::with_capacity(self.config.max_call_depth);
+        last.push(true);
+        let mut annotated = Vec::new();
+        self.fmt(&mut last, CallPathPoolId::ROOT, &layout, &mut annotated, f)?;
+        if layout.visible.is_some() {
+            let hidden = layout.hidden;
+            writeln!(
+                f,
+                "{:indent$}… {} call path{} with less own busy time not shown",
+                "",
+                hidden,
+                if hidden == 1 { "" } else { "s" },
+                indent = self.config.left_margin
+            )?;
+        }
+        if self.root.parent().is_none() {
+            self.fmt_untracked(f)?;
+        }
+        self.fmt_notes(&annotated, f)?;
+        if let Some(rollup) = self.config.target_rollup {
+            self.fmt_target_rollup(rollup, f)?;
+        }
+        if self.config.thread_breakdown {
+            self.fmt_thread_breakdown(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> DisplayableCallPathTiming<'a> {
+    /// The default columns, with the optional columns only if any call
+    /// path has values for them.
+    fn default_columns(&self) -> Vec<Column> {
+        let mut columns = vec![
+            Column::Calls,
+            Column::Alive,
+            Column::BusySum,
+            Column::OwnBusy,
+        ];
+        if any_call_path(self.pool, self.root, &|node| {
+            node.sum_io_wait() > Duration::default()
+        }) {
+            columns.push(Column::IoWait);
+        }
+        if any_call_path(self.pool, self.root, &|node| {
+            node.sum_cpu() > Duration::default()
+        }) {
+            columns.push(Column::Cpu);
+        }
+        if any_call_path(self.pool, self.root, &|node| {
+            node.error_count() + node.warn_count() > 0
+        }) {
+            columns.extend([Column::Errors, Column::Warnings]);
+        }
+        if self.baseline.is_some() {
+            columns.push(Column::VsAverage);
+        }
+        columns
+    }
+
+    fn fmt(
+        &self,
+        // this is wasteful
+        last: &mut Vec<bool>,
+        node_idx: CallPathPoolId,
+        layout: &Layout,
+        annotated: &mut Vec<&'a CallPathTiming>,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        let node = &self.pool[node_idx];
+        let (mut children, folded) = self.shown_children(node);
+        let mut collapsed = Vec::new();
+        if !children.is_empty() {
+            match self.config.sort_children {
+                SortOrder::FirstCreated => children.sort(),
+                SortOrder::FirstEntered => children.sort_by_key(|child_idx| {
+                    let first_entered = self.pool[*child_idx].first_entered();
+                    (first_entered.is_none(), first_entered, *child_idx)
+                }),
+                // Ties keep the order of creation.
+                SortOrder::Busy => children.sort_by_key(|child_idx| {
+                    let busy = self.pool[*child_idx].sum_with_children();
+                    (std::cmp::Reverse(busy), *child_idx)
+                }),
+                SortOrder::CallCount => children.sort_by_key(|child_idx| {
+                    let call_count = self.pool[*child_idx].call_count();
+                    (std::cmp::Reverse(call_count), *child_idx)
+                }),
+                SortOrder::Name => {
+                    children.sort_by_key(|child_idx| (self.pool[*child_idx].name(), *child_idx))
+                }
+            }
+            if let Some(visible) = &layout.visible {
+                children.retain(|child_idx| visible.contains(child_idx));
+            }
+            if let Some(collapse_below) = layout.collapse_below {
+                children.retain(|child_idx| {
+                    let child = &self.pool[*child_idx];
+                    let collapse = !child.is_event() && child.sum_with_children() < collapse_below;
+                    if collapse {
+                        collapsed.push(child);
+                    }
+                    !collapse
+                });
+            }
+        }
+
+        write!(f, "{:indent$}", "", indent = self.config.left_margin)?;
+        for (idx, column) in layout.columns.iter().enumerate() {
+            if idx > 0 {
+                f.write_str(" ")?;
+            }
+            self.fmt_cell(
+                *column,
+                node,
+                self.baseline_of(node_idx),
+                &folded,
+                layout,
+                f,
+            )?;
+            f.write_str(" ┊")?;
+        }
+        f.write_str(" ")?;
+
+        let child_connector = if children.is_empty() && collapsed.is_empty() {
+            "─"
+        } else {
+            "┬"
+        };
+        Self::fmt_branch(last, child_connector, f)?;
+        write!(f, " {}", node.name())?;
+        self.fmt_origin(node, f)?;
+        if node.is_retry() {
+            f.write_str(" (attempt 2+)")?;
+        }
+        if node.recursion_depth() > 0 {
+            write!(f, " (recursion depth {})", node.recursion_depth())?;
+        }
+        if node.cache_hit_count() + node.cache_miss_count() > 0 {
+            write!(
+                f,
+                " (hit {} / miss {})",
+                node.cache_hit_count(),
+                node.cache_miss_count()
+            )?;
+        }
+        for values in node.captured_fields() {
+            write!(f, " {{{}:", values.field())?;
+            for (value_idx, (value, count)) in values.values().enumerate() {
+                f.write_str(if value_idx == 0 { " " } else { ", " })?;
+                f.write_str(value)?;
+                if count > 1 {
+                    write!(f, " ({}×)", count)?;
+                }
+            }
+            if values.other_count() > 0 {
+                write!(f, ", {} more", values.other_count())?;
+                #[cfg(feature = "cardinality")]
+                write!(f, "; ~{} distinct", values.distinct_count())?;
+            }
+            f.write_str("}")?;
+        }
+        if node.notes().next().is_some() || node.follows_from().next().is_some() {
+            annotated.push(node);
+            write!(f, " [{}]", annotated.len())?;
+        }
+        writeln!(f)?;
+
+        for (idx, child_idx) in children.iter().enumerate() {
+            last.push(idx == children.len() - 1 && collapsed.is_empty());
+            self.fmt(last, *child_idx, layout, annotated, f)?;
+            last.pop();
+        }
+        if !collapsed.is_empty() {
+            last.push(true);
+            self.fmt_collapsed(last, &collapsed, layout, f)?;
+            last.pop();
+        }
+        Ok(())
+    }
+
+    /// True if the row of `node` is hidden by
+    /// [max_row_level](LoggingCallTreeCollectorBuilder::max_row_level) or
+    /// [hide_target](LoggingCallTreeCollectorBuilder::hide_target).
+    fn is_hidden(&self, node: &CallPathTiming) -> bool {
+        let meta = node.span_metadata();
+        matches!(self.config.max_row_level, Some(max_level) if meta.level() > max_level)
+            || self.config.hidden_targets.iter().any(|hidden| {
+                meta.target()
+                    .strip_prefix(hidden.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+    }
+
+    /// The children of `node` to show, with hidden children replaced by
+    /// their own children to show, and the summed values of the hidden
+    /// children in between.
+    fn shown_children(&self, node: &CallPathTiming) -> (Vec<CallPathPoolId>, Folded) {
+        let mut shown = Vec::new();
+        let mut folded = Folded::default();
+        let mut next = node.children().copied().collect::<Vec<_>>();
+        while let Some(child_idx) = next.pop() {
+            let child = &self.pool[child_idx];
+            if self.is_hidden(child) {
+                folded.own_busy += child.sum_without_children();
+                folded.io_wait += child.sum_io_wait();
+                folded.errors += child.error_count();
+                folded.warnings += child.warn_count();
+                next.extend(child.children().copied());
+            } else {
+                shown.push(child_idx);
+            }
+        }
+        (shown, folded)
+    }
+
+    /// Writes the target and the location of `node` if configured, like
+    /// ` (app::worker, src/worker.rs:42)`.
+    fn fmt_origin(&self, node: &CallPathTiming, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let meta = node.span_metadata();
+        let target = Some(meta.target()).filter(|_| self.config.with_target);
+        let location = match (meta.file(), meta.line()) {
+            (Some(file), Some(line)) if self.config.with_location => {
+                Some(format!("{}:{}", file, line))
+            }
+            (Some(file), None) if self.config.with_location => Some(file.to_string()),
+            _ => None,
+        };
+        match (target, location) {
+            (Some(target), Some(location)) => write!(f, " ({}, {})", target, location),
+            (Some(target), None) => write!(f, " ({})", target),
+            (None, Some(location)) => write!(f, " ({})", location),
+            (None, None) => Ok(()),
+        }
+    }
+
+    /// Writes the tree lines in front of the name of a call path.
+    fn fmt_branch(last: &[bool], child_connector: &str, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if last.len() > 1 {
+            for is_last in last.iter().skip(1).take(last.len() - 2) {
+                f.write_str(if *is_last { " " } else { "┊" })?;
+            }
+            f.write_str(if last[last.len() - 1] { "╰" } else { "├" })?;
+        }
+        f.write_str(child_connector)
+    }
+
+    /// Writes a single row for the `collapsed` children with their summed
+    /// calls and busy times.
+    fn fmt_collapsed(
+        &self,
+        last: &[bool],
+        collapsed: &[&CallPathTiming],
+        layout: &Layout,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        let calls = collapsed
+            .iter()
+            .map(|child| child.call_count())
+            .sum::<usize>();
+        let busy = collapsed
+            .iter()
+            .map(|child| child.sum_with_children())
+            .sum::<Duration>();
+        write!(f, "{:indent$}", "", indent = self.config.left_margin)?;
+        for (idx, column) in layout.columns.iter().enumerate() {
+            if idx > 0 {
+                f.write_str(" ")?;
+            }
+            let extra = layout.extra_widths[idx];
+            match column {
+                Column::Calls => write!(
+                    f,
+                    "{: >width$}",
+                    self.config.number_format.count(calls),
+                    width = 11 + extra
+                )?,
+                Column::BusySum => self.fmt_duration(busy, extra, f)?,
+                Column::PercentOfRoot => self.fmt_percent(busy, f)?,
+                _ => write!(f, "{:width$}", "", width = column.width() + extra)?,
+            }
+            f.write_str(" ┊")?;
+        }
+        f.write_str(" ")?;
+        Self::fmt_branch(last, "─", f)?;
+        writeln!(
+            f,
+            " … ({} fast call{})",
+            calls,
+            if calls == 1 { "" } else { "s" }
+        )
+    }
+
+    /// The call path of the baseline which matches `node_idx`, see
+    /// [Column::VsAverage].
+    fn baseline_of(&self, node_idx: CallPathPoolId) -> Option<&'a CallPathTiming> {
+        let (baseline, matching) = self.baseline.as_ref()?;
+        let baseline: &'a CallPathPool = baseline;
+        Some(&baseline[*matching.get(&node_idx)?])
+    }
+
+    /// Writes the value of `column` for `node`, including the `folded`
+    /// values of hidden children, right-aligned.
+    fn fmt_cell(
+        &self,
+        column: Column,
+        node: &CallPathTiming,
+        baseline: Option<&CallPathTiming>,
+        folded: &Folded,
+        layout: &Layout,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        let number_format = &self.config.number_format;
+        let extra = layout.extra_width(column);
+        let duration = match column {
+            Column::Calls => {
+                let calls = number_format.count(node.call_count());
+                return write!(f, "{: >width$}", calls, width = 11 + extra);
+            }
+            Column::Errors => {
+                let errors = number_format.small_count(node.error_count() + folded.errors);
+                return write!(f, "{: >width$}", errors, width = 5 + extra);
+            }
+            Column::Warnings => {
+                let warnings = number_format.small_count(node.warn_count() + folded.warnings);
+                return write!(f, "{: >width$}", warnings, width = 6 + extra);
+            }
+            Column::Polls => {
+                if node.is_event() {
+                    return write!(f, "{:width$}", "", width = 11 + extra);
+                }
+                let polls = number_format.count(node.poll_count());
+                return write!(f, "{: >width$}", polls, width = 11 + extra);
+            }
+            Column::PercentOfRoot => {
+                if node.is_event() {
+                    return write!(f, "{:14}", "");
+                }
+                return self.fmt_percent(node.sum_with_children(), f);
+            }
+            Column::VsAverage => {
+                let average = baseline.map_or(Duration::default(), CallPathTiming::mean_busy);
+                if node.is_event() || node.call_count() == 0 || average == Duration::default() {
+                    return write!(f, "{:10}", "");
+                }
+                let ratio = node.mean_busy().as_secs_f64() / average.as_secs_f64();
+                let tenths = (ratio * 10.0).round() as u128;
+                let ratio = number_format.decimal(tenths / 10, tenths % 10, 1, false);
+                return write!(f, "{: >9}×", ratio);
+            }
+            Column::Alive => node.span_alive(),
+            Column::BusySum => node.sum_with_children(),
+            Column::MeanBusy => node.mean_busy(),
+            Column::OwnBusy => {
+                f.write_str(" ")?;
+                node.sum_without_children() + folded.own_busy
+            }
+            Column::IoWait => node.sum_io_wait() + folded.io_wait,
+            Column::Cpu => node.sum_cpu(),
+            Column::Idle => node.idle_time(),
+        };
+        if node.is_event() {
+            // Events have no durations.
+            return write!(f, "{:width$}", "", width = 12 + extra);
+        }
+        match layout.hot_thresholds {
+            Some((warm, hot)) if column == Column::OwnBusy => {
+                let color = if duration >= hot {
+                    ANSI_RED
+                } else if duration >= warm {
+                    ANSI_YELLOW
+                } else {
+                    ANSI_GREEN
+                };
+                f.write_str(color)?;
+                self.fmt_duration(duration, extra, f)?;
+                f.write_str(ANSI_RESET)
+            }
+            _ => self.fmt_duration(duration, extra, f),
+        }
+    }
+
+    /// Writes `busy` as percentage of the busy time of the root
+    /// right-aligned in a column of 14 characters.
+    fn fmt_percent(&self, busy: Duration, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let root_busy = self.root.sum_with_children();
+        if root_busy == Duration::default() {
+            return write!(f, "{:14}", "");
+        }
+        let percent = 100.0 * busy.as_secs_f64() / root_busy.as_secs_f64();
+        let number_format = &self.config.number_format;
+        let percent = match &number_format.custom {
+            Some(custom) => custom(Number::Percent(percent)),
+            None => {
+                let tenths = (percent * 10.0).round() as u128;
+                number_format.decimal(tenths / 10, tenths % 10, 1, false)
+            }
+        };
+        write!(f, "{: >12} %", percent)
+    }
+
+    /// Writes `duration` in the configured unit right-aligned in a column
+    /// of 12 + `extra` characters.
+    fn fmt_duration(
+        &self,
+        duration: Duration,
+        extra: usize,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        let duration = self.format_duration(duration);
+        write!(f, "{: >width$}", duration, width = 12 + extra)
+    }
+
+    /// Writes `duration` in the configured unit.
+    fn format_duration(&self, duration: Duration) -> String {
+        self.config.format_duration(duration)
+    }
+
+    /// The number of characters by which `column` must be wider than its
+    /// default width to fit the largest value of the table, e.g. the
+    /// durations of batch jobs that run for hours.
+    ///
+    /// Collapsed rows and hidden rows folded into their ancestors show
+    /// sums, so the widths are based on upper bounds of the values.
+    fn extra_width(&self, column: Column) -> usize {
+        let mut nodes = vec![self.root];
+        let mut max_duration = Duration::default();
+        let mut sum_count = 0usize;
+        while let Some(node) = nodes.pop() {
+            nodes.extend(node.children().map(|child_idx| &self.pool[*child_idx]));
+            sum_count += match column {
+                Column::Calls => node.call_count(),
+                Column::Polls => node.poll_count(),
+                Column::Errors => node.error_count(),
+                Column::Warnings => node.warn_count(),
+                _ => 0,
+            };
+            if node.is_event() {
+                // Events have no durations.
+                continue;
+            }
+            max_duration = match column {
+                Column::Alive => max_duration.max(node.span_alive()),
+                Column::BusySum | Column::OwnBusy => max_duration.max(node.sum_with_children()),
+                Column::MeanBusy => max_duration.max(node.mean_busy()),
+                Column::IoWait => max_duration + node.sum_io_wait(),
+                Column::Cpu => max_duration.max(node.sum_cpu()),
+                Column::Idle => max_duration.max(node.idle_time()),
+                _ => max_duration,
+            };
+        }
+        let number_format = &self.config.number_format;
+        let (formatted, width) = match column {
+            Column::Calls | Column::Polls => (number_format.count(sum_count), column.width()),
+            Column::Errors | Column::Warnings => {
+                (number_format.small_count(sum_count), column.width())
+            }
+            // Percentages above 100% and ratios above 1000 are rare enough.
+            Column::PercentOfRoot | Column::VsAverage => return 0,
+            _ => (self.format_duration(max_duration), 12),
+        };
+        formatted.chars().count().saturating_sub(width)
+    }
+
+    /// Writes the busy times per target group below the table, see
+    /// [TargetRollup].
+    fn fmt_target_rollup(&self, rollup: TargetRollup, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut totals = Vec::new();
+        target_totals(rollup, self.pool, self.root, &mut Vec::new(), &mut totals);
+        totals.sort_by_key(|totals| std::cmp::Reverse(totals.own_busy));
+        // The own busy times are never larger than the busy times.
+        let extra = totals
+            .iter()
+            .map(|totals| self.format_duration(totals.busy).chars().count())
+            .max()
+            .unwrap_or_default()
+            .saturating_sub(12);
+
+        let unit = self.config.duration_unit;
+        let indent = self.config.left_margin;
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{:indent$}{:extra$}{} {:extra$}{} target",
+            "",
+            "",
+            Column::BusySum.header(unit),
+            "",
+            Column::OwnBusy.header(unit),
+            indent = indent,
+            extra = extra
+        )?;
+        writeln!(
+            f,
+            "{:indent$}{extra}{}─{extra}{}───────────────────────",
+            "",
+            Column::BusySum.separator(),
+            Column::OwnBusy.separator(),
+            indent = indent,
+            extra = "─".repeat(extra)
+        )?;
+        for totals in totals {
+            write!(f, "{:indent$}", "", indent = indent)?;
+            self.fmt_duration(totals.busy, extra, f)?;
+            f.write_str(" ┊  ")?;
+            self.fmt_duration(totals.own_busy, extra, f)?;
+            writeln!(f, " ┊ {}", totals.group)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the busy time per thread and call path below the table, see
+    /// [LoggingCallTreeCollectorBuilder::thread_breakdown].
+    fn fmt_thread_breakdown(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut rows = Vec::new();
+        let mut nodes = vec![self.root];
+        while let Some(node) = nodes.pop() {
+            let threads = node.per_thread_busy();
+            for thread in threads {
+                let label = match thread.thread_name() {
+                    // Runtimes often use the same name for all workers.
+                    Some(name)
+                        if threads
+                            .iter()
+                            .all(|other| other == thread || other.thread_name() != Some(name)) =>
+                    {
+                        name.to_string()
+                    }
+                    Some(name) => format!("{} ({})", name, thread.thread_id()),
+                    None => thread.thread_id().to_string(),
+                };
+                rows.push((node.path_string(self.pool, "/"), label, thread));
+            }
+            let mut children = node.children().copied().collect::<Vec<_>>();
+            children.sort_by_key(|child_idx| std::cmp::Reverse(*child_idx));
+            nodes.extend(children.into_iter().map(|child_idx| &self.pool[child_idx]));
+        }
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let extra = rows
+            .iter()
+            .map(|(_, _, thread)| self.format_duration(thread.busy()).chars().count())
+            .max()
+            .unwrap_or_default()
+            .saturating_sub(12);
+
+        let indent = self.config.left_margin;
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{:indent$}{:extra$}{} {} thread per call path",
+            "",
+            "",
+            Column::BusySum.header(self.config.duration_unit),
+            Column::Polls.header(self.config.duration_unit),
+            indent = indent,
+            extra = extra
+        )?;
+        writeln!(
+            f,
+            "{:indent$}{extra}{}─{}───────────────────────",
+            "",
+            Column::BusySum.separator(),
+            Column::Polls.separator(),
+            indent = indent,
+            extra = "─".repeat(extra)
+        )?;
+        for (path, label, thread) in rows {
+            write!(f, "{:indent$}", "", indent = indent)?;
+            self.fmt_duration(thread.busy(), extra, f)?;
+            writeln!(
+                f,
+                " ┊ {: >11} ┊ {} on {}",
+                self.config.number_format.count(thread.polls()),
+                path,
+                label
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Writes a line with the number of spans that were not tracked
+    /// individually, if any, see [CallPathPool::untracked_spans].
+    fn fmt_untracked(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let untracked = self.pool.untracked_spans();
+        if untracked == 0 {
+            return Ok(());
+        }
+        let reasons = [
+            (
+                self.pool.depth_capped_spans(),
+                "beyond the maximum call depth",
+            ),
+            (self.pool.skipped_spans(), "over the per tree budget"),
+            (self.pool.unmatched_exits(), "exited without being entered"),
+        ];
+        write!(
+            f,
+            "{:indent$}⚠ {} span{} not tracked individually:",
+            "",
+            untracked,
+            if untracked == 1 { "" } else { "s" },
+            indent = self.config.left_margin
+        )?;
+        let mut separator = " ";
+        for (count, reason) in reasons {
+            if count > 0 {
+                write!(f, "{}{} {}", separator, count, reason)?;
+                separator = ", ";
+            }
+        }
+        writeln!(f)
+    }
+
+    /// Lists the notes and the [followed call paths](CallPathTiming::follows_from)
+    /// of the `annotated` call paths below the table.
+    fn fmt_notes(&self, annotated: &[&CallPathTiming], f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if annotated.is_empty() {
+            return Ok(());
+        }
+        writeln!(f)?;
+        for (idx, node) in annotated.iter().enumerate() {
+            write!(
+                f,
+                "{:indent$}[{}]",
+                "",
+                idx + 1,
+                indent = self.config.left_margin
+            )?;
+            for (note_idx, (note, count)) in node.notes().enumerate() {
+                f.write_str(if note_idx == 0 { " " } else { ", " })?;
+                f.write_str(note)?;
+                if count > 1 {
+                    write!(f, " ({}×)", count)?;
+                }
+            }
+            for (link_idx, (path, count)) in node.follows_from().enumerate() {
+                let separator = match (link_idx, node.notes().next()) {
+                    (0, None) => " follows from ",
+                    (0, Some(_)) => "; follows from ",
+                    _ => ", ",
+                };
+                f.write_str(separator)?;
+                f.write_str(path)?;
+                if count > 1 {
+                    write!(f, " ({}×)", count)?;
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use quanta::Mock;
+    use tracing::Level;
+
+    use super::{
+        CollapseBelow, Column, DurationRounding, DurationUnit, FmtPrefix, HeaderLocation,
+        Highlight, LoggingCallTreeCollectorBuilder, Number, NumberFormat, OutputFormat, SortOrder,
+        TargetRollup, TextTableFormatter,
+    };
+    use crate::{
+        internal::test::{
+            collect_call_trees, collect_call_trees_with_builder, compound_call, cooking_party,
+            one_ns,
+        },
+        CallTreeCollectorBuilder,
+    };
+
+    #[test]
+    fn display_one_ns() {
+        let str = display_call_trees(|mock| one_ns(&mock));
+        assert_eq!(
+            &str,
+            indoc::indoc! {r#"
+                    # calls │   ∑ alive ms │    ∑ busy ms │ ∑ own busy ms │ span tree
+                ────────────┼──────────────┼──────────────┼────────────-──┼───────────────────────
+                      0 001 ┊        0.000 ┊        0.000 ┊         0.000 ┊ ─ one_ns
+
+            "#},
+            "got:\n{}",
+            str
+        );
+    }
+
+    #[test]
+    fn text_table_formatter() {
+        let call_trees = collect_call_trees(|mock| compound_call(&mock));
+        let collector = LoggingCallTreeCollectorBuilder::default().build();
+        let logged = collector.formatter().display(&call_trees[0]).to_string();
+
+        let mut out = String::new();
+        TextTableFormatter::default()
+            .fmt(&call_trees[0], &mut out)
+            .unwrap();
+        assert_eq!(out, logged);
+        assert!(out.starts_with(&format!("{:20}    # calls │", "")));
+        assert!(out.contains("┊ ╰─ one_ns\n"));
+    }
+
+    #[test]
+    fn builder_delegates_to_text_table_formatter() {
+        let call_trees = collect_call_trees(|mock| compound_call(&mock));
+        let builder = LoggingCallTreeCollectorBuilder::default()
+            .left_margin(2)
+            .columns([Column::Calls, Column::BusySum])
+            .max_rows(1);
+        let formatter = TextTableFormatter::new()
+            .left_margin(2)
+            .columns([Column::Calls, Column::BusySum])
+            .max_rows(1);
+        let table = formatter.display(&call_trees[0]).to_string();
+        assert_eq!(
+            builder
+                .build_formatter()
+                .display(&call_trees[0])
+                .to_string(),
+            table
+        );
+        let collector = LoggingCallTreeCollectorBuilder::default()
+            .table_formatter(formatter)
+            .build();
+        assert_eq!(collector.table(&call_trees[0]).to_string(), table);
+    }
+
+    #[test]
+    fn display_compound_call() {
+        let str = display_call_trees(|mock| compound_call(&mock));
+        assert_eq!(
+            &str,
+            indoc::indoc! {r#"
+                # calls │   ∑ alive ms │    ∑ busy ms │ ∑ own busy ms │ span tree
+            ────────────┼──────────────┼──────────────┼────────────-──┼───────────────────────
+                  0 001 ┊        0.001 ┊        0.001 ┊         0.001 ┊ ┬ compound_call
+                  0 003 ┊        0.000 ┊        0.000 ┊         0.000 ┊ ╰─ one_ns
+      
+            "#},
+            "got:\n{}",
+            str
+        );
+    }
+
+    #[test]
+    fn display_duration_decimals() {
+        let call = |mock: Arc<Mock>| {
+            let span = tracing::info_span!("sleepy");
+            let _enter = span.enter();
+            mock.increment(1_234_567);
+        };
+        let str = display_call_trees_with(
+            LoggingCallTreeCollectorBuilder::default()
+                .duration_decimals(1)
+                .duration_rounding(DurationRounding::Nearest),
+            call,
+        );
+        assert_eq!(
+            &str,
+            indoc::indoc! {r#"
+                # calls │   ∑ alive ms │    ∑ busy ms │ ∑ own busy ms │ span tree
+            ────────────┼──────────────┼──────────────┼────────────-──┼───────────────────────
+                  0 001 ┊          1.2 ┊          1.2 ┊           1.2 ┊ ─ sleepy
+
+            "#},
+            "got:\n{}",
+            str
+        );
+
+        let str = display_call_trees_with(
+            LoggingCallTreeCollectorBuilder::default().duration_decimals(6),
+            call,
+        );
+        assert_eq!(
+            &str,
+            indoc::indoc! {r#"
+                # calls │   ∑ alive ms │    ∑ busy ms │ ∑ own busy ms │ span tree
+            ────────────┼──────────────┼──────────────┼────────────-──┼───────────────────────
+                  0 001 ┊     1.234567 ┊     1.234567 ┊      1.234567 ┊ ─ sleepy
+
+            "#},
+            "got:\n{}",
+            str
+        );
+    }
+
+    #[test]
+    fn display_events() {
+        let str = display_call_trees_with_collector(
+            CallTreeCollectorBuilder::default().collect_events(true),
+            LoggingCallTreeCollectorBuilder::default(),
+            |mock| {
+                tracing::info_span!("incrementing").in_scope(|| {
+                    for _ in 0..1_000 {
+                        tracing::info!("increment");
+                    }
+                    one_ns(&mock);
+                })
+            },
+        );
+        assert_eq!(
+            &str,
+            indoc::indoc! {r#"
+                # calls │   ∑ alive ms │    ∑ busy ms │ ∑ own busy ms │ span tree
+            ────────────┼──────────────┼──────────────┼────────────-──┼───────────────────────
+                  0 001 ┊        0.000 ┊        0.000 ┊         0.000 ┊ ┬ incrementing
+                  1 000 ┊              ┊              ┊               ┊ ├─ increment
+                  0 001 ┊        0.000 ┊        0.000 ┊         0.000 ┊ ╰─ one_ns
+
+            "#},
+            "got:\n{}",
+            str
+        );
+    }
+
+    #[test]
+    fn display_io_wait() {
+        let str = display_call_trees(|mock| {
+            tracing::info_span!("request").in_scope(|| {
+                tracing::info_span!("read").in_scope(|| {
+                    mock.increment(2_000_000);
+                    crate::report_io_wait(Duration::from_millis(2));
+                });
+                one_ns(&mock);
+            })
+        });
+        assert_eq!(
+            &str,
+            indoc::indoc! {r#"
+                # calls │   ∑ alive ms │    ∑ busy ms │ ∑ own busy ms │ ∑ io wait ms │ span tree
+            ────────────┼──────────────┼──────────────┼────────────-──┼──────────────┼───────────────────────
+                  0 001 ┊        2.000 ┊        2.000 ┊         0.000 ┊        0.000 ┊ ┬ request
+                  0 001 ┊        2.000 ┊        2.000 ┊         2.000 ┊        2.000 ┊ ├─ read
+                  0 001 ┊        0.000 ┊        0.000 ┊         0.000 ┊        0.000 ┊ ╰─ one_ns
+
+            "#},
+            "got:\n{}",
+            str
+        );
+    }
+
+    #[test]
+    fn display_captured_fields() {
+        let str = display_call_trees_with_collector(
+            CallTreeCollectorBuilder::default().capture_field("http.route"),
+            LoggingCallTreeCollectorBuilder::default(),
+            |mock| {
+                tracing::info_span!("server").in_scope(|| {
+                    for route in ["/users", "/items", "/users"] {
+                        tracing::info_span!("handle", http.route = route)
+                            .in_scope(|| one_ns(&mock));
+                    }
+                    let late = tracing::info_span!("handle", http.route = tracing::field::Empty);
+                    late.record("http.route", &"/late");
+                })
+            },
+        );
+        assert_eq!(
+            &str,
+            indoc::indoc! {r#"
+                # calls │   ∑ alive ms │    ∑ busy ms │ ∑ own busy ms │ span tree
+            ────────────┼──────────────┼──────────────┼────────────-──┼───────────────────────
+                  0 001 ┊        0.000 ┊        0.000 ┊         0.000 ┊ ┬ server
+                  0 003 ┊        0.000 ┊        0.000 ┊         0.000 ┊ ├┬ handle {http.route: /users (2×), /items}
+                  0 003 ┊        0.000 ┊        0.000 ┊         0.000 ┊ ┊╰─ one_ns
+                  0 001 ┊        0.000 ┊        0.000 ┊         0.000 ┊ ╰─ handle {http.route: /late}
+
+            "#},
+            "got:\n{}",
+            str
+        );
+    }
+
+    #[cfg(feature = "cardinality")]
+    #[test]
+    fn display_distinct_count() {
+        let str = display_call_trees_with_collector(
+            CallTreeCollectorBuilder::default().capture_field("table"),
+            LoggingCallTreeCollectorBuilder::default().columns([Column::Calls]),
+            |_mock| {
+                tracing::info_span!("request").in_scope(|| {
+                    for table in 0..12 {
+                        tracing::info_span!("query", table).in_scope(|| {});
+                    }
+                })
+            },
+        );
+        assert_eq!(
+            &str,
+            indoc::indoc! {r#"
+                # calls │ span tree
+            ────────────┼───────────────────────
+                  0 001 ┊ ┬ request
+                  0 012 ┊ ╰─ query {table: 0, 1, 2, 3, 4, 5, 6, 7, 4 more; ~12 distinct}
+
+            "#},
+            "got:\n{}",
+            str
+        );
+    }
+
+    #[test]
+    fn display_highlight_own_busy() {
+        let call = |mock: Arc<Mock>| {
+            tracing::info_span!("request").in_scope(|| {
+                tracing::info_span!("cold").in_scope(|| mock.increment(1_000));
+                tracing::info_span!("warm").in_scope(|| mock.increment(2_000));
+                tracing::info_span!("hot").in_scope(|| mock.increment(3_000));
+            })
+        };
+        let colors = |highlight| {
+            let str = display_call_trees_with(
+                LoggingCallTreeCollectorBuilder::default()
+                    .columns([Column::OwnBusy])
+                    .highlight_own_busy(highlight),
+                call,
+            );
+            str.lines()
+                .skip(2)
+                .filter(|line| !line.is_empty())
+                .map(|line| {
+                    let name = line.rsplit(' ').next().unwrap();
+                    let color = &line.split("\x1b[").nth(1).unwrap()[..3];
+                    format!("{} {}", name, color)
+                })
+                .collect::<Vec<_>>()
+        };
+        let expected = vec!["request 32m", "cold 32m", "warm 33m", "hot 31m"];
+        assert_eq!(
+            colors(Highlight::Durations {
+                warm: Duration::from_micros(2),
+                hot: Duration::from_micros(3),
+            }),
+            expected
+        );
+        // Of the four call paths, the top quarter is warm, the maximum hot.
+        assert_eq!(
+            colors(Highlight::Percentiles {
+                warm: 0.75,
+                hot: 1.0
+            }),
+            expected
+        );
+    }
+
+    #[test]
+    fn display_columns() {
+        let str = display_call_trees_with(
+            LoggingCallTreeCollectorBuilder::default().columns([
+                Column::OwnBusy,
+                Column::Calls,
+                Column::Errors,
+            ]),
+            |mock| compound_call(&mock),
+        );
+        assert_eq!(
+            &str,
+            indoc::indoc! {r#"
+                ∑ own busy ms │     # calls │  #err │ span tree
+                ───────────-──┼─────────────┼───────┼───────────────────────
+                        0.001 ┊       0 001 ┊     0 ┊ ┬ compound_call
+                        0.000 ┊       0 003 ┊     0 ┊ ╰─ one_ns
+
+            "#},
+            "got:\n{}",
+            str
+        );
+    }
+
+    #[test]
+    fn display_polls() {
+        let str = display_call_trees_with(
+            LoggingCallTreeCollectorBuilder::default().columns([
+                Column::Calls,
+                Column::Polls,
+                Column::Idle,
+            ]),
+            |mock| {
+                let request = tracing::info_span!("request");
+                for _ in 0..3 {
+                    request.in_scope(|| {
+                        tracing::info_span!("query").in_scope(|| mock.increment(1_000));
+                    });
+                    mock.increment(2_000_000);
+                }
+            },
+        );
+        assert_eq!(
+            &str,
+            indoc::indoc! {r#"
+                # calls │     # polls │    ∑ idle ms │ span tree
+            ────────────┼─────────────┼──────────────┼───────────────────────
+                  0 001 ┊       0 003 ┊        4.000 ┊ ┬ request
+                  0 003 ┊       0 003 ┊        0.000 ┊ ╰─ query
+
+            "#},
+            "got:\n{}",
+            str
+        );
+    }
+
+    #[test]
+    fn display_thread_breakdown() {
+        let str = display_call_trees_with_collector(
+            CallTreeCollectorBuilder::default().per_thread_busy(true),
+            LoggingCallTreeCollectorBuilder::default()
+                .columns([Column::Calls])
+                .thread_breakdown(true),
+            |mock| {
+                let request = tracing::info_span!("request");
+                let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+                let poll = |busy| {
+                    request
+                        .in_scope(|| tracing::info_span!("query").in_scope(|| mock.increment(busy)))
+                };
+                for (worker, polls) in [("worker-1", vec![1_000, 10]), ("worker-2", vec![2_000])] {
+                    std::thread::scope(|scope| {
+                        std::thread::Builder::new()
+                            .name(worker.to_string())
+                            .spawn_scoped(scope, || {
+                                tracing::dispatcher::with_default(&dispatch, || {
+                                    polls.iter().for_each(|busy| poll(*busy))
+                                })
+                            })
+                            .unwrap();
+                    });
+                }
+            },
+        );
+        assert_eq!(
+            &str,
+            indoc::indoc! {r#"
+                # calls │ span tree
+            ────────────┼───────────────────────
+                  0 001 ┊ ┬ request
+                  0 003 ┊ ╰─ query
+
+               ∑ busy ms │     # polls │ thread per call path
+            ─────────────┼─────────────┼───────────────────────
+                   0.001 ┊       0 002 ┊ request on worker-1
+                   0.002 ┊       0 001 ┊ request on worker-2
+                   0.001 ┊       0 002 ┊ request/query on worker-1
+                   0.002 ┊       0 001 ┊ request/query on worker-2
+
+            "#},
+            "got:\n{}",
+            str
+        );
+    }
+
+    #[test]
+    fn display_percent_of_root() {
+        let str = display_call_trees_with(
+            LoggingCallTreeCollectorBuilder::default().columns([
+                Column::Calls,
+                Column::BusySum,
+                Column::PercentOfRoot,
+            ]),
+            |mock| {
+                tracing::info_span!("request").in_scope(|| {
+                    mock.increment(250_000);
+                    tracing::info_span!("query").in_scope(|| mock.increment(750_000));
+                })
+            },
+        );
+        assert_eq!(
+            &str,
+            indoc::indoc! {r#"
+                # calls │    ∑ busy ms │ % of root busy │ span tree
+            ────────────┼──────────────┼────────────────┼───────────────────────
+                  0 001 ┊        1.000 ┊        100.0 % ┊ ┬ request
+                  0 001 ┊        0.750 ┊         75.0 % ┊ ╰─ query
+
+            "#},
+            "got:\n{}",
+            str
+        );
+    }
+
+    #[test]
+    fn display_collapse_below() {
+        let call = |mock: Arc<Mock>| {
+            tracing::info_span!("request").in_scope(|| {
+                tracing::info_span!("helper").in_scope(|| {
+                    tracing::info_span!("nested_helper").in_scope(|| mock.increment(1_000));
+                });
+                tracing::info_span!("query").in_scope(|| mock.increment(900_000));
+                for _ in 0..2 {
+                    tracing::info_span!("format").in_scope(|| mock.increment(2_000));
+                }
+                tracing::info!("done");
+            })
+        };
+        let columns = [
+            Column::Calls,
+            Column::Alive,
+            Column::BusySum,
+            Column::PercentOfRoot,
+        ];
+        let expected = indoc::indoc! {r#"
+                # calls │   ∑ alive ms │    ∑ busy ms │ % of root busy │ span tree
+            ────────────┼──────────────┼──────────────┼────────────────┼───────────────────────
+                  0 001 ┊        0.905 ┊        0.905 ┊        100.0 % ┊ ┬ request
+                  0 001 ┊        0.900 ┊        0.900 ┊         99.4 % ┊ ├─ query
+                  0 001 ┊              ┊              ┊                ┊ ├─ done
+                  0 003 ┊              ┊        0.005 ┊          0.6 % ┊ ╰─ … (3 fast calls)
+
+        "#};
+        for collapse_below in [
+            CollapseBelow::Busy(Duration::from_micros(10)),
+            CollapseBelow::PercentOfRoot(1.0),
+        ] {
+            let str = display_call_trees_with_collector(
+                CallTreeCollectorBuilder::default().collect_events(true),
+                LoggingCallTreeCollectorBuilder::default()
+                    .columns(columns)
+                    .collapse_below(collapse_below),
+                call,
+            );
+            assert_eq!(&str, expected, "got:\n{}", str);
+        }
+    }
+
+    #[test]
+    fn display_max_rows() {
+        let str = display_call_trees_with(
+            LoggingCallTreeCollectorBuilder::default()
+                .columns([Column::Calls, Column::OwnBusy])
+                .max_rows(2),
+            |mock| {
+                tracing::info_span!("request").in_scope(|| {
+                    tracing::info_span!("a").in_scope(|| mock.increment(5_000));
+                    tracing::info_span!("b").in_scope(|| {
+                        tracing::info_span!("b1").in_scope(|| mock.increment(1_000));
+                        tracing::info_span!("b2").in_scope(|| mock.increment(10_000));
+                    });
+                    tracing::info_span!("c").in_scope(|| mock.increment(2_000));
+                })
+            },
+        );
+        assert_eq!(
+            &str,
+            indoc::indoc! {r#"
+                # calls │ ∑ own busy ms │ span tree
+            ────────────┼────────────-──┼───────────────────────
+                  0 001 ┊         0.000 ┊ ┬ request
+                  0 001 ┊         0.005 ┊ ├─ a
+                  0 001 ┊         0.000 ┊ ╰┬ b
+                  0 001 ┊         0.010 ┊  ╰─ b2
+            … 2 call paths with less own busy time not shown
+
+            "#},
+            "got:\n{}",
+            str
+        );
+    }
+
+    #[test]
+    fn display_target_and_location() {
+        let call = |_mock| tracing::info_span!(target: "app::worker", "run").in_scope(|| {});
+        let location = format!("{}:{}", file!(), line!() - 1);
+        let row = |builder: LoggingCallTreeCollectorBuilder| {
+            let str = display_call_trees_with(builder.columns([Column::Calls]), call);
+            str.lines().nth(2).unwrap().to_string()
+        };
+
+        assert_eq!(
+            row(LoggingCallTreeCollectorBuilder::default()),
+            "      0 001 ┊ ─ run"
+        );
+        assert_eq!(
+            row(LoggingCallTreeCollectorBuilder::default().with_target(true)),
+            "      0 001 ┊ ─ run (app::worker)"
+        );
+        assert_eq!(
+            row(LoggingCallTreeCollectorBuilder::default().with_location(true)),
+            format!("      0 001 ┊ ─ run ({})", location)
+        );
+        assert_eq!(
+            row(LoggingCallTreeCollectorBuilder::default()
+                .with_target(true)
+                .with_location(true)),
+            format!("      0 001 ┊ ─ run (app::worker, {})", location)
+        );
+    }
+
+    #[test]
+    fn display_hidden_rows() {
+        let call = |mock: Arc<Mock>| {
+            tracing::info_span!("request").in_scope(|| {
+                mock.increment(1_000);
+                tracing::debug_span!("pool").in_scope(|| {
+                    mock.increment(2_000);
+                    tracing::info_span!("query").in_scope(|| mock.increment(4_000));
+                });
+                tracing::info_span!(target: "hyper::client", "send").in_scope(|| {
+                    mock.increment(8_000);
+                    tracing::warn!("retrying");
+                });
+            })
+        };
+        let builder = || {
+            LoggingCallTreeCollectorBuilder::default().columns([
+                Column::Calls,
+                Column::BusySum,
+                Column::OwnBusy,
+                Column::Warnings,
+            ])
+        };
+        let str = display_call_trees_with(builder(), call);
+        assert_eq!(
+            &str,
+            indoc::indoc! {r#"
+                # calls │    ∑ busy ms │ ∑ own busy ms │  #warn │ span tree
+            ────────────┼──────────────┼────────────-──┼────────┼───────────────────────
+                  0 001 ┊        0.015 ┊         0.001 ┊      0 ┊ ┬ request
+                  0 001 ┊        0.006 ┊         0.002 ┊      0 ┊ ├┬ pool
+                  0 001 ┊        0.004 ┊         0.004 ┊      0 ┊ ┊╰─ query
+                  0 001 ┊        0.008 ┊         0.008 ┊      1 ┊ ╰─ send
+
+            "#},
+            "got:\n{}",
+            str
+        );
+
+        let str = display_call_trees_with(
+            builder().max_row_level(Level::INFO).hide_target("hyper"),
+            call,
+        );
+        assert_eq!(
+            &str,
+            indoc::indoc! {r#"
+                # calls │    ∑ busy ms │ ∑ own busy ms │  #warn │ span tree
+            ────────────┼──────────────┼────────────-──┼────────┼───────────────────────
+                  0 001 ┊        0.015 ┊         0.011 ┊      1 ┊ ┬ request
+                  0 001 ┊        0.004 ┊         0.004 ┊      0 ┊ ╰─ query
+
+            "#},
+            "got:\n{}",
+            str
+        );
+
+        // Only whole path segments of targets match.
+        let str = display_call_trees_with(builder().hide_target("hyp"), call);
+        assert!(str.contains("send"), "got:\n{}", str);
+    }
+
+    #[test]
+    fn display_wide_values() {
+        const HOUR: u64 = 3_600_000_000_000;
+        let str = display_call_trees_with(
+            LoggingCallTreeCollectorBuilder::default()
+                .columns([Column::Calls, Column::BusySum, Column::OwnBusy])
+                .collapse_below(CollapseBelow::Busy(Duration::from_secs(1)))
+                .target_rollup(TargetRollup::Crate),
+            |mock| {
+                tracing::info_span!("batch_job").in_scope(|| {
+                    tracing::info_span!("import").in_scope(|| mock.increment(30 * HOUR));
+                })
+            },
+        );
+        assert_eq!(
+            &str,
+            indoc::indoc! {r#"
+                # calls │     ∑ busy ms │  ∑ own busy ms │ span tree
+            ────────────┼───────────────┼─────────────-──┼───────────────────────
+                  0 001 ┊ 108000000.000 ┊          0.000 ┊ ┬ batch_job
+                  0 001 ┊ 108000000.000 ┊  108000000.000 ┊ ╰─ import
+
+                ∑ busy ms │  ∑ own busy ms │ target
+            ──────────────┼─────────────-──┼───────────────────────
+            108000000.000 ┊  108000000.000 ┊ reqray
+
+            "#},
+            "got:\n{}",
+            str
+        );
+    }
+
+    #[test]
+    fn display_target_rollup() {
+        let call = |mock: Arc<Mock>| {
+            tracing::info_span!(target: "app::handler", "request").in_scope(|| {
+                mock.increment(1_000);
+                tracing::info_span!(target: "sqlx::query", "query").in_scope(|| {
+                    mock.increment(2_000);
+                    tracing::info_span!(target: "app::codec", "decode")
+                        .in_scope(|| mock.increment(4_000));
+                });
+                tracing::info_span!(target: "sqlx::pool", "acquire")
+                    .in_scope(|| mock.increment(8_000));
+            })
+        };
+        let builder = |rollup| {
+            LoggingCallTreeCollectorBuilder::default()
+                .columns([Column::Calls])
+                .target_rollup(rollup)
+        };
+        let str = display_call_trees_with(builder(TargetRollup::Crate), call);
+        assert_eq!(
+            &str,
+            indoc::indoc! {r#"
+                # calls │ span tree
+            ────────────┼───────────────────────
+                  0 001 ┊ ┬ request
+                  0 001 ┊ ├┬ query
+                  0 001 ┊ ┊╰─ decode
+                  0 001 ┊ ╰─ acquire
+
+               ∑ busy ms │ ∑ own busy ms │ target
+            ─────────────┼────────────-──┼───────────────────────
+                   0.014 ┊         0.010 ┊ sqlx
+                   0.015 ┊         0.005 ┊ app
+
+            "#},
+            "got:\n{}",
+            str
+        );
+
+        let str = display_call_trees_with(builder(TargetRollup::Module), call);
+        assert!(
+            str.ends_with(indoc::indoc! {r#"
+               ∑ busy ms │ ∑ own busy ms │ target
+            ─────────────┼────────────-──┼───────────────────────
+                   0.008 ┊         0.008 ┊ sqlx::pool
+                   0.004 ┊         0.004 ┊ app::codec
+                   0.006 ┊         0.002 ┊ sqlx::query
+                   0.015 ┊         0.001 ┊ app::handler
+
+            "#}),
+            "got:\n{}",
+            str
+        );
+    }
+
+    #[test]
+    fn display_event_counts() {
+        let str = display_call_trees(|mock| {
+            tracing::info_span!("request").in_scope(|| {
+                tracing::warn!("slow");
+                tracing::info_span!("fetch").in_scope(|| {
+                    tracing::error!("failed");
+                    tracing::error!("failed again");
+                    tracing::info!("not counted");
+                });
+                one_ns(&mock);
+            })
+        });
+        assert_eq!(
+            &str,
+            indoc::indoc! {r#"
+                # calls │   ∑ alive ms │    ∑ busy ms │ ∑ own busy ms │  #err │  #warn │ span tree
+            ────────────┼──────────────┼──────────────┼────────────-──┼───────┼────────┼───────────────────────
+                  0 001 ┊        0.000 ┊        0.000 ┊         0.000 ┊     0 ┊      1 ┊ ┬ request
+                  0 001 ┊        0.000 ┊        0.000 ┊         0.000 ┊     2 ┊      0 ┊ ├─ fetch
+                  0 001 ┊        0.000 ┊        0.000 ┊         0.000 ┊     0 ┊      0 ┊ ╰─ one_ns
+
+            "#},
+            "got:\n{}",
+            str
+        );
+    }
+
+    #[test]
+    fn structured_output() {
+        use std::{fmt::Write, sync::Mutex};
+        use tracing_subscriber::{layer::Context, prelude::*, Layer};
+
+        /// Captures the fields of all events.
+        struct Capture(Arc<Mutex<Vec<String>>>);
+
+        impl<S: tracing::Subscriber> Layer<S> for Capture {
+            fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+                let mut fields = String::new();
+                let mut visitor = |field: &tracing::field::Field, value: &dyn std::fmt::Debug| {
+                    write!(&mut fields, " {}={:?}", field, value).unwrap();
+                };
+                event.record(&mut visitor);
+                self.0.lock().unwrap().push(fields);
+            }
+        }
+
+        let call_trees = crate::internal::test::collect_call_trees(|mock| compound_call(&mock));
+        let collector = LoggingCallTreeCollectorBuilder::default()
+            .output_format(OutputFormat::Structured)
+            .build();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let subscriber = tracing_subscriber::registry().with(Capture(events.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            for call_tree in &call_trees {
+                collector.log(call_tree);
+            }
+        });
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 3, "got:\n{:#?}", events);
+        assert!(events[0].starts_with(" message=Call summary of compound_call@"));
+        assert_eq!(
+            events[1..],
+            [
+                " message=call path call_tree=0 call_path=\"compound_call\" calls=1 \
+                 alive_ns=1113 busy_ns=1113 own_busy_ns=1110 io_wait_ns=0 \
+                 errors=0 warnings=0 retry=false",
+                " message=call path call_tree=0 call_path=\"compound_call/one_ns\" calls=3 \
+                 alive_ns=3 busy_ns=3 own_busy_ns=3 io_wait_ns=0 \
+                 errors=0 warnings=0 retry=false",
+            ],
+            "got:\n{:#?}",
+            events
+        );
+        drop(events);
+
+        let collector = LoggingCallTreeCollectorBuilder::default()
+            .output_format(OutputFormat::StructuredSummary)
+            .build();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let subscriber = tracing_subscriber::registry().with(Capture(events.clone()));
+        tracing::subscriber::with_default(subscriber, || collector.log(&call_trees[0]));
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 1, "got:\n{:#?}", events);
+        let (message, fields) = events[0].split_once(" root=").unwrap();
+        assert!(message.starts_with(" message=Call summary of compound_call@"));
+        let (fields, tree) = fields.split_once(" tree=").unwrap();
+        let line = call_trees[0].root().span_metadata().line().unwrap();
+        assert_eq!(
+            fields,
+            format!(
+                "\"compound_call\" file=\"src/internal.rs\" line={} total_alive_ms=0.001113 \
+                 total_busy_ms=0.001113 own_busy_ms=0.00111 call_paths=2 degraded=false \
+                 skipped_spans=0 untracked_spans=0",
+                line
+            )
+        );
+        let tree: String = serde_json::from_str(tree).unwrap();
+        assert_eq!(
+            tree,
+            r#"{"name":"compound_call","calls":1,"alive_ns":1113,"busy_ns":1113,"own_busy_ns":1110,"io_wait_ns":0,"children":[{"name":"one_ns","calls":3,"alive_ns":3,"busy_ns":3,"own_busy_ns":3,"io_wait_ns":0}]}"#
+        );
+    }
+
+    #[test]
+    fn stderr_fallback_rate_limit() {
+        let mut fallback = super::StderrFallback::default();
+        let mut out = Vec::new();
+        let start = std::time::Instant::now();
+        for secs in [0, 1, 9, 10] {
+            let now = start + Duration::from_secs(secs);
+            fallback.write(now, "tree", &"table\n", &mut out).unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "reqray: tree\n\ntable\nreqray: tree (2 call trees suppressed before)\n\ntable\n"
+        );
+    }
+
+    #[test]
+    fn min_root_durations() {
+        let call_trees =
+            collect_call_trees_with_builder(CallTreeCollectorBuilder::default(), |mock| {
+                tracing::info_span!("fast").in_scope(|| mock.increment(1_000));
+                let slow = tracing::info_span!("slow");
+                slow.in_scope(|| mock.increment(1_000_000));
+                mock.increment(1_000_000);
+            });
+        let fast = call_trees[0].root();
+        let slow = call_trees[1].root();
+
+        let busy = LoggingCallTreeCollectorBuilder::default()
+            .min_root_busy(Duration::from_millis(1))
+            .build();
+        assert!(!busy.is_slow_enough(fast));
+        assert!(busy.is_slow_enough(slow));
+
+        let alive = LoggingCallTreeCollectorBuilder::default()
+            .min_root_alive(Duration::from_millis(2))
+            .build();
+        assert!(!alive.is_slow_enough(fast));
+        assert!(alive.is_slow_enough(slow));
+        let both = LoggingCallTreeCollectorBuilder::default()
+            .min_root_busy(Duration::from_millis(1))
+            .min_root_alive(Duration::from_millis(3))
+            .build();
+        assert!(!both.is_slow_enough(slow));
+
+        let thresholds = both.thresholds_handle();
+        thresholds.set_min_root_alive(None);
+        assert!(both.is_slow_enough(slow));
+        assert!(!both.is_slow_enough(fast));
+        thresholds.set_min_root_busy(Some(Duration::ZERO));
+        assert!(both.is_slow_enough(fast));
+        assert_eq!(thresholds.min_root_busy(), Some(Duration::ZERO));
+        assert_eq!(thresholds.min_root_alive(), None);
+    }
+
+    #[test]
+    fn rate_limit() {
+        let call_trees =
+            collect_call_trees_with_builder(CallTreeCollectorBuilder::default(), |mock| {
+                tracing::info_span!("fast").in_scope(|| mock.increment(1_000));
+                tracing::info_span!("slow").in_scope(|| mock.increment(1_000_000));
+            });
+        let fast = call_trees[0].root();
+        let slow = call_trees[1].root();
+
+        let mut limiter = super::RateLimiter::new(
+            super::RateLimit::per_minute(6)
+                .with_burst(2)
+                .always_above_busy(Duration::from_millis(1)),
+        );
+        let start = std::time::Instant::now();
+        let admit = |limiter: &mut super::RateLimiter, root, secs| {
+            limiter.admit(root, start + Duration::from_secs(secs))
+        };
+        assert_eq!(admit(&mut limiter, fast, 0), Some(0));
+        assert_eq!(admit(&mut limiter, fast, 0), Some(0));
+        assert_eq!(admit(&mut limiter, fast, 1), None);
+        assert_eq!(admit(&mut limiter, fast, 2), None);
+        // Slow call trees are always logged and do not take tokens.
+        assert_eq!(admit(&mut limiter, slow, 3), Some(0));
+        // One token every ten seconds.
+        assert_eq!(admit(&mut limiter, fast, 10), Some(2));
+        assert_eq!(admit(&mut limiter, fast, 11), None);
+        assert_eq!(admit(&mut limiter, fast, 100), Some(1));
+        assert_eq!(admit(&mut limiter, fast, 100), Some(0));
+        assert_eq!(admit(&mut limiter, fast, 100), None);
+    }
+
+    #[test]
+    fn from_env() {
+        let lookup = |name: &str| match name {
+            "REQRAY_MAX_DEPTH" => Some("4".to_string()),
+            "REQRAY_MIN_ROOT_MS" => Some("2.5".to_string()),
+            "REQRAY_FORMAT" => Some("JSON".to_string()),
+            "REQRAY_LEVEL" => Some("loud".to_string()),
+            _ => None,
+        };
+        let builder = LoggingCallTreeCollectorBuilder::from_lookup(&lookup);
+        assert_eq!(builder.formatter.max_call_depth, 4);
+        assert_eq!(builder.min_root_busy, Some(Duration::from_micros(2_500)));
+        assert_eq!(builder.output_format, OutputFormat::StructuredSummary);
+        // Invalid, so the default.
+        assert_eq!(builder.level, Level::INFO);
 
-                let connect_me = if *last.iter().last().unwrap() {
-                    "╰"
-                } else {
-                    "├"
-                };
-                f.write_str(connect_me)?;
-                f.write_str(child_connector)?;
+        let collector = crate::CallTreeCollectorBuilder::from_lookup(&lookup)
+            .build_with_collector(builder.build());
+        assert_eq!(collector.handle().max_call_depth(), 4);
+        assert_eq!(collector.handle().sample_rate(), 1.0);
+    }
+
+    #[test]
+    fn level_and_target() {
+        use std::sync::Mutex;
+        use tracing_subscriber::{layer::Context, prelude::*, EnvFilter, Layer};
+
+        /// Captures the levels and targets of all events.
+        struct Capture(Arc<Mutex<Vec<(Level, String)>>>);
 
-                writeln!(f, " {}", node.static_span_meta().name())?;
+        impl<S: tracing::Subscriber> Layer<S> for Capture {
+            fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+                let meta = event.metadata();
+                let level_and_target = (*meta.level(), meta.target().to_string());
+                self.0.lock().unwrap().push(level_and_target);
             }
+        }
+
+        let call_trees =
+            collect_call_trees_with_builder(CallTreeCollectorBuilder::default(), |mock| {
+                tracing::info_span!("fast").in_scope(|| mock.increment(1_000));
+                tracing::info_span!("slow").in_scope(|| mock.increment(1_000_000));
+            });
+        let collector = LoggingCallTreeCollectorBuilder::default()
+            .level(Level::DEBUG)
+            .target("xray")
+            .warn_above_busy(Duration::from_millis(1))
+            .stderr_fallback(false)
+            .build();
+        let log_with_filter = |filter: &str| {
+            let events = Arc::new(Mutex::new(Vec::new()));
+            let subscriber = tracing_subscriber::registry()
+                .with(EnvFilter::new(filter))
+                .with(Capture(events.clone()));
+            tracing::subscriber::with_default(subscriber, || {
+                for call_tree in &call_trees {
+                    collector.log(call_tree);
+                }
+            });
+            let events = events.lock().unwrap().clone();
+            events
         };
 
-        let mut children = node.children().copied().collect::<Vec<_>>();
-        if !children.is_empty() {
-            children.sort();
-            let last_dx = children.len() - 1;
-            for (idx, child_idx) in children.iter().enumerate() {
-                let child = &self.pool[*child_idx];
-                last.push(idx == last_dx);
-                self.fmt(last, child, f)?;
-                last.pop();
+        assert_eq!(
+            log_with_filter("xray=debug"),
+            vec![
+                (Level::DEBUG, "xray".to_string()),
+                (Level::WARN, "xray".to_string())
+            ]
+        );
+        assert_eq!(
+            log_with_filter("info"),
+            vec![(Level::WARN, "xray".to_string())]
+        );
+        assert_eq!(log_with_filter("info,xray=off"), vec![]);
+
+        collector.thresholds_handle().set_warn_above_busy(None);
+        assert_eq!(log_with_filter("info"), vec![]);
+        collector
+            .thresholds_handle()
+            .set_warn_above_alive(Some(Duration::from_nanos(1)));
+        assert_eq!(log_with_filter("info").len(), 2);
+    }
+
+    #[test]
+    fn log_in_flight_with_level_and_target() {
+        use std::sync::Mutex;
+        use tracing_subscriber::{layer::Context, prelude::*, Layer};
+
+        /// Captures the levels, targets and messages of all events.
+        struct Capture(Arc<Mutex<Vec<(Level, String, String)>>>);
+
+        impl<S: tracing::Subscriber> Layer<S> for Capture {
+            fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+                let meta = event.metadata();
+                let mut message = String::new();
+                event.record(
+                    &mut |field: &tracing::field::Field, value: &dyn std::fmt::Debug| {
+                        if field.name() == "message" {
+                            message = format!("{:?}", value);
+                        }
+                    },
+                );
+                let event = (*meta.level(), meta.target().to_string(), message);
+                self.0.lock().unwrap().push(event);
             }
         }
-        Ok(())
-    }
-}
 
-#[cfg(test)]
-mod test {
-    use std::sync::Arc;
+        let (clock, mock) = quanta::Clock::mock();
+        let collector = CallTreeCollectorBuilder::default()
+            .clock(clock)
+            .track_in_flight(true)
+            .build_with_collector(crate::internal::test::FinishedCallTreeStore::default());
+        let stats = collector.stats();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let subscriber = tracing_subscriber::registry()
+            .with(collector)
+            .with(Capture(events.clone()));
+        let logger = LoggingCallTreeCollectorBuilder::default()
+            .level(Level::DEBUG)
+            .target("xray")
+            .warn_above_alive(Duration::from_nanos(100))
+            .build();
+        tracing::subscriber::with_default(subscriber, || {
+            let _request = tracing::info_span!("request");
+            mock.increment(50);
+            logger.log_in_flight(&stats.in_flight()[0]);
+            mock.increment(50);
+            logger.log_in_flight(&stats.in_flight()[0]);
+        });
 
-    use quanta::Mock;
+        let events = events.lock().unwrap().clone();
+        assert_eq!(
+            events
+                .iter()
+                .map(|(level, target, _)| (*level, target.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(Level::DEBUG, "xray"), (Level::WARN, "xray")]
+        );
+        assert!(events[0].2.starts_with("Call tree of request"));
+    }
 
-    use crate::internal::test::{collect_call_trees, compound_call, cooking_party, one_ns};
+    #[test]
+    fn fmt_prefix_width() {
+        assert_eq!(FmtPrefix::default().width("reqray::display"), 51);
+        assert_eq!(
+            FmtPrefix::default()
+                .with_target(false)
+                .width("reqray::display"),
+            34
+        );
+        assert_eq!(
+            FmtPrefix::default()
+                .without_time()
+                .with_level(false)
+                .with_target(false)
+                .width("reqray::display"),
+            0
+        );
+    }
 
     #[test]
-    fn display_one_ns() {
-        let str = display_call_trees(|mock| one_ns(&mock));
+    fn fmt_prefix_with_custom_target() {
+        let margin =
+            |builder: LoggingCallTreeCollectorBuilder| builder.build_formatter().left_margin;
+        assert_eq!(
+            margin(
+                LoggingCallTreeCollectorBuilder::default()
+                    .align_with_fmt_prefix(FmtPrefix::default())
+            ),
+            51
+        );
+        assert_eq!(
+            margin(
+                LoggingCallTreeCollectorBuilder::default()
+                    .target("xray")
+                    .align_with_fmt_prefix(FmtPrefix::default())
+            ),
+            40
+        );
+        assert_eq!(
+            margin(
+                LoggingCallTreeCollectorBuilder::default()
+                    .align_with_fmt_prefix(FmtPrefix::default())
+                    .target("xray")
+            ),
+            40
+        );
+        assert_eq!(
+            margin(
+                LoggingCallTreeCollectorBuilder::default()
+                    .align_with_fmt_prefix(FmtPrefix::default())
+                    .left_margin(3)
+            ),
+            3
+        );
+        let formatter =
+            TextTableFormatter::default().align_with_fmt_prefix(FmtPrefix::default(), "xray");
+        assert_eq!(formatter.left_margin, 40);
+    }
+
+    #[test]
+    fn display_sort_children() {
+        let call = |mock: Arc<Mock>| {
+            tracing::info_span!("request").in_scope(|| {
+                let created_first = tracing::info_span!("created_first");
+                let entered_first = tracing::info_span!("entered_first");
+                let _never_entered = tracing::info_span!("never_entered");
+                mock.increment(1_000);
+                entered_first.in_scope(|| mock.increment(1_000));
+                created_first.in_scope(|| mock.increment(1_000));
+            })
+        };
+        let str = display_call_trees_with(
+            LoggingCallTreeCollectorBuilder::default().sort_children(SortOrder::FirstEntered),
+            call,
+        );
         assert_eq!(
             &str,
             indoc::indoc! {r#"
-                    # calls │   ∑ alive ms │    ∑ busy ms │ ∑ own busy ms │ span tree
-                ────────────┼──────────────┼──────────────┼────────────-──┼───────────────────────
-                      0 001 ┊        0.000 ┊        0.000 ┊         0.000 ┊ ─ one_ns
+                # calls │   ∑ alive ms │    ∑ busy ms │ ∑ own busy ms │ span tree
+            ────────────┼──────────────┼──────────────┼────────────-──┼───────────────────────
+                  0 001 ┊        0.003 ┊        0.003 ┊         0.001 ┊ ┬ request
+                  0 001 ┊        0.003 ┊        0.001 ┊         0.001 ┊ ├─ entered_first
+                  0 001 ┊        0.003 ┊        0.001 ┊         0.001 ┊ ├─ created_first
+                  0 001 ┊        0.003 ┊        0.000 ┊         0.000 ┊ ╰─ never_entered
 
             "#},
             "got:\n{}",
@@ -175,22 +4004,213 @@ mod test {
     }
 
     #[test]
-    fn display_compound_call() {
-        let str = display_call_trees(|mock| compound_call(&mock));
+    fn display_sort_children_by_values() {
+        let call = |mock: Arc<Mock>| {
+            tracing::info_span!("request").in_scope(|| {
+                tracing::info_span!("cheap").in_scope(|| mock.increment(1_000));
+                for _ in 0..3 {
+                    tracing::info_span!("frequent").in_scope(|| mock.increment(1_000));
+                }
+                tracing::info_span!("expensive").in_scope(|| mock.increment(5_000));
+            })
+        };
+        let children = |order| {
+            let str = display_call_trees_with(
+                LoggingCallTreeCollectorBuilder::default().sort_children(order),
+                call,
+            );
+            str.lines()
+                .filter_map(|line| line.split("─ ").nth(1))
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            children(SortOrder::Busy),
+            vec!["expensive", "frequent", "cheap"]
+        );
+        assert_eq!(
+            children(SortOrder::CallCount),
+            vec!["frequent", "cheap", "expensive"]
+        );
+        assert_eq!(
+            children(SortOrder::Name),
+            vec!["cheap", "expensive", "frequent"]
+        );
+    }
+
+    #[test]
+    fn display_duration_unit() {
+        let call = |mock: Arc<Mock>| {
+            tracing::info_span!("fast").in_scope(|| mock.increment(1_234));
+        };
+        let str = display_call_trees_with(
+            LoggingCallTreeCollectorBuilder::default()
+                .duration_unit(DurationUnit::Microseconds)
+                .duration_decimals(1),
+            call,
+        );
         assert_eq!(
             &str,
             indoc::indoc! {r#"
-                # calls │   ∑ alive ms │    ∑ busy ms │ ∑ own busy ms │ span tree
+                # calls │   ∑ alive µs │    ∑ busy µs │ ∑ own busy µs │ span tree
             ────────────┼──────────────┼──────────────┼────────────-──┼───────────────────────
-                  0 001 ┊        0.001 ┊        0.001 ┊         0.001 ┊ ┬ compound_call
-                  0 003 ┊        0.000 ┊        0.000 ┊         0.000 ┊ ╰─ one_ns
-      
+                  0 001 ┊          1.2 ┊          1.2 ┊           1.2 ┊ ─ fast
+
+            "#},
+            "got:\n{}",
+            str
+        );
+
+        let str = display_call_trees_with(
+            LoggingCallTreeCollectorBuilder::default()
+                .duration_unit(DurationUnit::Nanoseconds)
+                .duration_decimals(3),
+            call,
+        );
+        assert_eq!(
+            &str,
+            indoc::indoc! {r#"
+                # calls │   ∑ alive ns │    ∑ busy ns │ ∑ own busy ns │ span tree
+            ────────────┼──────────────┼──────────────┼────────────-──┼───────────────────────
+                  0 001 ┊         1234 ┊         1234 ┊          1234 ┊ ─ fast
+
+            "#},
+            "got:\n{}",
+            str
+        );
+
+        let str = display_call_trees_with(
+            LoggingCallTreeCollectorBuilder::default()
+                .duration_unit(DurationUnit::Seconds)
+                .duration_decimals(9),
+            call,
+        );
+        assert_eq!(
+            &str,
+            indoc::indoc! {r#"
+                # calls │    ∑ alive s │     ∑ busy s │  ∑ own busy s │ span tree
+            ────────────┼──────────────┼──────────────┼────────────-──┼───────────────────────
+                  0 001 ┊  0.000001234 ┊  0.000001234 ┊   0.000001234 ┊ ─ fast
+
+            "#},
+            "got:\n{}",
+            str
+        );
+    }
+
+    #[test]
+    fn display_compared() {
+        // Same callsites in all requests.
+        let request = |mock: &Mock, step_nanos: u64, new_step: bool| {
+            tracing::info_span!("request").in_scope(|| {
+                tracing::info_span!("step").in_scope(|| mock.increment(step_nanos));
+                if new_step {
+                    tracing::info_span!("new_step").in_scope(|| mock.increment(1_000_000));
+                }
+            });
+        };
+        let call_trees = collect_call_trees(|mock| {
+            request(&mock, 1_000_000, false);
+            request(&mock, 1_000_000, false);
+            request(&mock, 4_000_000, true);
+        });
+        let mut baseline = call_trees[0].clone();
+        baseline.merge(&call_trees[1]);
+
+        let formatter = TextTableFormatter::new().left_margin(0).columns([
+            Column::Calls,
+            Column::MeanBusy,
+            Column::VsAverage,
+        ]);
+        assert_eq!(
+            formatter
+                .display_compared(&call_trees[2], &baseline)
+                .to_string(),
+            indoc::indoc! {r#"
+                # calls │    ⌀ busy ms │ × avg busy │ span tree
+            ────────────┼──────────────┼────────────┼───────────────────────
+                  0 001 ┊        5.000 ┊       5.0× ┊ ┬ request
+                  0 001 ┊        4.000 ┊       4.0× ┊ ├─ step
+                  0 001 ┊        1.000 ┊            ┊ ╰─ new_step
+            "#},
+        );
+        assert_eq!(
+            formatter.display(&call_trees[2]).to_string(),
+            indoc::indoc! {r#"
+                # calls │    ⌀ busy ms │ × avg busy │ span tree
+            ────────────┼──────────────┼────────────┼───────────────────────
+                  0 001 ┊        5.000 ┊            ┊ ┬ request
+                  0 001 ┊        4.000 ┊            ┊ ├─ step
+                  0 001 ┊        1.000 ┊            ┊ ╰─ new_step
+            "#},
+        );
+    }
+
+    #[test]
+    fn display_number_format() {
+        let call = |mock: Arc<Mock>| {
+            tracing::info_span!("request").in_scope(|| {
+                for _ in 0..1_234 {
+                    tracing::info_span!("repeated").in_scope(|| mock.increment(1_000_000));
+                }
+            })
+        };
+        let str = display_call_trees_with(
+            LoggingCallTreeCollectorBuilder::default()
+                .columns([Column::Calls, Column::BusySum, Column::PercentOfRoot])
+                .number_format(
+                    NumberFormat::default()
+                        .with_decimal_separator(',')
+                        .with_grouping_separator(Some('.'))
+                        .with_group_durations(true),
+                ),
+            call,
+        );
+        assert_eq!(
+            &str,
+            indoc::indoc! {r#"
+                # calls │    ∑ busy ms │ % of root busy │ span tree
+            ────────────┼──────────────┼────────────────┼───────────────────────
+                  0.001 ┊    1.234,000 ┊        100,0 % ┊ ┬ request
+                  1.234 ┊    1.234,000 ┊        100,0 % ┊ ╰─ repeated
+
+            "#},
+            "got:\n{}",
+            str
+        );
+
+        let str = display_call_trees_with(
+            LoggingCallTreeCollectorBuilder::default()
+                .columns([Column::Calls, Column::BusySum])
+                .number_format(NumberFormat::default().with_custom(|number| match number {
+                    Number::Count(count) => format!("{}x", count),
+                    Number::Duration(duration) => format!("{}s", duration.as_secs()),
+                    Number::Percent(_) => unreachable!(),
+                })),
+            call,
+        );
+        assert_eq!(
+            &str,
+            indoc::indoc! {r#"
+                # calls │    ∑ busy ms │ span tree
+            ────────────┼──────────────┼───────────────────────
+                     1x ┊           1s ┊ ┬ request
+                  1234x ┊           1s ┊ ╰─ repeated
+
             "#},
             "got:\n{}",
             str
         );
     }
 
+    #[test]
+    fn grouping() {
+        assert_eq!(super::group_digits("1", ' '), "1");
+        assert_eq!(super::group_digits("0001", ' '), "0 001");
+        assert_eq!(super::group_digits("123456", ' '), "123 456");
+        assert_eq!(super::group_digits("1234567", ','), "1,234,567");
+    }
+
     #[tracing::instrument]
     fn nest_deeply(mock: &Mock, nest: usize) {
         if nest == 0 {
@@ -219,7 +4239,9 @@ mod test {
                   0 001 ┊        5.005 ┊        5.005 ┊         1.001 ┊      ╰┬ nest_deeply
                   0 001 ┊        4.004 ┊        4.004 ┊         1.001 ┊       ╰┬ nest_deeply
                   0 001 ┊        3.003 ┊        3.003 ┊         1.001 ┊        ╰┬ nest_deeply
-                  0 001 ┊        2.002 ┊        2.002 ┊         2.002 ┊         ╰─ nest_deeply
+                  0 001 ┊        2.002 ┊        2.002 ┊         1.001 ┊         ╰┬ nest_deeply
+                  0 002 ┊        1.001 ┊        1.001 ┊         1.001 ┊          ╰─ … (deeper calls)
+            ⚠ 2 spans not tracked individually: 2 beyond the maximum call depth
             
             "#},
             "got:\n{}",
@@ -227,6 +4249,217 @@ mod test {
         );
     }
 
+    #[tracing::instrument(fields(reqray.note = "retried"))]
+    fn retried(mock: &Mock) {
+        for i in 0..3 {
+            let note = if i < 2 { "cache hit" } else { "cache miss" };
+            tracing::info_span!("lookup", reqray.note = note).in_scope(|| one_ns(mock));
+        }
+    }
+
+    #[test]
+    fn display_notes() {
+        let str = display_call_trees(|mock| retried(&mock));
+        assert_eq!(
+            &str,
+            indoc::indoc! {r#"
+                # calls │   ∑ alive ms │    ∑ busy ms │ ∑ own busy ms │ span tree
+            ────────────┼──────────────┼──────────────┼────────────-──┼───────────────────────
+                  0 001 ┊        0.000 ┊        0.000 ┊         0.000 ┊ ┬ retried [1]
+                  0 003 ┊        0.000 ┊        0.000 ┊         0.000 ┊ ╰┬ lookup [2]
+                  0 003 ┊        0.000 ┊        0.000 ┊         0.000 ┊  ╰─ one_ns
+
+            [1] retried
+            [2] cache hit (2×), cache miss
+
+            "#},
+            "got:\n{}",
+            str
+        );
+    }
+
+    #[test]
+    fn display_follows_from() {
+        let str = display_call_trees(|mock| {
+            let enqueue =
+                tracing::info_span!("request").in_scope(|| tracing::info_span!("enqueue"));
+            let handle = tracing::info_span!(parent: None, "handle", reqray.note = "batch");
+            handle.follows_from(&enqueue);
+            handle.in_scope(|| one_ns(&mock));
+        });
+        assert_eq!(
+            &str,
+            indoc::indoc! {r#"
+                # calls │   ∑ alive ms │    ∑ busy ms │ ∑ own busy ms │ span tree
+            ────────────┼──────────────┼──────────────┼────────────-──┼───────────────────────
+                  0 001 ┊        0.000 ┊        0.000 ┊         0.000 ┊ ┬ handle [1]
+                  0 001 ┊        0.000 ┊        0.000 ┊         0.000 ┊ ╰─ one_ns
+
+            [1] batch; follows from request/enqueue
+
+                # calls │   ∑ alive ms │    ∑ busy ms │ ∑ own busy ms │ span tree
+            ────────────┼──────────────┼──────────────┼────────────-──┼───────────────────────
+                  0 001 ┊        0.000 ┊        0.000 ┊         0.000 ┊ ┬ request
+                  0 001 ┊        0.000 ┊        0.000 ┊         0.000 ┊ ╰─ enqueue
+
+            "#},
+            "got:\n{}",
+            str
+        );
+    }
+
+    #[test]
+    fn display_collapsed_recursion() {
+        let str = display_call_trees_with_collector(
+            CallTreeCollectorBuilder::default().collapse_recursion(true),
+            LoggingCallTreeCollectorBuilder::default(),
+            |mock| {
+                tracing::info_span!("request").in_scope(|| {
+                    mock.increment(1);
+                    nest_deeply(&mock, 20)
+                })
+            },
+        );
+        assert_eq!(
+            &str,
+            indoc::indoc! {r#"
+                # calls │   ∑ alive ms │    ∑ busy ms │ ∑ own busy ms │ span tree
+            ────────────┼──────────────┼──────────────┼────────────-──┼───────────────────────
+                  0 001 ┊       20.020 ┊       20.020 ┊         0.000 ┊ ┬ request
+                  0 021 ┊       20.020 ┊       20.020 ┊        20.020 ┊ ╰─ nest_deeply (recursion depth 21)
+
+            "#},
+            "got:\n{}",
+            str
+        );
+    }
+
+    #[test]
+    fn display_cache_hits() {
+        let str = display_call_trees(|mock| {
+            tracing::info_span!("queries").in_scope(|| {
+                for hit in [true, true, false] {
+                    tracing::info_span!("query", cache.hit = hit).in_scope(|| one_ns(&mock));
+                }
+            })
+        });
+        assert_eq!(
+            &str,
+            indoc::indoc! {r#"
+                # calls │   ∑ alive ms │    ∑ busy ms │ ∑ own busy ms │ span tree
+            ────────────┼──────────────┼──────────────┼────────────-──┼───────────────────────
+                  0 001 ┊        0.000 ┊        0.000 ┊         0.000 ┊ ┬ queries
+                  0 003 ┊        0.000 ┊        0.000 ┊         0.000 ┊ ╰┬ query (hit 2 / miss 1)
+                  0 003 ┊        0.000 ┊        0.000 ┊         0.000 ┊  ╰─ one_ns
+
+            "#},
+            "got:\n{}",
+            str
+        );
+    }
+
+    #[test]
+    fn header_time_split() {
+        let call_trees = crate::internal::test::collect_call_trees(|mock| {
+            tracing::info_span!("request").in_scope(|| {
+                mock.increment(1_500_000);
+                crate::report_io_wait(Duration::from_millis(1));
+            })
+        });
+        let collector = LoggingCallTreeCollectorBuilder::default()
+            .time_split(true)
+            .build();
+        assert_eq!(
+            collector.time_split_label(&call_trees[0]),
+            " (local 0.500 ms, downstream 1.000 ms, waiting 0.000 ms)"
+        );
+    }
+
+    #[test]
+    fn header_location() {
+        let call_trees = crate::internal::test::collect_call_trees(|mock| compound_call(&mock));
+        let root = call_trees[0].root();
+        let line = root.span_metadata().line().unwrap();
+        let label = |builder: LoggingCallTreeCollectorBuilder| builder.build().root_label(root);
+
+        let builder = LoggingCallTreeCollectorBuilder::default();
+        assert_eq!(
+            label(builder),
+            format!("compound_call@src/internal.rs:{}", line)
+        );
+        let builder = LoggingCallTreeCollectorBuilder::default().clickable_location(true);
+        assert_eq!(
+            label(builder),
+            format!("compound_call at src/internal.rs:{}:1", line)
+        );
+        let builder =
+            LoggingCallTreeCollectorBuilder::default().header_location(HeaderLocation::Omitted);
+        assert_eq!(label(builder), "compound_call");
+
+        let crate_relative = |file| HeaderLocation::CrateRelative.path(file).unwrap();
+        assert_eq!(crate_relative("src/lib.rs"), "src/lib.rs");
+        assert_eq!(crate_relative("crates/api/src/lib.rs"), "src/lib.rs");
+        assert_eq!(
+            crate_relative("/home/me/.cargo/registry/src/index/hyper-0.14.0/src/client/mod.rs"),
+            "src/client/mod.rs"
+        );
+        assert_eq!(crate_relative("C:\\api\\tests\\it.rs"), "tests\\it.rs");
+        assert_eq!(crate_relative("build.rs"), "build.rs");
+    }
+
+    #[cfg(feature = "aggregate")]
+    #[test]
+    fn header_root_busy_distribution() {
+        use crate::{aggregate::AggregatingProcessor, FinishedCallTreeProcessor};
+
+        let call_trees = crate::internal::test::collect_call_trees(|mock| {
+            for _ in 0..4 {
+                tracing::info_span!("request").in_scope(|| mock.increment(1_000_000));
+            }
+            tracing::info_span!("other").in_scope(|| mock.increment(1_000_000));
+        });
+        let aggregating = AggregatingProcessor::new(|_| {}).with_interval(None);
+        let collector = LoggingCallTreeCollectorBuilder::default()
+            .header_location(HeaderLocation::Omitted)
+            .compare_with_aggregates(aggregating.handle())
+            .build();
+        for call_tree in &call_trees[..4] {
+            aggregating.process_finished_call(call_tree.clone());
+        }
+
+        assert_eq!(
+            collector.header(&call_trees[0], 0),
+            "Call summary of request (busy p50/p90/p99 1.000/1.000/1.000 ms over 4 calls)"
+        );
+        assert_eq!(collector.header(&call_trees[4], 0), "Call summary of other");
+    }
+
+    #[test]
+    fn header_context() {
+        let call_trees = crate::internal::test::collect_call_trees_with_builder(
+            CallTreeCollectorBuilder::default()
+                .context_field("request_id")
+                .context_field("user_id"),
+            |mock| {
+                let request = tracing::info_span!(
+                    "request",
+                    request_id = 42,
+                    user_id = tracing::field::Empty
+                );
+                request.in_scope(|| {
+                    // Only the fields of the root span are kept.
+                    tracing::info_span!("nested", request_id = 43).in_scope(|| one_ns(&mock));
+                });
+                request.record("user_id", &"alice");
+            },
+        );
+        let label = LoggingCallTreeCollectorBuilder::default()
+            .header_location(HeaderLocation::Omitted)
+            .build()
+            .tree_label(&call_trees[0]);
+        assert_eq!(label, "request request_id=42 user_id=alice");
+    }
+
     #[test]
     fn display_with_futures() {
         let str = display_call_trees(|mock| {
@@ -247,8 +4480,27 @@ mod test {
                   0 001 ┊        0.0X3 ┊        0.0X3 ┊         0.0X3 ┊ ╰─ eat_three
 
         "#};
+        // The spawned eat_three task sometimes creates its span first.
+        let spawned_first_pattern = indoc::indoc! {r#"
+                # calls │   ∑ alive ms │    ∑ busy ms │ ∑ own busy ms │ span tree
+            ────────────┼──────────────┼──────────────┼────────────-──┼───────────────────────
+                  0 001 ┊      101.XXX ┊      101.XXX ┊       101.XXX ┊ ┬ cooking_party
+                  0 001 ┊        0.0X3 ┊        0.0X3 ┊         0.0X3 ┊ ├─ eat_three
+                  0 001 ┊        0.03X ┊        0.03X ┊         0.03X ┊ ╰─ cook_three
+
+        "#};
+
+        if !matches_pattern(spawned_first_pattern, &str) {
+            pattern_matches(pattern, &str);
+        }
+    }
 
-        pattern_matches(pattern, &str);
+    fn matches_pattern(pattern: &str, actual: &str) -> bool {
+        pattern.len() == actual.len()
+            && pattern
+                .chars()
+                .zip(actual.chars())
+                .all(|(p, a)| p == 'X' || p == a)
     }
 
     fn pattern_matches(pattern: &str, actual: &str) {
@@ -278,23 +4530,28 @@ mod test {
     }
 
     fn display_call_trees(call: impl Fn(Arc<Mock>)) -> String {
-        use std::fmt::Write;
+        display_call_trees_with(LoggingCallTreeCollectorBuilder::default(), call)
+    }
+
+    fn display_call_trees_with(
+        builder: LoggingCallTreeCollectorBuilder,
+        call: impl Fn(Arc<Mock>),
+    ) -> String {
+        display_call_trees_with_collector(CallTreeCollectorBuilder::default(), builder, call)
+    }
 
-        let call_trees = collect_call_trees(call);
+    fn display_call_trees_with_collector(
+        collector_builder: CallTreeCollectorBuilder,
+        builder: LoggingCallTreeCollectorBuilder,
+        call: impl Fn(Arc<Mock>),
+    ) -> String {
+        let call_trees = collect_call_trees_with_builder(collector_builder, call);
+        let formatter = builder.left_margin(0).build_formatter();
 
         let mut out = String::new();
         for call_tree in call_trees {
-            writeln!(
-                &mut out,
-                "{}",
-                super::DisplayableCallPathTiming {
-                    max_call_depth: 10,
-                    left_margin: 0,
-                    pool: &call_tree,
-                    root: call_tree.root()
-                }
-            )
-            .unwrap();
+            formatter.fmt(&call_tree, &mut out).unwrap();
+            out.push('\n');
         }
         out
     }
@@ -0,0 +1,123 @@
+//! Events whose level and target are chosen at runtime, which the
+//! `tracing` macros do not support since they keep both in statics.
+
+use std::sync::{
+    atomic::{AtomicU8, Ordering},
+    Mutex, OnceLock, PoisonError,
+};
+
+use tracing::{
+    callsite::{self, Callsite},
+    field::FieldSet,
+    level_filters::{LevelFilter, STATIC_MAX_LEVEL},
+    metadata::Kind,
+    subscriber::Interest,
+    Level, Metadata,
+};
+
+/// All callsites created so far, they are leaked and reused.
+static CALLSITES: Mutex<Vec<&'static DynamicCallsite>> = Mutex::new(Vec::new());
+
+const INTEREST_NEVER: u8 = 0;
+const INTEREST_SOMETIMES: u8 = 1;
+const INTEREST_ALWAYS: u8 = 2;
+
+/// A callsite for events with a target and level from the configuration.
+pub(crate) struct DynamicCallsite {
+    target: &'static str,
+    level: Level,
+    fields: &'static [&'static str],
+    /// Only empty while the callsite is created, since the metadata refers
+    /// to the callsite.
+    metadata: OnceLock<Metadata<'static>>,
+    interest: AtomicU8,
+}
+
+impl DynamicCallsite {
+    /// The callsite for events with these properties, created on first
+    /// use. The first field should be `message`.
+    pub(crate) fn get(
+        target: &'static str,
+        level: Level,
+        fields: &'static [&'static str],
+    ) -> &'static DynamicCallsite {
+        let mut callsites = CALLSITES.lock().unwrap_or_else(PoisonError::into_inner);
+        let existing = callsites.iter().find(|callsite| {
+            callsite.target == target
+                && callsite.level == level
+                && std::ptr::eq(callsite.fields, fields)
+        });
+        if let Some(callsite) = existing {
+            return callsite;
+        }
+
+        let callsite: &'static DynamicCallsite = Box::leak(Box::new(DynamicCallsite {
+            target,
+            level,
+            fields,
+            metadata: OnceLock::new(),
+            interest: AtomicU8::new(INTEREST_SOMETIMES),
+        }));
+        let _ = callsite.metadata.set(Metadata::new(
+            "event",
+            target,
+            level,
+            Some(file!()),
+            None,
+            Some(module_path!()),
+            FieldSet::new(fields, callsite::Identifier(callsite)),
+            Kind::EVENT,
+        ));
+        callsite::register(callsite);
+        callsites.push(callsite);
+        callsite
+    }
+
+    /// Whether an event at this callsite would be recorded, like the check
+    /// in the `tracing` macros.
+    pub(crate) fn is_enabled(&self) -> bool {
+        if self.level > STATIC_MAX_LEVEL || self.level > LevelFilter::current() {
+            return false;
+        }
+        match self.interest.load(Ordering::Relaxed) {
+            INTEREST_NEVER => false,
+            INTEREST_ALWAYS => true,
+            _ => tracing::dispatcher::get_default(|dispatch| dispatch.enabled(self.metadata())),
+        }
+    }
+}
+
+impl Callsite for DynamicCallsite {
+    fn set_interest(&self, interest: Interest) {
+        let interest = if interest.is_never() {
+            INTEREST_NEVER
+        } else if interest.is_always() {
+            INTEREST_ALWAYS
+        } else {
+            INTEREST_SOMETIMES
+        };
+        self.interest.store(interest, Ordering::Relaxed);
+    }
+
+    fn metadata(&self) -> &Metadata<'_> {
+        self.metadata.get().expect("metadata set on creation")
+    }
+}
+
+/// Dispatches an event at a [DynamicCallsite] with one value per field, in
+/// the order of the field names.
+macro_rules! dispatch_event {
+    ($callsite:expr, $($value:expr),+ $(,)?) => {{
+        let metadata = tracing::callsite::Callsite::metadata($callsite);
+        let mut fields = metadata.fields().iter();
+        tracing::Event::dispatch(
+            metadata,
+            &metadata.fields().value_set(&[$((
+                &fields.next().expect("more values than fields"),
+                Some(&$value as &dyn tracing::Value),
+            )),+]),
+        );
+    }};
+}
+
+pub(crate) use dispatch_event;
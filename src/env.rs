@@ -0,0 +1,90 @@
+//! Configuration from environment variables like `REQRAY_SAMPLE_RATE`, see
+//! [CallTreeCollectorBuilder::from_env](crate::CallTreeCollectorBuilder::from_env).
+
+#[cfg(feature = "display")]
+use std::time::Duration;
+
+/// Looks up environment variables, replaced in tests.
+pub(crate) type Lookup<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+/// Looks up variables in the environment of the process.
+pub(crate) fn process_env(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+/// The parsed value of the variable `name`, `None` if it is not set.
+///
+/// Invalid values are ignored and reported on stderr, since the
+/// subscriber is typically not installed while configuring it.
+pub(crate) fn var<T>(
+    lookup: Lookup<'_>,
+    name: &str,
+    expected: &str,
+    parse: impl FnOnce(&str) -> Option<T>,
+) -> Option<T> {
+    let value = lookup(name)?;
+    let parsed = parse(value.trim());
+    if parsed.is_none() {
+        eprintln!(
+            "reqray: ignoring {}={:?}, expected {}",
+            name, value, expected
+        );
+    }
+    parsed
+}
+
+pub(crate) fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "on" | "yes" => Some(true),
+        "0" | "false" | "off" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+/// A fraction between `0.0` and `1.0`.
+pub(crate) fn parse_rate(value: &str) -> Option<f64> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|rate| (0.0..=1.0).contains(rate))
+}
+
+/// A non-negative number of milliseconds, e.g. `2.5`.
+#[cfg(feature = "display")]
+pub(crate) fn parse_millis(value: &str) -> Option<Duration> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|millis| millis.is_finite() && *millis >= 0.0)
+        .map(|millis| Duration::from_secs_f64(millis / 1_000.0))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_bool, parse_rate, var};
+
+    #[test]
+    fn parse_values() {
+        let lookup = |name: &str| match name {
+            "REQRAY_ENABLED" => Some("Off".to_string()),
+            "REQRAY_SAMPLE_RATE" => Some(" 0.25 ".to_string()),
+            "REQRAY_MIN_ROOT_MS" => Some("1.5".to_string()),
+            _ => Some("invalid".to_string()),
+        };
+        assert_eq!(var(&lookup, "REQRAY_ENABLED", "", parse_bool), Some(false));
+        assert_eq!(
+            var(&lookup, "REQRAY_SAMPLE_RATE", "", parse_rate),
+            Some(0.25)
+        );
+        #[cfg(feature = "display")]
+        assert_eq!(
+            var(&lookup, "REQRAY_MIN_ROOT_MS", "", super::parse_millis),
+            Some(std::time::Duration::from_micros(1_500))
+        );
+        assert_eq!(var(&lookup, "REQRAY_OTHER", "", parse_rate), None);
+        assert_eq!(parse_rate("1.5"), None);
+        #[cfg(feature = "display")]
+        assert_eq!(super::parse_millis("-1"), None);
+        assert_eq!(var(&|_| None, "REQRAY_ENABLED", "", parse_bool), None);
+    }
+}
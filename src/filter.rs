@@ -0,0 +1,111 @@
+use tracing::{Level, Metadata};
+
+/// Selects spans by their metadata, see
+/// [CallTreeCollectorBuilder::exclude_spans](crate::CallTreeCollectorBuilder::exclude_spans).
+///
+/// ```
+/// use reqray::{CallTreeCollectorBuilder, SpanFilter};
+/// use tracing::Level;
+///
+/// let builder = CallTreeCollectorBuilder::default()
+///     .exclude_spans(SpanFilter::target("h2"))
+///     .exclude_spans(SpanFilter::target("hyper"))
+///     .exclude_spans(SpanFilter::level(Level::TRACE))
+///     .include_spans(SpanFilter::name("connect*"));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpanFilter {
+    kind: FilterKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum FilterKind {
+    Target(String),
+    Name(String),
+    Level(Level),
+}
+
+impl SpanFilter {
+    /// Spans with this target or a target in a submodule of it, e.g.
+    /// `hyper` matches `hyper` and `hyper::proto::h1` but not `hyperx`.
+    pub fn target(target: impl Into<String>) -> Self {
+        SpanFilter {
+            kind: FilterKind::Target(target.into()),
+        }
+    }
+
+    /// Spans whose name matches `glob`, in which `*` matches any number of
+    /// characters and `?` a single character, e.g. `poll_*`.
+    pub fn name(glob: impl Into<String>) -> Self {
+        SpanFilter {
+            kind: FilterKind::Name(glob.into()),
+        }
+    }
+
+    /// Spans with `level` or a more verbose level, e.g. [Level::DEBUG]
+    /// matches `DEBUG` and `TRACE` spans.
+    pub fn level(level: Level) -> Self {
+        SpanFilter {
+            kind: FilterKind::Level(level),
+        }
+    }
+
+    pub(crate) fn matches(&self, metadata: &Metadata<'_>) -> bool {
+        match &self.kind {
+            FilterKind::Target(target) => metadata
+                .target()
+                .strip_prefix(target.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with("::")),
+            FilterKind::Name(glob) => glob_matches(glob.as_bytes(), metadata.name().as_bytes()),
+            FilterKind::Level(level) => metadata.level() >= level,
+        }
+    }
+}
+
+/// Whether `name` matches `glob` with `*` and `?` wildcards.
+fn glob_matches(glob: &[u8], name: &[u8]) -> bool {
+    // Greedy matching which backtracks to the last `*`.
+    let (mut glob_idx, mut name_idx) = (0, 0);
+    let mut last_star: Option<(usize, usize)> = None;
+    while name_idx < name.len() {
+        match glob.get(glob_idx) {
+            Some(b'*') => {
+                last_star = Some((glob_idx, name_idx));
+                glob_idx += 1;
+            }
+            Some(&c) if c == b'?' || c == name[name_idx] => {
+                glob_idx += 1;
+                name_idx += 1;
+            }
+            _ => match last_star {
+                Some((star_idx, star_name_idx)) => {
+                    glob_idx = star_idx + 1;
+                    name_idx = star_name_idx + 1;
+                    last_star = Some((star_idx, name_idx));
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[glob_idx..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::glob_matches;
+
+    #[test]
+    fn globs() {
+        let matches = |glob: &str, name: &str| glob_matches(glob.as_bytes(), name.as_bytes());
+        assert!(matches("poll", "poll"));
+        assert!(!matches("poll", "poll_read"));
+        assert!(matches("poll_*", "poll_read"));
+        assert!(matches("poll_*", "poll_"));
+        assert!(matches("*read*", "poll_read_buf"));
+        assert!(matches("p?ll", "pull"));
+        assert!(!matches("p?ll", "pll"));
+        assert!(matches("*a*b", "xaxxab"));
+        assert!(!matches("*a*b", "xaxxa"));
+        assert!(matches("*", ""));
+    }
+}
@@ -0,0 +1,157 @@
+//! Export finished call trees as folded stacks for flame graphs.
+//!
+//! Every call path with own busy time results in one line listing the
+//! names of all spans from the root to the call path, separated by `;`,
+//! followed by the own busy time in microseconds:
+//!
+//! ```text
+//! request;nested;repeated 61912
+//! ```
+//!
+//! This is the input format of `flamegraph.pl` and
+//! [inferno](https://github.com/jonhoo/inferno), e.g.
+//! `inferno-flamegraph < stacks.folded > flamegraph.svg`. Lines of
+//! different call trees with identical stacks are summed up by these
+//! tools.
+
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    callsite_cache::CallsiteCache, CallPathPool, CallPathTiming, FinishedCallTreeProcessor,
+};
+
+/// The frames of call paths with `;` and line breaks replaced.
+static FRAMES: CallsiteCache<str> = CallsiteCache::new();
+
+/// A [FinishedCallTreeProcessor] which writes every call tree as folded
+/// stacks to a writer, e.g. a file.
+///
+/// ```
+/// use reqray::{folded::FoldedStackProcessor, CallTreeCollectorBuilder};
+///
+/// let collector = CallTreeCollectorBuilder::default()
+///     .build_with_collector(FoldedStackProcessor::new(std::io::stdout()));
+/// ```
+pub struct FoldedStackProcessor<W: io::Write> {
+    writer: Mutex<W>,
+}
+
+impl<W: io::Write> FoldedStackProcessor<W> {
+    pub fn new(writer: W) -> Self {
+        FoldedStackProcessor {
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+impl<W: io::Write> FinishedCallTreeProcessor for FoldedStackProcessor<W> {
+    fn process_finished_call(&self, pool: CallPathPool) {
+        self.write(&pool)
+    }
+
+    fn process_shared_call(&self, pool: Arc<CallPathPool>) {
+        self.write(&pool)
+    }
+}
+
+impl<W: io::Write> FoldedStackProcessor<W> {
+    fn write(&self, pool: &CallPathPool) {
+        let mut writer = match self.writer.lock() {
+            Ok(writer) => writer,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Err(e) = write_folded_stacks(pool, &mut *writer) {
+            tracing::warn!("Could not write folded stacks: {}", e);
+        }
+    }
+}
+
+/// Writes the folded stacks of the call tree in `pool` to `out`.
+///
+/// Call paths without own busy time are skipped. `;` in span names is
+/// replaced by `:` and line breaks by spaces so that every frame remains
+/// intact.
+pub fn write_folded_stacks(pool: &CallPathPool, out: &mut impl io::Write) -> io::Result<()> {
+    let mut stack = String::new();
+    write_call_path(pool, pool.root(), &mut stack, out)
+}
+
+fn write_call_path(
+    pool: &CallPathPool,
+    node: &CallPathTiming,
+    stack: &mut String,
+    out: &mut impl io::Write,
+) -> io::Result<()> {
+    let parent_len = stack.len();
+    if parent_len > 0 {
+        stack.push(';');
+    }
+    let frame = FRAMES.get_or_insert_with(node, |node| {
+        node.name()
+            .chars()
+            .map(|c| match c {
+                ';' => ':',
+                '\n' | '\r' => ' ',
+                c => c,
+            })
+            .collect::<String>()
+            .into()
+    });
+    stack.push_str(&frame);
+    if node.is_retry() {
+        stack.push_str(" (attempt 2+)");
+    }
+
+    let own_micros = node.sum_without_children().as_micros();
+    if own_micros > 0 {
+        writeln!(out, "{} {}", stack, own_micros)?;
+    }
+
+    let mut children = node.children().copied().collect::<Vec<_>>();
+    children.sort();
+    for child_idx in children {
+        write_call_path(pool, &pool[child_idx], stack, out)?;
+    }
+    stack.truncate(parent_len);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use quanta::Mock;
+
+    use crate::internal::test::collect_call_trees;
+
+    #[tracing::instrument]
+    fn leaf(mock: &Mock) {
+        mock.increment(2_000);
+    }
+
+    #[test]
+    fn folded_stacks() {
+        let call_trees = collect_call_trees(|mock| {
+            tracing::info_span!("request").in_scope(|| {
+                mock.increment(1_000);
+                tracing::info_span!("nested;weird").in_scope(|| {
+                    leaf(&mock);
+                    leaf(&mock);
+                });
+                tracing::info_span!("empty").in_scope(|| leaf(&mock));
+            })
+        });
+
+        let mut out = Vec::new();
+        super::write_folded_stacks(&call_trees[0], &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            indoc::indoc! {"
+                request 1
+                request;nested:weird;leaf 4
+                request;empty;leaf 2
+            "}
+        );
+    }
+}
@@ -0,0 +1,286 @@
+//! A harness that drives a [CallTreeCollector](crate::CallTreeCollector)
+//! with randomized span lifecycles and checks the invariants of every
+//! finished call tree.
+//!
+//! Enable it with the `fuzz` feature:
+//!
+//! ```toml
+//! [dev-dependencies]
+//! reqray = { version = "0.4", features = ["fuzz"] }
+//! ```
+//!
+//! The generated workload contains spans that are entered concurrently
+//! from several threads, span handles that are dropped before the handles
+//! of their children, call chains much deeper than the maximum call depth
+//! and spans using the `reqray.attempt`, `reqray.note` and `cache.hit`
+//! fields. If you have a custom [FinishedCallTreeProcessor], pass it to
+//! [Fuzzer::run_with_processor] to run it on all generated call trees:
+//!
+//! ```
+//! use reqray::fuzz::Fuzzer;
+//!
+//! for seed in 0..4 {
+//!     Fuzzer::new(seed).trees(5).run();
+//! }
+//! ```
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+
+use tracing::{dispatcher, field, Dispatch, Span};
+use tracing_subscriber::prelude::*;
+
+use crate::{CallPathPool, CallTreeCollectorBuilder, FinishedCallTreeProcessor};
+
+/// Configures and runs a randomized workload, see the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct Fuzzer {
+    seed: u64,
+    trees: usize,
+    ops_per_tree: usize,
+    threads: usize,
+    max_call_depth: usize,
+}
+
+impl Fuzzer {
+    /// A fuzzer whose random decisions are derived from `seed`.
+    ///
+    /// Since threads are involved, the exact interleaving of span events
+    /// still differs between runs with the same seed.
+    pub fn new(seed: u64) -> Fuzzer {
+        Fuzzer {
+            seed,
+            trees: 20,
+            ops_per_tree: 100,
+            threads: 4,
+            max_call_depth: 5,
+        }
+    }
+
+    /// The number of call trees to generate. The default is `20`.
+    pub fn trees(mut self, trees: usize) -> Self {
+        self.trees = trees;
+        self
+    }
+
+    /// The number of random operations per call tree. The default is `100`.
+    pub fn ops_per_tree(mut self, ops_per_tree: usize) -> Self {
+        self.ops_per_tree = ops_per_tree;
+        self
+    }
+
+    /// The maximum number of threads entering spans concurrently. The
+    /// default is `4`.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = core::cmp::max(1, threads);
+        self
+    }
+
+    /// The max call depth of the collector. The default is `5`, so that
+    /// the depth cap is hit frequently.
+    pub fn max_call_depth(mut self, max_call_depth: usize) -> Self {
+        self.max_call_depth = core::cmp::max(2, max_call_depth);
+        self
+    }
+
+    /// Runs the workload and checks all finished call trees.
+    ///
+    /// Panics with the seed and a description of the problem if an
+    /// invariant is violated.
+    pub fn run(&self) {
+        self.run_with_processor(NoopProcessor)
+    }
+
+    /// Like [run](Self::run) but also hands every finished call tree to
+    /// `processor` after checking it.
+    pub fn run_with_processor<P>(&self, processor: P)
+    where
+        P: FinishedCallTreeProcessor + Send + Sync + 'static,
+    {
+        let checking = Arc::new(CheckingProcessor {
+            max_call_depth: self.max_call_depth,
+            finished: AtomicUsize::new(0),
+            violations: Mutex::new(Vec::new()),
+            inner: processor,
+        });
+        let collector = CallTreeCollectorBuilder::default()
+            .max_call_depth(self.max_call_depth)
+            .verify_clock(false)
+            .build_with_collector(checking.clone());
+        let dispatch = Dispatch::new(tracing_subscriber::registry().with(collector));
+
+        let mut rng = Rng(self.seed);
+        dispatcher::with_default(&dispatch, || {
+            for _ in 0..self.trees {
+                self.run_tree(&mut rng, &dispatch);
+            }
+        });
+
+        let violations = checking.violations.lock().unwrap();
+        assert!(
+            violations.is_empty(),
+            "seed {}: invariants violated:\n{}",
+            self.seed,
+            violations.join("\n")
+        );
+        assert_eq!(
+            checking.finished.load(Ordering::SeqCst),
+            self.trees,
+            "seed {}: unexpected number of finished call trees",
+            self.seed
+        );
+    }
+
+    fn run_tree(&self, rng: &mut Rng, dispatch: &Dispatch) {
+        let mut live = vec![random_span(rng, None)];
+        for _ in 0..self.ops_per_tree {
+            match rng.below(10) {
+                0..=3 => {
+                    let parent = &live[rng.below(live.len())];
+                    let child = random_span(rng, Some(parent));
+                    live.push(child);
+                }
+                4..=6 => self.enter_concurrently(rng, dispatch, &live),
+                7 if live.len() > 1 => {
+                    // Out of order: children may still be alive.
+                    let idx = 1 + rng.below(live.len() - 1);
+                    live.swap_remove(idx);
+                }
+                8 => {
+                    let span = &live[rng.below(live.len())];
+                    span.record("cache.hit", &(rng.below(2) == 0));
+                }
+                _ => {
+                    let depth = rng.below(3 * self.max_call_depth);
+                    let span = live[rng.below(live.len())].clone();
+                    span.in_scope(|| nest(rng, depth));
+                }
+            }
+        }
+        while !live.is_empty() {
+            live.swap_remove(rng.below(live.len()));
+        }
+    }
+
+    /// Enters random spans on several threads at the same time.
+    fn enter_concurrently(&self, rng: &mut Rng, dispatch: &Dispatch, live: &[Span]) {
+        let threads = 1 + rng.below(self.threads);
+        let work = (0..threads)
+            .map(|_| {
+                let spans = (0..1 + rng.below(4))
+                    .map(|_| live[rng.below(live.len())].clone())
+                    .collect::<Vec<_>>();
+                (spans, Rng(rng.next()))
+            })
+            .collect::<Vec<_>>();
+        std::thread::scope(|scope| {
+            for (spans, mut rng) in work {
+                scope.spawn(move || {
+                    dispatcher::with_default(dispatch, || enter_nested(&mut rng, &spans))
+                });
+            }
+        });
+    }
+}
+
+/// Enters all `spans` nested into each other and creates some contextual
+/// children along the way.
+fn enter_nested(rng: &mut Rng, spans: &[Span]) {
+    if let Some((first, rest)) = spans.split_first() {
+        first.in_scope(|| {
+            if rng.below(2) == 0 {
+                let child = random_span(rng, None);
+                child.in_scope(|| enter_nested(rng, rest));
+            } else {
+                enter_nested(rng, rest);
+            }
+        });
+    }
+}
+
+/// Creates a chain of `depth` nested spans below the current span.
+fn nest(rng: &mut Rng, depth: usize) {
+    if depth > 0 {
+        random_span(rng, None).in_scope(|| nest(rng, depth - 1));
+    }
+}
+
+/// Creates a span at one of several call sites with `parent` as explicit
+/// parent or the current span as parent.
+fn random_span(rng: &mut Rng, parent: Option<&Span>) -> Span {
+    let parent = match parent {
+        Some(parent) => parent.clone(),
+        None => Span::current(),
+    };
+    match rng.below(5) {
+        0 => tracing::info_span!(parent: &parent, "fuzz_plain"),
+        1 => tracing::info_span!(parent: &parent, "fuzz_other"),
+        2 => tracing::info_span!(
+            parent: &parent,
+            "fuzz_attempt",
+            reqray.attempt = 1 + rng.below(3) as u64
+        ),
+        3 => tracing::info_span!(parent: &parent, "fuzz_note", reqray.note = "fuzzed"),
+        _ => tracing::info_span!(parent: &parent, "fuzz_cache", cache.hit = field::Empty),
+    }
+}
+
+struct CheckingProcessor<P> {
+    max_call_depth: usize,
+    finished: AtomicUsize,
+    violations: Mutex<Vec<String>>,
+    inner: P,
+}
+
+impl<P: FinishedCallTreeProcessor> FinishedCallTreeProcessor for Arc<CheckingProcessor<P>> {
+    fn process_finished_call(&self, pool: CallPathPool) {
+        self.finished.fetch_add(1, Ordering::SeqCst);
+        if let Err(violation) = pool.check_invariants(self.max_call_depth) {
+            self.violations.lock().unwrap().push(violation);
+        }
+        self.inner.process_finished_call(pool);
+    }
+}
+
+struct NoopProcessor;
+
+impl FinishedCallTreeProcessor for NoopProcessor {
+    fn process_finished_call(&self, _pool: CallPathPool) {}
+}
+
+/// A tiny SplitMix64 generator, good enough to derive random decisions
+/// from a seed.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Fuzzer;
+
+    #[test]
+    fn fuzz_seeds() {
+        for seed in 0..16 {
+            Fuzzer::new(seed).run();
+        }
+    }
+
+    #[test]
+    fn fuzz_deep_and_narrow() {
+        Fuzzer::new(42).max_call_depth(2).threads(8).run();
+    }
+}
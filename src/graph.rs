@@ -0,0 +1,311 @@
+//! Render finished call trees as [Graphviz](https://graphviz.org) DOT or
+//! [Mermaid](https://mermaid.js.org) flowcharts, e.g. to embed the shape
+//! of a request in a design doc or an incident postmortem.
+//!
+//! Every call path becomes a node labeled with its name, the number of
+//! calls and the summed busy time:
+//!
+//! ```text
+//! flowchart TD
+//!     n0["request<br/>1 call, 12.051 ms busy"]
+//!     n1["nested<br/>2 calls, 11.932 ms busy"]
+//!     n0 --> n1
+//! ```
+//!
+//! Render DOT output e.g. with `dot -Tsvg tree.dot > tree.svg`, Mermaid
+//! output is rendered by GitHub and GitLab in fenced `mermaid` blocks.
+
+use std::{
+    fmt, io,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    callsite_cache::CallsiteCache, CallPathPool, CallPathTiming, FinishedCallTreeProcessor,
+};
+
+/// The names of call paths escaped for DOT.
+static DOT_NAMES: CallsiteCache<str> = CallsiteCache::new();
+/// The names of call paths escaped for Mermaid.
+static MERMAID_NAMES: CallsiteCache<str> = CallsiteCache::new();
+
+/// Formats a call tree as a Graphviz DOT digraph.
+///
+/// ```
+/// use reqray::{graph::CallTreeDot, CallPathPool};
+///
+/// fn to_dot(pool: &CallPathPool) -> String {
+///     CallTreeDot::new(pool).to_string()
+/// }
+/// ```
+pub struct CallTreeDot<'a> {
+    pool: &'a CallPathPool,
+}
+
+impl<'a> CallTreeDot<'a> {
+    pub fn new(pool: &'a CallPathPool) -> Self {
+        CallTreeDot { pool }
+    }
+}
+
+impl fmt::Display for CallTreeDot<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "digraph call_tree {{")?;
+        writeln!(f, "    node [shape=box];")?;
+        write_nodes(self.pool, f, &GraphFormat::Dot)?;
+        writeln!(f, "}}")
+    }
+}
+
+/// Formats a call tree as a Mermaid flowchart.
+///
+/// ```
+/// use reqray::{graph::CallTreeMermaid, CallPathPool};
+///
+/// fn to_markdown(pool: &CallPathPool) -> String {
+///     format!("```mermaid\n{}```\n", CallTreeMermaid::new(pool))
+/// }
+/// ```
+pub struct CallTreeMermaid<'a> {
+    pool: &'a CallPathPool,
+}
+
+impl<'a> CallTreeMermaid<'a> {
+    pub fn new(pool: &'a CallPathPool) -> Self {
+        CallTreeMermaid { pool }
+    }
+}
+
+impl fmt::Display for CallTreeMermaid<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "flowchart TD")?;
+        write_nodes(self.pool, f, &GraphFormat::Mermaid)
+    }
+}
+
+/// A [FinishedCallTreeProcessor] which writes every call tree as a DOT or
+/// Mermaid graph to a writer, separated by empty lines.
+///
+/// ```
+/// use reqray::{graph::GraphProcessor, CallTreeCollectorBuilder};
+///
+/// let collector = CallTreeCollectorBuilder::default()
+///     .build_with_collector(GraphProcessor::dot(std::io::stdout()));
+/// ```
+pub struct GraphProcessor<W: io::Write> {
+    format: GraphFormat,
+    writer: Mutex<W>,
+}
+
+enum GraphFormat {
+    Dot,
+    Mermaid,
+}
+
+impl<W: io::Write> GraphProcessor<W> {
+    /// Writes [CallTreeDot] graphs.
+    pub fn dot(writer: W) -> Self {
+        GraphProcessor {
+            format: GraphFormat::Dot,
+            writer: Mutex::new(writer),
+        }
+    }
+
+    /// Writes [CallTreeMermaid] flowcharts.
+    pub fn mermaid(writer: W) -> Self {
+        GraphProcessor {
+            format: GraphFormat::Mermaid,
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+impl<W: io::Write> FinishedCallTreeProcessor for GraphProcessor<W> {
+    fn process_finished_call(&self, pool: CallPathPool) {
+        self.write(&pool)
+    }
+
+    fn process_shared_call(&self, pool: Arc<CallPathPool>) {
+        self.write(&pool)
+    }
+}
+
+impl<W: io::Write> GraphProcessor<W> {
+    fn write(&self, pool: &CallPathPool) {
+        // Formatted up front, so that a graph is written at once.
+        let graph = match self.format {
+            GraphFormat::Dot => CallTreeDot::new(pool).to_string(),
+            GraphFormat::Mermaid => CallTreeMermaid::new(pool).to_string(),
+        };
+        let mut writer = match self.writer.lock() {
+            Ok(writer) => writer,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let result = writeln!(writer, "{}", graph).and_then(|_| writer.flush());
+        if let Err(e) = result {
+            tracing::warn!("Could not write call tree graph: {}", e);
+        }
+    }
+}
+
+/// Writes the nodes and edges of the call tree in `pool`, numbering the
+/// nodes in depth first order.
+fn write_nodes(
+    pool: &CallPathPool,
+    f: &mut fmt::Formatter<'_>,
+    format: &GraphFormat,
+) -> fmt::Result {
+    let mut next_node = 0;
+    write_call_path(pool, pool.root(), None, &mut next_node, f, format)
+}
+
+fn write_call_path(
+    pool: &CallPathPool,
+    node: &CallPathTiming,
+    parent: Option<usize>,
+    next_node: &mut usize,
+    f: &mut fmt::Formatter<'_>,
+    format: &GraphFormat,
+) -> fmt::Result {
+    let id = *next_node;
+    *next_node += 1;
+
+    // The suffix needs no escaping.
+    let retry = if node.is_retry() { " (attempt 2+)" } else { "" };
+    let calls = match node.call_count() {
+        1 => "1 call".to_string(),
+        count => format!("{} calls", count),
+    };
+    let details = if node.is_event() {
+        calls
+    } else {
+        format!(
+            "{}, {:.3} ms busy",
+            calls,
+            node.sum_with_children().as_secs_f64() * 1_000.0
+        )
+    };
+
+    match format {
+        GraphFormat::Dot => {
+            writeln!(
+                f,
+                "    n{} [label=\"{}{}\\n{}\"];",
+                id,
+                DOT_NAMES.get_or_insert_with(node, |node| escape_dot(node.name()).into()),
+                retry,
+                details
+            )?;
+            if let Some(parent) = parent {
+                writeln!(f, "    n{} -> n{};", parent, id)?;
+            }
+        }
+        GraphFormat::Mermaid => {
+            writeln!(
+                f,
+                "    n{}[\"{}{}<br/>{}\"]",
+                id,
+                MERMAID_NAMES.get_or_insert_with(node, |node| escape_mermaid(node.name()).into()),
+                retry,
+                details
+            )?;
+            if let Some(parent) = parent {
+                writeln!(f, "    n{} --> n{}", parent, id)?;
+            }
+        }
+    }
+
+    let mut children = node.children().copied().collect::<Vec<_>>();
+    children.sort();
+    for child_idx in children {
+        write_call_path(pool, &pool[child_idx], Some(id), next_node, f, format)?;
+    }
+    Ok(())
+}
+
+/// Escapes `name` for a quoted DOT string.
+fn escape_dot(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for c in name.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' | '\r' => escaped.push(' '),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Escapes `name` for a quoted Mermaid label, which may contain HTML.
+fn escape_mermaid(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for c in name.chars() {
+        match c {
+            '"' => escaped.push_str("#quot;"),
+            '<' => escaped.push_str("#lt;"),
+            '>' => escaped.push_str("#gt;"),
+            '\n' | '\r' => escaped.push(' '),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use quanta::Mock;
+
+    use super::{CallTreeDot, CallTreeMermaid};
+    use crate::internal::test::collect_call_trees;
+
+    #[tracing::instrument]
+    fn leaf(mock: &Mock) {
+        mock.increment(2_000_000);
+    }
+
+    fn call_tree() -> crate::CallPathPool {
+        let mut call_trees = collect_call_trees(|mock| {
+            tracing::info_span!("request").in_scope(|| {
+                mock.increment(1_000_000);
+                tracing::info_span!("load \"<user>\"").in_scope(|| {
+                    leaf(&mock);
+                    leaf(&mock);
+                });
+            })
+        });
+        call_trees.remove(0)
+    }
+
+    #[test]
+    fn dot() {
+        assert_eq!(
+            CallTreeDot::new(&call_tree()).to_string(),
+            indoc::indoc! {r#"
+                digraph call_tree {
+                    node [shape=box];
+                    n0 [label="request\n1 call, 5.000 ms busy"];
+                    n1 [label="load \"<user>\"\n1 call, 4.000 ms busy"];
+                    n0 -> n1;
+                    n2 [label="leaf\n2 calls, 4.000 ms busy"];
+                    n1 -> n2;
+                }
+            "#}
+        );
+    }
+
+    #[test]
+    fn mermaid() {
+        assert_eq!(
+            CallTreeMermaid::new(&call_tree()).to_string(),
+            indoc::indoc! {r#"
+                flowchart TD
+                    n0["request<br/>1 call, 5.000 ms busy"]
+                    n1["load #quot;#lt;user#gt;#quot;<br/>1 call, 4.000 ms busy"]
+                    n0 --> n1
+                    n2["leaf<br/>2 calls, 4.000 ms busy"]
+                    n1 --> n2
+            "#}
+        );
+    }
+}
@@ -0,0 +1,144 @@
+//! Changing the configuration of a running
+//! [CallTreeCollector](crate::CallTreeCollector).
+
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+
+/// A cheaply clonable handle to reconfigure a
+/// [CallTreeCollector](crate::CallTreeCollector) at runtime, see
+/// [CallTreeCollector::handle](crate::CallTreeCollector::handle).
+///
+/// The handle stays valid after the collector has been moved into the
+/// subscriber, so it can be used e.g. from an admin endpoint or a signal
+/// handler:
+///
+/// ```
+/// # #[cfg(feature = "display")] {
+/// use reqray::{CallTreeCollectorBuilder, display::LoggingCallTreeCollectorBuilder};
+/// use tracing_subscriber::{util::SubscriberInitExt, prelude::*};
+///
+/// let collector = CallTreeCollectorBuilder::default()
+///     .sample_rate(0.01)
+///     .build_with_collector(LoggingCallTreeCollectorBuilder::default().build());
+/// let handle = collector.handle();
+/// tracing_subscriber::registry().with(collector).init();
+///
+/// // Investigate a production issue.
+/// handle.set_sample_rate(1.0);
+/// handle.set_max_call_depth(20);
+/// // And turn it off again.
+/// handle.set_enabled(false);
+/// # }
+/// ```
+///
+/// Changes apply to call trees started afterwards, call trees in flight
+/// keep their maximum call depth and are still collected.
+#[derive(Clone)]
+pub struct CallTreeCollectorHandle {
+    inner: Arc<HandleInner>,
+}
+
+struct HandleInner {
+    enabled: AtomicBool,
+    /// Only collect call trees if a random number is below this threshold,
+    /// all if `u64::MAX`.
+    sample_threshold: AtomicU64,
+    max_call_depth: AtomicUsize,
+    /// `usize::MAX` if unlimited.
+    max_spans_per_tree: AtomicUsize,
+}
+
+impl CallTreeCollectorHandle {
+    pub(crate) fn new(
+        enabled: bool,
+        sample_rate: f64,
+        max_call_depth: usize,
+        max_spans_per_tree: Option<usize>,
+    ) -> Self {
+        let handle = CallTreeCollectorHandle {
+            inner: Arc::new(HandleInner {
+                enabled: AtomicBool::new(enabled),
+                sample_threshold: AtomicU64::new(u64::MAX),
+                max_call_depth: AtomicUsize::new(0),
+                max_spans_per_tree: AtomicUsize::new(usize::MAX),
+            }),
+        };
+        handle.set_sample_rate(sample_rate);
+        handle.set_max_call_depth(max_call_depth);
+        if let Some(max_spans_per_tree) = max_spans_per_tree {
+            handle.set_max_spans_per_tree(Some(max_spans_per_tree));
+        }
+        handle
+    }
+
+    /// Whether new call trees are collected.
+    pub fn is_enabled(&self) -> bool {
+        self.inner.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Starts or stops collecting new call trees. Call trees in flight are
+    /// still completed and handed over.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.inner.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// The fraction of call trees to collect, see
+    /// [CallTreeCollectorBuilder::sample_rate](crate::CallTreeCollectorBuilder::sample_rate).
+    pub fn sample_rate(&self) -> f64 {
+        match self.inner.sample_threshold.load(Ordering::Relaxed) {
+            u64::MAX => 1.0,
+            threshold => threshold as f64 / u64::MAX as f64,
+        }
+    }
+
+    /// Changes the fraction of call trees to collect, between `0.0` and
+    /// `1.0`.
+    pub fn set_sample_rate(&self, sample_rate: f64) {
+        let threshold = if sample_rate >= 1.0 {
+            u64::MAX
+        } else {
+            (sample_rate.max(0.0) * u64::MAX as f64) as u64
+        };
+        self.inner
+            .sample_threshold
+            .store(threshold, Ordering::Relaxed);
+    }
+
+    /// See
+    /// [CallTreeCollectorBuilder::max_call_depth](crate::CallTreeCollectorBuilder::max_call_depth).
+    pub fn max_call_depth(&self) -> usize {
+        self.inner.max_call_depth.load(Ordering::Relaxed)
+    }
+
+    /// Changes the maximum call depth of new call trees, at least `2`.
+    pub fn set_max_call_depth(&self, max_call_depth: usize) {
+        self.inner
+            .max_call_depth
+            .store(core::cmp::max(2, max_call_depth), Ordering::Relaxed);
+    }
+
+    /// See
+    /// [CallTreeCollectorBuilder::max_spans_per_tree](crate::CallTreeCollectorBuilder::max_spans_per_tree),
+    /// `None` if unlimited.
+    pub fn max_spans_per_tree(&self) -> Option<usize> {
+        match self.inner.max_spans_per_tree.load(Ordering::Relaxed) {
+            usize::MAX => None,
+            max => Some(max),
+        }
+    }
+
+    /// Changes the maximum number of spans per call tree, `None` for no
+    /// limit. Call trees in flight which exceed the new limit are
+    /// degraded.
+    pub fn set_max_spans_per_tree(&self, max_spans_per_tree: Option<usize>) {
+        let max = max_spans_per_tree.map_or(usize::MAX, |max| core::cmp::max(1, max));
+        self.inner.max_spans_per_tree.store(max, Ordering::Relaxed);
+    }
+
+    /// The threshold for sampling decisions, `u64::MAX` to collect all.
+    pub(crate) fn sample_threshold(&self) -> u64 {
+        self.inner.sample_threshold.load(Ordering::Relaxed)
+    }
+}
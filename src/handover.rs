@@ -0,0 +1,103 @@
+//! The handover of call trees whose root spans were not closed when the
+//! collector is dropped, see
+//! [CallTreeCollectorBuilder::hand_over_abandoned](crate::CallTreeCollectorBuilder::hand_over_abandoned).
+//!
+//! # Memory model
+//!
+//! A pending call tree is kept in a [Slot] which is shared between the
+//! extensions of its root span and the [PendingTrees] of the collector.
+//! Whoever takes the call tree out of its slot while holding the lock of
+//! the slot owns it: the root span when it is closed or the collector when
+//! it is dropped. So every call tree is processed exactly once, however
+//! both race.
+//!
+//! The registry only holds weak references, so that finished call trees
+//! are freed even before they are forgotten. The lock of the registry is
+//! never held while a slot is locked or the other way round.
+
+use std::collections::BTreeMap;
+
+use crate::sync::{self, lock, Arc, Mutex, Weak};
+
+/// A call tree shared with [PendingTrees], `None` once it was taken.
+pub(crate) type Slot<T> = Arc<Mutex<Option<T>>>;
+
+/// The call trees whose root spans were not closed yet by their serial,
+/// see `TreeRef::serial`.
+pub(crate) struct PendingTrees<T> {
+    trees: Mutex<BTreeMap<u64, Weak<Mutex<Option<T>>>>>,
+}
+
+impl<T> Default for PendingTrees<T> {
+    fn default() -> Self {
+        PendingTrees {
+            trees: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl<T> PendingTrees<T> {
+    /// Registers the new call tree `serial` and returns its slot.
+    pub(crate) fn register(&self, serial: u64, tree: T) -> Slot<T> {
+        let slot = Arc::new(Mutex::new(Some(tree)));
+        lock(&self.trees).insert(serial, sync::downgrade(&slot));
+        slot
+    }
+
+    /// Stops tracking the call tree `serial`, e.g. after it was finished.
+    pub(crate) fn forget(&self, serial: u64) {
+        lock(&self.trees).remove(&serial);
+    }
+
+    /// Takes all call trees which were not taken out of their slots yet.
+    pub(crate) fn take_abandoned(&self) -> Vec<T> {
+        let trees = std::mem::take(&mut *lock(&self.trees));
+        trees
+            .into_values()
+            .filter_map(|slot| slot.upgrade())
+            .filter_map(|slot| lock(&slot).take())
+            .collect()
+    }
+}
+
+#[cfg(all(test, reqray_loom))]
+mod loom_test {
+    use loom::thread;
+
+    use super::PendingTrees;
+    use crate::sync::{lock, Arc};
+
+    #[test]
+    fn loom_finish_races_with_take_abandoned() {
+        loom::model(|| {
+            let pending = Arc::new(PendingTrees::default());
+            let slot = pending.register(1, "tree");
+            let finisher = {
+                let pending = pending.clone();
+                thread::spawn(move || {
+                    let finished = lock(&slot).take();
+                    pending.forget(1);
+                    finished
+                })
+            };
+            let abandoned = pending.take_abandoned();
+            let finished = finisher.join().unwrap();
+            assert_eq!(abandoned.len() + finished.iter().count(), 1);
+        });
+    }
+
+    #[test]
+    fn loom_register_races_with_take_abandoned() {
+        loom::model(|| {
+            let pending = Arc::new(PendingTrees::default());
+            let registrar = {
+                let pending = pending.clone();
+                thread::spawn(move || pending.register(1, "tree"))
+            };
+            let abandoned = pending.take_abandoned();
+            let slot = registrar.join().unwrap();
+            // Either handed over or still in the slot for the root span.
+            assert_eq!(abandoned.len() + lock(&slot).iter().count(), 1);
+        });
+    }
+}
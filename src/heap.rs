@@ -0,0 +1,56 @@
+/// Heap usage while a call tree was alive, see
+/// [CallTreeCollectorBuilder::heap_stats_rate](crate::CallTreeCollectorBuilder::heap_stats_rate).
+///
+/// [dhat](https://docs.rs/dhat) only knows process-wide heap statistics,
+/// so allocations of concurrent requests are included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HeapSummary {
+    allocations: u64,
+    allocated_bytes: u64,
+    peak_bytes: usize,
+}
+
+impl HeapSummary {
+    /// The number of allocations.
+    pub fn allocations(&self) -> u64 {
+        self.allocations
+    }
+
+    /// The sum of the sizes of all allocations.
+    pub fn allocated_bytes(&self) -> u64 {
+        self.allocated_bytes
+    }
+
+    /// The peak heap growth above the heap size at the creation of the
+    /// root span.
+    ///
+    /// This is exact if the process-wide heap peak was reached while the
+    /// call tree was alive. Otherwise, only the heap growth when the root
+    /// span was closed is known.
+    pub fn peak_bytes(&self) -> usize {
+        self.peak_bytes
+    }
+}
+
+#[cfg(feature = "dhat")]
+impl HeapSummary {
+    /// The heap usage between two snapshots.
+    pub(crate) fn between(start: &dhat::HeapStats, end: &dhat::HeapStats) -> HeapSummary {
+        let peak_bytes = if end.max_bytes > start.max_bytes {
+            end.max_bytes.saturating_sub(start.curr_bytes)
+        } else {
+            end.curr_bytes.saturating_sub(start.curr_bytes)
+        };
+        HeapSummary {
+            allocations: end.total_blocks - start.total_blocks,
+            allocated_bytes: end.total_bytes - start.total_bytes,
+            peak_bytes,
+        }
+    }
+}
+
+/// The heap statistics at the creation of a root span, stored in its
+/// extensions.
+#[cfg(feature = "dhat")]
+pub(crate) struct HeapStatsAtStart(pub(crate) dhat::HeapStats);
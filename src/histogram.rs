@@ -0,0 +1,143 @@
+use std::{convert::TryFrom, time::Duration};
+
+/// The number of linear sub buckets per power of two as a power of two. With
+/// `3`, recorded values are accurate to 1/8 = 12.5%.
+const SUB_BUCKET_BITS: u32 = 3;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+
+/// A compact histogram of busy times in the spirit of HDR histograms:
+/// every power of two of nanoseconds is split into 8 linear buckets, so
+/// percentiles are accurate to 12.5% independent of the magnitude.
+///
+/// Enable with
+/// [CallTreeCollectorBuilder::with_histograms](crate::CallTreeCollectorBuilder::with_histograms).
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BusyHistogram {
+    counts: Vec<u64>,
+    total: u64,
+    max: u64,
+}
+
+impl BusyHistogram {
+    pub(crate) fn record(&mut self, busy: Duration) {
+        let nanos = u64::try_from(busy.as_nanos()).unwrap_or(u64::MAX);
+        let bucket = bucket(nanos);
+        if self.counts.len() <= bucket {
+            self.counts.resize(bucket + 1, 0);
+        }
+        self.counts[bucket] += 1;
+        self.total += 1;
+        self.max = core::cmp::max(self.max, nanos);
+    }
+
+    /// Adds the busy times recorded in `other`.
+    pub(crate) fn merge(&mut self, other: &BusyHistogram) {
+        if self.counts.len() < other.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+        for (count, other_count) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other_count;
+        }
+        self.total += other.total;
+        self.max = core::cmp::max(self.max, other.max);
+    }
+
+    /// The number of recorded busy times.
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    /// The number of recorded busy times which are at most `busy`, rounded
+    /// down to the buckets of the histogram.
+    #[cfg(feature = "openmetrics")]
+    pub(crate) fn count_at_most(&self, busy: Duration) -> u64 {
+        let nanos = u64::try_from(busy.as_nanos()).unwrap_or(u64::MAX);
+        self.counts
+            .iter()
+            .enumerate()
+            .take_while(|(bucket, _)| highest_in_bucket(*bucket) <= nanos)
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    /// The busy time below or at which `percentile` percent of the
+    /// recorded busy times are, e.g. `percentile(99.0)`.
+    ///
+    /// Returns `None` if nothing was recorded.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        if self.total == 0 {
+            return None;
+        }
+        let fraction = (percentile / 100.0).clamp(0.0, 1.0);
+        let rank = core::cmp::max(1, (fraction * self.total as f64).ceil() as u64);
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let highest = core::cmp::min(highest_in_bucket(bucket), self.max);
+                return Some(Duration::from_nanos(highest));
+            }
+        }
+        Some(Duration::from_nanos(self.max))
+    }
+}
+
+fn bucket(nanos: u64) -> usize {
+    if nanos < SUB_BUCKETS as u64 {
+        return nanos as usize;
+    }
+    let magnitude = 63 - nanos.leading_zeros();
+    let shift = magnitude - SUB_BUCKET_BITS;
+    let sub_bucket = (nanos >> shift) as usize & (SUB_BUCKETS - 1);
+    (shift as usize + 1) * SUB_BUCKETS + sub_bucket
+}
+
+fn highest_in_bucket(bucket: usize) -> u64 {
+    if bucket < SUB_BUCKETS {
+        return bucket as u64;
+    }
+    let shift = (bucket / SUB_BUCKETS - 1) as u32;
+    let lowest = ((SUB_BUCKETS + bucket % SUB_BUCKETS) as u64) << shift;
+    lowest + ((1u64 << shift) - 1)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{bucket, highest_in_bucket, BusyHistogram};
+
+    #[test]
+    fn buckets_cover_all_values() {
+        for nanos in (0..10_000).chain([u64::MAX - 1, u64::MAX]) {
+            let bucket = bucket(nanos);
+            assert!(nanos <= highest_in_bucket(bucket), "{}", nanos);
+            if bucket > 0 {
+                assert!(nanos > highest_in_bucket(bucket - 1), "{}", nanos);
+            }
+        }
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut histogram = BusyHistogram::default();
+        assert_eq!(histogram.percentile(50.0), None);
+        for micros in 1..=100 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        assert_eq!(histogram.count(), 100);
+        for (percentile, expected) in [(50.0, 50), (90.0, 90), (99.0, 99), (100.0, 100)] {
+            let actual = histogram.percentile(percentile).unwrap().as_nanos() as f64;
+            let expected = Duration::from_micros(expected).as_nanos() as f64;
+            assert!(
+                actual >= expected && actual <= expected * 1.125,
+                "p{}: {} vs {}",
+                percentile,
+                actual,
+                expected
+            );
+        }
+        assert_eq!(histogram.percentile(0.0), Some(Duration::from_nanos(1_023)));
+    }
+}
@@ -0,0 +1,378 @@
+//! Self-contained HTML reports of finished call trees for readers without
+//! a terminal, e.g. attached to an incident postmortem.
+//!
+//! Every call path is a collapsible node with a bar proportional to its
+//! busy time relative to the root. The children of all call paths can be
+//! sorted by call order, busy time, own busy time or number of calls.
+//! The reports need no external resources.
+
+use std::{
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::UNIX_EPOCH,
+};
+
+use crate::{
+    callsite_cache::CallsiteCache, clock::system_time_now, CallPathPool, CallPathTiming,
+    FinishedCallTreeProcessor,
+};
+
+/// The names of call paths with HTML special characters escaped.
+static ESCAPED_NAMES: CallsiteCache<str> = CallsiteCache::new();
+
+/// A [FinishedCallTreeProcessor] which writes every call tree as an HTML
+/// report, either as a file per call tree or appended to a single report.
+///
+/// ```no_run
+/// use reqray::{html::HtmlReportProcessor, CallTreeCollectorBuilder};
+///
+/// let collector = CallTreeCollectorBuilder::default()
+///     .build_with_collector(HtmlReportProcessor::to_dir("xrays").unwrap());
+/// ```
+pub struct HtmlReportProcessor {
+    target: Mutex<Target>,
+}
+
+enum Target {
+    /// A file per call tree in this directory.
+    Dir { dir: PathBuf, written: u64 },
+    /// All call trees appended to a single report.
+    Writer {
+        writer: Box<dyn Write + Send>,
+        needs_head: bool,
+    },
+}
+
+impl HtmlReportProcessor {
+    /// Writes a report per call tree into `dir`, creating it if necessary.
+    ///
+    /// The files are named after the time they were written, the number of
+    /// the call tree and its root, e.g.
+    /// `1644177717103-0-request.html`.
+    pub fn to_dir(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        Ok(HtmlReportProcessor {
+            target: Mutex::new(Target::Dir { dir, written: 0 }),
+        })
+    }
+
+    /// Appends all call trees to the report at `path`, creating it if
+    /// necessary.
+    pub fn append_to_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file: File = OpenOptions::new().create(true).append(true).open(path)?;
+        let needs_head = file.metadata()?.len() == 0;
+        Ok(Self::with_head(Box::new(file), needs_head))
+    }
+
+    /// Appends all call trees to a report written to `writer`.
+    ///
+    /// The report is never closed, which browsers tolerate, so that it can
+    /// be viewed while it is written.
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        Self::with_head(Box::new(writer), true)
+    }
+
+    fn with_head(writer: Box<dyn Write + Send>, needs_head: bool) -> Self {
+        HtmlReportProcessor {
+            target: Mutex::new(Target::Writer { writer, needs_head }),
+        }
+    }
+}
+
+impl FinishedCallTreeProcessor for HtmlReportProcessor {
+    fn process_finished_call(&self, pool: CallPathPool) {
+        self.write(&pool)
+    }
+
+    fn process_shared_call(&self, pool: Arc<CallPathPool>) {
+        self.write(&pool)
+    }
+}
+
+impl HtmlReportProcessor {
+    fn write(&self, pool: &CallPathPool) {
+        let mut target = match self.target.lock() {
+            Ok(target) => target,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let result = match &mut *target {
+            Target::Dir { dir, written } => {
+                let millis = system_time_now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis();
+                let name = file_name(pool.root().name());
+                let path = dir.join(format!("{}-{}-{}.html", millis, written, name));
+                *written += 1;
+                fs::write(path, html_report(pool))
+            }
+            Target::Writer { writer, needs_head } => {
+                let mut out = String::new();
+                if *needs_head {
+                    out.push_str(HEAD);
+                }
+                write_tree(pool, &mut out);
+                let result = writer
+                    .write_all(out.as_bytes())
+                    .and_then(|_| writer.flush());
+                if result.is_ok() {
+                    *needs_head = false;
+                }
+                result
+            }
+        };
+        if let Err(e) = result {
+            tracing::warn!("Could not write HTML report: {}", e);
+        }
+    }
+}
+
+/// A self-contained HTML document with the call tree in `pool`.
+pub fn html_report(pool: &CallPathPool) -> String {
+    let mut out = String::from(HEAD);
+    write_tree(pool, &mut out);
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+/// The document up to the opening `body` tag with the styles and the
+/// script to sort the call paths.
+const HEAD: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>reqray call trees</title>
+<style>
+body { font-family: sans-serif; margin: 1em 2em; }
+section { margin-bottom: 2em; }
+h2 { font-size: 1.1em; font-family: monospace; }
+summary { display: flex; gap: 1em; align-items: center; cursor: pointer; font-family: monospace; padding: 1px 0; }
+summary:hover { background: #f0f0f0; }
+details.leaf > summary { list-style: none; padding-left: 1em; cursor: default; }
+.children { margin-left: 1.5em; }
+.name { flex: 1; }
+.num { width: 8em; text-align: right; }
+.bar { width: 12em; height: 0.8em; background: #eee; }
+.bar > span { display: block; height: 100%; background: #e8743b; }
+.header { display: flex; gap: 1em; font-weight: bold; font-family: monospace; padding-left: 1em; }
+</style>
+<script>
+function sortCallPaths(button) {
+  var key = button.dataset.sort;
+  var lists = button.closest("section").querySelectorAll(".children");
+  lists.forEach(function (list) {
+    var nodes = Array.prototype.slice.call(list.children);
+    nodes.sort(function (a, b) {
+      return key === "order"
+        ? a.dataset.order - b.dataset.order
+        : b.dataset[key] - a.dataset[key];
+    });
+    nodes.forEach(function (node) { list.appendChild(node); });
+  });
+}
+</script>
+</head>
+<body>
+"#;
+
+/// Writes the call tree in `pool` as a `section`.
+fn write_tree(pool: &CallPathPool, out: &mut String) {
+    let root = pool.root();
+    let meta = root.span_metadata();
+    out.push_str("<section>\n<h2>");
+    escape(root.name(), out);
+    if let (Some(file), Some(line)) = (meta.file(), meta.line()) {
+        out.push('@');
+        escape(file, out);
+        let _ = write!(out, ":{}", line);
+    }
+    out.push_str("</h2>\n<p>Sort by ");
+    for (key, label) in [
+        ("order", "call order"),
+        ("busy", "busy time"),
+        ("own", "own busy time"),
+        ("calls", "calls"),
+    ]
+    .iter()
+    {
+        let _ = write!(
+            out,
+            "<button data-sort=\"{}\" onclick=\"sortCallPaths(this)\">{}</button> ",
+            key, label
+        );
+    }
+    out.push_str("</p>\n<div class=\"header\"><span class=\"name\">span tree</span>");
+    out.push_str("<span class=\"num\"># calls</span><span class=\"num\">∑ busy ms</span>");
+    out.push_str("<span class=\"num\">∑ own busy ms</span><span class=\"bar\"></span></div>\n");
+    let root_busy = root.sum_with_children().as_nanos();
+    write_call_path(pool, root, 0, root_busy, out);
+    out.push_str("</section>\n");
+}
+
+fn write_call_path(
+    pool: &CallPathPool,
+    node: &CallPathTiming,
+    order: usize,
+    root_busy: u128,
+    out: &mut String,
+) {
+    let busy = node.sum_with_children();
+    let own = node.sum_without_children();
+    let mut children = node.children().copied().collect::<Vec<_>>();
+    children.sort();
+
+    let _ = write!(
+        out,
+        "<details{} data-order=\"{}\" data-busy=\"{}\" data-own=\"{}\" data-calls=\"{}\"><summary>",
+        if children.is_empty() {
+            " class=\"leaf\""
+        } else {
+            " open"
+        },
+        order,
+        busy.as_nanos(),
+        own.as_nanos(),
+        node.call_count()
+    );
+    out.push_str("<span class=\"name\">");
+    out.push_str(&ESCAPED_NAMES.get_or_insert_with(node, |node| {
+        let mut name = String::new();
+        escape(node.name(), &mut name);
+        name.into()
+    }));
+    if node.is_retry() {
+        out.push_str(" (attempt 2+)");
+    }
+    let percent = if root_busy == 0 {
+        0.0
+    } else {
+        busy.as_nanos() as f64 * 100.0 / root_busy as f64
+    };
+    let _ = writeln!(
+        out,
+        "</span><span class=\"num\">{}</span><span class=\"num\">{:.3}</span>\
+         <span class=\"num\">{:.3}</span>\
+         <span class=\"bar\"><span style=\"width:{:.1}%\"></span></span></summary>",
+        node.call_count(),
+        busy.as_secs_f64() * 1_000.0,
+        own.as_secs_f64() * 1_000.0,
+        percent
+    );
+    if !children.is_empty() {
+        out.push_str("<div class=\"children\">\n");
+        for (order, child_idx) in children.into_iter().enumerate() {
+            write_call_path(pool, &pool[child_idx], order, root_busy, out);
+        }
+        out.push_str("</div>\n");
+    }
+    out.push_str("</details>\n");
+}
+
+/// Appends `text` to `out` with HTML special characters escaped.
+fn escape(text: &str, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+}
+
+/// A file name component for a span name, e.g. `GET /users` becomes
+/// `GET__users`.
+fn file_name(name: &str) -> String {
+    name.chars()
+        .take(64)
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use quanta::Mock;
+
+    use super::{file_name, html_report, HtmlReportProcessor};
+    use crate::{internal::test::collect_call_trees, FinishedCallTreeProcessor};
+
+    #[tracing::instrument]
+    fn leaf(mock: &Mock) {
+        mock.increment(1_000_000);
+    }
+
+    #[test]
+    fn report() {
+        let call_trees = collect_call_trees(|mock| {
+            tracing::info_span!("request").in_scope(|| {
+                mock.increment(2_000_000);
+                tracing::info_span!("<load>").in_scope(|| {
+                    leaf(&mock);
+                    leaf(&mock);
+                });
+            })
+        });
+        let report = html_report(&call_trees[0]);
+        assert!(report.starts_with("<!DOCTYPE html>"));
+        assert!(report.ends_with("</html>\n"));
+        assert!(report.contains("<h2>request@src/html.rs:"));
+        assert!(report.contains(
+            "<details open data-order=\"0\" data-busy=\"4000000\" data-own=\"2000000\" \
+             data-calls=\"1\"><summary><span class=\"name\">request</span>\
+             <span class=\"num\">1</span><span class=\"num\">4.000</span>\
+             <span class=\"num\">2.000</span>\
+             <span class=\"bar\"><span style=\"width:100.0%\"></span></span></summary>"
+        ));
+        assert!(report.contains("<span class=\"name\">&lt;load&gt;</span>"));
+        assert!(report.contains(
+            "<details class=\"leaf\" data-order=\"0\" data-busy=\"2000000\" \
+             data-own=\"2000000\" data-calls=\"2\">"
+        ));
+        assert!(report.contains("<span style=\"width:50.0%\">"));
+    }
+
+    #[test]
+    fn append_to_report() {
+        #[derive(Clone, Default)]
+        struct Shared(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+        impl std::io::Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let out = Shared::default();
+        let processor = HtmlReportProcessor::new(out.clone());
+        for call_tree in collect_call_trees(|mock| {
+            tracing::info_span!("first").in_scope(|| leaf(&mock));
+            tracing::info_span!("second").in_scope(|| leaf(&mock));
+        }) {
+            processor.process_finished_call(call_tree);
+        }
+        let report = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(report.matches("<!DOCTYPE html>").count(), 1);
+        assert_eq!(report.matches("<section>").count(), 2);
+    }
+
+    #[test]
+    fn file_names() {
+        assert_eq!(file_name("GET /users"), "GET__users");
+        assert_eq!(file_name("request"), "request");
+    }
+}
@@ -1,33 +1,651 @@
-use std::{collections::HashMap, fmt, thread::ThreadId, time::Duration};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread::ThreadId,
+    time::Duration,
+};
 use tracing::{
+    field::{Field, Visit},
     span::{self},
-    Id, Subscriber, warn,
+    warn, Id, Level, Subscriber,
 };
 use tracing_subscriber::{
     layer::Context,
-    registry::{ExtensionsMut, LookupSpan},
-    Layer,
+    registry::{ExtensionsMut, LookupSpan, SpanRef},
+    Layer, Registry,
 };
 
 use std::ops::{Index, IndexMut};
 
+#[cfg(feature = "dhat")]
+use crate::heap::HeapStatsAtStart;
+use crate::{
+    handover::{self, Slot},
+    stats::InFlightEntry,
+    sync::{lock, MutexGuard},
+    BusyHistogram, HeapSummary, ReqrayClock, SpanMetadata,
+};
+
 use tracing::{callsite, Metadata};
 
 /// Use a [CallPathPoolId] to index a [CallPathTiming] in a [CallPathPool].
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CallPathPoolId(usize);
 
+#[cfg(feature = "display")]
+impl CallPathPoolId {
+    /// The id of the root of every call tree.
+    pub(crate) const ROOT: CallPathPoolId = CallPathPoolId(0);
+}
+
 /// A [CallPathPool] contains all [CallPathTiming]s of a call tree
 /// indexed by [CallPathPoolId]s.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CallPathPool {
     pool: Vec<CallPathTiming>,
+    /// The number of spans collected in this call tree.
+    spans: usize,
+    /// The time spent in span creation and close callbacks of this call tree.
+    layer_time: Duration,
+    /// Set once the per tree budget was exceeded.
+    degraded: bool,
+    /// The number of spans ignored since the budget was exceeded.
+    skipped_spans: usize,
+    /// The number of exits of spans which were not entered before.
+    unmatched_exits: usize,
+    /// The values of the context fields of the root span.
+    context: Vec<(Cow<'static, str>, String)>,
+    /// The time at which the root span was created.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    created_at: u64,
+    /// The heap usage while the call tree was alive, if sampled.
+    heap: Option<HeapSummary>,
+    /// The maximum call depth when the call tree was started.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    max_call_depth: usize,
+}
+
+/// Callbacks for the call paths of a call tree, see [CallPathPool::visit].
+///
+/// `depth` is the depth below the root, `0` for the root.
+pub trait CallTreeVisitor {
+    /// Called for a call path before its children.
+    fn enter_node(&mut self, depth: usize, node: &CallPathTiming) {
+        let _ = (depth, node);
+    }
+
+    /// Called for a call path after its children.
+    fn leave_node(&mut self, depth: usize, node: &CallPathTiming) {
+        let _ = (depth, node);
+    }
+}
+
+/// The time of a call tree split into local work, downstream I/O and other
+/// waiting, see [CallPathPool::time_split].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TimeSplit {
+    local: Duration,
+    downstream: Duration,
+    waiting: Duration,
+}
+
+impl TimeSplit {
+    /// The own busy time of all call paths, i.e. the work done by this
+    /// service itself.
+    pub fn local(&self) -> Duration {
+        self.local
+    }
+
+    /// The I/O wait reported with [report_io_wait](crate::report_io_wait),
+    /// e.g. the time spent waiting for databases or other services.
+    pub fn downstream(&self) -> Duration {
+        self.downstream
+    }
+
+    /// The remaining time, e.g. waiting for locks, timers or a busy
+    /// executor.
+    pub fn waiting(&self) -> Duration {
+        self.waiting
+    }
 }
 
 impl CallPathPool {
     pub fn root(&self) -> &CallPathTiming {
         &self[CallPathPoolId(0)]
     }
+
+    /// The number of call paths in this call tree, including the root and
+    /// events.
+    pub fn call_path_count(&self) -> usize {
+        self.pool.len()
+    }
+
+    /// The number of spans collected in this call tree, which is at least
+    /// the number of call paths without events.
+    pub fn span_count(&self) -> usize {
+        self.spans
+    }
+
+    /// True if the per tree budget was exceeded so that later spans were
+    /// not collected, see [CallTreeCollectorBuilder::max_spans_per_tree].
+    ///
+    /// [CallTreeCollectorBuilder::max_spans_per_tree]: crate::CallTreeCollectorBuilder::max_spans_per_tree
+    pub fn is_degraded(&self) -> bool {
+        self.degraded
+    }
+
+    /// The number of spans that were not collected because the per tree
+    /// budget was exceeded.
+    pub fn skipped_spans(&self) -> usize {
+        self.skipped_spans
+    }
+
+    /// The number of spans beyond the
+    /// [maximum call depth](crate::CallTreeCollectorBuilder::max_call_depth),
+    /// which are only aggregated in [overflow](CallPathTiming::is_overflow)
+    /// call paths.
+    pub fn depth_capped_spans(&self) -> usize {
+        self.pool
+            .iter()
+            .filter(|timing| timing.overflow)
+            .map(|timing| timing.call_count)
+            .sum()
+    }
+
+    /// The number of times a span was exited on a thread without being
+    /// entered there before, e.g. when futures are instrumented with
+    /// `.in_current_span()`. The busy time of these spans is incomplete.
+    pub fn unmatched_exits(&self) -> usize {
+        self.unmatched_exits
+    }
+
+    /// The fields of the root span configured with
+    /// [CallTreeCollectorBuilder::context_field](crate::CallTreeCollectorBuilder::context_field)
+    /// and their values, e.g. `("request_id", "42")`, in the order in
+    /// which they were first recorded.
+    pub fn context(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.context
+            .iter()
+            .map(|(field, value)| (field.as_ref(), value.as_str()))
+    }
+
+    /// Sets the context field values recorded for the root span.
+    fn record_context(&mut self, context: Vec<(&'static str, String)>) {
+        for (field, value) in context {
+            match self
+                .context
+                .iter_mut()
+                .find(|(existing, _)| existing == field)
+            {
+                Some((_, existing_value)) => *existing_value = value,
+                None => self.context.push((Cow::Borrowed(field), value)),
+            }
+        }
+    }
+
+    /// The number of spans which were not tracked individually or whose
+    /// timing is incomplete: the sum of [skipped_spans](Self::skipped_spans),
+    /// [depth_capped_spans](Self::depth_capped_spans) and
+    /// [unmatched_exits](Self::unmatched_exits).
+    ///
+    /// If this is zero, the call tree is complete.
+    pub fn untracked_spans(&self) -> usize {
+        self.skipped_spans + self.depth_capped_spans() + self.unmatched_exits
+    }
+
+    /// The heap usage while the call tree was alive, if this call tree was
+    /// sampled for heap stats, see
+    /// [CallTreeCollectorBuilder::heap_stats_rate].
+    ///
+    /// [CallTreeCollectorBuilder::heap_stats_rate]: crate::CallTreeCollectorBuilder::heap_stats_rate
+    pub fn heap_summary(&self) -> Option<&HeapSummary> {
+        self.heap.as_ref()
+    }
+
+    /// Splits the alive time of the root into [local](TimeSplit::local),
+    /// [downstream](TimeSplit::downstream) and
+    /// [waiting](TimeSplit::waiting) time, e.g. to tell at a glance whether
+    /// a slow request was slow itself or waited for its dependencies.
+    ///
+    /// The three parts add up to the alive time of the root. Overlapping
+    /// times, e.g. blocking I/O reported while a span was busy or work on
+    /// several threads in parallel, count as downstream first, then as
+    /// local.
+    pub fn time_split(&self) -> TimeSplit {
+        let alive = self.root().span_alive();
+        let (own, io_wait) = self.pool.iter().fold(
+            (Duration::default(), Duration::default()),
+            |(own, io_wait), timing| (own + timing.sum_own, io_wait + timing.sum_io_wait),
+        );
+        let downstream = io_wait.min(alive);
+        let local = own.min(alive - downstream);
+        TimeSplit {
+            local,
+            downstream,
+            waiting: alive - downstream - local,
+        }
+    }
+
+    /// All call paths with their [path strings], parents before children,
+    /// e.g. as a starting point for flat exports like CSV or metrics.
+    ///
+    /// [path strings]: CallPathTiming::path_string
+    pub fn flatten<'a>(
+        &'a self,
+        separator: &'a str,
+    ) -> impl Iterator<Item = (String, &'a CallPathTiming)> + 'a {
+        self.pool
+            .iter()
+            .map(move |timing| (timing.path_string(self, separator), timing))
+    }
+
+    /// All call paths with their depth below the root, in depth-first order
+    /// with siblings in the order of their creation, e.g. to write custom
+    /// processors without recursing via [CallPathTiming::children]:
+    ///
+    /// ```
+    /// use reqray::CallPathPool;
+    ///
+    /// fn print_tree(pool: &CallPathPool) {
+    ///     for (depth, call_path) in pool.iter_depth_first() {
+    ///         println!("{:indent$}{}", "", call_path.name(), indent = 2 * depth);
+    ///     }
+    /// }
+    /// ```
+    ///
+    /// The order is the same for identical call trees.
+    pub fn iter_depth_first(&self) -> impl Iterator<Item = (usize, &CallPathTiming)> + '_ {
+        let mut stack = vec![(0, CallPathPoolId(0))];
+        std::iter::from_fn(move || {
+            let (depth, id) = stack.pop()?;
+            let timing = &self[id];
+            let mut children = timing.children.values().copied().collect::<Vec<_>>();
+            // The first created child is visited first.
+            children.sort_by(|a, b| b.cmp(a));
+            stack.extend(children.into_iter().map(|child_id| (depth + 1, child_id)));
+            Some((depth, timing))
+        })
+    }
+
+    /// Calls `visitor` for all call paths in the order of
+    /// [iter_depth_first](Self::iter_depth_first), leaving every call path
+    /// after all of its children, e.g. for exporters of nested formats:
+    ///
+    /// ```
+    /// use reqray::{CallPathPool, CallPathTiming, CallTreeVisitor};
+    ///
+    /// /// Writes the call tree as nested lists like `request(load, save)`.
+    /// #[derive(Default)]
+    /// struct Nested {
+    ///     out: String,
+    ///     has_children: Vec<bool>,
+    /// }
+    ///
+    /// impl CallTreeVisitor for Nested {
+    ///     fn enter_node(&mut self, _depth: usize, node: &CallPathTiming) {
+    ///         if let Some(has_children) = self.has_children.last_mut() {
+    ///             self.out.push_str(if *has_children { ", " } else { "(" });
+    ///             *has_children = true;
+    ///         }
+    ///         self.out.push_str(node.name());
+    ///         self.has_children.push(false);
+    ///     }
+    ///
+    ///     fn leave_node(&mut self, _depth: usize, _node: &CallPathTiming) {
+    ///         if self.has_children.pop() == Some(true) {
+    ///             self.out.push(')');
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// fn nested(pool: &CallPathPool) -> String {
+    ///     let mut visitor = Nested::default();
+    ///     pool.visit(&mut visitor);
+    ///     visitor.out
+    /// }
+    /// ```
+    pub fn visit(&self, visitor: &mut impl CallTreeVisitor) {
+        enum Step {
+            Enter(usize, CallPathPoolId),
+            Leave(usize, CallPathPoolId),
+        }
+
+        let mut stack = vec![Step::Enter(0, CallPathPoolId(0))];
+        while let Some(step) = stack.pop() {
+            match step {
+                Step::Enter(depth, id) => {
+                    let timing = &self[id];
+                    visitor.enter_node(depth, timing);
+                    stack.push(Step::Leave(depth, id));
+                    let mut children = timing.children.values().copied().collect::<Vec<_>>();
+                    // The first created child is visited first.
+                    children.sort_by(|a, b| b.cmp(a));
+                    stack.extend(
+                        children
+                            .into_iter()
+                            .map(|child_id| Step::Enter(depth + 1, child_id)),
+                    );
+                }
+                Step::Leave(depth, id) => visitor.leave_node(depth, &self[id]),
+            }
+        }
+    }
+
+    /// Extracts the call tree below `id` as a standalone pool with the call
+    /// path at `id` as its root, e.g. to render only the interesting part
+    /// of a huge call tree.
+    ///
+    /// Depths and ids are relative to the new root, the relative order of
+    /// the ids is kept. [CallPathTiming::first_entered] stays relative to
+    /// the creation of the original root span.
+    ///
+    /// Skipped spans and unmatched exits are not attributed to call paths,
+    /// so [is_degraded](Self::is_degraded),
+    /// [skipped_spans](Self::skipped_spans),
+    /// [unmatched_exits](Self::unmatched_exits),
+    /// [heap_summary](Self::heap_summary) and
+    /// [context](Self::context) of the subtree describe the whole call
+    /// tree, even if e.g. the skipped spans belonged to a sibling branch.
+    /// Only [depth_capped_spans](Self::depth_capped_spans) is specific to
+    /// the subtree.
+    ///
+    /// Panics if `id` is not part of this pool.
+    pub fn subtree(&self, id: CallPathPoolId) -> CallPathPool {
+        let mut old_ids = vec![id];
+        let mut next = 0;
+        while let Some(old_id) = old_ids.get(next) {
+            old_ids.extend(self[*old_id].children.values().copied());
+            next += 1;
+        }
+        // Parents are always created before their children, so sorting
+        // keeps the subtree root at index 0.
+        old_ids.sort();
+
+        let new_id = |old_id: &CallPathPoolId| {
+            CallPathPoolId(old_ids.binary_search(old_id).expect("child not in subtree"))
+        };
+        let root_depth = self[id].depth;
+        let pool = old_ids
+            .iter()
+            .map(|old_id| {
+                let mut timing = self[*old_id].clone();
+                timing.depth -= root_depth;
+                timing.parent = match timing.parent {
+                    Some(parent) if *old_id != id => Some(new_id(&parent)),
+                    _ => None,
+                };
+                for child_id in timing.children.values_mut() {
+                    *child_id = new_id(child_id);
+                }
+                timing
+            })
+            .collect::<Vec<_>>();
+        CallPathPool {
+            spans: pool.iter().map(|timing| timing.call_count).sum(),
+            pool,
+            layer_time: self.layer_time,
+            degraded: self.degraded,
+            skipped_spans: self.skipped_spans,
+            unmatched_exits: self.unmatched_exits,
+            context: self.context.clone(),
+            created_at: self.created_at,
+            heap: self.heap,
+            max_call_depth: self.max_call_depth,
+        }
+    }
+
+    /// Renumbers the call paths in depth-first order and releases unused
+    /// capacity, e.g. before storing call trees for a long time.
+    ///
+    /// Children are visited in the order in which they were created, so
+    /// the relative order of siblings is kept. Afterwards, identical call
+    /// trees have identical ids, so that their serializations can be
+    /// compared byte-for-byte.
+    pub fn compact(&mut self) {
+        let mut old_ids = Vec::with_capacity(self.pool.len());
+        let mut stack = vec![CallPathPoolId(0)];
+        while let Some(old_id) = stack.pop() {
+            old_ids.push(old_id);
+            let mut children = self[old_id].children.values().copied().collect::<Vec<_>>();
+            // The first created child is visited first.
+            children.sort_by(|a, b| b.cmp(a));
+            stack.extend(children);
+        }
+        let mut new_ids = vec![CallPathPoolId(0); self.pool.len()];
+        for (new_id, old_id) in old_ids.iter().enumerate() {
+            new_ids[old_id.0] = CallPathPoolId(new_id);
+        }
+
+        let mut old_pool = std::mem::take(&mut self.pool)
+            .into_iter()
+            .map(Some)
+            .collect::<Vec<_>>();
+        self.pool = old_ids
+            .iter()
+            .map(|old_id| {
+                let mut timing = old_pool[old_id.0].take().expect("call path visited twice");
+                timing.parent = timing.parent.map(|parent| new_ids[parent.0]);
+                for child_id in timing.children.values_mut() {
+                    *child_id = new_ids[child_id.0];
+                }
+                timing.children.shrink_to_fit();
+                timing.notes.shrink_to_fit();
+                timing.follows_from.shrink_to_fit();
+                timing.field_values.shrink_to_fit();
+                timing
+            })
+            .collect();
+    }
+
+    /// Adds the calls and durations of `other` to the identical call paths
+    /// of this call tree, adding call paths that are only in `other`, e.g.
+    /// to aggregate the call trees of many requests:
+    ///
+    /// ```
+    /// use reqray::CallPathPool;
+    ///
+    /// fn total(call_trees: &[CallPathPool]) -> Option<CallPathPool> {
+    ///     let (first, rest) = call_trees.split_first()?;
+    ///     let mut total = first.clone();
+    ///     for call_tree in rest {
+    ///         total.merge(call_tree);
+    ///     }
+    ///     Some(total)
+    /// }
+    /// ```
+    ///
+    /// Call paths are identical if the callsites along their paths are the
+    /// same. For deserialized call trees, which have no callsites, the
+    /// name, location and level of the spans are compared instead.
+    ///
+    /// The roots are always merged, so that the call count of the root is
+    /// the number of merged call trees. Usually, only call trees with the
+    /// same root callsite are merged, see
+    /// [AggregatingProcessor](crate::aggregate::AggregatingProcessor).
+    pub fn merge(&mut self, other: &CallPathPool) {
+        self.pool[0].add(other.root());
+        self.merge_children(CallPathPoolId(0), other, CallPathPoolId(0));
+        self.spans += other.spans;
+        self.layer_time += other.layer_time;
+        self.degraded |= other.degraded;
+        self.skipped_spans += other.skipped_spans;
+        self.unmatched_exits += other.unmatched_exits;
+    }
+
+    /// The child of `id` which matches `other_child` of another call tree
+    /// with `key`.
+    fn matching_child(
+        &self,
+        id: CallPathPoolId,
+        key: &CallPathKey,
+        other_child: &CallPathTiming,
+    ) -> Option<CallPathPoolId> {
+        let existing = match key {
+            // Ids of other call trees mean nothing here.
+            CallPathKey::Id(_) => None,
+            _ => self[id].children.get(key).copied(),
+        };
+        existing.or_else(|| {
+            // Deserialized call paths have no callsites to compare.
+            self[id]
+                .children
+                .values()
+                .copied()
+                .find(|child_id| self[*child_id].looks_like(other_child))
+        })
+    }
+
+    /// The call paths of `other` which correspond to the call paths of
+    /// this call tree, matched like in [merge](Self::merge), e.g. to
+    /// compare a call tree with an aggregate.
+    #[cfg(feature = "display")]
+    pub(crate) fn matching_call_paths(
+        &self,
+        other: &CallPathPool,
+    ) -> HashMap<CallPathPoolId, CallPathPoolId> {
+        let mut matching = HashMap::new();
+        matching.insert(CallPathPoolId::ROOT, CallPathPoolId::ROOT);
+        let mut next = vec![(CallPathPoolId::ROOT, CallPathPoolId::ROOT)];
+        while let Some((id, other_id)) = next.pop() {
+            for (key, child_id) in &self[id].children {
+                if let Some(other_child_id) = other.matching_child(other_id, key, &self[*child_id])
+                {
+                    matching.insert(*child_id, other_child_id);
+                    next.push((*child_id, other_child_id));
+                }
+            }
+        }
+        matching
+    }
+
+    /// Merges the children of `other_id` in `other` into the children of
+    /// `id`.
+    fn merge_children(
+        &mut self,
+        id: CallPathPoolId,
+        other: &CallPathPool,
+        other_id: CallPathPoolId,
+    ) {
+        let mut other_children = other[other_id].children.iter().collect::<Vec<_>>();
+        // Call paths only in `other` are added in the order of their creation.
+        other_children.sort_by_key(|(_, child_id)| **child_id);
+        for (key, other_child_id) in other_children {
+            let other_child = &other[*other_child_id];
+            let child_id = match self.matching_child(id, key, other_child) {
+                Some(child_id) => {
+                    self[child_id].add(other_child);
+                    child_id
+                }
+                None => {
+                    let child_id = CallPathPoolId(self.pool.len());
+                    let key = match key {
+                        CallPathKey::Id(_) => CallPathKey::Id(child_id),
+                        _ => key.clone(),
+                    };
+                    self[id].children.insert(key, child_id);
+                    let mut child = other_child.clone();
+                    child.parent = Some(id);
+                    child.depth = self[id].depth + 1;
+                    child.children = HashMap::new();
+                    self.pool.push(child);
+                    child_id
+                }
+            };
+            self.merge_children(child_id, other, *other_child_id);
+        }
+    }
+
+    /// The closest call path of spans of `span_meta` among `idx` and its
+    /// ancestors, to collapse recursion.
+    fn recursive_call_path(
+        &self,
+        idx: CallPathPoolId,
+        span_meta: &'static tracing::Metadata<'static>,
+        retry: bool,
+    ) -> Option<CallPathPoolId> {
+        let mut idx = Some(idx);
+        while let Some(current) = idx {
+            let timing = &self[current];
+            let same_callsite =
+                matches!(timing.span_meta, Some(meta) if meta.callsite() == span_meta.callsite());
+            if same_callsite && !timing.overflow && !timing.event && timing.retry == retry {
+                return Some(current);
+            }
+            idx = timing.parent;
+        }
+        None
+    }
+}
+
+#[cfg(any(test, feature = "fuzz"))]
+impl CallPathPool {
+    /// Checks the structural invariants of a finished call tree and
+    /// describes the first violation.
+    pub(crate) fn check_invariants(&self, max_call_depth: usize) -> Result<(), String> {
+        let root = self.root();
+        if root.depth != 0 || root.call_count != 1 {
+            return Err(format!("unexpected root: {:?}", root));
+        }
+        let mut parents = vec![None; self.pool.len()];
+        for (idx, timing) in self.pool.iter().enumerate() {
+            if timing.depth > max_call_depth || timing.depth == max_call_depth && !timing.overflow {
+                return Err(format!(
+                    "call path {} has depth {} but the max call depth is {}",
+                    idx, timing.depth, max_call_depth
+                ));
+            }
+            if timing.call_count == 0 {
+                return Err(format!("call path {} has no closed spans", idx));
+            }
+            if timing.sum_own > timing.sum_with_children {
+                return Err(format!(
+                    "call path {} has more own busy time than busy time: {:?}",
+                    idx, timing
+                ));
+            }
+            for &CallPathPoolId(child) in timing.children.values() {
+                let child_timing = self
+                    .pool
+                    .get(child)
+                    .ok_or_else(|| format!("call path {} has a dangling child {}", idx, child))?;
+                if child <= idx {
+                    return Err(format!(
+                        "call path {} has child {} which was created before it",
+                        idx, child
+                    ));
+                }
+                if let Some(other_parent) = parents[child].replace(idx) {
+                    return Err(format!(
+                        "call path {} has two parents: {} and {}",
+                        child, other_parent, idx
+                    ));
+                }
+                if child_timing.parent != Some(CallPathPoolId(idx)) {
+                    return Err(format!(
+                        "call path {} has parent {:?} instead of {}",
+                        child, child_timing.parent, idx
+                    ));
+                }
+                if child_timing.depth != timing.depth + 1 {
+                    return Err(format!(
+                        "call path {} has depth {} but its parent {} has depth {}",
+                        child, child_timing.depth, idx, timing.depth
+                    ));
+                }
+            }
+        }
+        match parents.iter().skip(1).position(Option::is_none) {
+            Some(orphan) => Err(format!("call path {} has no parent", orphan + 1)),
+            None => Ok(()),
+        }
+    }
 }
 
 impl Index<CallPathPoolId> for CallPathPool {
@@ -49,21 +667,248 @@ impl IndexMut<CallPathPoolId> for CallPathPool {
 /// the same and all the `callsite::Identifier`s of their ancestor
 /// spans are also the same.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CallPathTiming {
+    parent: Option<CallPathPoolId>,
     depth: usize,
     call_count: usize,
-    span_meta: &'static Metadata<'static>,
-    children: HashMap<callsite::Identifier, CallPathPoolId>,
+    /// `None` for deserialized call trees.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    span_meta: Option<&'static Metadata<'static>>,
+    metadata: SpanMetadata,
+    name: Cow<'static, str>,
+    #[cfg_attr(feature = "serde", serde(with = "children"))]
+    children: HashMap<CallPathKey, CallPathPoolId>,
+    retry: bool,
     span_life_time: Duration,
     sum_with_children: Duration,
     sum_own: Duration,
+    sum_awaited: Duration,
+    sum_io_wait: Duration,
+    poll_count: usize,
+    sum_idle: Duration,
+    sum_cpu: Duration,
+    thread_busy: Vec<ThreadBusy>,
+    error_count: usize,
+    warn_count: usize,
+    notes: Vec<(String, usize)>,
+    follows_from: Vec<(String, usize)>,
+    field_values: Vec<FieldValues>,
+    cache_hit_count: usize,
+    cache_hit_busy: Duration,
+    cache_miss_count: usize,
+    cache_miss_busy: Duration,
+    first_entered: Option<Duration>,
+    event: bool,
+    overflow: bool,
+    min_busy: Option<Duration>,
+    max_busy: Duration,
+    busy_histogram: Option<BusyHistogram>,
+    recursion_depth: usize,
+    /// The calls whose busy time is in `sum_with_children`, i.e. without
+    /// recursive calls nested in calls of this call path and without
+    /// deeper calls of overflow call paths.
+    outer_call_count: usize,
 }
 
 impl CallPathTiming {
+    fn new(
+        parent: Option<CallPathPoolId>,
+        depth: usize,
+        span_meta: &'static Metadata<'static>,
+        name: Cow<'static, str>,
+        retry: bool,
+    ) -> CallPathTiming {
+        CallPathTiming {
+            parent,
+            depth,
+            call_count: 0,
+            span_meta: Some(span_meta),
+            metadata: SpanMetadata::from(span_meta),
+            name,
+            children: HashMap::new(),
+            retry,
+            span_life_time: Duration::default(),
+            sum_with_children: Duration::default(),
+            sum_own: Duration::default(),
+            sum_awaited: Duration::default(),
+            sum_io_wait: Duration::default(),
+            poll_count: 0,
+            sum_idle: Duration::default(),
+            sum_cpu: Duration::default(),
+            thread_busy: Vec::new(),
+            error_count: 0,
+            warn_count: 0,
+            notes: Vec::new(),
+            follows_from: Vec::new(),
+            field_values: Vec::new(),
+            cache_hit_count: 0,
+            cache_hit_busy: Duration::default(),
+            cache_miss_count: 0,
+            cache_miss_busy: Duration::default(),
+            first_entered: None,
+            event: false,
+            overflow: false,
+            min_busy: None,
+            max_busy: Duration::default(),
+            busy_histogram: None,
+            recursion_depth: 0,
+            outer_call_count: 0,
+        }
+    }
+
+    /// True if `other` is probably the same call path in another call
+    /// tree, for call paths without callsites.
+    pub(crate) fn looks_like(&self, other: &CallPathTiming) -> bool {
+        if self.overflow || other.overflow {
+            // Named after the first span beyond the maximum call depth.
+            return self.overflow && other.overflow;
+        }
+        let same_callsite = match (self.span_meta, other.span_meta) {
+            (Some(meta), Some(other_meta)) => meta.callsite() == other_meta.callsite(),
+            _ => true,
+        };
+        same_callsite
+            && self.name == other.name
+            && self.retry == other.retry
+            && self.event == other.event
+            && self.metadata == other.metadata
+    }
+
+    /// Adds the calls and durations of `other` to this call path.
+    fn add(&mut self, other: &CallPathTiming) {
+        self.call_count += other.call_count;
+        self.span_life_time += other.span_life_time;
+        self.sum_with_children += other.sum_with_children;
+        self.sum_own += other.sum_own;
+        self.sum_awaited += other.sum_awaited;
+        self.sum_io_wait += other.sum_io_wait;
+        self.poll_count += other.poll_count;
+        self.sum_idle += other.sum_idle;
+        self.sum_cpu += other.sum_cpu;
+        for thread_busy in &other.thread_busy {
+            self.add_thread_busy(thread_busy);
+        }
+        self.error_count += other.error_count;
+        self.warn_count += other.warn_count;
+        for (note, count) in &other.notes {
+            match self.notes.iter_mut().find(|(existing, _)| existing == note) {
+                Some((_, existing_count)) => *existing_count += count,
+                None => self.notes.push((note.clone(), *count)),
+            }
+        }
+        for (path, count) in &other.follows_from {
+            match self
+                .follows_from
+                .iter_mut()
+                .find(|(existing, _)| existing == path)
+            {
+                Some((_, existing_count)) => *existing_count += count,
+                None => self.follows_from.push((path.clone(), *count)),
+            }
+        }
+        for values in &other.field_values {
+            match self
+                .field_values
+                .iter_mut()
+                .find(|existing| existing.field == values.field)
+            {
+                Some(existing) => existing.merge(values),
+                None => self.field_values.push(values.clone()),
+            }
+        }
+        self.cache_hit_count += other.cache_hit_count;
+        self.cache_hit_busy += other.cache_hit_busy;
+        self.cache_miss_count += other.cache_miss_count;
+        self.cache_miss_busy += other.cache_miss_busy;
+        self.first_entered = match (self.first_entered, other.first_entered) {
+            (Some(first), Some(other_first)) => Some(core::cmp::min(first, other_first)),
+            (first, other_first) => first.or(other_first),
+        };
+        self.min_busy = match (self.min_busy, other.min_busy) {
+            (Some(min), Some(other_min)) => Some(core::cmp::min(min, other_min)),
+            (min, other_min) => min.or(other_min),
+        };
+        self.max_busy = core::cmp::max(self.max_busy, other.max_busy);
+        self.recursion_depth = core::cmp::max(self.recursion_depth, other.recursion_depth);
+        self.outer_call_count += other.outer_call_count;
+        match (&mut self.busy_histogram, &other.busy_histogram) {
+            (Some(histogram), Some(other_histogram)) => histogram.merge(other_histogram),
+            (histogram @ None, Some(other_histogram)) => *histogram = Some(other_histogram.clone()),
+            (_, None) => {}
+        }
+    }
+
     /// The metadata associated with the called instrumented span,
     /// includes e.g. the name of the function that is being executed.
+    ///
+    /// # Panics
+    ///
+    /// For deserialized call trees, which have no static metadata. Use
+    /// [try_static_span_meta](Self::try_static_span_meta) or
+    /// [span_metadata](Self::span_metadata) for them.
     pub fn static_span_meta(&self) -> &'static Metadata<'static> {
         self.span_meta
+            .expect("deserialized call trees have no static span metadata")
+    }
+
+    /// Like [static_span_meta](Self::static_span_meta) but `None` for
+    /// deserialized call trees.
+    pub fn try_static_span_meta(&self) -> Option<&'static Metadata<'static>> {
+        self.span_meta
+    }
+
+    /// An owned snapshot of the [static span metadata](Self::static_span_meta)
+    /// that is also available for deserialized call trees.
+    pub fn span_metadata(&self) -> &SpanMetadata {
+        &self.metadata
+    }
+
+    /// The callsite of the spans of this call path, `None` for deserialized
+    /// and overflow call paths.
+    #[cfg(any(
+        feature = "folded",
+        feature = "chrome_trace",
+        feature = "graph",
+        feature = "html",
+        feature = "otel"
+    ))]
+    pub(crate) fn callsite(&self) -> Option<tracing::callsite::Identifier> {
+        match self.span_meta {
+            Some(meta) if !self.overflow => Some(meta.callsite()),
+            _ => None,
+        }
+    }
+
+    /// The name to display or export for this call path.
+    ///
+    /// This is the span name unless a
+    /// [span_name_mapper](crate::CallTreeCollectorBuilder::span_name_mapper)
+    /// was configured.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The parent call path, `None` for the root.
+    pub fn parent(&self) -> Option<CallPathPoolId> {
+        self.parent
+    }
+
+    /// The names of all call paths from the root to this one joined by
+    /// `separator`, e.g. `request/nested/repeated`.
+    ///
+    /// `pool` must be the pool containing this call path. Retries have the
+    /// same path string as first attempts, see [is_retry](Self::is_retry).
+    pub fn path_string(&self, pool: &CallPathPool, separator: &str) -> String {
+        let mut names = vec![self.name()];
+        let mut parent = self.parent;
+        while let Some(parent_idx) = parent {
+            let parent_timing = &pool[parent_idx];
+            names.push(parent_timing.name());
+            parent = parent_timing.parent;
+        }
+        names.reverse();
+        names.join(separator)
     }
 
     /// The number of times a new span with this call path was created.
@@ -91,365 +936,2931 @@ impl CallPathTiming {
         self.sum_own
     }
 
-    /// An iterator over the IDs of all children.
-    pub fn children(&self) -> impl Iterator<Item = &CallPathPoolId> {
-        self.children.values()
+    /// The shortest busy time of a single span with this call path.
+    pub fn min_busy(&self) -> Duration {
+        self.min_busy.unwrap_or_default()
     }
-}
-
-/// The span specific information.
-///
-/// The sums are folded into the referenced [CallPathTiming] when
-/// the span is closed.
-#[derive(Debug, Clone)]
-struct SpanTimingInfo {
-    call_path_idx: CallPathPoolId,
-    /// The time at which the span was first created.
-    created_at: u64,
-    sum_with_children: Duration,
-    sum_own: Duration,
-    /// Per thread info. We always access SpanTimingInfo in a thread-safe way
-    /// but we still need to keep some info per-thread:
-    /// While not typical, the same span can be entered multiple times from multiple threads.
-    per_thread: HashMap<ThreadId, PerThreadInfo>,
-}
 
-#[derive(Debug, Clone, Default)]
-struct PerThreadInfo {
-    last_enter: u64,
-    last_enter_own: u64,
-}
+    /// The longest busy time of a single span with this call path.
+    pub fn max_busy(&self) -> Duration {
+        self.max_busy
+    }
 
-impl SpanTimingInfo {
-    fn for_call_path_idx(call_path_idx: CallPathPoolId, created_at: u64) -> SpanTimingInfo {
-        SpanTimingInfo {
-            call_path_idx,
-            created_at,
-            sum_with_children: Duration::default(),
-            sum_own: Duration::default(),
-            per_thread: HashMap::new(),
+    /// The mean busy time of the spans with this call path.
+    ///
+    /// Together with [min_busy](Self::min_busy) and
+    /// [max_busy](Self::max_busy), this tells whether one of many calls
+    /// was slow or all of them.
+    ///
+    /// Recursive calls which were [collapsed] into an enclosing call of
+    /// the same call path are part of its busy time, so they do not count
+    /// as separate calls here.
+    ///
+    /// [collapsed]: crate::CallTreeCollectorBuilder::collapse_recursion
+    pub fn mean_busy(&self) -> Duration {
+        match self.outer_call_count {
+            0 => Duration::default(),
+            calls => {
+                let nanos = self.sum_with_children.as_nanos() / calls as u128;
+                Duration::from_nanos(nanos as u64)
+            }
         }
     }
-}
 
-// Implementation idea:
-//
-// Each Span has a [SpanTimingInfo]. In parallel, we build
-// an aggregated hierarchy for every call path of [CallPathTiming].
-// We have a [CallPathPool] owning all [CallPathTiming]s at the root span.
-// Whenever a Span is closed, we fold its aggregation values in
-// the corresponding [CallPathTiming].
-//
-// This way, when entering/leaving a span, we only touch the
-// span specific data without fancy lookups. This is important
-// in async code where a span might be entered/left many times.
-impl<S, H> Layer<S> for crate::CallTreeCollector<H>
-where
-    S: Subscriber + for<'span> LookupSpan<'span> + fmt::Debug,
-    H: crate::FinishedCallTreeProcessor + 'static,
-{
-    fn on_new_span(&self, _attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
-        let span = ctx.span(id).expect("no span in new_span");
-        match span.parent() {
-            None => {
-                // root
-                let pool = vec![CallPathTiming {
-                    depth: 0,
-                    call_count: 0,
-                    span_meta: span.metadata(),
-                    children: HashMap::new(),
-                    span_life_time: Duration::default(),
-                    sum_with_children: Duration::default(),
-                    sum_own: Duration::default(),
-                }];
-                let mut extensions: ExtensionsMut = span.extensions_mut();
-                extensions.insert(CallPathPool { pool });
-                let created_at = self.clock.start();
-                extensions.insert(SpanTimingInfo::for_call_path_idx(
-                    CallPathPoolId(0),
-                    created_at,
-                ));
-            }
-            Some(parent) => {
-                let mut parent_extensions = parent.extensions_mut();
-                let parent_span_info = parent_extensions.get_mut::<SpanTimingInfo>();
-                if parent_span_info.is_none() {
-                    // We are beyond the maximum tracing depth.
-                    return;
-                }
+    /// The histogram of busy times of the spans with this call path if
+    /// [histograms are enabled].
+    ///
+    /// [histograms are enabled]: crate::CallTreeCollectorBuilder::with_histograms
+    pub fn busy_histogram(&self) -> Option<&BusyHistogram> {
+        self.busy_histogram.as_ref()
+    }
 
-                let parent_call_path_idx = parent_span_info
-                    .expect("parent has no SpanTimingInfo")
-                    .call_path_idx;
-                let root = span
-                    .scope()
-                    .from_root()
-                    .next()
-                    .expect("span has a parent but no root");
-                let mut root_extensions: ExtensionsMut = if root.id() == parent.id() {
-                    parent_extensions
-                } else {
-                    // Do not keep multiple extensions locked at the same time.
-                    std::mem::drop(parent_extensions);
-                    root.extensions_mut()
-                };
-                let pool: &mut CallPathPool = root_extensions.get_mut::<CallPathPool>().unwrap();
-                let new_idx = CallPathPoolId(pool.pool.len());
-                let parent_call_path_timing = &mut pool[parent_call_path_idx];
-                let new_depth = parent_call_path_timing.depth + 1;
-                if new_depth >= self.max_call_depth {
-                    return;
-                }
-                let idx = parent_call_path_timing
-                    .children
-                    .get(&span.metadata().callsite());
-                let call_path_idx = match idx {
-                    Some(idx) => *idx,
-                    None => {
-                        parent_call_path_timing
-                            .children
-                            .insert(span.metadata().callsite(), new_idx);
-                        pool.pool.push(CallPathTiming {
-                            depth: new_depth,
-                            call_count: 0,
-                            span_meta: span.metadata(),
-                            children: HashMap::new(),
-                            span_life_time: Duration::default(),
-                            sum_with_children: Duration::default(),
-                            sum_own: Duration::default(),
-                        });
-                        new_idx
-                    }
-                };
-                // Do not keep multiple extensions locked at the same time.
-                std::mem::drop(root_extensions);
-                let mut extensions: ExtensionsMut = span.extensions_mut();
-                let created_at = self.clock.start();
-                extensions.insert(SpanTimingInfo::for_call_path_idx(call_path_idx, created_at));
+    /// The median busy time if histograms are enabled.
+    pub fn busy_p50(&self) -> Option<Duration> {
+        self.busy_histogram.as_ref()?.percentile(50.0)
+    }
+
+    /// The 90th percentile of the busy time if histograms are enabled.
+    pub fn busy_p90(&self) -> Option<Duration> {
+        self.busy_histogram.as_ref()?.percentile(90.0)
+    }
+
+    /// The 99th percentile of the busy time if histograms are enabled.
+    pub fn busy_p99(&self) -> Option<Duration> {
+        self.busy_histogram.as_ref()?.percentile(99.0)
+    }
+
+    /// The share of the parent's idle time attributed to spans with this
+    /// call path.
+    ///
+    /// Whenever the parent span is alive but not entered, e.g. an async
+    /// function waiting for a child future, the idle time is split evenly
+    /// between all children that are alive at that time. This tells
+    /// which child the parent was actually waiting for.
+    pub fn sum_awaited(&self) -> Duration {
+        self.sum_awaited
+    }
+
+    /// The total I/O wait time reported for spans at this call path with
+    /// [report_io_wait](crate::report_io_wait).
+    pub fn sum_io_wait(&self) -> Duration {
+        self.sum_io_wait
+    }
+
+    /// The number of times spans with this call path were entered, e.g.
+    /// how often the instrumented futures were polled.
+    ///
+    /// Entering a span in multiple threads at once counts once per thread.
+    pub fn poll_count(&self) -> usize {
+        self.poll_count
+    }
+
+    /// The summed time between exiting spans with this call path and
+    /// entering them again, e.g. how long the instrumented futures waited
+    /// to be polled again after returning `Pending`.
+    ///
+    /// The time before the first enter and after the last exit is not
+    /// included. Compared to "alive minus busy", a high idle time with
+    /// few [polls](Self::poll_count) hints at slow dependencies and a
+    /// high idle time with many polls at a starved executor.
+    pub fn idle_time(&self) -> Duration {
+        self.sum_idle
+    }
+
+    /// The CPU time consumed by the executing threads while spans with
+    /// this call path were entered, including children, if
+    /// [enabled](crate::CallTreeCollectorBuilder::cpu_time).
+    ///
+    /// A busy time much larger than the CPU time hints at blocking I/O or
+    /// lock contention, a similar CPU time at computation.
+    pub fn sum_cpu(&self) -> Duration {
+        self.sum_cpu
+    }
+
+    /// The busy time per thread that executed spans with this call path,
+    /// in the order the threads first finished executing them, if
+    /// [enabled](crate::CallTreeCollectorBuilder::per_thread_busy).
+    ///
+    /// For work-stealing runtimes, this tells whether a call path bounces
+    /// between threads or is stuck on one of them. Only the first few
+    /// threads are kept.
+    pub fn per_thread_busy(&self) -> &[ThreadBusy] {
+        &self.thread_busy
+    }
+
+    fn add_thread_busy(&mut self, thread_busy: &ThreadBusy) {
+        if let Some(existing) = self
+            .thread_busy
+            .iter_mut()
+            .find(|existing| existing.thread_id == thread_busy.thread_id)
+        {
+            existing.busy += thread_busy.busy;
+            existing.polls += thread_busy.polls;
+        } else if self.thread_busy.len() < MAX_THREADS_PER_CALL_PATH {
+            self.thread_busy.push(thread_busy.clone());
+        }
+    }
+
+    /// The number of `ERROR` events emitted inside spans with this call
+    /// path.
+    ///
+    /// Events inside spans beyond the
+    /// [maximum call depth](crate::CallTreeCollectorBuilder::max_call_depth)
+    /// are counted for the deepest collected ancestor.
+    pub fn error_count(&self) -> usize {
+        self.error_count
+    }
+
+    /// The number of `WARN` events emitted inside spans with this call
+    /// path, see [error_count](Self::error_count).
+    pub fn warn_count(&self) -> usize {
+        self.warn_count
+    }
+
+    /// When a span at this call path was entered for the first time,
+    /// relative to the creation of the root span.
+    ///
+    /// `None` if no span at this call path was ever entered.
+    pub fn first_entered(&self) -> Option<Duration> {
+        self.first_entered
+    }
+
+    /// True if this call path counts events instead of spans, see
+    /// [CallTreeCollectorBuilder::collect_events].
+    ///
+    /// The name of an event call path is the message of its first event
+    /// and it only has a [call count](Self::call_count), no durations.
+    ///
+    /// [CallTreeCollectorBuilder::collect_events]: crate::CallTreeCollectorBuilder::collect_events
+    pub fn is_event(&self) -> bool {
+        self.event
+    }
+
+    /// True if this call path aggregates all spans beyond the
+    /// [maximum call depth](crate::CallTreeCollectorBuilder::max_call_depth)
+    /// below its parent, named `… (deeper calls)`.
+    ///
+    /// Its busy time includes all deeper spans and its call count counts
+    /// all of them, so that the busy times still add up. It has no
+    /// children.
+    pub fn is_overflow(&self) -> bool {
+        self.overflow
+    }
+
+    /// The deepest nesting of recursive calls merged into this call path,
+    /// e.g. `3` if the span called itself and that call called it again.
+    /// `0` if no recursive calls were merged, see
+    /// [CallTreeCollectorBuilder::collapse_recursion](crate::CallTreeCollectorBuilder::collapse_recursion).
+    pub fn recursion_depth(&self) -> usize {
+        self.recursion_depth
+    }
+
+    /// The distinct notes recorded with the `reqray.note` field on spans
+    /// with this call path together with how often they were recorded.
+    ///
+    /// ```
+    /// let span = tracing::info_span!("lookup", reqray.note = "cache miss");
+    /// ```
+    ///
+    /// Only the first few distinct notes are kept.
+    pub fn notes(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.notes
+            .iter()
+            .map(|(note, count)| (note.as_str(), *count))
+    }
+
+    /// The call paths which spans with this call path follow from, e.g.
+    /// `request/enqueue`, together with how often such a link was
+    /// recorded.
+    ///
+    /// ```
+    /// # let request = tracing::info_span!("request");
+    /// let span = tracing::info_span!("handle");
+    /// span.follows_from(&request);
+    /// ```
+    ///
+    /// The followed call paths may be in other call trees, in which case
+    /// their paths start with the name of the other root. Only the first
+    /// few distinct call paths are kept. Spans which start their own call
+    /// tree can be moved into the followed call tree instead, see
+    /// [CallTreeCollectorBuilder::adopt_follows_from](crate::CallTreeCollectorBuilder::adopt_follows_from).
+    pub fn follows_from(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.follows_from
+            .iter()
+            .map(|(path, count)| (path.as_str(), *count))
+    }
+
+    /// The values of the fields captured with
+    /// [CallTreeCollectorBuilder::capture_field] for spans with this call
+    /// path, in the order in which the fields were first recorded.
+    ///
+    /// [CallTreeCollectorBuilder::capture_field]: crate::CallTreeCollectorBuilder::capture_field
+    pub fn captured_fields(&self) -> impl Iterator<Item = &FieldValues> {
+        self.field_values.iter()
+    }
+
+    /// The values of the captured field with the given name, see
+    /// [captured_fields](Self::captured_fields).
+    pub fn field_values(&self, field: &str) -> Option<&FieldValues> {
+        self.field_values
+            .iter()
+            .find(|values| values.field == field)
+    }
+
+    fn add_field_value(&mut self, field: &'static str, value: String) {
+        match self
+            .field_values
+            .iter_mut()
+            .find(|values| values.field == field)
+        {
+            Some(values) => values.add(value),
+            None => {
+                let mut values = FieldValues {
+                    field: Cow::Borrowed(field),
+                    values: Vec::new(),
+                    other: 0,
+                    #[cfg(feature = "cardinality")]
+                    distinct: Default::default(),
+                };
+                values.add(value);
+                self.field_values.push(values);
+            }
+        }
+    }
+
+    fn add_follows_from(&mut self, path: String) {
+        if let Some((_, count)) = self.follows_from.iter_mut().find(|(p, _)| *p == path) {
+            *count += 1;
+        } else if self.follows_from.len() < MAX_NOTES_PER_CALL_PATH {
+            self.follows_from.push((path, 1));
+        }
+    }
+
+    fn add_note(&mut self, note: String) {
+        if let Some((_, count)) = self.notes.iter_mut().find(|(n, _)| *n == note) {
+            *count += 1;
+        } else if self.notes.len() < MAX_NOTES_PER_CALL_PATH {
+            self.notes.push((note, 1));
+        }
+    }
+
+    /// Whether this call path aggregates retries, i.e. spans with a
+    /// `reqray.attempt` field greater than `1`.
+    ///
+    /// ```
+    /// # let attempt = 2;
+    /// let span = tracing::info_span!("fetch", reqray.attempt = attempt);
+    /// ```
+    ///
+    /// First attempts and retries of the same call site are aggregated in
+    /// separate call paths.
+    pub fn is_retry(&self) -> bool {
+        self.retry
+    }
+
+    /// The number of spans with this call path that were marked as
+    /// cache hits with a `cache.hit = true` field.
+    ///
+    /// ```
+    /// # let hit = true;
+    /// let span = tracing::info_span!("query", cache.hit = tracing::field::Empty);
+    /// // ... look up the cache ...
+    /// span.record("cache.hit", &hit);
+    /// ```
+    pub fn cache_hit_count(&self) -> usize {
+        self.cache_hit_count
+    }
+
+    /// The busy time of spans counted in [cache_hit_count](Self::cache_hit_count).
+    pub fn cache_hit_busy(&self) -> Duration {
+        self.cache_hit_busy
+    }
+
+    /// The number of spans with this call path that were marked as
+    /// cache misses with a `cache.hit = false` field.
+    pub fn cache_miss_count(&self) -> usize {
+        self.cache_miss_count
+    }
+
+    /// The busy time of spans counted in [cache_miss_count](Self::cache_miss_count).
+    pub fn cache_miss_busy(&self) -> Duration {
+        self.cache_miss_busy
+    }
+
+    /// An iterator over the IDs of all children.
+    pub fn children(&self) -> impl Iterator<Item = &CallPathPoolId> {
+        self.children.values()
+    }
+}
+
+/// The distinct values of a captured span field at a call path, see
+/// [CallTreeCollectorBuilder::capture_field].
+///
+/// Only the first few distinct values are kept, further values are
+/// only counted.
+///
+/// [CallTreeCollectorBuilder::capture_field]: crate::CallTreeCollectorBuilder::capture_field
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FieldValues {
+    field: Cow<'static, str>,
+    values: Vec<(String, usize)>,
+    other: usize,
+    #[cfg(feature = "cardinality")]
+    distinct: crate::cardinality::DistinctValues,
+}
+
+impl FieldValues {
+    /// The name of the field.
+    pub fn field(&self) -> &str {
+        &self.field
+    }
+
+    /// The first recorded value.
+    pub fn first(&self) -> Option<&str> {
+        self.values.first().map(|(value, _)| value.as_str())
+    }
+
+    /// The kept distinct values together with how often they were
+    /// recorded.
+    pub fn values(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.values
+            .iter()
+            .map(|(value, count)| (value.as_str(), *count))
+    }
+
+    /// The number of recorded values that were not kept because there
+    /// were too many distinct values.
+    pub fn other_count(&self) -> usize {
+        self.other
+    }
+
+    /// The number of distinct recorded values.
+    ///
+    /// This is exact as long as all distinct values were kept, and an
+    /// approximation with a standard error of about 6.5% otherwise. A
+    /// high count at a frequently called path, e.g. of a `table` field,
+    /// hints at N+1 patterns across different entities.
+    #[cfg(feature = "cardinality")]
+    pub fn distinct_count(&self) -> usize {
+        if self.other == 0 {
+            self.values.len()
+        } else {
+            // The estimate might be below the exactly known minimum.
+            core::cmp::max(self.values.len(), self.distinct.estimate())
+        }
+    }
+
+    /// Adds the values recorded in `other` for the same field.
+    fn merge(&mut self, other: &FieldValues) {
+        for (value, count) in &other.values {
+            if let Some((_, existing)) = self.values.iter_mut().find(|(v, _)| v == value) {
+                *existing += count;
+            } else if self.values.len() < MAX_FIELD_VALUES_PER_CALL_PATH {
+                self.values.push((value.clone(), *count));
+            } else {
+                self.other += count;
             }
+        }
+        self.other += other.other;
+        #[cfg(feature = "cardinality")]
+        self.distinct.merge(&other.distinct);
+    }
+
+    fn add(&mut self, value: String) {
+        #[cfg(feature = "cardinality")]
+        self.distinct.add(&value);
+        if let Some((_, count)) = self.values.iter_mut().find(|(v, _)| *v == value) {
+            *count += 1;
+        } else if self.values.len() < MAX_FIELD_VALUES_PER_CALL_PATH {
+            self.values.push((value, 1));
+        } else {
+            self.other += 1;
+        }
+    }
+}
+
+/// The busy time contributed by a single thread to a call path, see
+/// [CallPathTiming::per_thread_busy].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ThreadBusy {
+    /// The debug representation of the [ThreadId], unique per process.
+    thread_id: String,
+    thread_name: Option<String>,
+    busy: Duration,
+    polls: usize,
+}
+
+impl ThreadBusy {
+    fn current() -> ThreadBusy {
+        let thread = std::thread::current();
+        ThreadBusy {
+            thread_id: format!("{:?}", thread.id()),
+            thread_name: thread.name().map(str::to_string),
+            busy: Duration::default(),
+            polls: 0,
+        }
+    }
+
+    /// The id of the thread, like `ThreadId(3)`.
+    pub fn thread_id(&self) -> &str {
+        &self.thread_id
+    }
+
+    /// The name of the thread, if it has one.
+    pub fn thread_name(&self) -> Option<&str> {
+        self.thread_name.as_deref()
+    }
+
+    /// The summed busy time including children.
+    pub fn busy(&self) -> Duration {
+        self.busy
+    }
+
+    /// How often the spans were entered in the thread.
+    pub fn polls(&self) -> usize {
+        self.polls
+    }
+}
+
+/// Identifies a child call path of a [CallPathTiming].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum CallPathKey {
+    Callsite {
+        callsite: callsite::Identifier,
+        retry: bool,
+    },
+    /// All spans beyond the maximum call depth, see
+    /// [CallPathTiming::is_overflow].
+    Overflow,
+    /// Deserialized call paths have no callsite, no new children are
+    /// added to them.
+    #[cfg_attr(not(feature = "serde"), allow(dead_code))]
+    Id(CallPathPoolId),
+}
+
+/// Serializes the children of a [CallPathTiming] as a list of ids.
+#[cfg(feature = "serde")]
+mod children {
+    use std::collections::HashMap;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::{CallPathKey, CallPathPoolId};
+
+    pub fn serialize<S: Serializer>(
+        children: &HashMap<CallPathKey, CallPathPoolId>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut ids = children.values().copied().collect::<Vec<_>>();
+        ids.sort();
+        ids.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<CallPathKey, CallPathPoolId>, D::Error> {
+        let ids = Vec::<CallPathPoolId>::deserialize(deserializer)?;
+        Ok(ids
+            .into_iter()
+            .map(|id| (CallPathKey::Id(id), id))
+            .collect())
+    }
+}
+
+/// The call trees whose root spans were not closed yet by their
+/// [serial](TreeRef::serial), so that the collector can hand them over
+/// when it is dropped, see
+/// [CallTreeCollectorBuilder::hand_over_abandoned](crate::CallTreeCollectorBuilder::hand_over_abandoned).
+pub(crate) type PendingTrees = handover::PendingTrees<CallPathPool>;
+
+/// The call tree collected so far, stored in the extensions of its root
+/// span.
+pub(crate) struct PendingCallTree {
+    tree: PendingTree,
+    /// Distinguishes call trees whose root spans had the same id, see
+    /// [TreeRef::serial].
+    serial: u64,
+}
+
+/// Where a [PendingCallTree] keeps its call tree.
+enum PendingTree {
+    /// Only in the span extensions. Only `None` after the call tree was
+    /// finished.
+    Owned(Option<CallPathPool>),
+    /// Shared with the [PendingTrees] of the collector. `None` after the
+    /// call tree was finished or handed over on drop.
+    Shared(Slot<CallPathPool>),
+}
+
+/// Access to a [PendingCallTree] which was not finished or handed over
+/// yet.
+pub(crate) enum PendingGuard<'a> {
+    Owned(&'a mut Option<CallPathPool>),
+    Shared(MutexGuard<'a, Option<CallPathPool>>),
+}
+
+/// The serial number of the next call tree.
+static NEXT_TREE_SERIAL: AtomicU64 = AtomicU64::new(0);
+
+impl PendingCallTree {
+    /// A new call tree with the root `pool`, shared with `pending_trees`
+    /// if the collector hands over abandoned call trees.
+    fn new(pool: CallPathPool, serial: u64, pending_trees: Option<&PendingTrees>) -> Self {
+        let tree = match pending_trees {
+            Some(pending_trees) => PendingTree::Shared(pending_trees.register(serial, pool)),
+            None => PendingTree::Owned(Some(pool)),
         };
+        PendingCallTree { tree, serial }
     }
 
-    fn on_enter(&self, _id: &tracing::Id, ctx: Context<S>) {
-        let leave_parent = self.clock.end();
-        let span = ctx.lookup_current().expect("no span in new_span");
-        if span.extensions().get::<SpanTimingInfo>().is_none() {
-            // yes, this is an extra check but:
-            // * it has to occur before we check for the parent
-            // * taking the "start" clock value below should be one of the last
-            //   operations
-            return;
+    /// The call tree, `None` if it was handed over already.
+    fn lock(&mut self) -> Option<PendingGuard<'_>> {
+        let guard = match &mut self.tree {
+            PendingTree::Owned(tree) => PendingGuard::Owned(tree),
+            PendingTree::Shared(tree) => PendingGuard::Shared(lock(tree)),
+        };
+        if guard.slot().is_some() {
+            Some(guard)
+        } else {
+            None
+        }
+    }
+
+    /// A copy of the call tree collected so far, `None` if it was handed
+    /// over already.
+    pub(crate) fn snapshot(&self) -> Option<CallPathPool> {
+        match &self.tree {
+            PendingTree::Owned(tree) => tree.clone(),
+            PendingTree::Shared(tree) => lock(tree).clone(),
         }
+    }
 
-        if let Some(parent) = span.parent() {
-            let mut extensions = parent.extensions_mut();
-            if let Some(timing_info) = extensions.get_mut::<SpanTimingInfo>() {
-                if let Some(thread_info) = timing_info.per_thread.get(&std::thread::current().id()) {
-                    let last_enter_own = thread_info.last_enter_own;
-                    let delta = self.clock.delta(last_enter_own, leave_parent);
-                    timing_info.sum_own += delta;
+    /// The call tree `tree` in the extensions of its root span, `None` if
+    /// it was already finished.
+    fn of<'e>(
+        root_extensions: &'e mut ExtensionsMut<'_>,
+        tree: &TreeRef,
+    ) -> Option<PendingGuard<'e>> {
+        root_extensions
+            .get_mut::<PendingCallTree>()
+            .filter(|pending| pending.serial == tree.serial)?
+            .lock()
+    }
+}
+
+impl PendingGuard<'_> {
+    fn slot(&self) -> &Option<CallPathPool> {
+        match self {
+            PendingGuard::Owned(tree) => tree,
+            PendingGuard::Shared(tree) => tree,
+        }
+    }
+
+    fn slot_mut(&mut self) -> &mut Option<CallPathPool> {
+        match self {
+            PendingGuard::Owned(tree) => tree,
+            PendingGuard::Shared(tree) => tree,
+        }
+    }
+
+    /// Takes the finished call tree.
+    fn finish(mut self) -> CallPathPool {
+        self.slot_mut().take().expect("call tree already taken")
+    }
+}
+
+/// Refers to a call tree, see [SpanTimingInfo::tree].
+#[derive(Debug, Clone, PartialEq)]
+struct TreeRef {
+    /// The root span, which holds the [PendingCallTree].
+    ///
+    /// This is not necessarily the outermost span or even an ancestor, see
+    /// [CallTreeCollectorBuilder::root_predicate](crate::CallTreeCollectorBuilder::root_predicate)
+    /// and [CallTreeCollectorBuilder::adopt_follows_from](crate::CallTreeCollectorBuilder::adopt_follows_from).
+    root: Id,
+    /// Span ids are reused after spans are closed. Adopted spans can
+    /// outlive the root span of their call tree, so the id alone does not
+    /// identify the call tree.
+    serial: u64,
+}
+
+impl std::ops::Deref for PendingGuard<'_> {
+    type Target = CallPathPool;
+
+    fn deref(&self) -> &CallPathPool {
+        self.slot().as_ref().expect("call tree already taken")
+    }
+}
+
+impl std::ops::DerefMut for PendingGuard<'_> {
+    fn deref_mut(&mut self) -> &mut CallPathPool {
+        self.slot_mut().as_mut().expect("call tree already taken")
+    }
+}
+
+/// The span specific information.
+///
+/// The sums are folded into the referenced [CallPathTiming] when
+/// the span is closed.
+#[derive(Debug, Clone)]
+struct SpanTimingInfo {
+    call_path_idx: CallPathPoolId,
+    /// The call tree of the span.
+    tree: TreeRef,
+    /// The time at which the span was first created.
+    created_at: u64,
+    sum_with_children: Duration,
+    sum_own: Duration,
+    /// Per thread info. We always access SpanTimingInfo in a thread-safe way
+    /// but we still need to keep some info per-thread:
+    /// While not typical, the same span can be entered multiple times from multiple threads.
+    per_thread: HashMap<ThreadId, PerThreadInfo>,
+    /// Since when the span has been alive without being entered in any
+    /// thread, if it is idle.
+    idle_since: Option<u64>,
+    /// The children that are currently alive and the idle time attributed
+    /// to them so far.
+    awaited_children: Vec<AwaitedChild>,
+    /// The value of the `cache.hit` field.
+    cache_hit: Option<bool>,
+    /// The time at which the span was first entered.
+    first_entered_at: Option<u64>,
+    /// The I/O wait time reported for the span.
+    io_wait: Duration,
+    /// The number of times the span was entered.
+    poll_count: usize,
+    /// The time between exiting the span in all threads and entering it
+    /// again.
+    idle: Duration,
+    /// When the span was last exited in all threads, if it was entered
+    /// before.
+    last_exit: Option<u64>,
+    /// The busy time per thread, only if enabled.
+    thread_busy: Vec<(ThreadId, ThreadBusy)>,
+    /// The CPU time of the threads while the span was entered.
+    cpu: Duration,
+    /// The number of `ERROR` events inside the span.
+    error_count: usize,
+    /// The number of `WARN` events inside the span.
+    warn_count: usize,
+    /// Set if the span is nested in another span of its call path because
+    /// of collapsed recursion. Only its own values are added to the call
+    /// path, the busy time of the outer span already includes it.
+    nested: bool,
+}
+
+#[derive(Debug, Clone)]
+struct AwaitedChild {
+    id: Id,
+    awaited: Duration,
+}
+
+#[derive(Debug, Clone, Default)]
+struct PerThreadInfo {
+    last_enter: u64,
+    last_enter_own: u64,
+    /// The CPU time of the thread when the span was entered, if recorded.
+    last_enter_cpu: Option<Duration>,
+    /// The number of children currently entered on this thread. Own time
+    /// only accumulates while this is zero.
+    entered_children: usize,
+}
+
+impl SpanTimingInfo {
+    fn for_call_path_idx(
+        call_path_idx: CallPathPoolId,
+        tree: TreeRef,
+        created_at: u64,
+    ) -> SpanTimingInfo {
+        SpanTimingInfo {
+            call_path_idx,
+            tree,
+            created_at,
+            sum_with_children: Duration::default(),
+            sum_own: Duration::default(),
+            per_thread: HashMap::new(),
+            idle_since: Some(created_at),
+            awaited_children: Vec::new(),
+            cache_hit: None,
+            first_entered_at: None,
+            io_wait: Duration::default(),
+            poll_count: 0,
+            idle: Duration::default(),
+            last_exit: None,
+            thread_busy: Vec::new(),
+            cpu: Duration::default(),
+            error_count: 0,
+            warn_count: 0,
+            nested: false,
+        }
+    }
+
+    /// Splits the idle time up to `now` between all alive children.
+    fn settle_awaited(&mut self, clock: &dyn ReqrayClock, now: u64) {
+        if let Some(idle_since) = self.idle_since {
+            if !self.awaited_children.is_empty() {
+                let share = clock.delta(idle_since, now) / self.awaited_children.len() as u32;
+                for child in &mut self.awaited_children {
+                    child.awaited += share;
                 }
             }
+            self.idle_since = Some(now);
         }
+    }
 
-        let mut extensions = span.extensions_mut();
-        if let Some(timing_info) = extensions.get_mut::<SpanTimingInfo>() {
-            let mut per_thread = timing_info
-                .per_thread
-                .entry(std::thread::current().id())
-                .or_default();
-            let start = self.clock.start();
-            per_thread.last_enter = start;
-            per_thread.last_enter_own = start;
+    fn start_awaiting(&mut self, clock: &dyn ReqrayClock, now: u64, child: &Id) {
+        self.settle_awaited(clock, now);
+        self.awaited_children.push(AwaitedChild {
+            id: child.clone(),
+            awaited: Duration::default(),
+        });
+    }
+
+    /// Returns the idle time attributed to the closed `child`.
+    fn stop_awaiting(&mut self, clock: &dyn ReqrayClock, now: u64, child: &Id) -> Duration {
+        self.settle_awaited(clock, now);
+        match self.awaited_children.iter().position(|c| &c.id == child) {
+            Some(idx) => self.awaited_children.swap_remove(idx).awaited,
+            None => Duration::default(),
         }
     }
+}
+
+/// The span field used to annotate call paths, see [CallPathTiming::notes].
+pub(crate) const NOTE_FIELD: &str = "reqray.note";
+
+/// The span field used to separate retries from first attempts, see
+/// [CallPathTiming::is_retry].
+pub(crate) const ATTEMPT_FIELD: &str = "reqray.attempt";
+
+/// The span field used to mark cache hits and misses, see
+/// [CallPathTiming::cache_hit_count].
+pub(crate) const CACHE_HIT_FIELD: &str = "cache.hit";
+
+/// The span field used to mark spans as roots of call trees, see
+/// [CallTreeCollectorBuilder::root_predicate](crate::CallTreeCollectorBuilder::root_predicate).
+pub(crate) const ROOT_FIELD: &str = "reqray.root";
+
+/// The maximum number of distinct notes and followed call paths kept per
+/// call path.
+const MAX_NOTES_PER_CALL_PATH: usize = 8;
+
+/// The name of the call paths aggregating all spans beyond the maximum
+/// call depth, see [CallPathTiming::is_overflow].
+pub(crate) const OVERFLOW_NAME: &str = "… (deeper calls)";
+
+/// Marks spans below a span at an [overflow](CallPathTiming::is_overflow)
+/// call path, which are only counted for it.
+struct DeeperCall {
+    overflow_idx: CallPathPoolId,
+    tree: TreeRef,
+}
+
+/// Marks spans which are inlined into their parents, see
+/// [CallTreeCollectorBuilder::exclude_spans](crate::CallTreeCollectorBuilder::exclude_spans).
+struct ExcludedSpan;
+
+/// `span` or its closest ancestor which is not excluded.
+fn skip_excluded<'a, S>(span: SpanRef<'a, S>) -> Option<SpanRef<'a, S>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    span.scope()
+        .find(|span| span.extensions().get::<ExcludedSpan>().is_none())
+}
+
+/// The closest ancestor of `span` which is not excluded, its parent in the
+/// call tree.
+fn collected_parent<'a, S>(span: &SpanRef<'a, S>) -> Option<SpanRef<'a, S>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    span.parent().and_then(skip_excluded)
+}
+
+/// The call trees and call paths of `span` and its ancestors, see
+/// [CallTreeCollectorBuilder::collapse_recursion](crate::CallTreeCollectorBuilder::collapse_recursion).
+fn ancestor_call_paths<S>(span: &SpanRef<'_, S>) -> Vec<(u64, CallPathPoolId)>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    span.scope()
+        .filter_map(|span| {
+            span.extensions()
+                .get::<SpanTimingInfo>()
+                .map(|info| (info.tree.serial, info.call_path_idx))
+        })
+        .collect()
+}
+
+/// The maximum number of threads kept per call path.
+const MAX_THREADS_PER_CALL_PATH: usize = 16;
+
+/// The maximum number of distinct values kept per captured field and call
+/// path.
+const MAX_FIELD_VALUES_PER_CALL_PATH: usize = 8;
+
+/// The values of span fields that have a special meaning for reqray.
+#[derive(Debug, Default)]
+struct SpanFields {
+    /// The value of the field configured with
+    /// [name_field](crate::CallTreeCollectorBuilder::name_field).
+    name: Option<String>,
+    /// The value of the [NOTE_FIELD].
+    note: Option<String>,
+    /// The value of the [ATTEMPT_FIELD].
+    attempt: Option<u64>,
+    /// The value of the [CACHE_HIT_FIELD].
+    cache_hit: Option<bool>,
+    /// The value of the [ROOT_FIELD].
+    root: bool,
+    /// The values of fields configured with
+    /// [capture_field](crate::CallTreeCollectorBuilder::capture_field).
+    captured: Vec<(&'static str, String)>,
+    /// The values of fields configured with
+    /// [context_field](crate::CallTreeCollectorBuilder::context_field),
+    /// only used for root spans.
+    context: Vec<(&'static str, String)>,
+}
+
+impl SpanFields {
+    /// Whether any fields need to be applied to the [CallPathTiming].
+    fn has_call_path_fields(&self) -> bool {
+        self.name.is_some() || self.note.is_some() || !self.captured.is_empty()
+    }
+}
+
+/// Extracts [SpanFields] from span attributes or records.
+struct SpanFieldsVisitor<'a> {
+    name_field: Option<&'static str>,
+    captured_fields: &'a [&'static str],
+    context_fields: &'a [&'static str],
+    fields: SpanFields,
+}
+
+impl<'a> SpanFieldsVisitor<'a> {
+    fn record_value(&mut self, field: &Field, value: String) {
+        if self.captured_fields.contains(&field.name()) {
+            self.fields.captured.push((field.name(), value.clone()));
+        }
+        if self.context_fields.contains(&field.name()) {
+            self.fields.context.push((field.name(), value.clone()));
+        }
+        if Some(field.name()) == self.name_field {
+            self.fields.name = Some(value);
+        } else if field.name() == NOTE_FIELD {
+            self.fields.note = Some(value);
+        }
+    }
+}
+
+impl<'a> Visit for SpanFieldsVisitor<'a> {
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if field.name() == CACHE_HIT_FIELD {
+            self.fields.cache_hit = Some(value);
+        } else if field.name() == ROOT_FIELD {
+            self.fields.root = value;
+        }
+        self.record_value(field, value.to_string());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        if field.name() == ATTEMPT_FIELD {
+            self.fields.attempt = Some(value.max(0) as u64);
+        }
+        self.record_value(field, value.to_string());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name() == ATTEMPT_FIELD {
+            self.fields.attempt = Some(value);
+        }
+        self.record_value(field, value.to_string());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == ATTEMPT_FIELD {
+            self.fields.attempt = value.parse().ok();
+        }
+        self.record_value(field, value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let value = format!("{:?}", value);
+        if field.name() == ATTEMPT_FIELD {
+            self.fields.attempt = value.parse().ok();
+        }
+        self.record_value(field, value);
+    }
+}
+
+/// Extracts the message of an event.
+#[derive(Default)]
+struct EventMessageVisitor {
+    message: Option<String>,
+}
+
+impl Visit for EventMessageVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message = Some(format!("{:?}", value));
+        }
+    }
+}
+
+/// Hands over the call trees whose root spans were not closed, e.g.
+/// because they were leaked, see
+/// [CollectorStats::abandoned_trees](crate::CollectorStats::abandoned_trees).
+///
+/// Call paths of spans that were not closed either show up without calls
+/// or durations.
+impl<H: crate::FinishedCallTreeProcessor + 'static> Drop for crate::CallTreeCollector<H> {
+    fn drop(&mut self) {
+        let abandoned = match &self.pending_trees {
+            Some(pending) => pending.take_abandoned(),
+            None => return,
+        };
+        for tree in abandoned {
+            self.stats.add_abandoned_tree();
+            self.processor.process_finished_call(tree);
+        }
+    }
+}
+
+impl<H: crate::FinishedCallTreeProcessor + 'static> crate::CallTreeCollector<H> {
+    /// Stops tracking the call tree `serial` for the handover on drop.
+    fn forget_pending(&self, serial: u64) {
+        if let Some(pending) = &self.pending_trees {
+            pending.forget(serial);
+        }
+    }
+
+    /// True if no further spans should be collected for `pool`.
+    fn exceeds_budget(&self, pool: &CallPathPool) -> bool {
+        matches!(self.settings.max_spans_per_tree(), Some(max) if pool.spans >= max)
+            || matches!(self.max_layer_time_per_tree, Some(max) if pool.layer_time >= max)
+    }
+
+    /// Extracts the [SpanFields] of a span, `record` is called only if
+    /// the span has any of the fields.
+    fn span_fields(
+        &self,
+        span_meta: &Metadata<'_>,
+        record: impl FnOnce(&mut SpanFieldsVisitor),
+    ) -> SpanFields {
+        let has_fields = span_meta.fields().iter().any(|field| match field.name() {
+            NOTE_FIELD | ATTEMPT_FIELD | CACHE_HIT_FIELD | ROOT_FIELD => true,
+            name => {
+                Some(name) == self.name_field
+                    || self.captured_fields.contains(&name)
+                    || self.context_fields.contains(&name)
+            }
+        });
+        if !has_fields {
+            return SpanFields::default();
+        }
+        let mut visitor = SpanFieldsVisitor {
+            name_field: self.name_field,
+            captured_fields: &self.captured_fields,
+            context_fields: &self.context_fields,
+            fields: SpanFields::default(),
+        };
+        record(&mut visitor);
+        visitor.fields
+    }
+
+    /// Moves the call tree of `span`, which must not contain other spans
+    /// yet, into the call tree `followed` as a child of the call path
+    /// `followed_idx`.
+    ///
+    /// Returns false if `span` was left alone, e.g. because the followed
+    /// call tree is already degraded or too deep.
+    fn adopt<S>(
+        &self,
+        ctx: &Context<'_, S>,
+        span: &SpanRef<'_, S>,
+        followed_idx: CallPathPoolId,
+        followed: &TreeRef,
+    ) -> bool
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let mut extensions = span.extensions_mut();
+        let mut own_tree = match extensions.remove::<PendingCallTree>() {
+            Some(own_tree) => own_tree,
+            None => return false,
+        };
+        // Only call trees of a single span are adopted.
+        let single_span = matches!(own_tree.lock(), Some(tree) if tree.spans == 1);
+        if !single_span {
+            extensions.insert(own_tree);
+            return false;
+        }
+        // Do not keep multiple extensions locked at the same time.
+        std::mem::drop(extensions);
+
+        let adopted = tree_root(ctx, followed).and_then(|root| {
+            let mut root_extensions = root.extensions_mut();
+            let mut pool = PendingCallTree::of(&mut root_extensions, followed)?;
+            let depth = pool[followed_idx].depth + 1;
+            if pool.degraded || self.exceeds_budget(&pool) || depth >= pool.max_call_depth {
+                return None;
+            }
+            let mut timing = own_tree.lock()?.root().clone();
+            timing.parent = Some(followed_idx);
+            timing.depth = depth;
+            let key = CallPathKey::Callsite {
+                callsite: span.metadata().callsite(),
+                retry: timing.retry,
+            };
+            let new_idx = CallPathPoolId(pool.pool.len());
+            let idx = *pool[followed_idx].children.entry(key).or_insert(new_idx);
+            if idx == new_idx {
+                pool.pool.push(timing);
+            } else {
+                // Keeps the notes and captured fields.
+                pool[idx].add(&timing);
+            }
+            pool.spans += 1;
+            Some(idx)
+        });
+
+        let mut extensions = span.extensions_mut();
+        let idx = match adopted {
+            Some(idx) => idx,
+            None => {
+                extensions.insert(own_tree);
+                return false;
+            }
+        };
+        self.forget_pending(own_tree.serial);
+        #[cfg(feature = "dhat")]
+        extensions.remove::<HeapStatsAtStart>();
+        if let Some(in_flight) = extensions.remove::<Arc<InFlightEntry>>() {
+            self.stats.deregister(&in_flight);
+        }
+        if let Some(timing_info) = extensions.get_mut::<SpanTimingInfo>() {
+            timing_info.call_path_idx = idx;
+            timing_info.tree = followed.clone();
+        }
+        true
+    }
+
+    /// Applies the [SpanFields] to the call path of a span.
+    fn apply_span_fields(&self, call_path_timing: &mut CallPathTiming, fields: SpanFields) {
+        if let Some(name) = fields.name {
+            call_path_timing.name = self.recorded_span_name(name);
+        }
+        if let Some(note) = fields.note {
+            call_path_timing.add_note(note);
+        }
+        for (field, value) in fields.captured {
+            call_path_timing.add_field_value(field, value);
+        }
+    }
+}
+
+/// Attributes I/O wait time to `span`, see [report_io_wait](crate::report_io_wait).
+pub(crate) fn report_io_wait_for(span: &tracing::Span, wait: Duration) {
+    span.with_subscriber(|(id, dispatch)| {
+        let registry = match dispatch.downcast_ref::<Registry>() {
+            Some(registry) => registry,
+            None => return,
+        };
+        if let Some(span) = registry.span(id) {
+            if let Some(timing_info) = span.extensions_mut().get_mut::<SpanTimingInfo>() {
+                timing_info.io_wait += wait;
+            }
+        }
+    });
+}
+
+/// Counts `span` for the [overflow](CallPathTiming::is_overflow) call
+/// path of an ancestor and marks it, so that its children are counted as
+/// well.
+fn count_deeper_call<S>(
+    ctx: &Context<'_, S>,
+    span: &SpanRef<'_, S>,
+    overflow_idx: CallPathPoolId,
+    tree: TreeRef,
+) where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let root = match tree_root(ctx, &tree) {
+        Some(root) => root,
+        None => return,
+    };
+    span.extensions_mut().insert(DeeperCall {
+        overflow_idx,
+        tree: tree.clone(),
+    });
+    let mut root_extensions = root.extensions_mut();
+    let pool = PendingCallTree::of(&mut root_extensions, &tree);
+    if let Some(mut pool) = pool {
+        pool[overflow_idx].call_count += 1;
+    }
+}
+
+/// The root span of `tree`, `None` if it was already closed.
+fn tree_root<'a, S>(ctx: &'a Context<'_, S>, tree: &TreeRef) -> Option<SpanRef<'a, S>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    ctx.span(&tree.root)
+}
+
+// Implementation idea:
+//
+// Each Span has a [SpanTimingInfo]. In parallel, we build
+// an aggregated hierarchy for every call path of [CallPathTiming].
+// We have a [CallPathPool] owning all [CallPathTiming]s at the root span.
+// Whenever a Span is closed, we fold its aggregation values in
+// the corresponding [CallPathTiming].
+//
+// This way, when entering/leaving a span, we only touch the
+// span specific data without fancy lookups. This is important
+// in async code where a span might be entered/left many times.
+impl<S, H> Layer<S> for crate::CallTreeCollector<H>
+where
+    S: Subscriber + for<'span> LookupSpan<'span> + fmt::Debug,
+    H: crate::FinishedCallTreeProcessor + 'static,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("no span in new_span");
+        let mut span_fields = self.span_fields(attrs.metadata(), |visitor| attrs.record(visitor));
+        let retry = matches!(span_fields.attempt, Some(attempt) if attempt > 1);
+        let cache_hit = span_fields.cache_hit;
+        let is_root = span_fields.root
+            || match &self.root_predicate {
+                Some(root_predicate) => root_predicate(span.metadata()),
+                None => span.parent().is_none(),
+            };
+        match collected_parent(&span) {
+            _ if is_root => {
+                if !self.sample_root(span.metadata()) {
+                    return;
+                }
+                self.warn_about_unreliable_clock();
+                let context = std::mem::take(&mut span_fields.context)
+                    .into_iter()
+                    .map(|(field, value)| (Cow::Borrowed(field), value))
+                    .collect();
+                let mut root_timing = CallPathTiming::new(
+                    None,
+                    0,
+                    span.metadata(),
+                    self.span_name(span.metadata()),
+                    retry,
+                );
+                self.apply_span_fields(&mut root_timing, span_fields);
+                let name = root_timing.name.clone();
+                let pool = vec![root_timing];
+                let created_at = self.clock.start();
+                let mut extensions: ExtensionsMut = span.extensions_mut();
+                if let Some(in_flight) = self.stats.register(id.clone(), name, created_at) {
+                    extensions.insert(in_flight);
+                }
+                let serial = NEXT_TREE_SERIAL.fetch_add(1, Ordering::Relaxed);
+                let pool = CallPathPool {
+                    pool,
+                    spans: 1,
+                    layer_time: Duration::default(),
+                    degraded: false,
+                    skipped_spans: 0,
+                    unmatched_exits: 0,
+                    context,
+                    created_at,
+                    heap: None,
+                    max_call_depth: self.settings.max_call_depth(),
+                };
+                extensions.insert(PendingCallTree::new(
+                    pool,
+                    serial,
+                    self.pending_trees.as_ref(),
+                ));
+                let tree = TreeRef {
+                    root: id.clone(),
+                    serial,
+                };
+                let mut timing_info =
+                    SpanTimingInfo::for_call_path_idx(CallPathPoolId(0), tree, created_at);
+                timing_info.cache_hit = cache_hit;
+                extensions.insert(timing_info);
+                #[cfg(feature = "dhat")]
+                if self.sample_heap_stats() {
+                    extensions.insert(HeapStatsAtStart(dhat::HeapStats::get()));
+                }
+            }
+            // Neither a root nor inside a call tree.
+            None => {}
+            Some(_) if self.is_excluded(span.metadata()) => {
+                span.extensions_mut().insert(ExcludedSpan);
+            }
+            Some(parent) => {
+                let callback_start = self.max_layer_time_per_tree.map(|_| self.clock.start());
+                let ancestor_paths = if self.collapse_recursion {
+                    ancestor_call_paths(&parent)
+                } else {
+                    Vec::new()
+                };
+                let mut parent_extensions = parent.extensions_mut();
+                let parent_span_info = parent_extensions.get_mut::<SpanTimingInfo>();
+                if parent_span_info.is_none() {
+                    // We are beyond the maximum tracing depth or the call
+                    // tree is not collected.
+                    let deeper_call = parent_extensions
+                        .get_mut::<DeeperCall>()
+                        .map(|deeper| (deeper.overflow_idx, deeper.tree.clone()));
+                    std::mem::drop(parent_extensions);
+                    if let Some((overflow_idx, tree)) = deeper_call {
+                        count_deeper_call(&ctx, &span, overflow_idx, tree);
+                    }
+                    return;
+                }
+
+                let parent_span_info = parent_span_info.expect("parent has no SpanTimingInfo");
+                let parent_call_path_idx = parent_span_info.call_path_idx;
+                let tree = parent_span_info.tree.clone();
+                let root = match tree_root(&ctx, &tree) {
+                    Some(root) => root,
+                    None => return,
+                };
+                let mut root_extensions: ExtensionsMut = if root.id() == parent.id() {
+                    parent_extensions
+                } else {
+                    // Do not keep multiple extensions locked at the same time.
+                    std::mem::drop(parent_extensions);
+                    root.extensions_mut()
+                };
+                let mut pool = match PendingCallTree::of(&mut root_extensions, &tree) {
+                    Some(pool) => pool,
+                    // The call tree of an adopted parent was finished.
+                    None => return,
+                };
+                if let Some(callback_start) = callback_start {
+                    pool.layer_time += self.clock.delta(callback_start, self.clock.end());
+                }
+                if pool.degraded || self.exceeds_budget(&pool) {
+                    pool.degraded = true;
+                    pool.skipped_spans += 1;
+                    return;
+                }
+                let recursive_idx = if self.collapse_recursion {
+                    pool.recursive_call_path(parent_call_path_idx, span.metadata(), retry)
+                } else {
+                    None
+                };
+                let (call_path_idx, overflow) = match recursive_idx {
+                    Some(idx) => (idx, false),
+                    None => {
+                        let new_idx = CallPathPoolId(pool.pool.len());
+                        let max_call_depth = pool.max_call_depth;
+                        let parent_call_path_timing = &mut pool[parent_call_path_idx];
+                        let new_depth = parent_call_path_timing.depth + 1;
+                        if new_depth > max_call_depth {
+                            // The parent is the overflow call path.
+                            parent_call_path_timing.call_count += 1;
+                            std::mem::drop(pool);
+                            std::mem::drop(root_extensions);
+                            span.extensions_mut().insert(DeeperCall {
+                                overflow_idx: parent_call_path_idx,
+                                tree,
+                            });
+                            return;
+                        }
+                        let overflow = new_depth == max_call_depth;
+                        let key = if overflow {
+                            CallPathKey::Overflow
+                        } else {
+                            CallPathKey::Callsite {
+                                callsite: span.metadata().callsite(),
+                                retry,
+                            }
+                        };
+                        let idx = parent_call_path_timing.children.get(&key);
+                        let call_path_idx = match idx {
+                            Some(idx) => *idx,
+                            None => {
+                                parent_call_path_timing.children.insert(key, new_idx);
+                                let mut timing = if overflow {
+                                    CallPathTiming::new(
+                                        Some(parent_call_path_idx),
+                                        new_depth,
+                                        span.metadata(),
+                                        Cow::Borrowed(OVERFLOW_NAME),
+                                        false,
+                                    )
+                                } else {
+                                    CallPathTiming::new(
+                                        Some(parent_call_path_idx),
+                                        new_depth,
+                                        span.metadata(),
+                                        self.span_name(span.metadata()),
+                                        retry,
+                                    )
+                                };
+                                timing.overflow = overflow;
+                                pool.pool.push(timing);
+                                new_idx
+                            }
+                        };
+                        (call_path_idx, overflow)
+                    }
+                };
+                // Ancestors of the same call path, only with collapsed
+                // recursion.
+                let enclosing = ancestor_paths
+                    .iter()
+                    .filter(|(serial, idx)| *serial == tree.serial && *idx == call_path_idx)
+                    .count();
+                if enclosing > 0 {
+                    let timing = &mut pool[call_path_idx];
+                    timing.recursion_depth = core::cmp::max(timing.recursion_depth, enclosing + 1);
+                }
+                if span_fields.has_call_path_fields() && !overflow {
+                    self.apply_span_fields(&mut pool[call_path_idx], span_fields);
+                }
+                pool.spans += 1;
+                let spans = pool.spans;
+                std::mem::drop(pool);
+                if let Some(in_flight) = root_extensions.get_mut::<Arc<InFlightEntry>>() {
+                    in_flight.set_spans(spans);
+                }
+                // Do not keep multiple extensions locked at the same time.
+                std::mem::drop(root_extensions);
+                let created_at = self.clock.start();
+                if let Some(parent_info) = parent.extensions_mut().get_mut::<SpanTimingInfo>() {
+                    parent_info.start_awaiting(&*self.clock, created_at, id);
+                }
+                let mut extensions: ExtensionsMut = span.extensions_mut();
+                let mut timing_info =
+                    SpanTimingInfo::for_call_path_idx(call_path_idx, tree, created_at);
+                timing_info.cache_hit = cache_hit;
+                timing_info.nested = enclosing > 0;
+                extensions.insert(timing_info);
+            }
+        };
+    }
+
+    fn on_enter(&self, _id: &tracing::Id, ctx: Context<S>) {
+        let leave_parent = self.clock.end();
+        let span = ctx.lookup_current().expect("no span in new_span");
+        if span.extensions().get::<SpanTimingInfo>().is_none() {
+            // yes, this is an extra check but:
+            // * it has to occur before we check for the parent
+            // * taking the "start" clock value below should be one of the last
+            //   operations
+            return;
+        }
+
+        if let Some(parent) = collected_parent(&span) {
+            let mut extensions = parent.extensions_mut();
+            if let Some(timing_info) = extensions.get_mut::<SpanTimingInfo>() {
+                if let Some(thread_info) =
+                    timing_info.per_thread.get_mut(&std::thread::current().id())
+                {
+                    if thread_info.entered_children == 0 {
+                        let delta = self.clock.delta(thread_info.last_enter_own, leave_parent);
+                        timing_info.sum_own += delta;
+                    }
+                    thread_info.entered_children += 1;
+                }
+            }
+        }
+
+        let mut extensions = span.extensions_mut();
+        if let Some(timing_info) = extensions.get_mut::<SpanTimingInfo>() {
+            #[cfg(feature = "cpu_time")]
+            let cpu = if self.cpu_time {
+                crate::cpu::thread_cpu_time()
+            } else {
+                None
+            };
+            #[cfg(not(feature = "cpu_time"))]
+            let cpu = None;
+            let start = self.clock.start();
+            timing_info.settle_awaited(&*self.clock, start);
+            timing_info.idle_since = None;
+            timing_info.first_entered_at.get_or_insert(start);
+            timing_info.poll_count += 1;
+            if let Some(last_exit) = timing_info.last_exit.take() {
+                timing_info.idle += self.clock.delta(last_exit, start);
+            }
+            let per_thread = timing_info
+                .per_thread
+                .entry(std::thread::current().id())
+                .or_default();
+            per_thread.last_enter = start;
+            per_thread.last_enter_own = start;
+            per_thread.last_enter_cpu = cpu;
+        }
+    }
+
+    fn on_exit(&self, id: &tracing::Id, ctx: Context<'_, S>) {
+        let end = self.clock.end();
+        #[cfg(feature = "cpu_time")]
+        let cpu = if self.cpu_time {
+            crate::cpu::thread_cpu_time()
+        } else {
+            None
+        };
+        #[cfg(not(feature = "cpu_time"))]
+        let cpu: Option<Duration> = None;
+        let span = ctx.span(id).unwrap();
+
+        let mut extensions = span.extensions_mut();
+        let timing_info = extensions.get_mut::<SpanTimingInfo>();
+        if timing_info.is_none() {
+            return;
+        }
+        let timing_info = timing_info.unwrap();
+
+        let tree = timing_info.tree.clone();
+        let per_thread = timing_info.per_thread.get(&std::thread::current().id());
+        let missing_thread_info = per_thread.is_none();
+        if let Some(per_thread) = per_thread {
+            let wall_duration = self.clock.delta(per_thread.last_enter, end);
+            timing_info.sum_with_children += wall_duration;
+            if let (Some(enter_cpu), Some(cpu)) = (per_thread.last_enter_cpu, cpu) {
+                timing_info.cpu += cpu.saturating_sub(enter_cpu);
+            }
+            if self.per_thread_busy {
+                let thread_id = std::thread::current().id();
+                let idx = match timing_info
+                    .thread_busy
+                    .iter()
+                    .position(|(id, _)| *id == thread_id)
+                {
+                    Some(idx) => idx,
+                    None => {
+                        timing_info
+                            .thread_busy
+                            .push((thread_id, ThreadBusy::current()));
+                        timing_info.thread_busy.len() - 1
+                    }
+                };
+                let thread_busy = &mut timing_info.thread_busy[idx].1;
+                thread_busy.busy += wall_duration;
+                thread_busy.polls += 1;
+            }
+            if per_thread.entered_children == 0 {
+                let own_duration = self.clock.delta(per_thread.last_enter_own, end);
+                timing_info.sum_own += own_duration;
+            }
+
+            // It is likely that we will be entered by the same thread again,
+            // but we do not want to bloat memory if we are constantly entered
+            // in different threads.
+            timing_info.per_thread.remove(&std::thread::current().id());
+            if timing_info.per_thread.is_empty() {
+                timing_info.idle_since = Some(end);
+                timing_info.last_exit = Some(end);
+            }
+        }
+
+        // Make sure that we do not hold two extension locks at once.
+        std::mem::drop(extensions);
+
+        if missing_thread_info {
+            // In on_enter we ensure that the per thread info exists -- so I don't exactly understand
+            // when this can happen.
+            //
+            // Only warn after dropping the lock: on_event counts the warning
+            // for this span.
+            warn!("Missing thread info for current thread on exit. \n\
+                   Cannot account own time correctly. \n\
+                   If you use .in_current_span() or .or_current(), a span might be entered and exited multiple times.\n\
+                   Future versions of reqray might support this properly. Sorry for the inconvenience.\n");
+            if let Some(root) = tree_root(&ctx, &tree) {
+                let mut root_extensions = root.extensions_mut();
+                let pool = PendingCallTree::of(&mut root_extensions, &tree);
+                if let Some(mut pool) = pool {
+                    pool.unmatched_exits += 1;
+                }
+            }
+        }
+
+        if let Some(parent) = collected_parent(&span) {
+            let mut extensions = parent.extensions_mut();
+            // Roots might have parents outside of any call tree.
+            let timing_info = match extensions.get_mut::<SpanTimingInfo>() {
+                Some(timing_info) => timing_info,
+                None => return,
+            };
+            let enter_own = self.clock.start();
+            timing_info
+                .per_thread
+                .entry(std::thread::current().id())
+                .and_modify(|per_thread| {
+                    per_thread.entered_children = per_thread.entered_children.saturating_sub(1);
+                    if per_thread.entered_children == 0 {
+                        per_thread.last_enter_own = enter_own;
+                    }
+                });
+        }
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("no span in record");
+        let mut span_fields = self.span_fields(span.metadata(), |visitor| values.record(visitor));
+        if !span_fields.has_call_path_fields()
+            && span_fields.cache_hit.is_none()
+            && span_fields.context.is_empty()
+        {
+            return;
+        }
+
+        let (call_path_idx, tree) = match span.extensions_mut().get_mut::<SpanTimingInfo>() {
+            Some(timing_info) => {
+                if span_fields.cache_hit.is_some() {
+                    timing_info.cache_hit = span_fields.cache_hit;
+                }
+                (timing_info.call_path_idx, timing_info.tree.clone())
+            }
+            // We are beyond the maximum tracing depth.
+            None => return,
+        };
+        let context = match std::mem::take(&mut span_fields.context) {
+            // Only the context of the root span is kept.
+            context if tree.root == *id => context,
+            _ => Vec::new(),
+        };
+        if !span_fields.has_call_path_fields() && context.is_empty() {
+            return;
+        }
+        let root = match tree_root(&ctx, &tree) {
+            Some(root) => root,
+            None => return,
+        };
+        let mut root_extensions = root.extensions_mut();
+        let pool = PendingCallTree::of(&mut root_extensions, &tree);
+        if let Some(mut pool) = pool {
+            pool.record_context(context);
+            if span_fields.has_call_path_fields() && !pool[call_path_idx].overflow {
+                self.apply_span_fields(&mut pool[call_path_idx], span_fields);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &tracing::Event<'_>, ctx: Context<'_, S>) {
+        let level = *event.metadata().level();
+        if !self.collect_events && level != Level::ERROR && level != Level::WARN {
+            return;
+        }
+        let span = match ctx.event_span(event).and_then(skip_excluded) {
+            Some(span) => span,
+            None => return,
+        };
+        if level == Level::ERROR || level == Level::WARN {
+            // Spans beyond the maximum call depth have no timing info.
+            for span in span.scope() {
+                if let Some(timing_info) = span.extensions_mut().get_mut::<SpanTimingInfo>() {
+                    if level == Level::ERROR {
+                        timing_info.error_count += 1;
+                    } else {
+                        timing_info.warn_count += 1;
+                    }
+                    break;
+                }
+            }
+        }
+        if !self.collect_events {
+            return;
+        }
+        let (call_path_idx, tree) = match span.extensions().get::<SpanTimingInfo>() {
+            Some(timing_info) => (timing_info.call_path_idx, timing_info.tree.clone()),
+            // We are beyond the maximum tracing depth.
+            None => return,
+        };
+        let root = match tree_root(&ctx, &tree) {
+            Some(root) => root,
+            None => return,
+        };
+        let mut root_extensions = root.extensions_mut();
+        let mut pool = match PendingCallTree::of(&mut root_extensions, &tree) {
+            Some(pool) if !pool.degraded => pool,
+            _ => return,
+        };
+        let new_idx = CallPathPoolId(pool.pool.len());
+        let max_call_depth = pool.max_call_depth;
+        let parent_call_path_timing = &mut pool[call_path_idx];
+        let new_depth = parent_call_path_timing.depth + 1;
+        if new_depth >= max_call_depth {
+            return;
+        }
+        let key = CallPathKey::Callsite {
+            callsite: event.metadata().callsite(),
+            retry: false,
+        };
+        let call_path_idx = match parent_call_path_timing.children.get(&key) {
+            Some(idx) => *idx,
+            None => {
+                parent_call_path_timing.children.insert(key, new_idx);
+                let mut visitor = EventMessageVisitor::default();
+                event.record(&mut visitor);
+                let name = match visitor.message {
+                    Some(message) => Cow::Owned(message),
+                    None => Cow::Borrowed(event.metadata().name()),
+                };
+                let mut timing = CallPathTiming::new(
+                    Some(call_path_idx),
+                    new_depth,
+                    event.metadata(),
+                    name,
+                    false,
+                );
+                timing.event = true;
+                pool.pool.push(timing);
+                new_idx
+            }
+        };
+        pool[call_path_idx].call_count += 1;
+    }
+
+    fn on_follows_from(&self, id: &Id, follows: &Id, ctx: Context<'_, S>) {
+        let (span, follows) = match (ctx.span(id), ctx.span(follows)) {
+            (Some(span), Some(follows)) => (span, follows),
+            _ => return,
+        };
+        let (followed_idx, followed) = match follows.extensions().get::<SpanTimingInfo>() {
+            Some(timing_info) => (timing_info.call_path_idx, timing_info.tree.clone()),
+            None => return,
+        };
+        let (call_path_idx, tree, unentered_root) = match span.extensions().get::<SpanTimingInfo>()
+        {
+            Some(timing_info) => (
+                timing_info.call_path_idx,
+                timing_info.tree.clone(),
+                timing_info.tree.root == *id && timing_info.first_entered_at.is_none(),
+            ),
+            None => return,
+        };
+        if self.adopt_follows_from
+            && unentered_root
+            && tree != followed
+            && self.adopt(&ctx, &span, followed_idx, &followed)
+        {
+            return;
+        }
+
+        let path = match tree_root(&ctx, &followed) {
+            Some(root) => {
+                let mut root_extensions = root.extensions_mut();
+                let pool = match PendingCallTree::of(&mut root_extensions, &followed) {
+                    Some(pool) => pool,
+                    None => return,
+                };
+                pool[followed_idx].path_string(&pool, "/")
+            }
+            None => return,
+        };
+        if let Some(root) = tree_root(&ctx, &tree) {
+            let mut root_extensions = root.extensions_mut();
+            let pool = PendingCallTree::of(&mut root_extensions, &tree);
+            if let Some(mut pool) = pool {
+                pool[call_path_idx].add_follows_from(path);
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<S>) {
+        let closed = self.clock.end();
+        let span = ctx.span(&id).expect("no span in close");
+        let mut extensions = span.extensions_mut();
+        let timing_info = extensions.remove::<SpanTimingInfo>();
+        if timing_info.is_none() {
+            return;
+        }
+        let timing_info = timing_info.unwrap();
+        // Make sure that we do not hold two extension locks at once.
+        std::mem::drop(extensions);
+
+        let awaited = collected_parent(&span)
+            .and_then(|parent| {
+                parent
+                    .extensions_mut()
+                    .get_mut::<SpanTimingInfo>()
+                    .map(|parent_info| parent_info.stop_awaiting(&*self.clock, closed, &id))
+            })
+            .unwrap_or_default();
+
+        let root = match tree_root(&ctx, &timing_info.tree) {
+            Some(root) => root,
+            None => return,
+        };
+        let mut root_extensions: ExtensionsMut = root.extensions_mut();
+
+        let mut pool = match PendingCallTree::of(&mut root_extensions, &timing_info.tree) {
+            Some(pool) => pool,
+            // An adopted span outlived the call tree it was adopted
+            // into.
+            None => return,
+        };
+        let first_entered = timing_info
+            .first_entered_at
+            .map(|first_entered_at| self.clock.delta(pool.created_at, first_entered_at));
+        let call_path_timing: &mut CallPathTiming = &mut pool[timing_info.call_path_idx];
+        call_path_timing.call_count += 1;
+        call_path_timing.sum_own += timing_info.sum_own;
+        call_path_timing.sum_io_wait += timing_info.io_wait;
+        call_path_timing.poll_count += timing_info.poll_count;
+        call_path_timing.error_count += timing_info.error_count;
+        call_path_timing.warn_count += timing_info.warn_count;
+        if !timing_info.nested {
+            if let Some(first_entered) = first_entered {
+                call_path_timing.first_entered = Some(match call_path_timing.first_entered {
+                    Some(earlier) => core::cmp::min(earlier, first_entered),
+                    None => first_entered,
+                });
+            }
+            call_path_timing.span_life_time += self.clock.delta(timing_info.created_at, closed);
+            call_path_timing.sum_with_children += timing_info.sum_with_children;
+            call_path_timing.outer_call_count += 1;
+            call_path_timing.min_busy = Some(match call_path_timing.min_busy {
+                Some(min_busy) => core::cmp::min(min_busy, timing_info.sum_with_children),
+                None => timing_info.sum_with_children,
+            });
+            call_path_timing.max_busy =
+                core::cmp::max(call_path_timing.max_busy, timing_info.sum_with_children);
+            if self.histograms {
+                call_path_timing
+                    .busy_histogram
+                    .get_or_insert_with(BusyHistogram::default)
+                    .record(timing_info.sum_with_children);
+            }
+            call_path_timing.sum_awaited += awaited;
+            call_path_timing.sum_idle += timing_info.idle;
+            call_path_timing.sum_cpu += timing_info.cpu;
+            for (_, thread_busy) in &timing_info.thread_busy {
+                call_path_timing.add_thread_busy(thread_busy);
+            }
+            match timing_info.cache_hit {
+                Some(true) => {
+                    call_path_timing.cache_hit_count += 1;
+                    call_path_timing.cache_hit_busy += timing_info.sum_with_children;
+                }
+                Some(false) => {
+                    call_path_timing.cache_miss_count += 1;
+                    call_path_timing.cache_miss_busy += timing_info.sum_with_children;
+                }
+                None => {}
+            }
+        }
+        if self.max_layer_time_per_tree.is_some() {
+            pool.layer_time += self.clock.delta(closed, self.clock.end());
+        }
+
+        if timing_info.tree.root == id {
+            #[allow(unused_mut)]
+            let mut pool = pool.finish();
+            root_extensions.remove::<PendingCallTree>();
+            self.forget_pending(timing_info.tree.serial);
+            #[cfg(feature = "dhat")]
+            if let Some(HeapStatsAtStart(start)) = root_extensions.remove::<HeapStatsAtStart>() {
+                pool.heap = Some(HeapSummary::between(&start, &dhat::HeapStats::get()));
+            }
+            if pool.degraded {
+                self.stats.add_degraded_tree();
+            }
+            if let Some(in_flight) = root_extensions.remove::<Arc<InFlightEntry>>() {
+                self.stats.deregister(&in_flight);
+            }
+
+            self.processor.process_finished_call(pool);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use futures::channel::mpsc::{channel, Receiver, Sender};
+    use quanta::{Clock, Mock};
+    use tracing::{field::Visit, info};
+    use tracing_subscriber::fmt;
+
+    use super::{CallPathPoolId, SpanFields, SpanFieldsVisitor};
+    use crate::{CallPathPool, CallTreeCollectorBuilder, FinishedCallTreeProcessor};
+
+    #[tracing::instrument]
+    pub fn one_ns(mock: &Mock) {
+        mock.increment(1);
+    }
+
+    #[test]
+    fn test_simple() {
+        let call_trees = collect_call_trees(|mock| {
+            one_ns(&mock);
+        });
+
+        assert_eq!(call_trees.len(), 1, "{:#?}", call_trees);
+
+        let first_call = &call_trees[0];
+        assert_eq!(first_call.pool.len(), 1, "{:#?}", first_call.pool);
+        let first_call_root = first_call.root();
+        assert_eq!(
+            first_call_root.static_span_meta().name(),
+            "one_ns",
+            "{:#?}",
+            first_call
+        );
+        assert_eq!(first_call_root.call_count(), 1, "{:#?}", first_call);
+        assert_eq!(
+            first_call_root.sum_with_children(),
+            Duration::from_nanos(1),
+            "{:#?}",
+            first_call
+        );
+        assert_eq!(
+            first_call_root.sum_without_children(),
+            Duration::from_nanos(1),
+            "{:#?}",
+            first_call
+        );
+    }
+
+    #[tracing::instrument]
+    pub fn compound_call(mock: &Mock) {
+        mock.increment(10);
+        one_ns(mock);
+        mock.increment(100);
+        one_ns(mock);
+        one_ns(mock);
+        mock.increment(1000);
+    }
+
+    #[test]
+    fn test_compound() {
+        let call_trees = collect_call_trees(|mock| {
+            compound_call(&mock);
+        });
+
+        assert_eq!(call_trees.len(), 1, "{:#?}", call_trees);
+
+        let first_call = &call_trees[0];
+        assert_eq!(first_call.pool.len(), 2, "{:#?}", first_call.pool);
+
+        let first_call_root = first_call.root();
+        assert_eq!(
+            first_call_root.static_span_meta().name(),
+            "compound_call",
+            "{:#?}",
+            first_call
+        );
+        assert_eq!(first_call_root.call_count(), 1, "{:#?}", first_call);
+        assert_eq!(
+            first_call_root.sum_with_children(),
+            Duration::from_nanos(1113),
+            "{:#?}",
+            first_call
+        );
+        assert_eq!(
+            first_call_root.sum_without_children(),
+            Duration::from_nanos(1110),
+            "{:#?}",
+            first_call
+        );
+        assert_eq!(first_call_root.children().count(), 1, "{:#?}", call_trees);
+
+        let nested_call_idx = *first_call_root.children().next().unwrap();
+        let nested_call = &first_call[nested_call_idx];
+        assert_eq!(nested_call.static_span_meta().name(), "one_ns");
+        assert_eq!(nested_call.call_count(), 3);
+        assert_eq!(nested_call.sum_with_children(), Duration::from_nanos(3));
+        assert_eq!(nested_call.sum_without_children(), Duration::from_nanos(3));
+    }
+
+    #[test]
+    fn test_span_name_mapper() {
+        let builder = CallTreeCollectorBuilder::default()
+            .span_name_mapper(|name| name.trim_end_matches("_call").to_uppercase());
+        let call_trees = collect_call_trees_with_builder(builder, |mock| {
+            compound_call(&mock);
+        });
+
+        let first_call = &call_trees[0];
+        let first_call_root = first_call.root();
+        assert_eq!(first_call_root.name(), "COMPOUND");
+        assert_eq!(first_call_root.static_span_meta().name(), "compound_call");
+        let nested_call_idx = *first_call_root.children().next().unwrap();
+        assert_eq!(first_call[nested_call_idx].name(), "ONE_NS");
+    }
+
+    #[tracing::instrument(fields(otel.name = tracing::field::Empty))]
+    pub fn late_named(mock: &Mock) {
+        tracing::Span::current().record("otel.name", &"GET /late");
+        one_ns(mock);
+    }
+
+    #[test]
+    fn test_name_field() {
+        let builder = CallTreeCollectorBuilder::default().name_field("otel.name");
+        let call_trees = collect_call_trees_with_builder(builder, |mock| {
+            let span = tracing::info_span!("early_named", otel.name = "GET /early");
+            let _enter = span.enter();
+            late_named(&mock);
+        });
+
+        let first_call = &call_trees[0];
+        let first_call_root = first_call.root();
+        assert_eq!(first_call_root.name(), "GET /early");
+        let nested_call_idx = *first_call_root.children().next().unwrap();
+        let nested_call = &first_call[nested_call_idx];
+        assert_eq!(nested_call.name(), "GET /late");
+        assert_eq!(nested_call.static_span_meta().name(), "late_named");
+    }
+
+    #[test]
+    fn test_awaited() {
+        let call_trees = collect_call_trees(|mock| {
+            let parent = tracing::info_span!("parent");
+            let (first, second) =
+                parent.in_scope(|| (tracing::info_span!("first"), tracing::info_span!("second")));
+            // Both children are alive while the parent is idle.
+            mock.increment(100);
+            parent.in_scope(|| drop(first));
+            mock.increment(10);
+            parent.in_scope(|| drop(second));
+            // No children left to attribute to.
+            mock.increment(1_000);
+        });
+
+        let first_call = &call_trees[0];
+        let first_call_root = first_call.root();
+        assert_eq!(first_call_root.sum_awaited(), Duration::default());
+        let mut children = first_call_root.children().copied().collect::<Vec<_>>();
+        children.sort();
+        let first = &first_call[children[0]];
+        assert_eq!(first.name(), "first");
+        assert_eq!(first.sum_awaited(), Duration::from_nanos(50));
+        let second = &first_call[children[1]];
+        assert_eq!(second.name(), "second");
+        assert_eq!(second.sum_awaited(), Duration::from_nanos(60));
+    }
+
+    #[test]
+    fn test_overflow() {
+        let call_trees = collect_call_trees_with_builder(
+            CallTreeCollectorBuilder::default().max_call_depth(3),
+            |mock| {
+                tracing::info_span!("request").in_scope(|| {
+                    tracing::info_span!("handler").in_scope(|| {
+                        for _ in 0..2 {
+                            tracing::info_span!("deep").in_scope(|| {
+                                mock.increment(10);
+                                tracing::info_span!("deeper").in_scope(|| one_ns(&mock));
+                            });
+                        }
+                    });
+                });
+            },
+        );
+
+        let call_tree = &call_trees[0];
+        call_tree.check_invariants(3).unwrap();
+        let names = call_tree
+            .iter_depth_first()
+            .map(|(depth, node)| (depth, node.name(), node.call_count()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                (0, "request", 1),
+                (1, "handler", 1),
+                (2, "deep", 2),
+                (3, "… (deeper calls)", 4)
+            ]
+        );
+        let (_, deep) = call_tree.iter_depth_first().nth(2).unwrap();
+        assert_eq!(deep.sum_with_children(), Duration::from_nanos(22));
+        assert_eq!(deep.sum_without_children(), Duration::from_nanos(20));
+        let (_, overflow) = call_tree.iter_depth_first().nth(3).unwrap();
+        assert!(overflow.is_overflow());
+        assert_eq!(overflow.sum_with_children(), Duration::from_nanos(2));
+        assert_eq!(overflow.sum_without_children(), Duration::from_nanos(2));
+    }
+
+    #[test]
+    fn test_untracked_spans() {
+        let call_trees = collect_call_trees_with_builder(
+            CallTreeCollectorBuilder::default().max_call_depth(2),
+            |mock| {
+                tracing::info_span!("request").in_scope(|| {
+                    let handler = tracing::info_span!("handler");
+                    handler.in_scope(|| {
+                        tracing::info_span!("deep").in_scope(|| one_ns(&mock));
+                    });
+                    // Exit without a matching enter.
+                    tracing::dispatcher::get_default(|dispatch| {
+                        dispatch.exit(&handler.id().unwrap())
+                    });
+                });
+            },
+        );
+
+        let call_tree = &call_trees[0];
+        assert_eq!(call_tree.depth_capped_spans(), 2);
+        assert_eq!(call_tree.unmatched_exits(), 1);
+        assert_eq!(call_tree.skipped_spans(), 0);
+        assert_eq!(call_tree.untracked_spans(), 3);
+    }
+
+    #[test]
+    fn test_root_predicate() {
+        let call_trees = collect_call_trees_with_builder(
+            CallTreeCollectorBuilder::default()
+                .root_predicate(|meta| meta.name() == "http_request"),
+            |mock| {
+                tracing::info_span!("server").in_scope(|| {
+                    for _ in 0..2 {
+                        tracing::info_span!("http_request").in_scope(|| {
+                            mock.increment(10);
+                            one_ns(&mock)
+                        });
+                    }
+                    tracing::info_span!("background").in_scope(|| one_ns(&mock));
+                })
+            },
+        );
+        assert_eq!(call_trees.len(), 2, "{:#?}", call_trees);
+        for call_tree in &call_trees {
+            let names = call_tree
+                .iter_depth_first()
+                .map(|(_, node)| node.name())
+                .collect::<Vec<_>>();
+            assert_eq!(names, vec!["http_request", "one_ns"]);
+            assert_eq!(
+                call_tree.root().sum_with_children(),
+                Duration::from_nanos(11)
+            );
+        }
+
+        // The marker field works without a predicate.
+        let call_trees = collect_call_trees(|mock| {
+            tracing::info_span!("job").in_scope(|| {
+                mock.increment(100);
+                tracing::info_span!("step", reqray.root = true).in_scope(|| one_ns(&mock));
+            })
+        });
+        let roots = call_trees
+            .iter()
+            .map(|call_tree| (call_tree.root().name(), call_tree.call_path_count()))
+            .collect::<Vec<_>>();
+        assert_eq!(roots, vec![("step", 2), ("job", 1)]);
+        assert_eq!(
+            call_trees[1].root().sum_without_children(),
+            Duration::from_nanos(100)
+        );
+    }
+
+    #[test]
+    fn test_follows_from() {
+        let message_passing = |mock: Arc<Mock>| {
+            let request = tracing::info_span!("request");
+            let enqueue = request.in_scope(|| tracing::info_span!("enqueue"));
+            // A worker handles the message in a fresh span.
+            let handle = tracing::info_span!(parent: None, "handle");
+            handle.follows_from(&enqueue);
+            drop(enqueue);
+            handle.in_scope(|| one_ns(&mock));
+            drop(handle);
+            // E.g. from another task.
+            tracing::info_span!(parent: &request, "reply").in_scope(|| one_ns(&mock));
+        };
+        let call_trees = collect_call_trees(message_passing);
+        let trees = call_trees
+            .iter()
+            .map(|call_tree| {
+                call_tree
+                    .iter_depth_first()
+                    .map(|(_, node)| node.name())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            trees,
+            vec![
+                vec!["handle", "one_ns"],
+                vec!["request", "enqueue", "reply", "one_ns"]
+            ]
+        );
+        assert_eq!(
+            call_trees[0].root().follows_from().collect::<Vec<_>>(),
+            vec![("request/enqueue", 1)]
+        );
+
+        let call_trees = collect_call_trees_with_builder(
+            CallTreeCollectorBuilder::default().adopt_follows_from(true),
+            message_passing,
+        );
+        assert_eq!(call_trees.len(), 1, "{:#?}", call_trees);
+        let call_tree = &call_trees[0];
+        let paths = call_tree
+            .iter_depth_first()
+            .map(|(_, node)| (node.path_string(call_tree, "/"), node.call_count()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec![
+                ("request".to_string(), 1),
+                ("request/enqueue".to_string(), 1),
+                ("request/enqueue/handle".to_string(), 1),
+                ("request/enqueue/handle/one_ns".to_string(), 1),
+                ("request/reply".to_string(), 1),
+                ("request/reply/one_ns".to_string(), 1),
+            ]
+        );
+        let handle = call_tree
+            .iter_depth_first()
+            .find(|(_, node)| node.name() == "handle")
+            .unwrap()
+            .1;
+        assert_eq!(handle.sum_with_children(), Duration::from_nanos(1));
+        assert_eq!(handle.follows_from().count(), 0);
+        call_tree.check_invariants(10).unwrap();
+    }
+
+    #[test]
+    fn test_polls() {
+        let call_trees = collect_call_trees(|mock| {
+            let parent = tracing::info_span!("parent");
+            // Not idle before the first poll.
+            mock.increment(1_000);
+            for idle in [100, 10] {
+                parent.in_scope(|| one_ns(&mock));
+                mock.increment(idle);
+            }
+            parent.in_scope(|| {
+                let child = tracing::info_span!("child");
+                child.in_scope(|| mock.increment(1));
+                mock.increment(5);
+                child.in_scope(|| mock.increment(1));
+            });
+            // Nor after the last one.
+            mock.increment(1_000);
+        });
+
+        let root = call_trees[0].root();
+        assert_eq!(root.poll_count(), 3);
+        assert_eq!(root.idle_time(), Duration::from_nanos(110));
+        let mut children = root.children().copied().collect::<Vec<_>>();
+        children.sort();
+        let one_ns = &call_trees[0][children[0]];
+        assert_eq!((one_ns.call_count(), one_ns.poll_count()), (2, 2));
+        assert_eq!(one_ns.idle_time(), Duration::default());
+        let child = &call_trees[0][children[1]];
+        assert_eq!(child.poll_count(), 2);
+        assert_eq!(child.idle_time(), Duration::from_nanos(5));
+    }
+
+    #[test]
+    fn test_time_split() {
+        let call_trees = collect_call_trees(|mock| {
+            let request = tracing::info_span!("request");
+            request.in_scope(|| mock.increment(3));
+            let query = request.in_scope(|| tracing::info_span!("query"));
+            query.in_scope(|| mock.increment(1));
+            mock.increment(10);
+            query.in_scope(|| crate::report_io_wait_for(&query, Duration::from_nanos(6)));
+            drop(query);
+            request.in_scope(|| {
+                // Blocking I/O while busy.
+                mock.increment(2);
+                crate::report_io_wait(Duration::from_nanos(2));
+            });
+        });
+
+        let split = call_trees[0].time_split();
+        assert_eq!(split.downstream(), Duration::from_nanos(8));
+        assert_eq!(split.local(), Duration::from_nanos(6));
+        assert_eq!(split.waiting(), Duration::from_nanos(2));
+        assert_eq!(
+            split.local() + split.downstream() + split.waiting(),
+            call_trees[0].root().span_alive()
+        );
+    }
+
+    #[test]
+    fn test_notes() {
+        let call_trees = collect_call_trees(|mock| {
+            let span = tracing::info_span!("lookups", reqray.note = tracing::field::Empty);
+            let _enter = span.enter();
+            for i in 0..3 {
+                let note = if i == 0 { "cache miss" } else { "cache hit" };
+                tracing::info_span!("lookup", reqray.note = note).in_scope(|| one_ns(&mock));
+            }
+            span.record("reqray.note", &"done");
+        });
+
+        let first_call = &call_trees[0];
+        let first_call_root = first_call.root();
+        assert_eq!(
+            first_call_root.notes().collect::<Vec<_>>(),
+            vec![("done", 1)]
+        );
+        let lookup_idx = *first_call_root.children().next().unwrap();
+        assert_eq!(
+            first_call[lookup_idx].notes().collect::<Vec<_>>(),
+            vec![("cache miss", 1), ("cache hit", 2)]
+        );
+    }
+
+    #[tracing::instrument(skip(mock))]
+    pub fn fetch(mock: &Mock) {
+        for attempt in 1..=3 {
+            tracing::info_span!("attempt", reqray.attempt = attempt).in_scope(|| one_ns(mock));
+        }
+    }
+
+    #[test]
+    fn test_attempts() {
+        let call_trees = collect_call_trees(|mock| fetch(&mock));
+
+        let first_call = &call_trees[0];
+        let first_call_root = first_call.root();
+        assert!(!first_call_root.is_retry());
+        let mut children = first_call_root.children().copied().collect::<Vec<_>>();
+        children.sort();
+        assert_eq!(children.len(), 2, "{:#?}", first_call);
+        let first_attempt = &first_call[children[0]];
+        assert!(!first_attempt.is_retry());
+        assert_eq!(first_attempt.call_count(), 1);
+        let retries = &first_call[children[1]];
+        assert!(retries.is_retry());
+        assert_eq!(retries.call_count(), 2);
+        assert_eq!(retries.sum_with_children(), Duration::from_nanos(2));
+    }
+
+    #[test]
+    fn test_attempt_field_types() {
+        let span = tracing::subscriber::with_default(tracing_subscriber::registry(), || {
+            tracing::info_span!("attempt", reqray.attempt = tracing::field::Empty)
+        });
+        let field = span.field(super::ATTEMPT_FIELD).unwrap();
+        let attempt = |record: &dyn Fn(&mut SpanFieldsVisitor<'_>)| {
+            let mut visitor = SpanFieldsVisitor {
+                name_field: None,
+                captured_fields: &[],
+                context_fields: &[],
+                fields: SpanFields::default(),
+            };
+            record(&mut visitor);
+            visitor.fields.attempt
+        };
+        assert_eq!(attempt(&|visitor| visitor.record_i64(&field, -1)), Some(0));
+        assert_eq!(attempt(&|visitor| visitor.record_u64(&field, 2)), Some(2));
+        assert_eq!(attempt(&|visitor| visitor.record_str(&field, "3")), Some(3));
+        assert_eq!(
+            attempt(&|visitor| visitor.record_debug(&field, &4)),
+            Some(4)
+        );
+    }
+
+    #[test]
+    fn test_cache_hits() {
+        let call_trees = collect_call_trees(|mock| {
+            let span = tracing::info_span!("queries");
+            let _enter = span.enter();
+            for i in 0..4 {
+                let query = tracing::info_span!("query", cache.hit = tracing::field::Empty);
+                let _enter = query.enter();
+                mock.increment(10);
+                query.record("cache.hit", &(i > 0));
+            }
+            tracing::info_span!("query", cache.hit = false).in_scope(|| mock.increment(1));
+        });
+
+        let first_call = &call_trees[0];
+        let mut children = first_call.root().children().copied().collect::<Vec<_>>();
+        children.sort();
+        let recorded = &first_call[children[0]];
+        assert_eq!(recorded.cache_hit_count(), 3);
+        assert_eq!(recorded.cache_hit_busy(), Duration::from_nanos(30));
+        assert_eq!(recorded.cache_miss_count(), 1);
+        assert_eq!(recorded.cache_miss_busy(), Duration::from_nanos(10));
+        let at_creation = &first_call[children[1]];
+        assert_eq!(at_creation.cache_hit_count(), 0);
+        assert_eq!(at_creation.cache_miss_count(), 1);
+        assert_eq!(at_creation.cache_miss_busy(), Duration::from_nanos(1));
+    }
+
+    #[test]
+    fn test_flatten() {
+        let call_trees = collect_call_trees(|mock| {
+            tracing::info_span!("request").in_scope(|| {
+                one_ns(&mock);
+                compound_call(&mock);
+            })
+        });
+
+        let paths = call_trees[0]
+            .flatten("/")
+            .map(|(path, timing)| (path, timing.call_count()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec![
+                ("request".to_string(), 1),
+                ("request/one_ns".to_string(), 1),
+                ("request/compound_call".to_string(), 1),
+                ("request/compound_call/one_ns".to_string(), 3),
+            ]
+        );
+    }
 
-    fn on_exit(&self, id: &tracing::Id, ctx: Context<'_, S>) {
-        let end = self.clock.end();
-        let span = ctx.span(id).unwrap();
+    #[test]
+    fn test_subtree() {
+        let call_trees = collect_call_trees(|mock| {
+            tracing::info_span!("request").in_scope(|| {
+                one_ns(&mock);
+                compound_call(&mock);
+                tracing::info_span!("db").in_scope(|| {
+                    compound_call(&mock);
+                    one_ns(&mock);
+                });
+            })
+        });
 
-        let mut extensions = span.extensions_mut();
-        let timing_info = extensions.get_mut::<SpanTimingInfo>();
-        if timing_info.is_none() {
-            return;
-        }
-        let timing_info = timing_info.unwrap();
+        let first_call = &call_trees[0];
+        let mut children = first_call.root().children().copied().collect::<Vec<_>>();
+        children.sort();
+        let db = first_call.subtree(children[2]);
+        assert_eq!(db.pool.len(), 4, "{:#?}", db);
+        assert_eq!(db.root().name(), "db");
+        assert_eq!(db.root().depth, 0);
+        let mut db_children = db.root().children().copied().collect::<Vec<_>>();
+        db_children.sort();
+        let compound = &db[db_children[0]];
+        assert_eq!(compound.name(), "compound_call");
+        assert_eq!(compound.depth, 1);
+        assert_eq!(db[db_children[1]].name(), "one_ns");
+        let compound_children = compound.children().copied().collect::<Vec<_>>();
+        assert_eq!(db[compound_children[0]].call_count(), 3);
+        assert_eq!(db.check_invariants(10), Ok(()));
+        assert_eq!(db.root().parent(), None);
+        assert_eq!(
+            db[compound_children[0]].path_string(&db, ";"),
+            "db;compound_call;one_ns"
+        );
+    }
 
-        if let Some(per_thread) = &timing_info.per_thread.get(&std::thread::current().id()) {
-            let wall_duration = self.clock.delta(per_thread.last_enter, end);
-            timing_info.sum_with_children += wall_duration;
-            let own_duration = self.clock.delta(per_thread.last_enter_own, end);
-            timing_info.sum_own += own_duration;
-    
-            // It is likely that we will be entered by the same thread again,
-            // but we do not want to bloat memory if we are constantly entered
-            // in different threads.
-            timing_info.per_thread.remove(&std::thread::current().id());    
-        } else {
-            // In on_enter we ensure that the per thread info exists -- so I don't exactly understand
-            // when this can happen.
-            warn!("Missing thread info for current thread on exit. \n\
-                   Cannot account own time correctly. \n\
-                   If you use .in_current_span() or .or_current(), a span might be entered and exited multiple times.\n\
-                   Future versions of reqray might support this properly. Sorry for the inconvenience.\n");
-        }
+    #[test]
+    fn test_compact() {
+        let call_trees = collect_call_trees(|mock| {
+            // The same call site, so that both calls share a call path.
+            let a = |nested: bool| {
+                tracing::info_span!("a").in_scope(|| {
+                    if nested {
+                        tracing::info_span!("c").in_scope(|| one_ns(&mock));
+                    }
+                })
+            };
+            tracing::info_span!("request").in_scope(|| {
+                a(false);
+                tracing::info_span!("b").in_scope(|| one_ns(&mock));
+                a(true);
+            })
+        });
 
-        // Make sure that we do not hold two extension locks at once.
-        std::mem::drop(extensions);
+        let mut pool = call_trees[0].clone();
+        let paths =
+            |pool: &CallPathPool| pool.flatten("/").map(|(path, _)| path).collect::<Vec<_>>();
+        assert_eq!(
+            paths(&pool),
+            vec![
+                "request",
+                "request/a",
+                "request/b",
+                "request/b/one_ns",
+                "request/a/c",
+                "request/a/c/one_ns"
+            ]
+        );
+        pool.compact();
+        assert_eq!(
+            paths(&pool),
+            vec![
+                "request",
+                "request/a",
+                "request/a/c",
+                "request/a/c/one_ns",
+                "request/b",
+                "request/b/one_ns"
+            ]
+        );
+        assert_eq!(pool.check_invariants(10), Ok(()));
+        assert_eq!(pool.pool.capacity(), pool.pool.len());
+        assert_eq!(
+            pool.root().sum_with_children(),
+            call_trees[0].root().sum_with_children()
+        );
+        let a = &pool[CallPathPoolId(1)];
+        assert_eq!(a.call_count(), 2);
+        assert_eq!(
+            a.children().copied().collect::<Vec<_>>(),
+            vec![CallPathPoolId(2)]
+        );
+        assert_eq!(pool[CallPathPoolId(2)].parent(), Some(CallPathPoolId(1)));
 
-        if let Some(parent) = span.parent() {
-            let mut extensions = parent.extensions_mut();
-            let timing_info = extensions
-                .get_mut::<SpanTimingInfo>()
-                .expect("parent has no SpanTimingInfo");
-            let enter_own = self.clock.start();
-            timing_info
-                .per_thread
-                .entry(std::thread::current().id())
-                .and_modify(|per_thread| {
-                    per_thread.last_enter_own = enter_own;
-                });
-        }
+        // Compacting is idempotent.
+        let compacted = format!("{:?}", pool.flatten("/").collect::<Vec<_>>());
+        pool.compact();
+        assert_eq!(
+            format!("{:?}", pool.flatten("/").collect::<Vec<_>>()),
+            compacted
+        );
     }
 
-    fn on_close(&self, id: Id, ctx: Context<S>) {
-        let closed = self.clock.end();
-        let span = ctx.span(&id).expect("no span in close");
-        let mut extensions = span.extensions_mut();
-        let timing_info = extensions.remove::<SpanTimingInfo>();
-        if timing_info.is_none() {
-            return;
-        }
-        let timing_info = timing_info.unwrap();
-        let root_extensions_opt = span.scope().from_root().next();
-        let mut root_extensions: ExtensionsMut = match root_extensions_opt.as_ref() {
-            Some(re) => {
-                // Make sure that we do not hold two extension locks at once.
-                std::mem::drop(extensions);
-                re.extensions_mut()
-            }
-            None => extensions,
+    #[test]
+    fn test_merge() {
+        let call_trees = collect_call_trees(|mock| {
+            let request = |extra: bool| {
+                tracing::info_span!("request").in_scope(|| {
+                    compound_call(&mock);
+                    if extra {
+                        tracing::info_span!("extra").in_scope(|| mock.increment(100));
+                    }
+                })
+            };
+            request(false);
+            request(true);
+        });
+        let mut merged = call_trees[0].clone();
+        merged.merge(&call_trees[1]);
+
+        let calls = |pool: &CallPathPool| {
+            pool.flatten("/")
+                .map(|(path, node)| (path, node.call_count(), node.sum_with_children().as_nanos()))
+                .collect::<Vec<_>>()
         };
+        assert_eq!(
+            calls(&merged),
+            vec![
+                ("request".to_string(), 2, 2326),
+                ("request/compound_call".to_string(), 2, 2226),
+                ("request/compound_call/one_ns".to_string(), 6, 6),
+                ("request/extra".to_string(), 1, 100),
+            ]
+        );
+        assert_eq!(merged.root().min_busy(), Duration::from_nanos(1113));
+        assert_eq!(merged.root().max_busy(), Duration::from_nanos(1213));
+        let extra = merged.flatten("/").last().unwrap().1;
+        assert_eq!(merged[extra.parent().unwrap()].name(), "request");
 
-        let pool: &mut CallPathPool = root_extensions
-            .get_mut::<CallPathPool>()
-            .expect("no pool in root Span");
-        let call_path_timing: &mut CallPathTiming = &mut pool[timing_info.call_path_idx];
-        call_path_timing.call_count += 1;
-        call_path_timing.span_life_time += self.clock.delta(timing_info.created_at, closed);
-        call_path_timing.sum_with_children += timing_info.sum_with_children;
-        call_path_timing.sum_own += timing_info.sum_own;
+        // Merging into the call tree with more call paths gives the same.
+        let mut merged_other_way = call_trees[1].clone();
+        merged_other_way.merge(&call_trees[0]);
+        assert_eq!(calls(&merged_other_way), calls(&merged));
+    }
 
-        if span.parent().is_none() {
-            let pool = root_extensions
-                .remove::<CallPathPool>()
-                .expect("no pool in root Span");
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_merge_deserialized() {
+        let call_trees = collect_call_trees(|mock| compound_call(&mock));
+        let json = serde_json::to_string(&call_trees[0]).unwrap();
+        let mut pool: CallPathPool = serde_json::from_str(&json).unwrap();
+        let deserialized = pool.clone();
+        pool.merge(&deserialized);
+        pool.merge(&call_trees[0]);
 
-            self.processor.process_finished_call(pool);
-        }
+        let calls = pool
+            .flatten("/")
+            .map(|(path, node)| (path, node.call_count()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            calls,
+            vec![
+                ("compound_call".to_string(), 3),
+                ("compound_call/one_ns".to_string(), 9)
+            ]
+        );
     }
-}
 
-#[cfg(test)]
-pub(crate) mod test {
-    use std::{
-        sync::{Arc, Mutex},
-        time::Duration,
-    };
+    #[test]
+    fn test_iter_depth_first() {
+        let call_trees = collect_call_trees(|mock| {
+            tracing::info_span!("request").in_scope(|| {
+                tracing::info_span!("a").in_scope(|| {});
+                tracing::info_span!("b").in_scope(|| one_ns(&mock));
+                tracing::info_span!("a").in_scope(|| {
+                    tracing::info_span!("c").in_scope(|| one_ns(&mock));
+                });
+            })
+        });
+        let pool = &call_trees[0];
+        let tree = pool
+            .iter_depth_first()
+            .map(|(depth, timing)| {
+                let parent = timing.parent().map(|parent| pool[parent].name());
+                (depth, timing.name(), parent)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tree,
+            vec![
+                (0, "request", None),
+                (1, "a", Some("request")),
+                (1, "b", Some("request")),
+                (2, "one_ns", Some("b")),
+                (1, "a", Some("request")),
+                (2, "c", Some("a")),
+                (3, "one_ns", Some("c")),
+            ]
+        );
+        assert_eq!(pool.iter_depth_first().count(), pool.call_path_count());
+    }
 
-    use futures::channel::mpsc::{channel, Receiver, Sender};
-    use quanta::{Clock, Mock};
-    use tracing::{info, Instrument};
-    use tracing_subscriber::fmt;
+    #[test]
+    fn test_visit() {
+        use super::CallTreeVisitor;
+        use crate::CallPathTiming;
 
-    use crate::{CallPathPool, CallTreeCollectorBuilder, FinishedCallTreeProcessor};
+        /// Records the visited call paths, leaving ones with a `/`.
+        #[derive(Default)]
+        struct Recorder(Vec<String>);
 
-    #[tracing::instrument]
-    pub fn one_ns(mock: &Mock) {
-        mock.increment(1);
+        impl CallTreeVisitor for Recorder {
+            fn enter_node(&mut self, depth: usize, node: &CallPathTiming) {
+                self.0.push(format!("{}{}", depth, node.name()));
+            }
+
+            fn leave_node(&mut self, depth: usize, node: &CallPathTiming) {
+                self.0.push(format!("/{}{}", depth, node.name()));
+            }
+        }
+
+        let call_trees = collect_call_trees(|mock| {
+            tracing::info_span!("request").in_scope(|| {
+                tracing::info_span!("a").in_scope(|| one_ns(&mock));
+                tracing::info_span!("b").in_scope(|| {});
+            })
+        });
+        let mut recorder = Recorder::default();
+        call_trees[0].visit(&mut recorder);
+        assert_eq!(
+            recorder.0,
+            vec![
+                "0request",
+                "1a",
+                "2one_ns",
+                "/2one_ns",
+                "/1a",
+                "1b",
+                "/1b",
+                "/0request"
+            ]
+        );
+        // Both callbacks are optional.
+        struct Nothing;
+        impl CallTreeVisitor for Nothing {}
+        call_trees[0].visit(&mut Nothing);
     }
 
     #[test]
-    fn test_simple() {
+    fn test_min_max_mean() {
         let call_trees = collect_call_trees(|mock| {
-            one_ns(&mock);
+            tracing::info_span!("repeating").in_scope(|| {
+                for nanos in [10, 60, 20] {
+                    tracing::info_span!("repeated").in_scope(|| mock.increment(nanos));
+                }
+            })
         });
 
-        assert_eq!(call_trees.len(), 1, "{:#?}", call_trees);
-
         let first_call = &call_trees[0];
-        assert_eq!(first_call.pool.len(), 1, "{:#?}", first_call.pool);
-        let first_call_root = first_call.root();
-        assert_eq!(
-            first_call_root.static_span_meta().name(),
-            "one_ns",
-            "{:#?}",
-            first_call
+        let children = first_call.root().children().copied().collect::<Vec<_>>();
+        let repeated = &first_call[children[0]];
+        assert_eq!(repeated.min_busy(), Duration::from_nanos(10));
+        assert_eq!(repeated.max_busy(), Duration::from_nanos(60));
+        assert_eq!(repeated.mean_busy(), Duration::from_nanos(30));
+        assert_eq!(first_call.root().min_busy(), first_call.root().max_busy());
+        assert_eq!(repeated.busy_p50(), None);
+        // More calls than fit into a u32.
+        #[cfg(target_pointer_width = "64")]
+        {
+            let mut many = repeated.clone();
+            many.outer_call_count = 1 << 33;
+            many.sum_with_children = Duration::from_secs(3 << 33);
+            assert_eq!(many.mean_busy(), Duration::from_secs(3));
+        }
+    }
+
+    #[cfg(all(feature = "cpu_time", unix))]
+    #[test]
+    fn test_cpu_time() {
+        let call_trees = collect_call_trees_with_builder(
+            CallTreeCollectorBuilder::default().cpu_time(true),
+            |_mock| {
+                tracing::info_span!("request").in_scope(|| {
+                    tracing::info_span!("compute").in_scope(|| {
+                        let start = std::time::Instant::now();
+                        while start.elapsed() < Duration::from_millis(20) {
+                            std::hint::spin_loop();
+                        }
+                    });
+                    tracing::info_span!("sleep")
+                        .in_scope(|| std::thread::sleep(Duration::from_millis(20)));
+                })
+            },
         );
-        assert_eq!(first_call_root.call_count(), 1, "{:#?}", first_call);
-        assert_eq!(
-            first_call_root.sum_with_children(),
-            Duration::from_nanos(1),
-            "{:#?}",
-            first_call
+
+        let root = call_trees[0].root();
+        let mut children = root.children().copied().collect::<Vec<_>>();
+        children.sort();
+        let compute = &call_trees[0][children[0]];
+        let sleep = &call_trees[0][children[1]];
+        assert!(
+            compute.sum_cpu() >= Duration::from_millis(5),
+            "{:?}",
+            compute
+        );
+        assert!(sleep.sum_cpu() < Duration::from_millis(5), "{:?}", sleep);
+        assert!(root.sum_cpu() >= compute.sum_cpu() + sleep.sum_cpu());
+    }
+
+    #[test]
+    fn test_histograms() {
+        let call_trees = collect_call_trees_with_builder(
+            CallTreeCollectorBuilder::default().with_histograms(true),
+            |mock| {
+                tracing::info_span!("repeating").in_scope(|| {
+                    for i in 0..100 {
+                        let nanos = if i == 99 { 1_000 } else { 4 };
+                        tracing::info_span!("repeated").in_scope(|| mock.increment(nanos));
+                    }
+                })
+            },
         );
+
+        let first_call = &call_trees[0];
+        let children = first_call.root().children().copied().collect::<Vec<_>>();
+        let repeated = &first_call[children[0]];
+        assert_eq!(repeated.busy_histogram().unwrap().count(), 100);
+        assert_eq!(repeated.busy_p50(), Some(Duration::from_nanos(4)));
+        assert_eq!(repeated.busy_p99(), Some(Duration::from_nanos(4)));
         assert_eq!(
-            first_call_root.sum_without_children(),
-            Duration::from_nanos(1),
-            "{:#?}",
-            first_call
+            repeated.busy_histogram().unwrap().percentile(100.0),
+            Some(Duration::from_nanos(1_000))
         );
     }
 
-    #[tracing::instrument]
-    pub fn compound_call(mock: &Mock) {
-        mock.increment(10);
-        one_ns(mock);
-        mock.increment(100);
-        one_ns(mock);
-        one_ns(mock);
-        mock.increment(1000);
+    #[test]
+    fn test_events() {
+        let call_trees = collect_call_trees_with_builder(
+            CallTreeCollectorBuilder::default().collect_events(true),
+            |mock| {
+                tracing::info_span!("incrementing").in_scope(|| {
+                    for _ in 0..3 {
+                        info!("increment 1_000");
+                        mock.increment(1_000);
+                    }
+                    one_ns(&mock);
+                })
+            },
+        );
+
+        let first_call = &call_trees[0];
+        let mut children = first_call.root().children().copied().collect::<Vec<_>>();
+        children.sort();
+        let events = &first_call[children[0]];
+        assert!(events.is_event());
+        assert_eq!(events.name(), "increment 1_000");
+        assert_eq!(events.call_count(), 3);
+        assert_eq!(events.sum_with_children(), Duration::default());
+        assert!(!first_call[children[1]].is_event());
     }
 
     #[test]
-    fn test_compound() {
-        let call_trees = collect_call_trees(|mock| {
+    fn test_sampling() {
+        let sampled = |sample_rate| {
+            collect_call_trees_with_builder(
+                CallTreeCollectorBuilder::default().sample_rate(sample_rate),
+                |mock| {
+                    for _ in 0..1_000 {
+                        compound_call(&mock);
+                    }
+                },
+            )
+        };
+
+        assert_eq!(sampled(0.0).len(), 0);
+        assert_eq!(sampled(1.0).len(), 1_000);
+        let half = sampled(0.5);
+        assert!(350 < half.len() && half.len() < 650, "{}", half.len());
+        assert!(half.iter().all(|pool| pool.pool.len() == 2));
+    }
+
+    #[test]
+    fn test_runtime_reconfiguration() {
+        use tracing_subscriber::prelude::*;
+
+        let call_trees = FinishedCallTreeStore::default();
+        let (clock, mock) = Clock::mock();
+        let collector = CallTreeCollectorBuilder::default()
+            .clock(clock)
+            .enabled(false)
+            .build_with_collector(call_trees.clone());
+        let handle = collector.handle();
+        let nested = || {
+            tracing::info_span!("a").in_scope(|| {
+                tracing::info_span!("b").in_scope(|| tracing::info_span!("c").in_scope(|| {}))
+            })
+        };
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(collector), || {
+            compound_call(&mock);
+            handle.set_enabled(true);
             compound_call(&mock);
+
+            handle.set_sample_rate(0.0);
+            assert_eq!(handle.sample_rate(), 0.0);
+            compound_call(&mock);
+            handle.set_sample_rate(1.0);
+
+            // Call trees in flight keep their maximum call depth.
+            tracing::info_span!("in_flight").in_scope(|| {
+                handle.set_max_call_depth(2);
+                nested();
+            });
+            nested();
         });
 
-        assert_eq!(call_trees.len(), 1, "{:#?}", call_trees);
+        let call_trees = call_trees.into_vec();
+        let roots = call_trees
+            .iter()
+            .map(|pool| pool.root().name())
+            .collect::<Vec<_>>();
+        assert_eq!(roots, vec!["compound_call", "in_flight", "a"]);
+        assert_eq!(call_trees[1].call_path_count(), 4);
+        call_trees[1].check_invariants(10).unwrap();
+        assert_eq!(call_trees[2].call_path_count(), 3);
+        call_trees[2].check_invariants(2).unwrap();
+    }
 
-        let first_call = &call_trees[0];
-        assert_eq!(first_call.pool.len(), 2, "{:#?}", first_call.pool);
+    fn mutual_recursion(mock: &Mock, depth: usize) {
+        tracing::info_span!("a").in_scope(|| {
+            mock.increment(10);
+            tracing::info_span!("b").in_scope(|| {
+                mock.increment(100);
+                if depth > 1 {
+                    mutual_recursion(mock, depth - 1);
+                } else {
+                    one_ns(mock);
+                }
+            })
+        })
+    }
 
-        let first_call_root = first_call.root();
-        assert_eq!(
-            first_call_root.static_span_meta().name(),
-            "compound_call",
-            "{:#?}",
-            first_call
+    #[test]
+    fn test_collapse_recursion() {
+        let call_trees = collect_call_trees_with_builder(
+            CallTreeCollectorBuilder::default().collapse_recursion(true),
+            |mock| {
+                tracing::info_span!("request").in_scope(|| mutual_recursion(&mock, 3));
+            },
         );
-        assert_eq!(first_call_root.call_count(), 1, "{:#?}", first_call);
+        let pool = &call_trees[0];
+        pool.check_invariants(10).unwrap();
+        let rows = pool
+            .pool
+            .iter()
+            .map(|timing| {
+                (
+                    timing.name(),
+                    timing.depth,
+                    timing.call_count(),
+                    timing.sum_with_children().as_nanos(),
+                    timing.sum_without_children().as_nanos(),
+                    timing.recursion_depth(),
+                )
+            })
+            .collect::<Vec<_>>();
         assert_eq!(
-            first_call_root.sum_with_children(),
-            Duration::from_nanos(1113),
-            "{:#?}",
-            first_call
+            rows,
+            vec![
+                ("request", 0, 1, 331, 0, 0),
+                ("a", 1, 3, 331, 30, 3),
+                ("b", 2, 3, 321, 300, 3),
+                ("one_ns", 3, 1, 1, 1, 0),
+            ]
+        );
+        // Only the outermost call of each call path is a separate call.
+        assert_eq!(pool.pool[1].mean_busy(), Duration::from_nanos(331));
+        assert_eq!(pool.pool[2].mean_busy(), Duration::from_nanos(321));
+    }
+
+    #[test]
+    fn test_excluded_spans() {
+        use std::collections::HashMap;
+
+        use tracing::Level;
+
+        use crate::SpanFilter;
+
+        let call_trees = collect_call_trees_with_builder(
+            CallTreeCollectorBuilder::default()
+                .collect_events(true)
+                .exclude_spans(SpanFilter::level(Level::TRACE))
+                .include_spans(SpanFilter::name("kept")),
+            |mock| {
+                tracing::info_span!("request").in_scope(|| {
+                    mock.increment(10);
+                    tracing::trace_span!("poll").in_scope(|| {
+                        mock.increment(100);
+                        tracing::trace_span!("poll_inner").in_scope(|| {
+                            tracing::info!("inlined event");
+                            tracing::info_span!("query").in_scope(|| mock.increment(1000));
+                        });
+                        tracing::trace_span!("kept").in_scope(|| mock.increment(1));
+                    });
+                })
+            },
         );
+        assert_eq!(call_trees.len(), 1);
+        let pool = &call_trees[0];
+        pool.check_invariants(10).unwrap();
+        let root = pool.root();
+        assert_eq!(root.name(), "request");
+        assert_eq!(root.sum_with_children(), Duration::from_nanos(1111));
+        assert_eq!(root.sum_without_children(), Duration::from_nanos(110));
+        let children = root
+            .children()
+            .map(|idx| (pool[*idx].name(), pool[*idx].sum_with_children().as_nanos()))
+            .collect::<HashMap<_, _>>();
         assert_eq!(
-            first_call_root.sum_without_children(),
-            Duration::from_nanos(1110),
-            "{:#?}",
-            first_call
+            children,
+            [("inlined event", 0), ("query", 1000), ("kept", 1)]
+                .iter()
+                .copied()
+                .collect()
         );
-        assert_eq!(first_call_root.children().count(), 1, "{:#?}", call_trees);
+    }
 
-        let nested_call_idx = *first_call_root.children().next().unwrap();
-        let nested_call = &first_call[nested_call_idx];
-        assert_eq!(nested_call.static_span_meta().name(), "one_ns");
-        assert_eq!(nested_call.call_count(), 3);
-        assert_eq!(nested_call.sum_with_children(), Duration::from_nanos(3));
-        assert_eq!(nested_call.sum_without_children(), Duration::from_nanos(3));
+    #[test]
+    fn test_span_budget() {
+        let call_trees = collect_call_trees_with_builder(
+            CallTreeCollectorBuilder::default().max_spans_per_tree(3),
+            |mock| {
+                compound_call(&mock);
+                one_ns(&mock);
+            },
+        );
+
+        let degraded = &call_trees[0];
+        assert!(degraded.is_degraded());
+        assert_eq!(degraded.skipped_spans(), 1);
+        let children = degraded.root().children().copied().collect::<Vec<_>>();
+        assert_eq!(degraded[children[0]].call_count(), 2);
+
+        let complete = &call_trees[1];
+        assert!(!complete.is_degraded());
+        assert_eq!(complete.skipped_spans(), 0);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() {
+        let call_trees = collect_call_trees_with_builder(
+            CallTreeCollectorBuilder::default().with_histograms(true),
+            |mock| compound_call(&mock),
+        );
+        let json = serde_json::to_string(&call_trees[0]).unwrap();
+        let pool: CallPathPool = serde_json::from_str(&json).unwrap();
+        pool.check_invariants(10).unwrap();
+
+        let root = pool.root();
+        assert!(root.try_static_span_meta().is_none());
+        assert_eq!(root.span_metadata(), call_trees[0].root().span_metadata());
+        assert_eq!(root.span_metadata().level(), tracing::Level::INFO);
+        assert_eq!(root.sum_with_children(), Duration::from_nanos(1113));
+        let child = *root.children().next().unwrap();
+        assert_eq!(pool[child].call_count(), 3);
+        assert_eq!(pool[child].busy_p50(), Some(Duration::from_nanos(1)));
+        assert_eq!(pool.subtree(child).root().name(), "one_ns");
+    }
+
+    #[cfg(feature = "dhat")]
+    #[test]
+    fn test_heap_stats() {
+        let _profiler = dhat::Profiler::builder().testing().build();
+        let call_trees = collect_call_trees_with_builder(
+            CallTreeCollectorBuilder::default().heap_stats_rate(1.0),
+            |mock| compound_call(&mock),
+        );
+        assert!(call_trees[0].heap_summary().is_some());
+
+        let call_trees = collect_call_trees(|mock| compound_call(&mock));
+        assert!(call_trees[0].heap_summary().is_none());
     }
 
     #[tracing::instrument(skip(mock, receiver))]
@@ -477,7 +3888,7 @@ pub(crate) mod test {
         // Use "no" buffer (which means a buffer of one for each sender)
         // to enforce a deterministic order.
         let (sender, receiver) = channel(0);
-        use tracing_futures::WithSubscriber;
+        use crate::task::XrayFutureExt;
         info!("CP increment 1_000_000");
         mock.increment(1_000_000);
 
@@ -486,8 +3897,7 @@ pub(crate) mod test {
             async {
                 eat_three(mock, receiver).await;
             }
-            .in_current_span()
-            .with_current_subscriber()
+            .in_current_xray()
         });
         cook_three(mock.clone(), sender).await;
 
@@ -496,6 +3906,58 @@ pub(crate) mod test {
         mock.increment(100_000_000);
     }
 
+    #[test]
+    fn test_abandoned_call_trees_discarded_by_default() {
+        use tracing_subscriber::prelude::*;
+
+        let call_trees = FinishedCallTreeStore::default();
+        let (clock, mock) = Clock::mock();
+        let collector = CallTreeCollectorBuilder::default()
+            .clock(clock)
+            .build_with_collector(call_trees.clone());
+        let stats = collector.stats();
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(collector), || {
+            let leaked = tracing::info_span!("leaked");
+            leaked.in_scope(|| one_ns(&mock));
+            tracing::dispatcher::get_default(|dispatch| dispatch.clone_span(&leaked.id().unwrap()));
+        });
+
+        assert_eq!(stats.abandoned_trees(), 0);
+        assert!(call_trees.into_vec().is_empty());
+    }
+
+    #[test]
+    fn test_abandoned_call_trees() {
+        use tracing_subscriber::prelude::*;
+
+        let call_trees = FinishedCallTreeStore::default();
+        let (clock, mock) = Clock::mock();
+        let collector = CallTreeCollectorBuilder::default()
+            .clock(clock)
+            .hand_over_abandoned(true)
+            .build_with_collector(call_trees.clone());
+        let stats = collector.stats();
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(collector), || {
+            tracing::info_span!("finished").in_scope(|| one_ns(&mock));
+            let leaked = tracing::info_span!("leaked");
+            leaked.in_scope(|| one_ns(&mock));
+            // Keeps the span open without keeping the subscriber alive.
+            tracing::dispatcher::get_default(|dispatch| dispatch.clone_span(&leaked.id().unwrap()));
+        });
+
+        assert_eq!(stats.abandoned_trees(), 1);
+        let call_trees = call_trees.into_vec();
+        let roots = call_trees
+            .iter()
+            .map(|pool| pool.root().name())
+            .collect::<Vec<_>>();
+        assert_eq!(roots, vec!["finished", "leaked"]);
+        let leaked = &call_trees[1];
+        assert_eq!(leaked.root().call_count(), 0);
+        let children = leaked.root().children().copied().collect::<Vec<_>>();
+        assert_eq!(leaked[children[0]].call_count(), 1);
+    }
+
     #[test]
     fn test_with_futures() {
         let call_tree = collect_call_trees(|mock| {
@@ -510,12 +3972,19 @@ pub(crate) mod test {
     }
 
     pub fn collect_call_trees(call: impl Fn(Arc<Mock>)) -> Vec<CallPathPool> {
+        collect_call_trees_with_builder(CallTreeCollectorBuilder::default(), call)
+    }
+
+    pub fn collect_call_trees_with_builder(
+        builder: CallTreeCollectorBuilder,
+        call: impl Fn(Arc<Mock>),
+    ) -> Vec<CallPathPool> {
         use tracing_subscriber::prelude::*;
 
         let call_trees = FinishedCallTreeStore::default();
         {
             let (clock, mock) = Clock::mock();
-            let call_tree_collector = CallTreeCollectorBuilder::default()
+            let call_tree_collector = builder
                 .clock(clock)
                 .build_with_collector(call_trees.clone());
             let fmt_layer = fmt::layer()
@@ -533,7 +4002,7 @@ pub(crate) mod test {
     }
 
     #[derive(Clone, Default)]
-    struct FinishedCallTreeStore {
+    pub(crate) struct FinishedCallTreeStore {
         store: Arc<Mutex<Vec<CallPathPool>>>,
     }
 
@@ -558,4 +4027,20 @@ pub(crate) mod test {
             guard.push(pool);
         }
     }
+
+    #[test]
+    fn test_shared_pool() {
+        let pool = collect_call_trees(|mock| compound_call(&mock)).remove(0);
+        let shared = Arc::new(pool);
+        let store = FinishedCallTreeStore::default();
+        store.process_shared_call(shared.clone());
+        store.process_shared_call(shared);
+
+        let stored = store.into_vec();
+        assert_eq!(stored.len(), 2);
+        for pool in stored {
+            assert_eq!(pool.pool.len(), 2);
+            assert_eq!(pool.root().name(), "compound_call");
+        }
+    }
 }
@@ -0,0 +1,70 @@
+//! Minimal JSON output without a serialization dependency.
+
+use std::io::{self, Write};
+
+#[cfg(feature = "display")]
+use crate::{CallPathPool, CallPathTiming};
+
+/// Writes `str` as a JSON string literal.
+pub(crate) fn write_json_str(str: &str, out: &mut impl Write) -> io::Result<()> {
+    out.write_all(b"\"")?;
+    for c in str.chars() {
+        match c {
+            '"' => out.write_all(b"\\\"")?,
+            '\\' => out.write_all(b"\\\\")?,
+            '\n' => out.write_all(b"\\n")?,
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32)?,
+            c => write!(out, "{}", c)?,
+        }
+    }
+    out.write_all(b"\"")
+}
+
+/// Writes `node` and its children as nested JSON objects, children in the
+/// order of their creation. Durations are in nanoseconds, events have
+/// none.
+#[cfg(feature = "display")]
+pub(crate) fn write_call_tree(
+    pool: &CallPathPool,
+    node: &CallPathTiming,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    out.write_all(b"{\"name\":")?;
+    write_json_str(node.name(), out)?;
+    write!(out, ",\"calls\":{}", node.call_count())?;
+    if node.is_event() {
+        out.write_all(b",\"event\":true")?;
+    } else {
+        write!(
+            out,
+            ",\"alive_ns\":{},\"busy_ns\":{},\"own_busy_ns\":{},\"io_wait_ns\":{}",
+            node.span_alive().as_nanos(),
+            node.sum_with_children().as_nanos(),
+            node.sum_without_children().as_nanos(),
+            node.sum_io_wait().as_nanos()
+        )?;
+    }
+    if node.error_count() > 0 {
+        write!(out, ",\"errors\":{}", node.error_count())?;
+    }
+    if node.warn_count() > 0 {
+        write!(out, ",\"warnings\":{}", node.warn_count())?;
+    }
+    if node.is_retry() {
+        out.write_all(b",\"retry\":true")?;
+    }
+
+    let mut children = node.children().copied().collect::<Vec<_>>();
+    if !children.is_empty() {
+        children.sort();
+        out.write_all(b",\"children\":[")?;
+        for (idx, child_idx) in children.into_iter().enumerate() {
+            if idx > 0 {
+                out.write_all(b",")?;
+            }
+            write_call_tree(pool, &pool[child_idx], out)?;
+        }
+        out.write_all(b"]")?;
+    }
+    out.write_all(b"}")
+}
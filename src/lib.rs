@@ -47,6 +47,7 @@
 //! this, then you simply need to add the highlighted line:
 //!
 //! ```
+//! # #[cfg(feature = "display")] {
 //!     use reqray::CallTreeCollector;
 //!     use tracing_subscriber::{EnvFilter, util::SubscriberInitExt, fmt, prelude::*};
 //!
@@ -63,13 +64,42 @@
 //!         .with(filter_layer)
 //!         .with(fmt_layer)
 //!         .init();
+//! # }
 //! ```
 //!
+//! Spans can leave breadcrumbs in the call tree with a `reqray.note` field.
+//! The distinct notes of every call path are listed below the table:
+//!
+//! ```
+//! let span = tracing::info_span!("lookup", reqray.note = "cache miss");
+//! ```
+//!
+//! Similarly, spans with a `reqray.attempt` field greater than `1` are
+//! aggregated in a separate `(attempt 2+)` row, so retries are not mixed up
+//! with first attempts. And spans with a boolean `cache.hit` field are
+//! counted as cache hits or misses, e.g. `query (hit 943 / miss 57)`.
+//! Spans with a `reqray.root = true` field start their own call tree, see
+//! [CallTreeCollectorBuilder::root_predicate].
+//!
+//! The call tree table lives behind the default `display` feature. If you
+//! only need the [CallTreeCollector] layer together with your own
+//! [FinishedCallTreeProcessor], e.g. to keep compile times and binary size
+//! down, disable the default features:
+//!
+//! ```toml
+//! [dependencies]
+//! reqray = { version = "0.4", default-features = false }
+//! ```
+//!
+//! Processors and exporters beyond the table, e.g. `aggregate` or
+//! `processors`, are opt-in features.
+//!
 //! Instead of `CallTreeCollector::default()` you can chose a more explicit
 //! config using [CallTreeCollectorBuilder] and
 //! [LoggingCallTreeCollectorBuilder].
 //!
 //! ```
+//! # #[cfg(feature = "display")] {
 //! use reqray::{CallTreeCollectorBuilder, display::LoggingCallTreeCollectorBuilder};
 //! use tracing_subscriber::{EnvFilter, util::SubscriberInitExt, fmt, prelude::*};
 //!
@@ -92,17 +122,110 @@
 //! #    .with(filter_layer)
 //! #    .with(fmt_layer)
 //! #    .init();
+//! # }
 //! ```
 
+#[cfg(feature = "aggregate")]
+pub mod aggregate;
+#[cfg(feature = "anomalies")]
+pub mod anomalies;
+#[cfg(any(
+    feature = "folded",
+    feature = "chrome_trace",
+    feature = "graph",
+    feature = "html",
+    feature = "otel"
+))]
+mod callsite_cache;
+#[cfg(feature = "cardinality")]
+mod cardinality;
+#[cfg(feature = "chrome_trace")]
+pub mod chrome_trace;
+#[cfg(feature = "ci")]
+pub mod ci;
+mod clock;
+#[cfg(feature = "cpu_time")]
+mod cpu;
+#[cfg(feature = "csv")]
+pub mod csv;
+#[cfg(feature = "diff")]
+pub mod diff;
+#[cfg(feature = "display")]
 pub mod display;
+#[cfg(feature = "display")]
+mod dynamic_event;
+mod env;
+mod filter;
+#[cfg(feature = "folded")]
+pub mod folded;
+#[cfg(any(test, feature = "fuzz"))]
+pub mod fuzz;
+#[cfg(feature = "graph")]
+pub mod graph;
+mod handle;
+mod handover;
+mod heap;
+mod histogram;
+#[cfg(feature = "html")]
+pub mod html;
 mod internal;
+#[cfg(any(feature = "display", feature = "chrome_trace"))]
+mod json;
+mod meta;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "openmetrics")]
+pub mod openmetrics;
+#[cfg(feature = "otel")]
+pub mod otel;
+#[cfg(any(test, feature = "processors"))]
+pub mod processors;
+#[cfg(feature = "proto")]
+pub mod proto;
+#[cfg(feature = "replay")]
+pub mod replay;
+mod snapshot;
+mod stats;
+mod sync;
+#[cfg(any(test, feature = "task"))]
+pub mod task;
+#[cfg(any(test, feature = "test_util"))]
+pub mod test_util;
+#[cfg(feature = "display")]
+pub mod writer;
 
+use std::{
+    borrow::Cow,
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Once,
+    },
+    time::Duration,
+};
+
+use clock::SharedClock;
+#[cfg(feature = "display")]
 use display::{LoggingCallTreeCollector, LoggingCallTreeCollectorBuilder};
-use quanta::Clock;
+use tracing::Metadata;
 
 // These are internal and republished here to force code in the
 // display model to use the public interface.
-pub use internal::{CallPathPool, CallPathPoolId, CallPathTiming};
+pub use clock::{InstantClock, ReqrayClock};
+pub use filter::SpanFilter;
+pub use handle::CallTreeCollectorHandle;
+pub use heap::HeapSummary;
+pub use histogram::BusyHistogram;
+pub use internal::{
+    CallPathPool, CallPathPoolId, CallPathTiming, CallTreeVisitor, FieldValues, ThreadBusy,
+    TimeSplit,
+};
+pub use meta::SpanMetadata;
+pub use snapshot::{CallPathSnapshot, CallTreeSnapshot};
+pub use stats::{CollectorStats, InFlightTree, Watchdog};
 
 /// A [tracing::Subscriber] which collects call trees and hands finished trees
 /// to a [FinishedCallTreeProcessor].
@@ -114,12 +237,174 @@ pub use internal::{CallPathPool, CallPathPoolId, CallPathTiming};
 /// [LoggingCallTreeCollectorBuilder] to customize your setup.
 pub struct CallTreeCollector<H: FinishedCallTreeProcessor + 'static> {
     /// The clock to use for determing call timings.
-    clock: Clock,
-    /// Ignore calls beyond this depth.
-    max_call_depth: usize,
+    clock: SharedClock,
+    /// Maps span names to the names used in the call tree.
+    span_name_mapper: Option<SpanNameMapper>,
+    /// `None` if spans without parents start call trees.
+    root_predicate: Option<RootPredicate>,
+    /// Spans which are inlined into their parents unless included.
+    excluded_spans: Vec<SpanFilter>,
+    /// Overrides `excluded_spans`.
+    included_spans: Vec<SpanFilter>,
+    /// Merge spans into call paths of the same callsite above them.
+    collapse_recursion: bool,
+    /// Move new call trees into the call trees they follow from.
+    adopt_follows_from: bool,
+    /// A span field whose value overrides the span name.
+    name_field: Option<&'static str>,
+    /// Span fields whose values are aggregated per call path.
+    captured_fields: Vec<&'static str>,
+    /// Fields of root spans which are kept with the call tree.
+    context_fields: Vec<&'static str>,
+    /// The relative deviation of quanta from [std::time::Instant] if
+    /// `clock` fell back to an [InstantClock] because of it, see
+    /// [CallTreeCollectorBuilder::verify_clock].
+    unreliable_clock_skew: Option<f64>,
+    /// Ensures that we warn about the clock fallback only once.
+    clock_warning: Once,
+    /// Stop collecting spans of a call tree after spending this much time
+    /// in its callbacks.
+    max_layer_time_per_tree: Option<Duration>,
+    /// Counters and in-flight call trees.
+    stats: CollectorStats,
+    /// Count events as leaf call paths.
+    collect_events: bool,
+    /// Record a histogram of busy times per call path.
+    histograms: bool,
+    /// Record the busy time per thread and call path.
+    per_thread_busy: bool,
+    /// Record the CPU time per call path.
+    #[cfg(feature = "cpu_time")]
+    cpu_time: bool,
+    /// Decides whether to collect a call tree before the sample rate.
+    sampler: Option<Sampler>,
+    /// Settings which can be changed at runtime: whether to collect call
+    /// trees at all, the sample rate, the maximum call depth and the
+    /// maximum number of spans per tree.
+    settings: CallTreeCollectorHandle,
+    /// The state of the random number generator for sampling.
+    sample_state: AtomicU64,
+    /// Only record heap stats if a random number is below this threshold.
+    #[cfg(feature = "dhat")]
+    heap_stats_threshold: Option<u64>,
     processor: H,
+    /// Call trees whose root span was not closed yet, handed over on drop,
+    /// see [CallTreeCollectorBuilder::hand_over_abandoned].
+    pending_trees: Option<internal::PendingTrees>,
 }
 
+/// Maps a span name to the name recorded for its call path.
+type SpanNameMapper = Box<dyn Fn(&str) -> String + Send + Sync + 'static>;
+
+/// Decides whether a span starts a new call tree.
+type RootPredicate = Box<dyn Fn(&Metadata<'_>) -> bool + Send + Sync + 'static>;
+
+/// Decides whether to collect the call tree of a root span, `None` to
+/// leave it to the sample rate.
+type Sampler = Box<dyn Fn(&Metadata<'_>) -> Option<bool> + Send + Sync + 'static>;
+
+impl<H: FinishedCallTreeProcessor + 'static> CallTreeCollector<H> {
+    /// The number of call trees so far which exceeded the per tree budget,
+    /// see [CallTreeCollectorBuilder::max_spans_per_tree].
+    pub fn degraded_trees(&self) -> usize {
+        self.stats.degraded_trees()
+    }
+
+    /// A handle to the statistics of this collector which can be kept
+    /// after the collector was added to a subscriber.
+    pub fn stats(&self) -> CollectorStats {
+        self.stats.clone()
+    }
+
+    /// A handle to change the configuration of this collector at runtime
+    /// which can be kept after the collector was added to a subscriber.
+    pub fn handle(&self) -> CallTreeCollectorHandle {
+        self.settings.clone()
+    }
+
+    /// Whether spans with the given metadata are inlined into their
+    /// parents, see [CallTreeCollectorBuilder::exclude_spans].
+    pub(crate) fn is_excluded(&self, span_meta: &Metadata<'_>) -> bool {
+        self.excluded_spans
+            .iter()
+            .any(|filter| filter.matches(span_meta))
+            && !self
+                .included_spans
+                .iter()
+                .any(|filter| filter.matches(span_meta))
+    }
+
+    /// The name recorded for call paths of spans with the given metadata.
+    pub(crate) fn span_name(&self, span_meta: &'static Metadata<'static>) -> Cow<'static, str> {
+        match &self.span_name_mapper {
+            Some(mapper) => Cow::Owned(mapper(span_meta.name())),
+            None => Cow::Borrowed(span_meta.name()),
+        }
+    }
+
+    /// Warns once if the default clock was found to be unreliable and
+    /// replaced.
+    ///
+    /// We do not warn while building the collector since the subscriber
+    /// is typically not installed yet at that time.
+    pub(crate) fn warn_about_unreliable_clock(&self) {
+        if let Some(skew) = self.unreliable_clock_skew {
+            self.clock_warning.call_once(|| {
+                tracing::warn!(
+                    "The TSC based clock of reqray deviates by {:.0}% from std::time::Instant. \
+                     Falling back to the slower reqray::InstantClock.",
+                    skew * 100.0
+                );
+            });
+        }
+    }
+
+    /// Decides whether to collect the call tree of a new root span.
+    pub(crate) fn sample_root(&self, span_meta: &Metadata<'_>) -> bool {
+        if !self.settings.is_enabled() {
+            return false;
+        }
+        if let Some(sampled) = self.sampler.as_ref().and_then(|sampler| sampler(span_meta)) {
+            return sampled;
+        }
+        match self.settings.sample_threshold() {
+            u64::MAX => true,
+            threshold => self.random() < threshold,
+        }
+    }
+
+    /// Decides whether to record heap stats for a new call tree.
+    #[cfg(feature = "dhat")]
+    pub(crate) fn sample_heap_stats(&self) -> bool {
+        match self.heap_stats_threshold {
+            None => false,
+            Some(threshold) => self.random() < threshold,
+        }
+    }
+
+    /// A random number for sampling decisions.
+    fn random(&self) -> u64 {
+        // SplitMix64 with a shared state, good enough for sampling.
+        let mut z = self
+            .sample_state
+            .fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed)
+            .wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// The name recorded for call paths of spans with a
+    /// [name_field](CallTreeCollectorBuilder::name_field) value.
+    pub(crate) fn recorded_span_name(&self, name: String) -> Cow<'static, str> {
+        match &self.span_name_mapper {
+            Some(mapper) => Cow::Owned(mapper(&name)),
+            None => Cow::Owned(name),
+        }
+    }
+}
+
+#[cfg(feature = "display")]
 impl Default for CallTreeCollector<LoggingCallTreeCollector> {
     fn default() -> Self {
         CallTreeCollectorBuilder::default()
@@ -127,6 +412,27 @@ impl Default for CallTreeCollector<LoggingCallTreeCollector> {
     }
 }
 
+/// Reports that the current span waited `wait` for I/O, e.g. from an
+/// async runtime or an I/O library that measures syscall or completion
+/// queue latency.
+///
+/// The reported times are summed up per call path, see
+/// [CallPathTiming::sum_io_wait], and shown as `∑ io wait ms` column.
+/// This makes network or disk time explicit instead of inferring it
+/// from idle time.
+///
+/// Reports outside of collected spans are ignored. Like
+/// [InFlightTree::partial_call_tree], this only works if the subscriber
+/// of the span is based on a [tracing_subscriber::Registry].
+pub fn report_io_wait(wait: Duration) {
+    report_io_wait_for(&tracing::Span::current(), wait);
+}
+
+/// Reports that `span` waited `wait` for I/O, see [report_io_wait].
+pub fn report_io_wait_for(span: &tracing::Span, wait: Duration) {
+    internal::report_io_wait_for(span, wait);
+}
+
 /// A [FinishedCallTreeProcessor] uses the aggregated call tree for
 /// something useful.
 ///
@@ -138,13 +444,68 @@ impl Default for CallTreeCollector<LoggingCallTreeCollector> {
 /// * Send the call tree elswhere for further aggregation
 pub trait FinishedCallTreeProcessor {
     fn process_finished_call(&self, pool: CallPathPool);
+
+    /// Processes a call tree that is shared with other processors, e.g.
+    /// when fanning out to several processors or handing the tree to a
+    /// background exporter.
+    ///
+    /// The default implementation hands the pool to
+    /// [process_finished_call](Self::process_finished_call), which clones
+    /// it unless this is the last reference. Processors that only read the
+    /// pool should override this to avoid the clone.
+    fn process_shared_call(&self, pool: Arc<CallPathPool>) {
+        let pool = Arc::try_unwrap(pool).unwrap_or_else(|pool| CallPathPool::clone(&pool));
+        self.process_finished_call(pool);
+    }
 }
 
+/// Like [FinishedCallTreeProcessor] but for processors which are naturally
+/// async, e.g. exporters based on an async HTTP or gRPC client.
+///
+/// Call trees finish on the request path, so they are handed to a
+/// background thread first with
+/// [ChannelProcessor::new_async](processors::ChannelProcessor::new_async),
+/// which awaits the returned futures one after another:
+///
+/// ```
+/// # #[cfg(feature = "processors")] {
+/// use std::sync::Arc;
+///
+/// use reqray::{
+///     processors::{ChannelProcessor, QueueFullPolicy},
+///     AsyncFinishedCallTreeProcessor, CallPathPool, CallTreeCollectorBuilder, ProcessorFuture,
+/// };
+///
+/// struct Exporter;
+///
+/// impl AsyncFinishedCallTreeProcessor for Exporter {
+///     fn process_finished_call(&self, pool: Arc<CallPathPool>) -> ProcessorFuture<'_> {
+///         Box::pin(async move {
+///             // e.g. post the call tree to a collector service
+///         })
+///     }
+/// }
+///
+/// let collector = CallTreeCollectorBuilder::default().build_with_collector(
+///     ChannelProcessor::new_async(Exporter, 1_000, QueueFullPolicy::Drop),
+/// );
+/// # }
+/// ```
+pub trait AsyncFinishedCallTreeProcessor {
+    fn process_finished_call(&self, pool: Arc<CallPathPool>) -> ProcessorFuture<'_>;
+}
+
+/// A future returned by an [AsyncFinishedCallTreeProcessor].
+///
+/// It is `Send` so that executors can drive it on any thread.
+pub type ProcessorFuture<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
 /// Configure & Build [CallTreeCollector]s.
 ///
 /// Example:
 ///
 /// ```
+/// # #[cfg(feature = "display")] {
 /// use reqray::{CallTreeCollectorBuilder, display::LoggingCallTreeCollectorBuilder};
 ///
 /// let collector =
@@ -155,51 +516,538 @@ pub trait FinishedCallTreeProcessor {
 ///                  .left_margin(20)
 ///                  .build()
 ///         );
+/// # }
 /// ```
 pub struct CallTreeCollectorBuilder {
-    clock: Option<Clock>,
+    clock: Option<SharedClock>,
+    enabled: bool,
     max_call_depth: usize,
+    span_name_mapper: Option<SpanNameMapper>,
+    root_predicate: Option<RootPredicate>,
+    excluded_spans: Vec<SpanFilter>,
+    included_spans: Vec<SpanFilter>,
+    collapse_recursion: bool,
+    adopt_follows_from: bool,
+    name_field: Option<&'static str>,
+    captured_fields: Vec<&'static str>,
+    context_fields: Vec<&'static str>,
+    verify_clock: bool,
+    max_spans_per_tree: Option<usize>,
+    max_layer_time_per_tree: Option<Duration>,
+    collect_events: bool,
+    histograms: bool,
+    per_thread_busy: bool,
+    #[cfg(feature = "cpu_time")]
+    cpu_time: bool,
+    sample_rate: f64,
+    sampler: Option<Sampler>,
+    track_in_flight: bool,
+    hand_over_abandoned: bool,
+    #[cfg(feature = "dhat")]
+    heap_stats_rate: f64,
 }
 
 impl Default for CallTreeCollectorBuilder {
     fn default() -> Self {
         CallTreeCollectorBuilder {
             clock: None,
+            enabled: true,
             max_call_depth: 10,
+            span_name_mapper: None,
+            root_predicate: None,
+            excluded_spans: Vec::new(),
+            included_spans: Vec::new(),
+            collapse_recursion: false,
+            adopt_follows_from: false,
+            name_field: None,
+            captured_fields: Vec::new(),
+            context_fields: Vec::new(),
+            verify_clock: true,
+            max_spans_per_tree: None,
+            max_layer_time_per_tree: None,
+            collect_events: false,
+            histograms: false,
+            per_thread_busy: false,
+            #[cfg(feature = "cpu_time")]
+            cpu_time: false,
+            sample_rate: 1.0,
+            sampler: None,
+            track_in_flight: false,
+            hand_over_abandoned: false,
+            #[cfg(feature = "dhat")]
+            heap_stats_rate: 0.0,
         }
     }
 }
 
 impl CallTreeCollectorBuilder {
+    /// The default configuration with the settings from these environment
+    /// variables if they are set, like `EnvFilter::from_default_env` in
+    /// `tracing_subscriber`:
+    ///
+    /// * `REQRAY_ENABLED`, e.g. `false`, see [enabled](Self::enabled).
+    /// * `REQRAY_MAX_DEPTH`, e.g. `15`, see
+    ///   [max_call_depth](Self::max_call_depth).
+    /// * `REQRAY_SAMPLE_RATE`, e.g. `0.01`, see
+    ///   [sample_rate](Self::sample_rate).
+    /// * `REQRAY_MAX_SPANS_PER_TREE`, e.g. `10000`, see
+    ///   [max_spans_per_tree](Self::max_spans_per_tree).
+    ///
+    /// Invalid values are ignored and reported on stderr. Settings applied
+    /// to the returned builder override the environment. See
+    /// [LoggingCallTreeCollectorBuilder::from_env] for the output.
+    pub fn from_env() -> Self {
+        Self::from_lookup(&env::process_env)
+    }
+
+    pub(crate) fn from_lookup(lookup: env::Lookup<'_>) -> Self {
+        let mut builder = Self::default();
+        if let Some(enabled) = env::var(lookup, "REQRAY_ENABLED", "true or false", env::parse_bool)
+        {
+            builder = builder.enabled(enabled);
+        }
+        if let Some(depth) = env::var(lookup, "REQRAY_MAX_DEPTH", "a number", |value| {
+            value.parse().ok()
+        }) {
+            builder = builder.max_call_depth(depth);
+        }
+        if let Some(rate) = env::var(
+            lookup,
+            "REQRAY_SAMPLE_RATE",
+            "a number between 0.0 and 1.0",
+            env::parse_rate,
+        ) {
+            builder = builder.sample_rate(rate);
+        }
+        if let Some(max) = env::var(lookup, "REQRAY_MAX_SPANS_PER_TREE", "a number", |value| {
+            value.parse().ok()
+        }) {
+            builder = builder.max_spans_per_tree(max);
+        }
+        builder
+    }
+
     /// The clock to use for measure execution time.
     ///
-    /// The default is to use a real clock, but you can pass
-    /// in a mock clock for testing.
-    pub fn clock(mut self, clock: Clock) -> Self {
-        self.clock = Some(clock);
+    /// The default is a [quanta::Clock](https://docs.rs/quanta) with the
+    /// `quanta` feature and an [InstantClock] otherwise. If the calibration
+    /// of `quanta` is unreliable on your platform, the default falls back to
+    /// an [InstantClock], see [verify_clock](Self::verify_clock). You can
+    /// also pass in a mock clock for testing.
+    pub fn clock(mut self, clock: impl ReqrayClock) -> Self {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Whether to collect call trees right from the start. Enabled by
+    /// default.
+    ///
+    /// Collection can be switched on and off at runtime with
+    /// [CallTreeCollectorHandle::set_enabled].
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
         self
     }
 
     /// The maximum call depth of the call tree to record -- must be
     /// at least `2`.
     ///
-    /// Call paths below this depth are capped -- so their execution
-    /// is recorded as if they were inlined.
+    /// Spans below this depth are aggregated in a single
+    /// `… (deeper calls)` call path below their deepest recorded ancestor,
+    /// see [CallPathTiming::is_overflow].
     pub fn max_call_depth(mut self, max_call_depth: usize) -> Self {
         self.max_call_depth = max_call_depth;
         self
     }
 
+    /// Map span names before they are recorded in the call tree, e.g. to
+    /// strip IDs from generated span names:
+    ///
+    /// ```
+    /// use reqray::CallTreeCollectorBuilder;
+    ///
+    /// let builder = CallTreeCollectorBuilder::default()
+    ///     .span_name_mapper(|name| match name.strip_prefix("job-") {
+    ///         Some(_) => "job-*".to_string(),
+    ///         None => name.to_string(),
+    ///     });
+    /// ```
+    ///
+    /// The mapper is called once per call path, not for every span. The
+    /// mapped name is available as [CallPathTiming::name] and is used by
+    /// all bundled processors.
+    pub fn span_name_mapper<F>(mut self, mapper: F) -> Self
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.span_name_mapper = Some(Box::new(mapper));
+        self
+    }
+
+    /// Start call trees at spans matching `predicate` instead of at spans
+    /// without parents, e.g. at the request span of a web framework which
+    /// is nested in a long-lived server span:
+    ///
+    /// ```
+    /// use reqray::CallTreeCollectorBuilder;
+    ///
+    /// let builder = CallTreeCollectorBuilder::default()
+    ///     .root_predicate(|meta| meta.name() == "http_request");
+    /// ```
+    ///
+    /// The predicate may also check the target, the level or the names of
+    /// the fields of a span. Spans which neither match nor are inside a
+    /// call tree are ignored. Matching spans inside a call tree start a
+    /// new call tree, which is not part of the outer one.
+    ///
+    /// Independent of the predicate, spans with a `reqray.root = true`
+    /// field always start a call tree:
+    ///
+    /// ```
+    /// let span = tracing::info_span!("request", reqray.root = true);
+    /// ```
+    pub fn root_predicate<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&Metadata<'_>) -> bool + Send + Sync + 'static,
+    {
+        self.root_predicate = Some(Box::new(predicate));
+        self
+    }
+
+    /// Leave spans matching `filter` out of call trees, e.g. the many
+    /// `TRACE` spans of `hyper` or `h2`, see [SpanFilter]. Can be called
+    /// multiple times, spans matching any filter are excluded.
+    ///
+    /// Excluded spans are inlined into their parent like in a profiler:
+    /// their busy time counts as own busy time of the closest collected
+    /// ancestor, and their children and events are attached to that
+    /// ancestor. Spans which start call trees are never excluded.
+    pub fn exclude_spans(mut self, filter: SpanFilter) -> Self {
+        self.excluded_spans.push(filter);
+        self
+    }
+
+    /// Keep spans matching `filter` in call trees even if they match a
+    /// filter of [exclude_spans](Self::exclude_spans).
+    pub fn include_spans(mut self, filter: SpanFilter) -> Self {
+        self.included_spans.push(filter);
+        self
+    }
+
+    /// Whether to merge recursive calls into a single call path. Disabled
+    /// by default.
+    ///
+    /// A span is merged into the closest call path above it with the same
+    /// callsite, so that e.g. a recursive tree traversal shows up as one
+    /// row instead of one row per level, and does not hit the
+    /// [maximum call depth](Self::max_call_depth). The call count of the
+    /// call path includes the recursive calls, its busy time only the
+    /// outermost ones, since they include the others. The deepest
+    /// recursion is available as [CallPathTiming::recursion_depth].
+    pub fn collapse_recursion(mut self, collapse_recursion: bool) -> Self {
+        self.collapse_recursion = collapse_recursion;
+        self
+    }
+
+    /// Whether a span which starts a call tree and is linked with
+    /// [tracing::Span::follows_from] to a span in another call tree is
+    /// moved into that call tree, below the call path of the followed
+    /// span. Disabled by default.
+    ///
+    /// This keeps call trees together in message-passing architectures,
+    /// where a worker handles a message in a fresh span:
+    ///
+    /// ```
+    /// # let request = tracing::info_span!("request");
+    /// let handle = tracing::info_span!(parent: None, "handle_message");
+    /// handle.follows_from(&request);
+    /// ```
+    ///
+    /// Only spans which were not entered yet and have no children are
+    /// moved. Other links are recorded as
+    /// [CallPathTiming::follows_from]. A moved span must be closed before
+    /// the root span of the call tree it was moved into, otherwise it is
+    /// not recorded at all.
+    ///
+    /// Spans created with an explicit `parent:` are always part of the
+    /// call tree of that parent.
+    pub fn adopt_follows_from(mut self, adopt_follows_from: bool) -> Self {
+        self.adopt_follows_from = adopt_follows_from;
+        self
+    }
+
+    /// Use the value of this span field as the call path name instead of
+    /// the span name, e.g. `"otel.name"`.
+    ///
+    /// The field is honored both when the span is created and when it is
+    /// recorded later with [tracing::Span::record], so late-bound names
+    /// like a matched route still show up. Since a call path aggregates
+    /// all spans at the same call site, the last recorded value wins.
+    ///
+    /// A configured [span_name_mapper](Self::span_name_mapper) is also
+    /// applied to the field values.
+    pub fn name_field(mut self, name_field: &'static str) -> Self {
+        self.name_field = Some(name_field);
+        self
+    }
+
+    /// Record the distinct values of this span field per call path, e.g.
+    /// `"http.route"`, see [CallPathTiming::captured_fields]. Can be called
+    /// multiple times to capture several fields.
+    ///
+    /// This distinguishes spans of the same call site with different
+    /// meanings, e.g. two routes handled by the same function. Values are
+    /// captured both when the span is created and when it is recorded
+    /// later. The values are shown after the span name in the call tree
+    /// table.
+    ///
+    /// With the `cardinality` feature, the number of distinct values is
+    /// estimated even if there are too many to keep them all, see
+    /// [FieldValues::distinct_count].
+    pub fn capture_field(mut self, field: &'static str) -> Self {
+        if !self.captured_fields.contains(&field) {
+            self.captured_fields.push(field);
+        }
+        self
+    }
+
+    /// Keep the value of this field of root spans with the call tree, e.g.
+    /// `"request_id"`, see [CallPathPool::context]. Can be called multiple
+    /// times to keep several fields.
+    ///
+    /// ```
+    /// use reqray::CallTreeCollectorBuilder;
+    ///
+    /// let builder = CallTreeCollectorBuilder::default()
+    ///     .context_field("request_id")
+    ///     .context_field("http.method");
+    /// ```
+    ///
+    /// The [LoggingCallTreeCollector](display::LoggingCallTreeCollector)
+    /// writes the values in the header of the call tree, so that it can be
+    /// correlated with the other log lines of the same request. Values
+    /// recorded later with [tracing::Span::record] replace earlier ones.
+    pub fn context_field(mut self, field: &'static str) -> Self {
+        if !self.context_fields.contains(&field) {
+            self.context_fields.push(field);
+        }
+        self
+    }
+
+    /// Whether to check the default clock against [std::time::Instant]
+    /// when building the collector. Enabled by default.
+    ///
+    /// The check runs only once per process, but blocks building the first
+    /// collector for about 10 ms, and up to 30 ms if the clock deviates.
+    /// If the clock deviates noticeably, e.g. because of an unreliable TSC
+    /// calibration in a VM, an [InstantClock] is used instead and a warning
+    /// is logged when the first call tree is collected. A clock passed in
+    /// with [clock](Self::clock) is never checked.
+    pub fn verify_clock(mut self, verify_clock: bool) -> Self {
+        self.verify_clock = verify_clock;
+        self
+    }
+
+    /// Stop collecting further spans of a call tree once it contains this
+    /// many spans. Unlimited by default.
+    ///
+    /// This protects the tail latency of requests with pathological
+    /// instrumentation, e.g. a span per item in a huge loop. Spans after
+    /// the limit are not part of the call tree, as if they were never
+    /// created. The call tree is marked as [degraded] and the
+    /// [skipped spans] are counted. [CallTreeCollector::degraded_trees]
+    /// counts the affected call trees.
+    ///
+    /// [degraded]: CallPathPool::is_degraded
+    /// [skipped spans]: CallPathPool::skipped_spans
+    pub fn max_spans_per_tree(mut self, max_spans_per_tree: usize) -> Self {
+        self.max_spans_per_tree = Some(core::cmp::max(1, max_spans_per_tree));
+        self
+    }
+
+    /// Stop collecting further spans of a call tree once this much time
+    /// was spent in its span creation and close callbacks. Unlimited by
+    /// default.
+    ///
+    /// Works like [max_spans_per_tree](Self::max_spans_per_tree), but
+    /// measuring the time needs additional clock reads.
+    pub fn max_layer_time_per_tree(mut self, max_layer_time_per_tree: Duration) -> Self {
+        self.max_layer_time_per_tree = Some(max_layer_time_per_tree);
+        self
+    }
+
+    /// Whether to count events emitted directly inside a span as leaf call
+    /// paths of that span. Disabled by default.
+    ///
+    /// All events of the same call site are counted in one call path named
+    /// after the message of the first event, so e.g. 1 000 `increment`
+    /// log statements in a loop show up as a single row in the call tree.
+    /// Events have no durations. See [CallPathTiming::is_event].
+    pub fn collect_events(mut self, collect_events: bool) -> Self {
+        self.collect_events = collect_events;
+        self
+    }
+
+    /// Whether to record a [BusyHistogram] per call path, see
+    /// [CallPathTiming::busy_histogram]. Disabled by default.
+    ///
+    /// For call paths with many calls, percentiles tell more than the
+    /// mean. A histogram takes up to a few KiB per call path, though.
+    pub fn with_histograms(mut self, histograms: bool) -> Self {
+        self.histograms = histograms;
+        self
+    }
+
+    /// Whether to record which threads executed each call path and how
+    /// much busy time each of them contributed, see
+    /// [CallPathTiming::per_thread_busy]. Disabled by default.
+    pub fn per_thread_busy(mut self, per_thread_busy: bool) -> Self {
+        self.per_thread_busy = per_thread_busy;
+        self
+    }
+
+    /// Whether to record the CPU time of the current thread while spans
+    /// are entered, see [CallPathTiming::sum_cpu]. Disabled by default.
+    ///
+    /// Compared to the busy time, which is wall-clock time, this tells
+    /// whether a slow call path was computing or waiting, e.g. for
+    /// blocking I/O or a lock. Reading the thread clock costs a system
+    /// call on every enter and exit, though. Only supported on Unix.
+    #[cfg(feature = "cpu_time")]
+    pub fn cpu_time(mut self, cpu_time: bool) -> Self {
+        self.cpu_time = cpu_time;
+        self
+    }
+
+    /// The fraction of call trees to collect, between `0.0` and `1.0`. The
+    /// default is `1.0`, i.e. all call trees are collected.
+    ///
+    /// The decision is made randomly when a root span is created. Spans of
+    /// call trees that are not sampled cost little more than a lookup in
+    /// the span registry, so a low sample rate allows running reqray
+    /// permanently in services with high request rates.
+    pub fn sample_rate(mut self, sample_rate: f64) -> Self {
+        self.sample_rate = sample_rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Decides whether to collect the call tree of a root span before the
+    /// [sample rate](Self::sample_rate) does, which only applies if
+    /// `sampler` returns `None`.
+    ///
+    /// E.g. to collect call trees for the same requests as the distributed
+    /// traces, follow the sampling decision of the remote parent with
+    /// [parent_sampling_decision](crate::otel::parent_sampling_decision) of
+    /// the `otel` feature:
+    ///
+    /// ```
+    /// # #[cfg(feature = "otel")] {
+    /// use reqray::CallTreeCollectorBuilder;
+    ///
+    /// let builder = CallTreeCollectorBuilder::default()
+    ///     .sample_rate(0.01)
+    ///     .sampler(reqray::otel::parent_sampling_decision);
+    /// # }
+    /// ```
+    ///
+    /// Disabling the collector with
+    /// [CallTreeCollectorHandle::set_enabled] overrides the sampler.
+    pub fn sampler<F>(mut self, sampler: F) -> Self
+    where
+        F: Fn(&Metadata<'_>) -> Option<bool> + Send + Sync + 'static,
+    {
+        self.sampler = Some(Box::new(sampler));
+        self
+    }
+
+    /// Whether to keep track of call trees whose root span has not been
+    /// closed yet, see [CollectorStats::in_flight]. Disabled by default.
+    ///
+    /// This allows spotting stuck requests while they are still running.
+    /// It costs a global lock when creating and closing root spans.
+    pub fn track_in_flight(mut self, track_in_flight: bool) -> Self {
+        self.track_in_flight = track_in_flight;
+        self
+    }
+
+    /// Whether to hand over the call trees whose root span is still alive
+    /// when the collector is dropped, e.g. because a span was leaked in a
+    /// test or a short CLI, see [CollectorStats::abandoned_trees].
+    /// Disabled by default, so these call trees are discarded.
+    ///
+    /// Like [track_in_flight](Self::track_in_flight), this costs a global
+    /// lock when creating and closing root spans, and a lock of the call
+    /// tree for every span.
+    pub fn hand_over_abandoned(mut self, hand_over_abandoned: bool) -> Self {
+        self.hand_over_abandoned = hand_over_abandoned;
+        self
+    }
+
+    /// The fraction of collected call trees for which to record a
+    /// [HeapSummary] with [dhat](https://docs.rs/dhat), between `0.0` and
+    /// `1.0`. The default is `0.0`.
+    ///
+    /// A dhat heap [Profiler](dhat::Profiler) must be running whenever a
+    /// sampled root span is created or closed, otherwise dhat panics.
+    /// The summary is available as [CallPathPool::heap_summary] and is
+    /// shown in the header of the call tree table.
+    #[cfg(feature = "dhat")]
+    pub fn heap_stats_rate(mut self, heap_stats_rate: f64) -> Self {
+        self.heap_stats_rate = heap_stats_rate.clamp(0.0, 1.0);
+        self
+    }
+
     /// Build the [CallTreeCollector] handing over the finished call trees
     /// to `collector`.
     pub fn build_with_collector<H>(self, processor: H) -> CallTreeCollector<H>
     where
         H: FinishedCallTreeProcessor + 'static,
     {
+        let (clock, unreliable_clock_skew) = match self.clock {
+            Some(clock) => (clock, None),
+            None => clock::default_clock(self.verify_clock),
+        };
+        let stats = CollectorStats::new(clock.clone(), self.track_in_flight);
         CallTreeCollector {
-            clock: self.clock.unwrap_or_else(Clock::new),
-            max_call_depth: core::cmp::max(2, self.max_call_depth),
+            clock,
+            span_name_mapper: self.span_name_mapper,
+            root_predicate: self.root_predicate,
+            excluded_spans: self.excluded_spans,
+            included_spans: self.included_spans,
+            collapse_recursion: self.collapse_recursion,
+            adopt_follows_from: self.adopt_follows_from,
+            name_field: self.name_field,
+            captured_fields: self.captured_fields,
+            context_fields: self.context_fields,
+            unreliable_clock_skew,
+            clock_warning: Once::new(),
+            max_layer_time_per_tree: self.max_layer_time_per_tree,
+            stats,
+            collect_events: self.collect_events,
+            histograms: self.histograms,
+            per_thread_busy: self.per_thread_busy,
+            #[cfg(feature = "cpu_time")]
+            cpu_time: self.cpu_time,
+            sampler: self.sampler,
+            settings: CallTreeCollectorHandle::new(
+                self.enabled,
+                self.sample_rate,
+                self.max_call_depth,
+                self.max_spans_per_tree,
+            ),
+            sample_state: AtomicU64::new(RandomState::new().build_hasher().finish()),
+            #[cfg(feature = "dhat")]
+            heap_stats_threshold: if self.heap_stats_rate <= 0.0 {
+                None
+            } else {
+                Some((self.heap_stats_rate * u64::MAX as f64) as u64)
+            },
             processor,
+            pending_trees: if self.hand_over_abandoned {
+                Some(Default::default())
+            } else {
+                None
+            },
         }
     }
 }
@@ -0,0 +1,80 @@
+use std::borrow::Cow;
+
+use tracing::{Level, Metadata};
+
+/// An owned snapshot of the [Metadata] of the spans of a call path.
+///
+/// Unlike [Metadata], it survives serialization, see
+/// [CallPathTiming::span_metadata](crate::CallPathTiming::span_metadata).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SpanMetadata {
+    name: Cow<'static, str>,
+    target: Cow<'static, str>,
+    #[cfg_attr(feature = "serde", serde(with = "level"))]
+    level: Level,
+    module_path: Option<Cow<'static, str>>,
+    file: Option<Cow<'static, str>>,
+    line: Option<u32>,
+}
+
+impl SpanMetadata {
+    /// The name of the span.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The target of the span, typically the module path.
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// The level of the span.
+    pub fn level(&self) -> Level {
+        self.level
+    }
+
+    /// The module path of the code that created the span.
+    pub fn module_path(&self) -> Option<&str> {
+        self.module_path.as_deref()
+    }
+
+    /// The source file of the code that created the span.
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    /// The line of the code that created the span.
+    pub fn line(&self) -> Option<u32> {
+        self.line
+    }
+}
+
+impl From<&'static Metadata<'static>> for SpanMetadata {
+    fn from(meta: &'static Metadata<'static>) -> Self {
+        SpanMetadata {
+            name: Cow::Borrowed(meta.name()),
+            target: Cow::Borrowed(meta.target()),
+            level: *meta.level(),
+            module_path: meta.module_path().map(Cow::Borrowed),
+            file: meta.file().map(Cow::Borrowed),
+            line: meta.line(),
+        }
+    }
+}
+
+/// Serializes [Level]s as strings like `"INFO"`.
+#[cfg(feature = "serde")]
+mod level {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use tracing::Level;
+
+    pub fn serialize<S: Serializer>(level: &Level, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(level.as_str())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Level, D::Error> {
+        let level = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        level.parse().map_err(D::Error::custom)
+    }
+}
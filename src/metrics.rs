@@ -0,0 +1,288 @@
+//! Derive metrics from finished call trees via the
+//! [metrics](https://docs.rs/metrics) facade.
+//!
+//! For every call path of every finished call tree,
+//! [MetricsCallTreeProcessor] records
+//!
+//! * `reqray_calls_total`: a counter of the spans at the call path,
+//! * `reqray_busy_seconds`: a histogram of the busy time of the call path
+//!   per call tree,
+//!
+//! labeled with the `call_path`, e.g. `request/nested/repeated`, and for
+//! every call tree
+//!
+//! * `reqray_tree_call_paths`: a histogram of the number of call paths,
+//! * `reqray_tree_spans`: a histogram of the number of spans,
+//!
+//! to tune limits like
+//! [max_spans_per_tree](crate::CallTreeCollectorBuilder::max_spans_per_tree),
+//! and with a cap on the number of call paths
+//!
+//! * `reqray_suppressed_call_paths`: a gauge of the distinct call paths
+//!   which were not exported.
+//!
+//! Install
+//! any `metrics` recorder, e.g. a Prometheus exporter, to get cheap
+//! request rates and latencies without separate instrumentation.
+//!
+//! Every call path results in a separate time series. Keep the number of
+//! distinct span names in check, e.g. with
+//! [span_name_mapper](crate::CallTreeCollectorBuilder::span_name_mapper),
+//! or cap the number of exported call paths with
+//! [with_max_call_paths](MetricsCallTreeProcessor::with_max_call_paths).
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashSet},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+};
+
+use crate::{CallPathPool, FinishedCallTreeProcessor};
+
+/// The `call_path` label of call paths beyond
+/// [with_max_call_paths](MetricsCallTreeProcessor::with_max_call_paths).
+pub const OTHER_CALL_PATH: &str = "other";
+
+/// A [FinishedCallTreeProcessor] which records metrics for every call path.
+///
+/// ```
+/// use reqray::{metrics::MetricsCallTreeProcessor, CallTreeCollectorBuilder};
+///
+/// let collector = CallTreeCollectorBuilder::default()
+///     .build_with_collector(MetricsCallTreeProcessor::default());
+/// ```
+pub struct MetricsCallTreeProcessor {
+    prefix: String,
+    separator: String,
+    /// `None` if unlimited.
+    max_call_paths: Option<usize>,
+    call_paths: Mutex<ExportedCallPaths>,
+}
+
+/// The call paths seen so far if their number is capped.
+#[derive(Default)]
+struct ExportedCallPaths {
+    exported: HashSet<String>,
+    /// The hashes of the call paths folded into [OTHER_CALL_PATH].
+    suppressed: HashSet<u64>,
+}
+
+impl Default for MetricsCallTreeProcessor {
+    fn default() -> Self {
+        MetricsCallTreeProcessor {
+            prefix: "reqray".to_string(),
+            separator: "/".to_string(),
+            max_call_paths: None,
+            call_paths: Mutex::new(ExportedCallPaths::default()),
+        }
+    }
+}
+
+impl MetricsCallTreeProcessor {
+    /// The prefix of the metric names, `reqray` by default.
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// The separator between span names in the `call_path` label, `/` by
+    /// default.
+    pub fn with_separator(mut self, separator: impl Into<String>) -> Self {
+        self.separator = separator.into();
+        self
+    }
+
+    /// Exports at most `max_call_paths` distinct call paths, so that
+    /// dynamically named spans cannot explode the number of time series.
+    /// Unlimited by default.
+    ///
+    /// New call paths are exported while there is room, the busiest call
+    /// paths of a call tree first. Once exported, a call path stays
+    /// exported. All other call paths are recorded with the `call_path`
+    /// label [OTHER_CALL_PATH], and `reqray_suppressed_call_paths` gauges
+    /// their distinct number.
+    pub fn with_max_call_paths(mut self, max_call_paths: impl Into<Option<usize>>) -> Self {
+        self.max_call_paths = max_call_paths.into();
+        self
+    }
+
+    /// The call paths of `pool` with their `call_path` labels, the
+    /// busiest first if the call paths are capped.
+    fn labeled_call_paths<'a>(
+        &'a self,
+        pool: &'a CallPathPool,
+    ) -> Vec<(String, &'a crate::CallPathTiming)> {
+        let mut call_paths = pool.flatten(&self.separator).collect::<Vec<_>>();
+        let max_call_paths = match self.max_call_paths {
+            Some(max_call_paths) => max_call_paths,
+            None => return call_paths,
+        };
+        call_paths.sort_by_key(|(_, timing)| core::cmp::Reverse(timing.sum_with_children()));
+        let mut state = match self.call_paths.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        for (call_path, _) in &mut call_paths {
+            if state.exported.contains(call_path.as_str()) {
+                continue;
+            }
+            if state.exported.len() < max_call_paths {
+                state.exported.insert(call_path.clone());
+                continue;
+            }
+            let mut hasher = DefaultHasher::new();
+            call_path.hash(&mut hasher);
+            state.suppressed.insert(hasher.finish());
+            *call_path = OTHER_CALL_PATH.to_string();
+        }
+        ::metrics::gauge!(format!("{}_suppressed_call_paths", self.prefix))
+            .set(state.suppressed.len() as f64);
+        call_paths
+    }
+
+    fn record(&self, pool: &CallPathPool) {
+        let calls = format!("{}_calls_total", self.prefix);
+        let busy = format!("{}_busy_seconds", self.prefix);
+        ::metrics::histogram!(format!("{}_tree_call_paths", self.prefix))
+            .record(pool.call_path_count() as f64);
+        ::metrics::histogram!(format!("{}_tree_spans", self.prefix))
+            .record(pool.span_count() as f64);
+        for (call_path, timing) in self.labeled_call_paths(pool) {
+            ::metrics::counter!(calls.clone(), "call_path" => call_path.clone())
+                .increment(timing.call_count() as u64);
+            if !timing.is_event() {
+                ::metrics::histogram!(busy.clone(), "call_path" => call_path)
+                    .record(timing.sum_with_children().as_secs_f64());
+            }
+        }
+    }
+}
+
+impl FinishedCallTreeProcessor for MetricsCallTreeProcessor {
+    fn process_finished_call(&self, pool: CallPathPool) {
+        self.record(&pool)
+    }
+
+    fn process_shared_call(&self, pool: Arc<CallPathPool>) {
+        self.record(&pool)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use metrics_util::{
+        debugging::{DebugValue, DebuggingRecorder},
+        MetricKind,
+    };
+
+    use super::{MetricsCallTreeProcessor, OTHER_CALL_PATH};
+    use crate::{
+        internal::test::{collect_call_trees, compound_call},
+        FinishedCallTreeProcessor,
+    };
+
+    #[test]
+    fn metrics_per_call_path() {
+        let call_trees = collect_call_trees(|mock| compound_call(&mock));
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, || {
+            let processor = MetricsCallTreeProcessor::default();
+            for pool in call_trees {
+                processor.process_finished_call(pool);
+            }
+        });
+
+        let mut calls = Vec::new();
+        let mut busy = Vec::new();
+        let mut tree_sizes = Vec::new();
+        for (key, _, _, value) in snapshotter.snapshot().into_vec() {
+            if let ("reqray_tree_call_paths" | "reqray_tree_spans", DebugValue::Histogram(values)) =
+                (key.key().name(), &value)
+            {
+                let values = values
+                    .iter()
+                    .map(|value| value.into_inner())
+                    .collect::<Vec<_>>();
+                tree_sizes.push((key.key().name().to_string(), values));
+                continue;
+            }
+            let call_path = key.key().labels().next().unwrap().value().to_string();
+            match (key.kind(), value) {
+                (MetricKind::Counter, DebugValue::Counter(count)) => {
+                    assert_eq!(key.key().name(), "reqray_calls_total");
+                    calls.push((call_path, count));
+                }
+                (MetricKind::Histogram, DebugValue::Histogram(values)) => {
+                    assert_eq!(key.key().name(), "reqray_busy_seconds");
+                    busy.push((call_path, values.len()));
+                }
+                other => panic!("unexpected metric {:?}", other),
+            }
+        }
+        calls.sort();
+        busy.sort();
+        tree_sizes.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            tree_sizes,
+            vec![
+                ("reqray_tree_call_paths".to_string(), vec![2.0]),
+                ("reqray_tree_spans".to_string(), vec![4.0]),
+            ]
+        );
+        assert_eq!(
+            calls,
+            vec![
+                ("compound_call".to_string(), 1),
+                ("compound_call/one_ns".to_string(), 3),
+            ]
+        );
+        assert_eq!(
+            busy,
+            vec![
+                ("compound_call".to_string(), 1),
+                ("compound_call/one_ns".to_string(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn max_call_paths() {
+        let call_trees = collect_call_trees(|mock| {
+            compound_call(&mock);
+            compound_call(&mock);
+        });
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, || {
+            let processor = MetricsCallTreeProcessor::default().with_max_call_paths(1);
+            for pool in call_trees {
+                processor.process_finished_call(pool);
+            }
+        });
+
+        let mut calls = Vec::new();
+        let mut suppressed = None;
+        for (key, _, _, value) in snapshotter.snapshot().into_vec() {
+            match (key.key().name(), value) {
+                ("reqray_calls_total", DebugValue::Counter(count)) => {
+                    let call_path = key.key().labels().next().unwrap().value().to_string();
+                    calls.push((call_path, count));
+                }
+                ("reqray_suppressed_call_paths", DebugValue::Gauge(gauge)) => {
+                    suppressed = Some(gauge.into_inner());
+                }
+                _ => {}
+            }
+        }
+        calls.sort();
+        assert_eq!(
+            calls,
+            vec![
+                ("compound_call".to_string(), 2),
+                (OTHER_CALL_PATH.to_string(), 6),
+            ]
+        );
+        assert_eq!(suppressed, Some(1.0));
+    }
+}
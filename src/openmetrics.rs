@@ -0,0 +1,298 @@
+//! Encode the aggregates of an
+//! [AggregatingProcessor](crate::aggregate::AggregatingProcessor) in the
+//! [OpenMetrics](https://openmetrics.io) text format, which Prometheus
+//! scrapes, without installing a `metrics` recorder:
+//!
+//! ```text
+//! # TYPE reqray_busy_seconds histogram
+//! # UNIT reqray_busy_seconds seconds
+//! # HELP reqray_busy_seconds The busy time per span of a call path.
+//! reqray_busy_seconds_bucket{call_path="request",le="0.005"} 12
+//! reqray_busy_seconds_bucket{call_path="request",le="+Inf"} 20
+//! reqray_busy_seconds_count{call_path="request"} 20
+//! reqray_busy_seconds_sum{call_path="request"} 0.241
+//! # TYPE reqray_calls counter
+//! # HELP reqray_calls The number of spans of a call path.
+//! reqray_calls_total{call_path="request"} 20
+//! # EOF
+//! ```
+//!
+//! The buckets are derived from the
+//! [busy histograms](crate::CallTreeCollectorBuilder::with_histograms) of
+//! the call paths and accurate to 12.5%. Without histograms, only the
+//! `+Inf` bucket is written.
+
+use std::{collections::BTreeMap, fmt::Write as _, io, path::Path, time::Duration};
+
+use crate::{aggregate::Aggregate, BusyHistogram};
+
+/// Encodes aggregates in the OpenMetrics text format, see the
+/// [module docs](self).
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use reqray::openmetrics::OpenMetricsEncoder;
+///
+/// let encoder = OpenMetricsEncoder::default()
+///     .with_buckets_for(
+///         "request/query",
+///         [Duration::from_micros(100), Duration::from_millis(1)],
+///     );
+/// ```
+#[derive(Debug, Clone)]
+pub struct OpenMetricsEncoder {
+    prefix: String,
+    separator: String,
+    buckets: Vec<Duration>,
+    /// By call path prefix.
+    buckets_for: Vec<(String, Vec<Duration>)>,
+}
+
+impl Default for OpenMetricsEncoder {
+    fn default() -> Self {
+        OpenMetricsEncoder {
+            prefix: "reqray".to_string(),
+            separator: "/".to_string(),
+            buckets: [5, 10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000]
+                .iter()
+                .map(|millis| Duration::from_millis(*millis))
+                .collect(),
+            buckets_for: Vec::new(),
+        }
+    }
+}
+
+/// The totals of a call path over all aggregates.
+#[derive(Default)]
+struct CallPathTotals {
+    calls: usize,
+    busy: Duration,
+    histogram: Option<BusyHistogram>,
+}
+
+impl OpenMetricsEncoder {
+    /// The prefix of the metric names, `reqray` by default.
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// The separator between span names in the `call_path` label, `/` by
+    /// default.
+    pub fn with_separator(mut self, separator: impl Into<String>) -> Self {
+        self.separator = separator.into();
+        self
+    }
+
+    /// The upper bounds of the buckets of all call paths without
+    /// [buckets of their own](Self::with_buckets_for). The default ranges
+    /// from 5 ms to 10 s like the default buckets of the Prometheus client
+    /// libraries.
+    pub fn with_buckets(mut self, buckets: impl IntoIterator<Item = Duration>) -> Self {
+        self.buckets = sorted(buckets);
+        self
+    }
+
+    /// The upper bounds of the buckets of `call_path` and all call paths
+    /// below it, e.g. finer buckets for `request/query`. Can be called
+    /// several times, the longest matching call path wins.
+    pub fn with_buckets_for(
+        mut self,
+        call_path: impl Into<String>,
+        buckets: impl IntoIterator<Item = Duration>,
+    ) -> Self {
+        self.buckets_for.push((call_path.into(), sorted(buckets)));
+        self
+    }
+
+    /// The buckets of `call_path`.
+    fn buckets(&self, call_path: &str) -> &[Duration] {
+        self.buckets_for
+            .iter()
+            .filter(|(prefix, _)| {
+                call_path == prefix
+                    || call_path
+                        .strip_prefix(prefix.as_str())
+                        .is_some_and(|rest| rest.starts_with(&self.separator))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(&self.buckets, |(_, buckets)| buckets)
+    }
+
+    /// The OpenMetrics text of all call paths in `aggregates`. Call paths
+    /// with the same names in different aggregates are added up.
+    pub fn encode(&self, aggregates: &[Aggregate]) -> String {
+        let mut totals = BTreeMap::<String, CallPathTotals>::new();
+        for aggregate in aggregates {
+            for (call_path, node) in aggregate.call_tree().flatten(&self.separator) {
+                if node.is_event() {
+                    continue;
+                }
+                let totals = totals.entry(call_path).or_default();
+                totals.calls += node.call_count();
+                totals.busy += node.sum_with_children();
+                match (&mut totals.histogram, node.busy_histogram()) {
+                    (Some(histogram), Some(other)) => histogram.merge(other),
+                    (histogram @ None, Some(other)) => *histogram = Some(other.clone()),
+                    (_, None) => {}
+                }
+            }
+        }
+
+        let busy = format!("{}_busy_seconds", self.prefix);
+        let calls = format!("{}_calls", self.prefix);
+        let mut out = String::new();
+        let _ = writeln!(out, "# TYPE {} histogram", busy);
+        let _ = writeln!(out, "# UNIT {} seconds", busy);
+        let _ = writeln!(
+            out,
+            "# HELP {} The busy time per span of a call path.",
+            busy
+        );
+        for (call_path, totals) in &totals {
+            let label = escape_label(call_path);
+            if let Some(histogram) = &totals.histogram {
+                for le in self.buckets(call_path) {
+                    let count = core::cmp::min(histogram.count_at_most(*le), totals.calls as u64);
+                    let _ = writeln!(
+                        out,
+                        "{}_bucket{{call_path=\"{}\",le=\"{}\"}} {}",
+                        busy,
+                        label,
+                        le.as_secs_f64(),
+                        count
+                    );
+                }
+            }
+            let _ = writeln!(
+                out,
+                "{}_bucket{{call_path=\"{}\",le=\"+Inf\"}} {}",
+                busy, label, totals.calls
+            );
+            let _ = writeln!(
+                out,
+                "{}_count{{call_path=\"{}\"}} {}",
+                busy, label, totals.calls
+            );
+            let _ = writeln!(
+                out,
+                "{}_sum{{call_path=\"{}\"}} {}",
+                busy,
+                label,
+                totals.busy.as_secs_f64()
+            );
+        }
+        let _ = writeln!(out, "# TYPE {} counter", calls);
+        let _ = writeln!(out, "# HELP {} The number of spans of a call path.", calls);
+        for (call_path, totals) in &totals {
+            let _ = writeln!(
+                out,
+                "{}_total{{call_path=\"{}\"}} {}",
+                calls,
+                escape_label(call_path),
+                totals.calls
+            );
+        }
+        out.push_str("# EOF\n");
+        out
+    }
+
+    /// Writes the [encoded](Self::encode) `aggregates` to `path`, replacing
+    /// the file atomically, e.g. for the textfile collector of the
+    /// Prometheus node exporter.
+    pub fn write_to_file(
+        &self,
+        aggregates: &[Aggregate],
+        path: impl AsRef<Path>,
+    ) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, self.encode(aggregates))?;
+        std::fs::rename(&tmp, path)
+    }
+}
+
+fn sorted(buckets: impl IntoIterator<Item = Duration>) -> Vec<Duration> {
+    let mut buckets = buckets.into_iter().collect::<Vec<_>>();
+    buckets.sort();
+    buckets.dedup();
+    buckets
+}
+
+/// Escapes `value` for a quoted label value.
+fn escape_label(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{escape_label, OpenMetricsEncoder};
+    use crate::{
+        aggregate::AggregatingProcessor, internal::test::collect_call_trees_with_builder,
+        CallTreeCollectorBuilder, FinishedCallTreeProcessor,
+    };
+
+    #[test]
+    fn encode() {
+        let call_trees = collect_call_trees_with_builder(
+            CallTreeCollectorBuilder::default().with_histograms(true),
+            |mock| {
+                for busy in [1_000_000, 3_000_000] {
+                    tracing::info_span!("request").in_scope(|| {
+                        mock.increment(busy);
+                        tracing::info_span!("query").in_scope(|| mock.increment(200_000));
+                        tracing::info!("done");
+                    });
+                }
+            },
+        );
+        let processor = AggregatingProcessor::new(|_| {}).with_interval(None);
+        let handle = processor.handle();
+        for call_tree in call_trees {
+            processor.process_finished_call(call_tree);
+        }
+        let encoder = OpenMetricsEncoder::default()
+            .with_buckets([Duration::from_millis(2), Duration::from_millis(5)])
+            .with_buckets_for("request/query", [Duration::from_millis(1)]);
+        assert_eq!(
+            handle.open_metrics(&encoder),
+            indoc::indoc! {r#"
+                # TYPE reqray_busy_seconds histogram
+                # UNIT reqray_busy_seconds seconds
+                # HELP reqray_busy_seconds The busy time per span of a call path.
+                reqray_busy_seconds_bucket{call_path="request",le="0.002"} 1
+                reqray_busy_seconds_bucket{call_path="request",le="0.005"} 2
+                reqray_busy_seconds_bucket{call_path="request",le="+Inf"} 2
+                reqray_busy_seconds_count{call_path="request"} 2
+                reqray_busy_seconds_sum{call_path="request"} 0.0044
+                reqray_busy_seconds_bucket{call_path="request/query",le="0.001"} 2
+                reqray_busy_seconds_bucket{call_path="request/query",le="+Inf"} 2
+                reqray_busy_seconds_count{call_path="request/query"} 2
+                reqray_busy_seconds_sum{call_path="request/query"} 0.0004
+                # TYPE reqray_calls counter
+                # HELP reqray_calls The number of spans of a call path.
+                reqray_calls_total{call_path="request"} 2
+                reqray_calls_total{call_path="request/query"} 2
+                # EOF
+            "#}
+        );
+    }
+
+    #[test]
+    fn label_escaping() {
+        assert_eq!(escape_label("GET \"/a\\b\"\n"), "GET \\\"/a\\\\b\\\"\\n");
+    }
+}
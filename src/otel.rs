@@ -0,0 +1,276 @@
+//! Export finished call trees as [OpenTelemetry](https://opentelemetry.io)
+//! spans, e.g. to Tempo or Jaeger via an OTLP exporter.
+//!
+//! Call trees only contain aggregated timings per call path, not the
+//! individual spans. Therefore, every call path becomes a single synthetic
+//! span lasting its summed busy time, laid out like in
+//! [chrome_trace](crate::chrome_trace): the children of a call path start
+//! one after another from the start of their parent. The aggregated values
+//! are attached as `reqray.*` attributes.
+//!
+//! Every call tree becomes its own trace, starting when its root span was
+//! created.
+//!
+//! To collect call trees for the same requests as distributed traces, see
+//! [parent_sampling_decision].
+
+use std::{sync::Arc, time::SystemTime};
+
+use opentelemetry::{
+    trace::{SpanKind, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+use tracing::Metadata;
+
+use crate::{
+    callsite_cache::CallsiteCache, clock::system_time_now, CallPathPool, CallPathTiming,
+    FinishedCallTreeProcessor,
+};
+
+/// The `code.*` attributes of call paths.
+static CODE_ATTRIBUTES: CallsiteCache<[KeyValue]> = CallsiteCache::new();
+
+/// A [sampler](crate::CallTreeCollectorBuilder::sampler) which collects
+/// call trees if and only if the current OpenTelemetry context has a
+/// sampled span, e.g. the remote parent of a request extracted from its
+/// `traceparent` header and attached while the root span is created:
+///
+/// ```
+/// use opentelemetry::{propagation::TextMapPropagator, Context};
+/// use opentelemetry_sdk::propagation::TraceContextPropagator;
+/// use std::collections::HashMap;
+///
+/// fn handle(headers: &HashMap<String, String>) {
+///     let parent = TraceContextPropagator::new().extract(headers);
+///     let _guard = parent.attach();
+///     let _span = tracing::info_span!("request").entered();
+///     // ...
+/// }
+/// ```
+///
+/// Without a valid span in the current context, e.g. at the start of a
+/// new trace, the decision is left to the
+/// [sample rate](crate::CallTreeCollectorBuilder::sample_rate). The
+/// sampling decision of spans which the `tracing-opentelemetry` layer
+/// creates for the root span itself is only made when they are exported,
+/// so it cannot be followed.
+pub fn parent_sampling_decision(_: &Metadata<'_>) -> Option<bool> {
+    let context = Context::current();
+    let span = context.span();
+    let span_context = span.span_context();
+    if span_context.is_valid() {
+        Some(span_context.is_sampled())
+    } else {
+        None
+    }
+}
+
+/// A [FinishedCallTreeProcessor] which creates a span for every call path
+/// with the given [Tracer].
+///
+/// Use the tracer of a configured exporter pipeline, e.g. an OTLP exporter
+/// with a batch span processor, so that call trees are not exported on
+/// the request path:
+///
+/// ```
+/// use opentelemetry::trace::noop::NoopTracer;
+/// use reqray::{otel::OtelCallTreeProcessor, CallTreeCollectorBuilder};
+///
+/// let collector = CallTreeCollectorBuilder::default()
+///     .build_with_collector(OtelCallTreeProcessor::new(NoopTracer::new()));
+/// ```
+pub struct OtelCallTreeProcessor<T: Tracer> {
+    tracer: T,
+}
+
+impl<T> OtelCallTreeProcessor<T>
+where
+    T: Tracer,
+    T::Span: Send + Sync + 'static,
+{
+    pub fn new(tracer: T) -> Self {
+        OtelCallTreeProcessor { tracer }
+    }
+
+    fn export(&self, pool: &CallPathPool) {
+        let root = pool.root();
+        // The root span was closed just now.
+        let start = system_time_now() - root.span_alive();
+        self.export_call_path(pool, root, start, &Context::new());
+    }
+
+    fn export_call_path(
+        &self,
+        pool: &CallPathPool,
+        node: &CallPathTiming,
+        start: SystemTime,
+        parent_cx: &Context,
+    ) {
+        if node.is_event() {
+            return;
+        }
+        let mut attributes = vec![
+            KeyValue::new("reqray.call_count", node.call_count() as i64),
+            KeyValue::new("reqray.alive_seconds", node.span_alive().as_secs_f64()),
+            KeyValue::new(
+                "reqray.busy_seconds",
+                node.sum_with_children().as_secs_f64(),
+            ),
+            KeyValue::new(
+                "reqray.own_busy_seconds",
+                node.sum_without_children().as_secs_f64(),
+            ),
+        ];
+        if node.is_retry() {
+            attributes.push(KeyValue::new("reqray.retry", true));
+        }
+        if node.parent().is_none() {
+            let split = pool.time_split();
+            attributes.extend([
+                KeyValue::new("reqray.local_seconds", split.local().as_secs_f64()),
+                KeyValue::new(
+                    "reqray.downstream_seconds",
+                    split.downstream().as_secs_f64(),
+                ),
+                KeyValue::new("reqray.waiting_seconds", split.waiting().as_secs_f64()),
+            ]);
+        }
+        if node.parent().is_none() && pool.is_degraded() {
+            attributes.push(KeyValue::new(
+                "reqray.skipped_spans",
+                pool.skipped_spans() as i64,
+            ));
+        }
+        attributes.extend_from_slice(&CODE_ATTRIBUTES.get_or_insert_with(node, code_attributes));
+        let span = self
+            .tracer
+            .span_builder(node.name().to_string())
+            .with_kind(SpanKind::Internal)
+            .with_start_time(start)
+            .with_attributes(attributes)
+            .start_with_context(&self.tracer, parent_cx);
+        let cx = parent_cx.with_span(span);
+
+        let mut children = node.children().copied().collect::<Vec<_>>();
+        children.sort();
+        let mut child_start = start;
+        for child_idx in children {
+            let child = &pool[child_idx];
+            self.export_call_path(pool, child, child_start, &cx);
+            child_start += child.sum_with_children();
+        }
+        cx.span()
+            .end_with_timestamp(start + node.sum_with_children());
+    }
+}
+
+/// The location of the code that created the spans of `node`.
+fn code_attributes(node: &CallPathTiming) -> Arc<[KeyValue]> {
+    let meta = node.span_metadata();
+    let mut attributes = Vec::new();
+    if let Some(file) = meta.file() {
+        attributes.push(KeyValue::new("code.filepath", Arc::<str>::from(file)));
+    }
+    if let Some(line) = meta.line() {
+        attributes.push(KeyValue::new("code.lineno", i64::from(line)));
+    }
+    attributes.into()
+}
+
+impl<T> FinishedCallTreeProcessor for OtelCallTreeProcessor<T>
+where
+    T: Tracer,
+    T::Span: Send + Sync + 'static,
+{
+    fn process_finished_call(&self, pool: CallPathPool) {
+        self.export(&pool)
+    }
+
+    fn process_shared_call(&self, pool: Arc<CallPathPool>) {
+        self.export(&pool)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_sdk::{testing::trace::InMemorySpanExporter, trace::TracerProvider};
+
+    use super::{parent_sampling_decision, OtelCallTreeProcessor};
+    use crate::{
+        internal::test::{collect_call_trees, collect_call_trees_with_builder, compound_call},
+        CallTreeCollectorBuilder, FinishedCallTreeProcessor,
+    };
+
+    #[test]
+    fn spans_per_call_path() {
+        let call_trees = collect_call_trees(|mock| compound_call(&mock));
+        let split = call_trees[0].time_split();
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let processor = OtelCallTreeProcessor::new(provider.tracer("reqray"));
+        for pool in call_trees {
+            processor.process_finished_call(pool);
+        }
+
+        let spans = exporter.get_finished_spans().unwrap();
+        assert_eq!(spans.len(), 2);
+        let root = spans
+            .iter()
+            .find(|span| span.name == "compound_call")
+            .unwrap();
+        let child = spans.iter().find(|span| span.name == "one_ns").unwrap();
+        assert_eq!(child.parent_span_id, root.span_context.span_id());
+        assert_eq!(child.span_context.trace_id(), root.span_context.trace_id());
+        let call_count = child
+            .attributes
+            .iter()
+            .find(|kv| kv.key.as_str() == "reqray.call_count")
+            .map(|kv| kv.value.clone());
+        assert_eq!(call_count, Some(3i64.into()));
+        let local = root
+            .attributes
+            .iter()
+            .find(|kv| kv.key.as_str() == "reqray.local_seconds")
+            .map(|kv| kv.value.clone());
+        assert_eq!(local, Some(split.local().as_secs_f64().into()));
+        assert_eq!(
+            root.end_time.duration_since(root.start_time).unwrap(),
+            std::time::Duration::from_nanos(1113)
+        );
+    }
+
+    #[test]
+    fn follows_parent_sampling_decision() {
+        use opentelemetry::{
+            trace::{SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState},
+            Context,
+        };
+
+        let collected = |parent: Option<TraceFlags>, sample_rate| {
+            let context = parent.map(|flags| {
+                Context::new().with_remote_span_context(SpanContext::new(
+                    TraceId::from_u128(1),
+                    SpanId::from_u64(1),
+                    flags,
+                    true,
+                    TraceState::default(),
+                ))
+            });
+            let _guard = context.map(Context::attach);
+            collect_call_trees_with_builder(
+                CallTreeCollectorBuilder::default()
+                    .sample_rate(sample_rate)
+                    .sampler(parent_sampling_decision),
+                |mock| compound_call(&mock),
+            )
+            .len()
+        };
+        assert_eq!(collected(Some(TraceFlags::SAMPLED), 0.0), 1);
+        assert_eq!(collected(Some(TraceFlags::default()), 1.0), 0);
+        assert_eq!(collected(None, 1.0), 1);
+        assert_eq!(collected(None, 0.0), 0);
+    }
+}
@@ -0,0 +1,615 @@
+//! Generic [FinishedCallTreeProcessor]s which combine other processors.
+
+use std::sync::Arc;
+// Threads are not supported on `wasm32`.
+#[cfg(not(target_arch = "wasm32"))]
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, SyncSender},
+    },
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, JoinHandle, Thread},
+};
+
+use crate::{
+    AsyncFinishedCallTreeProcessor, CallPathPool, CallPathTiming, FinishedCallTreeProcessor,
+    ProcessorFuture,
+};
+
+/// Hands every call tree to several processors, e.g. to log it and to
+/// export it as folded stacks:
+///
+/// ```
+/// # #[cfg(all(feature = "display", feature = "folded"))] {
+/// use reqray::{
+///     display::LoggingCallTreeCollectorBuilder, folded::FoldedStackProcessor,
+///     processors::MultiProcessor, CallTreeCollectorBuilder,
+/// };
+///
+/// let collector = CallTreeCollectorBuilder::default().build_with_collector(
+///     MultiProcessor::default()
+///         .with(LoggingCallTreeCollectorBuilder::default().build())
+///         .with(FoldedStackProcessor::new(std::io::stderr())),
+/// );
+/// # }
+/// ```
+///
+/// The call tree is shared between the processors with
+/// [FinishedCallTreeProcessor::process_shared_call]. For a fixed number of
+/// processors, tuples like `(logging, folded)` work as well.
+#[derive(Default)]
+pub struct MultiProcessor {
+    processors: Vec<Box<dyn FinishedCallTreeProcessor + Send + Sync + 'static>>,
+}
+
+impl MultiProcessor {
+    /// Adds a processor which is called after all previously added ones.
+    pub fn with<P>(mut self, processor: P) -> Self
+    where
+        P: FinishedCallTreeProcessor + Send + Sync + 'static,
+    {
+        self.processors.push(Box::new(processor));
+        self
+    }
+}
+
+impl FinishedCallTreeProcessor for MultiProcessor {
+    fn process_finished_call(&self, pool: CallPathPool) {
+        self.process_shared_call(Arc::new(pool))
+    }
+
+    fn process_shared_call(&self, pool: Arc<CallPathPool>) {
+        for processor in &self.processors {
+            processor.process_shared_call(pool.clone());
+        }
+    }
+}
+
+/// Only forwards call trees whose root matches a predicate, e.g. to
+/// x-ray request handlers but not background jobs:
+///
+/// ```
+/// # #[cfg(feature = "display")] {
+/// use reqray::{
+///     display::LoggingCallTreeCollectorBuilder, processors::FilteredProcessor,
+///     CallTreeCollectorBuilder,
+/// };
+///
+/// let collector = CallTreeCollectorBuilder::default().build_with_collector(
+///     FilteredProcessor::by_root_name(
+///         LoggingCallTreeCollectorBuilder::default().build(),
+///         "handle_request",
+///     ),
+/// );
+/// # }
+/// ```
+pub struct FilteredProcessor<P> {
+    inner: P,
+    predicate: Box<dyn Fn(&CallPathTiming) -> bool + Send + Sync + 'static>,
+}
+
+impl<P: FinishedCallTreeProcessor> FilteredProcessor<P> {
+    /// Forwards call trees to `inner` if `predicate` returns true for
+    /// their root.
+    pub fn new<F>(inner: P, predicate: F) -> Self
+    where
+        F: Fn(&CallPathTiming) -> bool + Send + Sync + 'static,
+    {
+        FilteredProcessor {
+            inner,
+            predicate: Box::new(predicate),
+        }
+    }
+
+    /// Forwards call trees whose root has the given
+    /// [name](CallPathTiming::name).
+    pub fn by_root_name(inner: P, name: impl Into<String>) -> Self {
+        let name = name.into();
+        Self::new(inner, move |root| root.name() == name)
+    }
+
+    /// Forwards call trees whose root span target is `target` or a module
+    /// below it, e.g. `my_app::handlers` matches `my_app::handlers::users`.
+    pub fn by_root_target(inner: P, target: impl Into<String>) -> Self {
+        let target = target.into();
+        Self::new(inner, move |root| {
+            let root_target = root.span_metadata().target();
+            match root_target.strip_prefix(target.as_str()) {
+                Some(rest) => rest.is_empty() || rest.starts_with("::"),
+                None => false,
+            }
+        })
+    }
+
+    /// Forwards call trees whose root span was declared in the given
+    /// source file, e.g. `src/handlers.rs`.
+    pub fn by_root_file(inner: P, file: impl Into<String>) -> Self {
+        let file = file.into();
+        Self::new(inner, move |root| {
+            root.span_metadata().file() == Some(file.as_str())
+        })
+    }
+}
+
+impl<P: FinishedCallTreeProcessor> FinishedCallTreeProcessor for FilteredProcessor<P> {
+    fn process_finished_call(&self, pool: CallPathPool) {
+        if (self.predicate)(pool.root()) {
+            self.inner.process_finished_call(pool);
+        }
+    }
+
+    fn process_shared_call(&self, pool: Arc<CallPathPool>) {
+        if (self.predicate)(pool.root()) {
+            self.inner.process_shared_call(pool);
+        }
+    }
+}
+
+/// What a [ChannelProcessor] does with a call tree if its queue is full.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueFullPolicy {
+    /// Drop the new call tree, see [ChannelProcessor::dropped_trees].
+    Drop,
+    /// Block the closing root span until there is room in the queue.
+    Block,
+}
+
+/// Hands call trees to another processor on a background thread, so that
+/// e.g. formatting and logging them does not add latency to the request.
+///
+/// Call trees are queued in a bounded channel. Dropping the
+/// [ChannelProcessor], e.g. together with the subscriber, processes the
+/// remaining queued call trees and waits for the background thread.
+///
+/// The background thread does not inherit thread local default
+/// subscribers. Processors that log, like the logging collector, need a
+/// global default subscriber, e.g. installed with `init()`.
+///
+/// ```
+/// # #[cfg(feature = "display")] {
+/// use reqray::{
+///     display::LoggingCallTreeCollectorBuilder,
+///     processors::{ChannelProcessor, QueueFullPolicy},
+///     CallTreeCollectorBuilder,
+/// };
+///
+/// let collector = CallTreeCollectorBuilder::default().build_with_collector(
+///     ChannelProcessor::new(
+///         LoggingCallTreeCollectorBuilder::default().build(),
+///         1_000,
+///         QueueFullPolicy::Drop,
+///     ),
+/// );
+/// # }
+/// ```
+#[cfg(not(target_arch = "wasm32"))]
+pub struct ChannelProcessor {
+    sender: Option<SyncSender<Arc<CallPathPool>>>,
+    policy: QueueFullPolicy,
+    dropped_trees: AtomicU64,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl ChannelProcessor {
+    /// Starts a background thread which hands call trees to `inner`.
+    /// Up to `capacity` call trees are queued.
+    ///
+    /// Panics if `capacity` is zero: without a queue, call trees could
+    /// only be handed over while the background thread is idle, and
+    /// [QueueFullPolicy::Drop] would drop nearly all of them.
+    pub fn new<P>(inner: P, capacity: usize, policy: QueueFullPolicy) -> Self
+    where
+        P: FinishedCallTreeProcessor + Send + 'static,
+    {
+        assert!(
+            capacity > 0,
+            "the capacity of a ChannelProcessor must not be zero"
+        );
+        let (sender, receiver) = mpsc::sync_channel::<Arc<CallPathPool>>(capacity);
+        let thread = thread::Builder::new()
+            .name("reqray-processor".to_string())
+            .spawn(move || {
+                for pool in receiver {
+                    inner.process_shared_call(pool);
+                }
+            })
+            .expect("could not spawn reqray processor thread");
+        ChannelProcessor {
+            sender: Some(sender),
+            policy,
+            dropped_trees: AtomicU64::new(0),
+            thread: Some(thread),
+        }
+    }
+
+    /// Like [new](Self::new) but for an [AsyncFinishedCallTreeProcessor].
+    ///
+    /// The background thread awaits the futures of `inner` one after
+    /// another with a minimal executor. Use [BlockOnProcessor::with_executor]
+    /// for futures which need a specific runtime.
+    pub fn new_async<P>(inner: P, capacity: usize, policy: QueueFullPolicy) -> Self
+    where
+        P: AsyncFinishedCallTreeProcessor + Send + 'static,
+    {
+        Self::new(BlockOnProcessor::new(inner), capacity, policy)
+    }
+
+    /// The number of call trees dropped because the queue was full or the
+    /// background thread has stopped, e.g. because `inner` panicked.
+    pub fn dropped_trees(&self) -> u64 {
+        self.dropped_trees.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FinishedCallTreeProcessor for ChannelProcessor {
+    fn process_finished_call(&self, pool: CallPathPool) {
+        self.process_shared_call(Arc::new(pool))
+    }
+
+    fn process_shared_call(&self, pool: Arc<CallPathPool>) {
+        // This runs inside the subscriber, so failures are only counted
+        // instead of being logged.
+        let sender = self.sender.as_ref().expect("sender only taken on drop");
+        let sent = match self.policy {
+            QueueFullPolicy::Drop => sender.try_send(pool).is_ok(),
+            QueueFullPolicy::Block => sender.send(pool).is_ok(),
+        };
+        if !sent {
+            self.dropped_trees.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Drop for ChannelProcessor {
+    fn drop(&mut self) {
+        // Closes the channel so that the thread stops after processing
+        // the queued call trees.
+        self.sender.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+type Executor = Box<dyn Fn(ProcessorFuture<'_>) + Send + Sync>;
+
+/// Adapts an [AsyncFinishedCallTreeProcessor] to a
+/// [FinishedCallTreeProcessor] by blocking until its future completes.
+///
+/// This blocks the thread that finishes the call tree, so it is meant to
+/// run behind a [ChannelProcessor], see [ChannelProcessor::new_async].
+/// Futures which need a specific runtime, e.g. tokio based clients, are
+/// driven by that runtime with [with_executor](Self::with_executor):
+///
+/// ```
+/// # use std::sync::Arc;
+/// # use reqray::{AsyncFinishedCallTreeProcessor, CallPathPool, ProcessorFuture};
+/// use reqray::processors::{BlockOnProcessor, ChannelProcessor, QueueFullPolicy};
+///
+/// # struct Exporter;
+/// # impl AsyncFinishedCallTreeProcessor for Exporter {
+/// #     fn process_finished_call(&self, pool: Arc<CallPathPool>) -> ProcessorFuture<'_> {
+/// #         Box::pin(async {})
+/// #     }
+/// # }
+/// let runtime = tokio::runtime::Runtime::new().unwrap();
+/// let handle = runtime.handle().clone();
+/// let processor = ChannelProcessor::new(
+///     BlockOnProcessor::with_executor(Exporter, move |future| handle.block_on(future)),
+///     1_000,
+///     QueueFullPolicy::Drop,
+/// );
+/// ```
+pub struct BlockOnProcessor<P> {
+    inner: P,
+    executor: Executor,
+}
+
+impl<P: AsyncFinishedCallTreeProcessor> BlockOnProcessor<P> {
+    /// Drives the futures of `inner` with a minimal executor which only
+    /// supports runtime independent futures.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn new(inner: P) -> Self {
+        Self::with_executor(inner, |future| block_on(future))
+    }
+
+    /// Drives the futures of `inner` with `executor`, which must run the
+    /// given future to completion, e.g. with the `block_on` of a runtime.
+    pub fn with_executor<E>(inner: P, executor: E) -> Self
+    where
+        E: Fn(ProcessorFuture<'_>) + Send + Sync + 'static,
+    {
+        BlockOnProcessor {
+            inner,
+            executor: Box::new(executor),
+        }
+    }
+}
+
+impl<P: AsyncFinishedCallTreeProcessor> FinishedCallTreeProcessor for BlockOnProcessor<P> {
+    fn process_finished_call(&self, pool: CallPathPool) {
+        self.process_shared_call(Arc::new(pool))
+    }
+
+    fn process_shared_call(&self, pool: Arc<CallPathPool>) {
+        (self.executor)(self.inner.process_finished_call(pool))
+    }
+}
+
+/// Wakes up the thread which polls the future.
+#[cfg(not(target_arch = "wasm32"))]
+struct ThreadWaker(Thread);
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Polls `future` on the current thread until it completes.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+macro_rules! tuple_processor {
+    ($($name:ident),+) => {
+        impl<$($name),+> FinishedCallTreeProcessor for ($($name,)+)
+        where
+            $($name: FinishedCallTreeProcessor),+
+        {
+            fn process_finished_call(&self, pool: CallPathPool) {
+                self.process_shared_call(Arc::new(pool))
+            }
+
+            #[allow(non_snake_case)]
+            fn process_shared_call(&self, pool: Arc<CallPathPool>) {
+                let ($($name,)+) = self;
+                $($name.process_shared_call(pool.clone());)+
+            }
+        }
+    };
+}
+
+tuple_processor!(A, B);
+tuple_processor!(A, B, C);
+tuple_processor!(A, B, C, D);
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{mpsc, Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::{
+        BlockOnProcessor, ChannelProcessor, FilteredProcessor, MultiProcessor, QueueFullPolicy,
+    };
+    use crate::{
+        internal::test::{collect_call_trees, compound_call, one_ns, FinishedCallTreeStore},
+        AsyncFinishedCallTreeProcessor, CallPathPool, FinishedCallTreeProcessor, ProcessorFuture,
+    };
+
+    #[test]
+    fn multi_processor() {
+        let first = FinishedCallTreeStore::default();
+        let second = FinishedCallTreeStore::default();
+        let multi = MultiProcessor::default()
+            .with(first.clone())
+            .with(second.clone());
+        for pool in collect_call_trees(|mock| compound_call(&mock)) {
+            multi.process_finished_call(pool);
+        }
+        drop(multi);
+
+        for store in [first, second] {
+            let pools = store.into_vec();
+            assert_eq!(pools.len(), 1);
+            assert_eq!(pools[0].root().name(), "compound_call");
+        }
+    }
+
+    #[test]
+    fn filtered_processor() {
+        let filtered = |filter: fn(FinishedCallTreeStore) -> FilteredProcessor<_>| {
+            let store = FinishedCallTreeStore::default();
+            let processor = filter(store.clone());
+            for pool in collect_call_trees(|mock| {
+                one_ns(&mock);
+                compound_call(&mock);
+            }) {
+                processor.process_finished_call(pool);
+            }
+            drop(processor);
+            store
+                .into_vec()
+                .iter()
+                .map(|pool| pool.root().name().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            filtered(|store| FilteredProcessor::by_root_name(store, "compound_call")),
+            vec!["compound_call"]
+        );
+        assert_eq!(
+            filtered(|store| FilteredProcessor::by_root_target(store, "reqray")),
+            vec!["one_ns", "compound_call"]
+        );
+        assert!(filtered(|store| FilteredProcessor::by_root_target(store, "req")).is_empty());
+        assert_eq!(
+            filtered(|store| FilteredProcessor::by_root_file(store, "src/internal.rs")).len(),
+            2
+        );
+        assert!(filtered(|store| FilteredProcessor::by_root_file(store, file!())).is_empty());
+        assert_eq!(
+            filtered(|store| FilteredProcessor::new(store, |root| root.call_count() == 1)).len(),
+            2
+        );
+    }
+
+    #[test]
+    fn channel_processor() {
+        let store = FinishedCallTreeStore::default();
+        let processor = ChannelProcessor::new(store.clone(), 10, QueueFullPolicy::Block);
+        for pool in collect_call_trees(|mock| {
+            for _ in 0..3 {
+                compound_call(&mock);
+            }
+        }) {
+            processor.process_finished_call(pool);
+        }
+        drop(processor);
+
+        assert_eq!(store.into_vec().len(), 3);
+    }
+
+    /// Signals when it starts processing and then waits for `gate`.
+    struct Gated {
+        started: Mutex<mpsc::Sender<()>>,
+        gate: Arc<Mutex<()>>,
+        store: FinishedCallTreeStore,
+    }
+
+    impl FinishedCallTreeProcessor for Gated {
+        fn process_finished_call(&self, pool: CallPathPool) {
+            self.started.lock().unwrap().send(()).unwrap();
+            let _open = self.gate.lock().unwrap();
+            self.store.process_finished_call(pool);
+        }
+    }
+
+    #[test]
+    fn channel_processor_drops_when_full() {
+        let store = FinishedCallTreeStore::default();
+        let gate = Arc::new(Mutex::new(()));
+        let closed = gate.lock().unwrap();
+        let (started, started_receiver) = mpsc::channel();
+        let gated = Gated {
+            started: Mutex::new(started),
+            gate: gate.clone(),
+            store: store.clone(),
+        };
+        let processor = ChannelProcessor::new(gated, 1, QueueFullPolicy::Drop);
+
+        let mut pools = collect_call_trees(|mock| {
+            for _ in 0..3 {
+                compound_call(&mock);
+            }
+        })
+        .into_iter();
+        processor.process_finished_call(pools.next().unwrap());
+        // The first call tree is being processed, the second one is queued
+        // and the third one is dropped.
+        started_receiver.recv().unwrap();
+        for pool in pools {
+            processor.process_finished_call(pool);
+        }
+        assert_eq!(processor.dropped_trees(), 1);
+
+        drop(closed);
+        drop(processor);
+        assert_eq!(store.into_vec().len(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "must not be zero")]
+    fn channel_processor_rejects_zero_capacity() {
+        ChannelProcessor::new(FinishedCallTreeStore::default(), 0, QueueFullPolicy::Drop);
+    }
+
+    /// Stores call trees after waiting for another thread, like an
+    /// exporter waiting for a response.
+    struct AsyncStore(FinishedCallTreeStore);
+
+    impl AsyncFinishedCallTreeProcessor for AsyncStore {
+        fn process_finished_call(&self, pool: Arc<CallPathPool>) -> ProcessorFuture<'_> {
+            Box::pin(async move {
+                let (sender, receiver) = futures::channel::oneshot::channel();
+                std::thread::spawn(move || sender.send(pool).unwrap());
+                self.0.process_shared_call(receiver.await.unwrap());
+            })
+        }
+    }
+
+    #[test]
+    fn async_processor() {
+        let store = FinishedCallTreeStore::default();
+        let processor =
+            ChannelProcessor::new_async(AsyncStore(store.clone()), 10, QueueFullPolicy::Block);
+        for pool in collect_call_trees(|mock| {
+            for _ in 0..3 {
+                compound_call(&mock);
+            }
+        }) {
+            processor.process_finished_call(pool);
+        }
+        drop(processor);
+
+        assert_eq!(store.into_vec().len(), 3);
+    }
+
+    /// Needs a tokio runtime for its timer.
+    struct TokioStore(FinishedCallTreeStore);
+
+    impl AsyncFinishedCallTreeProcessor for TokioStore {
+        fn process_finished_call(&self, pool: Arc<CallPathPool>) -> ProcessorFuture<'_> {
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                self.0.process_shared_call(pool);
+            })
+        }
+    }
+
+    #[test]
+    fn async_processor_with_runtime() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let handle = runtime.handle().clone();
+        let store = FinishedCallTreeStore::default();
+        let processor = ChannelProcessor::new(
+            BlockOnProcessor::with_executor(TokioStore(store.clone()), move |future| {
+                handle.block_on(future)
+            }),
+            10,
+            QueueFullPolicy::Block,
+        );
+        for pool in collect_call_trees(|mock| compound_call(&mock)) {
+            processor.process_finished_call(pool);
+        }
+        drop(processor);
+
+        assert_eq!(store.into_vec().len(), 1);
+    }
+
+    #[test]
+    fn tuple_processor() {
+        let stores = (
+            FinishedCallTreeStore::default(),
+            FinishedCallTreeStore::default(),
+            FinishedCallTreeStore::default(),
+        );
+        for pool in collect_call_trees(|mock| compound_call(&mock)) {
+            stores.process_finished_call(pool);
+        }
+
+        let (a, b, c) = stores;
+        for store in [a, b, c] {
+            assert_eq!(store.into_vec().len(), 1);
+        }
+    }
+}
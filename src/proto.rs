@@ -0,0 +1,132 @@
+//! [prost](https://docs.rs/prost) messages for finished call trees as
+//! described by `proto/reqray.proto`, e.g. to send call trees to a service
+//! written in another language:
+//!
+//! ```
+//! use prost::Message;
+//! use reqray::{proto::CallTree, CallPathPool};
+//!
+//! fn encode(pool: &CallPathPool) -> Vec<u8> {
+//!     CallTree::from(pool).encode_to_vec()
+//! }
+//! ```
+//!
+//! The messages are generated with prost-build 0.13 and checked in, so that
+//! building reqray does not need `protoc`. Regenerate
+//! `src/proto/reqray.v1.rs` after changing the schema.
+
+use std::time::Duration;
+
+use crate::{CallPathPool, CallPathTiming};
+
+#[rustfmt::skip]
+#[allow(clippy::all)]
+mod generated {
+    include!("proto/reqray.v1.rs");
+}
+
+pub use generated::{CacheStats, CallPath, CallTree, Note};
+
+impl From<&CallPathPool> for CallTree {
+    /// Lists the call paths in depth first order, so that parents come
+    /// before their children.
+    fn from(pool: &CallPathPool) -> Self {
+        let mut call_paths = Vec::with_capacity(pool.call_path_count());
+        // The index of the last call path per depth.
+        let mut ancestors: Vec<u32> = Vec::new();
+        for (depth, node) in pool.iter_depth_first() {
+            ancestors.truncate(depth);
+            let parent = ancestors.last().copied();
+            ancestors.push(call_paths.len() as u32);
+            call_paths.push(call_path(node, parent));
+        }
+        CallTree {
+            call_paths,
+            degraded: pool.is_degraded(),
+            skipped_spans: pool.skipped_spans() as u64,
+        }
+    }
+}
+
+fn call_path(node: &CallPathTiming, parent: Option<u32>) -> CallPath {
+    let meta = node.span_metadata();
+    let cache = (node.cache_hit_count() + node.cache_miss_count() > 0).then(|| CacheStats {
+        hit_count: node.cache_hit_count() as u64,
+        hit_busy_ns: nanos(node.cache_hit_busy()),
+        miss_count: node.cache_miss_count() as u64,
+        miss_busy_ns: nanos(node.cache_miss_busy()),
+    });
+    CallPath {
+        parent,
+        name: node.name().to_string(),
+        target: meta.target().to_string(),
+        file: meta.file().map(str::to_string),
+        line: meta.line(),
+        call_count: node.call_count() as u64,
+        retry: node.is_retry(),
+        event: node.is_event(),
+        sum_alive_ns: nanos(node.span_alive()),
+        sum_busy_ns: nanos(node.sum_with_children()),
+        sum_own_busy_ns: nanos(node.sum_without_children()),
+        sum_awaited_ns: nanos(node.sum_awaited()),
+        min_busy_ns: nanos(node.min_busy()),
+        max_busy_ns: nanos(node.max_busy()),
+        first_entered_ns: node.first_entered().map(nanos),
+        notes: node
+            .notes()
+            .map(|(note, count)| Note {
+                note: note.to_string(),
+                count: count as u64,
+            })
+            .collect(),
+        cache,
+    }
+}
+
+fn nanos(duration: Duration) -> u64 {
+    duration.as_nanos() as u64
+}
+
+#[cfg(test)]
+mod test {
+    use prost::Message;
+
+    use super::CallTree;
+    use crate::internal::test::{collect_call_trees, compound_call};
+
+    #[test]
+    fn from_call_path_pool() {
+        let call_trees = collect_call_trees(|mock| {
+            tracing::info_span!("request").in_scope(|| {
+                compound_call(&mock);
+                tracing::info_span!("query", cache.hit = true).in_scope(|| mock.increment(5));
+            })
+        });
+        let call_tree = CallTree::from(&call_trees[0]);
+        let decoded = CallTree::decode(call_tree.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(decoded, call_tree);
+
+        let paths = decoded
+            .call_paths
+            .iter()
+            .map(|path| (path.parent, path.name.as_str(), path.call_count))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec![
+                (None, "request", 1),
+                (Some(0), "compound_call", 1),
+                (Some(1), "one_ns", 3),
+                (Some(0), "query", 1),
+            ]
+        );
+        let compound_call = &decoded.call_paths[1];
+        assert_eq!(compound_call.sum_busy_ns, 1113);
+        assert_eq!(compound_call.sum_own_busy_ns, 1110);
+        assert_eq!(compound_call.file.as_deref(), Some("src/internal.rs"));
+        assert_eq!(compound_call.cache, None);
+        let cache = decoded.call_paths[3].cache.unwrap();
+        assert_eq!((cache.hit_count, cache.hit_busy_ns), (1, 5));
+        assert_eq!(cache.miss_count, 0);
+    }
+}
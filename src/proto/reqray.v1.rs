@@ -0,0 +1,77 @@
+// This file is @generated by prost-build.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CallTree {
+    #[prost(message, repeated, tag = "1")]
+    pub call_paths: ::prost::alloc::vec::Vec<CallPath>,
+    /// True if the per tree budget was exceeded and later spans are missing.
+    #[prost(bool, tag = "2")]
+    pub degraded: bool,
+    /// The number of spans that were not collected because of the budget.
+    #[prost(uint64, tag = "3")]
+    pub skipped_spans: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CallPath {
+    /// The index of the parent call path, absent for the root.
+    #[prost(uint32, optional, tag = "1")]
+    pub parent: ::core::option::Option<u32>,
+    /// The span name, possibly mapped or overridden by a name field.
+    #[prost(string, tag = "2")]
+    pub name: ::prost::alloc::string::String,
+    /// The target and source location of the span callsite.
+    #[prost(string, tag = "3")]
+    pub target: ::prost::alloc::string::String,
+    #[prost(string, optional, tag = "4")]
+    pub file: ::core::option::Option<::prost::alloc::string::String>,
+    #[prost(uint32, optional, tag = "5")]
+    pub line: ::core::option::Option<u32>,
+    /// The number of spans with this call path (events for event rows).
+    #[prost(uint64, tag = "6")]
+    pub call_count: u64,
+    /// True for aggregated retries, i.e. spans with `reqray.attempt` > 1.
+    #[prost(bool, tag = "7")]
+    pub retry: bool,
+    /// True for rows counting events instead of spans. They have no
+    /// durations.
+    #[prost(bool, tag = "8")]
+    pub event: bool,
+    #[prost(uint64, tag = "9")]
+    pub sum_alive_ns: u64,
+    #[prost(uint64, tag = "10")]
+    pub sum_busy_ns: u64,
+    #[prost(uint64, tag = "11")]
+    pub sum_own_busy_ns: u64,
+    #[prost(uint64, tag = "12")]
+    pub sum_awaited_ns: u64,
+    #[prost(uint64, tag = "13")]
+    pub min_busy_ns: u64,
+    #[prost(uint64, tag = "14")]
+    pub max_busy_ns: u64,
+    /// Relative to the creation of the root span.
+    #[prost(uint64, optional, tag = "15")]
+    pub first_entered_ns: ::core::option::Option<u64>,
+    #[prost(message, repeated, tag = "16")]
+    pub notes: ::prost::alloc::vec::Vec<Note>,
+    #[prost(message, optional, tag = "17")]
+    pub cache: ::core::option::Option<CacheStats>,
+}
+/// A distinct `reqray.note` value and how often it was recorded.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Note {
+    #[prost(string, tag = "1")]
+    pub note: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "2")]
+    pub count: u64,
+}
+/// Spans with a boolean `cache.hit` field.
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct CacheStats {
+    #[prost(uint64, tag = "1")]
+    pub hit_count: u64,
+    #[prost(uint64, tag = "2")]
+    pub hit_busy_ns: u64,
+    #[prost(uint64, tag = "3")]
+    pub miss_count: u64,
+    #[prost(uint64, tag = "4")]
+    pub miss_busy_ns: u64,
+}
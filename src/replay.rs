@@ -0,0 +1,221 @@
+//! Re-emit finished call trees as synthetic `tracing` spans and events into
+//! another subscriber, e.g. a file-backed JSON
+//! [fmt](https://docs.rs/tracing-subscriber/latest/tracing_subscriber/fmt/)
+//! subscriber, so that existing tracing tooling can consume them.
+//!
+//! Like for [otel](crate::otel), every call path becomes a single span,
+//! nested like the call tree. The aggregated values are attached as fields:
+//!
+//! * `name`, `path`, `span_target`, `file` and `line` describe the call
+//!   path,
+//! * `calls`, `alive_ns`, `busy_ns`, `own_busy_ns`, `errors` and
+//!   `warnings` are the aggregated values, see [CallPathTiming].
+//!
+//! Call paths of [events](CallPathTiming::is_event) become events with the
+//! descriptive fields and `calls`. All spans and events use the
+//! [REPLAY_TARGET] target and the level of the original span or event.
+
+use std::sync::Arc;
+
+use tracing::{span, Dispatch, Level, Span};
+
+use crate::{CallPathPool, CallPathTiming, FinishedCallTreeProcessor};
+
+/// The target of all replayed spans and events.
+pub const REPLAY_TARGET: &str = "reqray::replay";
+
+/// A [FinishedCallTreeProcessor] which [replays](replay) every call tree
+/// into the given subscriber.
+///
+/// ```
+/// use reqray::{replay::ReplayProcessor, CallTreeCollectorBuilder};
+///
+/// let replayed = tracing_subscriber::fmt()
+///     .with_writer(std::io::stderr)
+///     .finish();
+/// let collector =
+///     CallTreeCollectorBuilder::default().build_with_collector(ReplayProcessor::new(replayed));
+/// ```
+///
+/// Do not pass a subscriber which contains the collector itself, since the
+/// replayed spans would form new call trees.
+#[derive(Debug, Clone)]
+pub struct ReplayProcessor {
+    dispatch: Dispatch,
+}
+
+impl ReplayProcessor {
+    pub fn new(subscriber: impl Into<Dispatch>) -> Self {
+        ReplayProcessor {
+            dispatch: subscriber.into(),
+        }
+    }
+}
+
+impl FinishedCallTreeProcessor for ReplayProcessor {
+    fn process_finished_call(&self, pool: CallPathPool) {
+        tracing::dispatcher::with_default(&self.dispatch, || replay(&pool))
+    }
+
+    fn process_shared_call(&self, pool: Arc<CallPathPool>) {
+        tracing::dispatcher::with_default(&self.dispatch, || replay(&pool))
+    }
+}
+
+/// Emits the call tree into the default subscriber of the current thread,
+/// children in the order they were first called.
+pub fn replay(pool: &CallPathPool) {
+    replay_call_path(pool, pool.root(), None);
+}
+
+/// Expands to the given `span!` or `event!` invocation with a constant
+/// level, since callsites need static metadata.
+macro_rules! at_level {
+    ($level:expr, $mac:ident!(target: $target:expr, parent: $parent:expr, $($rest:tt)*)) => {
+        match $level {
+            Level::TRACE => tracing::$mac!(target: $target, parent: $parent, Level::TRACE, $($rest)*),
+            Level::DEBUG => tracing::$mac!(target: $target, parent: $parent, Level::DEBUG, $($rest)*),
+            Level::INFO => tracing::$mac!(target: $target, parent: $parent, Level::INFO, $($rest)*),
+            Level::WARN => tracing::$mac!(target: $target, parent: $parent, Level::WARN, $($rest)*),
+            _ => tracing::$mac!(target: $target, parent: $parent, Level::ERROR, $($rest)*),
+        }
+    };
+}
+
+fn replay_call_path(pool: &CallPathPool, node: &CallPathTiming, parent: Option<span::Id>) {
+    let meta = node.span_metadata();
+    let path = node.path_string(pool, "/");
+    if node.is_event() {
+        at_level!(
+            meta.level(),
+            event!(
+                target: REPLAY_TARGET,
+                parent: parent,
+                name = node.name(),
+                path = path.as_str(),
+                span_target = meta.target(),
+                file = meta.file(),
+                line = meta.line(),
+                calls = node.call_count() as u64,
+            )
+        );
+        return;
+    }
+    let span: Span = at_level!(
+        meta.level(),
+        span!(
+            target: REPLAY_TARGET,
+            parent: parent,
+            "call_path",
+            name = node.name(),
+            path = path.as_str(),
+            span_target = meta.target(),
+            file = meta.file(),
+            line = meta.line(),
+            calls = node.call_count() as u64,
+            alive_ns = node.span_alive().as_nanos() as u64,
+            busy_ns = node.sum_with_children().as_nanos() as u64,
+            own_busy_ns = node.sum_without_children().as_nanos() as u64,
+            errors = node.error_count() as u64,
+            warnings = node.warn_count() as u64,
+        )
+    );
+
+    let mut children = node.children().copied().collect::<Vec<_>>();
+    children.sort();
+    for child_idx in children {
+        replay_call_path(pool, &pool[child_idx], span.id());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fmt,
+        sync::{Arc, Mutex},
+    };
+
+    use tracing::{
+        field::{Field, Visit},
+        span, Event, Subscriber,
+    };
+    use tracing_subscriber::{layer::Context, prelude::*, registry::LookupSpan, Layer};
+
+    use super::ReplayProcessor;
+    use crate::{
+        internal::test::{collect_call_trees_with_builder, compound_call},
+        CallTreeCollectorBuilder, FinishedCallTreeProcessor,
+    };
+
+    /// Records the kind, parent and fields except `file` of all spans and events.
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    struct FieldsVisitor<'a>(&'a mut String);
+
+    impl Visit for FieldsVisitor<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            if field.name() != "file" {
+                self.0.push_str(&format!(" {}={:?}", field.name(), value));
+            }
+        }
+    }
+
+    impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Recorder {
+        fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+            let parent = ctx.span(id).unwrap().parent().map(|parent| parent.name());
+            let mut line = format!("span parent={:?}", parent);
+            attrs.record(&mut FieldsVisitor(&mut line));
+            self.0.lock().unwrap().push(line);
+        }
+
+        fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+            let mut line = format!("event {}", event.metadata().level());
+            event.record(&mut FieldsVisitor(&mut line));
+            self.0.lock().unwrap().push(line);
+        }
+    }
+
+    #[test]
+    fn replays_call_paths() {
+        let builder = CallTreeCollectorBuilder::default().collect_events(true);
+        let call_trees = collect_call_trees_with_builder(builder, |mock| {
+            compound_call(&mock);
+            tracing::info_span!("with_event").in_scope(|| tracing::warn!("done"));
+        });
+        let recorder = Recorder::default();
+        let processor = ReplayProcessor::new(tracing_subscriber::registry().with(recorder.clone()));
+        for pool in call_trees {
+            processor.process_finished_call(pool);
+        }
+
+        let recorded = recorder.0.lock().unwrap();
+        let lines = recorded
+            .iter()
+            // Line numbers of the test helpers are not stable.
+            .map(|recorded| {
+                recorded
+                    .split(' ')
+                    .filter(|field| !field.starts_with("line="))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                "span parent=None name=\"compound_call\" path=\"compound_call\" \
+                 span_target=\"reqray::internal::test\" calls=1 alive_ns=1113 busy_ns=1113 \
+                 own_busy_ns=1110 errors=0 warnings=0",
+                "span parent=Some(\"call_path\") name=\"one_ns\" path=\"compound_call/one_ns\" \
+                 span_target=\"reqray::internal::test\" calls=3 alive_ns=3 busy_ns=3 \
+                 own_busy_ns=3 errors=0 warnings=0",
+                "span parent=None name=\"with_event\" path=\"with_event\" \
+                 span_target=\"reqray::replay::test\" calls=1 alive_ns=0 busy_ns=0 \
+                 own_busy_ns=0 errors=0 warnings=1",
+                "event WARN name=\"done\" path=\"with_event/done\" \
+                 span_target=\"reqray::replay::test\" calls=1",
+            ]
+        );
+    }
+}
@@ -0,0 +1,163 @@
+use std::time::Duration;
+
+use crate::{CallPathPool, CallPathTiming, CallTreeVisitor};
+
+/// An owned, nested copy of a call tree without any references to `tracing`
+/// metadata, e.g. to send call trees to other processes, persist them or
+/// hand them over an FFI boundary.
+///
+/// ```
+/// use reqray::{CallPathPool, CallTreeSnapshot};
+///
+/// fn slowest_child(pool: &CallPathPool) -> Option<String> {
+///     let snapshot = CallTreeSnapshot::from(pool);
+///     snapshot
+///         .root
+///         .children
+///         .into_iter()
+///         .max_by_key(|child| child.busy)
+///         .map(|child| child.name)
+/// }
+/// ```
+///
+/// With the `serde` feature, snapshots can be serialized as well.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CallTreeSnapshot {
+    pub root: CallPathSnapshot,
+    /// See [CallPathPool::is_degraded].
+    pub degraded: bool,
+    /// See [CallPathPool::skipped_spans].
+    pub skipped_spans: usize,
+    /// See [CallPathPool::untracked_spans].
+    pub untracked_spans: usize,
+}
+
+/// A call path of a [CallTreeSnapshot] with its children, see
+/// [CallPathTiming] for the meaning of the values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CallPathSnapshot {
+    pub name: String,
+    pub target: String,
+    /// The level of the spans, like `INFO`.
+    pub level: String,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    pub is_event: bool,
+    pub is_retry: bool,
+    pub is_overflow: bool,
+    pub calls: usize,
+    pub alive: Duration,
+    pub busy: Duration,
+    pub own_busy: Duration,
+    pub io_wait: Duration,
+    pub polls: usize,
+    pub idle: Duration,
+    pub cpu: Duration,
+    pub errors: usize,
+    pub warnings: usize,
+    /// In the order of their creation.
+    pub children: Vec<CallPathSnapshot>,
+}
+
+impl From<&CallPathTiming> for CallPathSnapshot {
+    /// Copies `timing` without its children.
+    fn from(timing: &CallPathTiming) -> Self {
+        let meta = timing.span_metadata();
+        CallPathSnapshot {
+            name: timing.name().to_string(),
+            target: meta.target().to_string(),
+            level: meta.level().to_string(),
+            file: meta.file().map(str::to_string),
+            line: meta.line(),
+            is_event: timing.is_event(),
+            is_retry: timing.is_retry(),
+            is_overflow: timing.is_overflow(),
+            calls: timing.call_count(),
+            alive: timing.span_alive(),
+            busy: timing.sum_with_children(),
+            own_busy: timing.sum_without_children(),
+            io_wait: timing.sum_io_wait(),
+            polls: timing.poll_count(),
+            idle: timing.idle_time(),
+            cpu: timing.sum_cpu(),
+            errors: timing.error_count(),
+            warnings: timing.warn_count(),
+            children: Vec::new(),
+        }
+    }
+}
+
+/// Builds the nested call paths, the root remains in `parents` at the end.
+#[derive(Default)]
+struct SnapshotBuilder {
+    parents: Vec<CallPathSnapshot>,
+}
+
+impl CallTreeVisitor for SnapshotBuilder {
+    fn enter_node(&mut self, _depth: usize, node: &CallPathTiming) {
+        self.parents.push(node.into());
+    }
+
+    fn leave_node(&mut self, _depth: usize, _node: &CallPathTiming) {
+        if self.parents.len() > 1 {
+            let node = self.parents.pop().expect("entered before");
+            self.parents
+                .last_mut()
+                .expect("checked length")
+                .children
+                .push(node);
+        }
+    }
+}
+
+impl From<&CallPathPool> for CallTreeSnapshot {
+    fn from(pool: &CallPathPool) -> Self {
+        let mut builder = SnapshotBuilder::default();
+        pool.visit(&mut builder);
+        CallTreeSnapshot {
+            root: builder.parents.pop().expect("every call tree has a root"),
+            degraded: pool.is_degraded(),
+            skipped_spans: pool.skipped_spans(),
+            untracked_spans: pool.untracked_spans(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::CallTreeSnapshot;
+    use crate::internal::test::{collect_call_trees, compound_call};
+
+    #[test]
+    fn snapshot_call_tree() {
+        let call_trees = collect_call_trees(|mock| compound_call(&mock));
+        let snapshot = CallTreeSnapshot::from(&call_trees[0]);
+        drop(call_trees);
+
+        // Owned, so it can go anywhere.
+        let snapshot = std::thread::spawn(move || snapshot).join().unwrap();
+        let root = &snapshot.root;
+        assert_eq!(root.name, "compound_call");
+        assert_eq!(root.target, "reqray::internal::test");
+        assert_eq!(root.level, "INFO");
+        assert_eq!(root.file.as_deref(), Some("src/internal.rs"));
+        assert_eq!(root.busy, Duration::from_nanos(1113));
+        assert_eq!(root.own_busy, Duration::from_nanos(1110));
+        assert_eq!(root.children.len(), 1);
+        let child = &root.children[0];
+        assert_eq!((child.name.as_str(), child.calls), ("one_ns", 3));
+        assert!(child.children.is_empty());
+        assert!(!snapshot.degraded);
+
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_string(&snapshot).unwrap();
+            let deserialized: CallTreeSnapshot = serde_json::from_str(&json).unwrap();
+            assert_eq!(deserialized, snapshot);
+        }
+    }
+}
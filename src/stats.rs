@@ -0,0 +1,425 @@
+//! Statistics about a running [CallTreeCollector](crate::CallTreeCollector),
+//! including the call trees that are still in flight.
+
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex, PoisonError,
+    },
+    thread,
+    time::Duration,
+};
+
+use tracing::span;
+use tracing_subscriber::{registry::LookupSpan, Registry};
+
+use crate::{clock::SharedClock, internal::PendingCallTree, CallPathPool};
+
+/// A cheaply clonable handle to the statistics of a
+/// [CallTreeCollector](crate::CallTreeCollector), see
+/// [CallTreeCollector::stats](crate::CallTreeCollector::stats).
+///
+/// The handle stays valid after the collector has been moved into the
+/// subscriber, so it can be polled e.g. from a debug endpoint.
+#[derive(Clone)]
+pub struct CollectorStats {
+    inner: Arc<StatsInner>,
+}
+
+struct StatsInner {
+    clock: SharedClock,
+    /// The number of call trees which exceeded their budget.
+    degraded_trees: AtomicUsize,
+    /// The number of call trees whose root span was never closed.
+    abandoned_trees: AtomicUsize,
+    /// Used to tell apart in-flight trees if span IDs are reused.
+    next_tree_id: AtomicU64,
+    /// The in-flight call trees by tree ID, `None` if not tracked.
+    in_flight: Option<Mutex<HashMap<u64, Arc<InFlightEntry>>>>,
+}
+
+/// The shared state of an in-flight call tree, stored in the extensions
+/// of its root span.
+#[derive(Debug)]
+pub(crate) struct InFlightEntry {
+    tree_id: u64,
+    root: span::Id,
+    name: Cow<'static, str>,
+    created_at: u64,
+    spans: AtomicUsize,
+}
+
+impl InFlightEntry {
+    /// Updates the number of spans collected for this tree.
+    pub(crate) fn set_spans(&self, spans: usize) {
+        self.spans.store(spans, Ordering::Relaxed);
+    }
+}
+
+impl CollectorStats {
+    pub(crate) fn new(clock: SharedClock, track_in_flight: bool) -> CollectorStats {
+        CollectorStats {
+            inner: Arc::new(StatsInner {
+                clock,
+                degraded_trees: AtomicUsize::new(0),
+                abandoned_trees: AtomicUsize::new(0),
+                next_tree_id: AtomicU64::new(0),
+                in_flight: if track_in_flight {
+                    Some(Mutex::new(HashMap::new()))
+                } else {
+                    None
+                },
+            }),
+        }
+    }
+
+    /// The number of call trees so far which exceeded the per tree budget,
+    /// see [CallTreeCollectorBuilder::max_spans_per_tree](crate::CallTreeCollectorBuilder::max_spans_per_tree).
+    pub fn degraded_trees(&self) -> usize {
+        self.inner.degraded_trees.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn add_degraded_tree(&self) {
+        self.inner.degraded_trees.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The number of call trees whose root span was never closed, e.g.
+    /// because it was leaked when the subscriber was dropped.
+    ///
+    /// Always `0` unless enabled with
+    /// [CallTreeCollectorBuilder::hand_over_abandoned](crate::CallTreeCollectorBuilder::hand_over_abandoned),
+    /// which hands these call trees to the processor when the
+    /// [CallTreeCollector](crate::CallTreeCollector) is dropped. Call paths
+    /// of spans that were not closed either show up without calls or
+    /// durations, like in [InFlightTree::partial_call_tree].
+    pub fn abandoned_trees(&self) -> usize {
+        self.inner.abandoned_trees.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn add_abandoned_tree(&self) {
+        self.inner.abandoned_trees.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The number of call trees whose root span has not been closed yet.
+    ///
+    /// Always `0` unless enabled with
+    /// [CallTreeCollectorBuilder::track_in_flight](crate::CallTreeCollectorBuilder::track_in_flight).
+    pub fn in_flight_trees(&self) -> usize {
+        match &self.inner.in_flight {
+            Some(in_flight) => in_flight
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .len(),
+            None => 0,
+        }
+    }
+
+    /// The call trees whose root span has not been closed yet, the longest
+    /// alive first.
+    ///
+    /// Always empty unless enabled with
+    /// [CallTreeCollectorBuilder::track_in_flight](crate::CallTreeCollectorBuilder::track_in_flight).
+    pub fn in_flight(&self) -> Vec<InFlightTree> {
+        let in_flight = match &self.inner.in_flight {
+            Some(in_flight) => in_flight,
+            None => return Vec::new(),
+        };
+        let entries: Vec<Arc<InFlightEntry>> = in_flight
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .cloned()
+            .collect();
+        let now = self.inner.clock.end();
+        let mut trees: Vec<InFlightTree> = entries
+            .into_iter()
+            .map(|entry| InFlightTree {
+                alive: self.inner.clock.delta(entry.created_at, now),
+                spans: entry.spans.load(Ordering::Relaxed),
+                entry,
+            })
+            .collect();
+        trees.sort_by_key(|tree| std::cmp::Reverse(tree.alive));
+        trees
+    }
+
+    /// Registers a new call tree if in-flight trees are tracked.
+    pub(crate) fn register(
+        &self,
+        root: span::Id,
+        name: Cow<'static, str>,
+        created_at: u64,
+    ) -> Option<Arc<InFlightEntry>> {
+        let in_flight = self.inner.in_flight.as_ref()?;
+        let entry = Arc::new(InFlightEntry {
+            tree_id: self.inner.next_tree_id.fetch_add(1, Ordering::Relaxed),
+            root,
+            name,
+            created_at,
+            spans: AtomicUsize::new(1),
+        });
+        in_flight
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(entry.tree_id, entry.clone());
+        Some(entry)
+    }
+
+    /// Starts a [Watchdog] thread which calls `on_overlong` once for every
+    /// call tree that is still in flight after `max_alive`, e.g. to log a
+    /// partial call tree of a stuck request:
+    ///
+    /// ```
+    /// # #[cfg(feature = "display")] {
+    /// use std::time::Duration;
+    /// use reqray::{CallTreeCollectorBuilder, display::LoggingCallTreeCollectorBuilder};
+    /// use tracing_subscriber::{util::SubscriberInitExt, prelude::*};
+    ///
+    /// let collector = CallTreeCollectorBuilder::default()
+    ///     .track_in_flight(true)
+    ///     .build_with_collector(LoggingCallTreeCollectorBuilder::default().build());
+    /// let stats = collector.stats();
+    /// tracing_subscriber::registry().with(collector).init();
+    ///
+    /// let logger = LoggingCallTreeCollectorBuilder::default().build();
+    /// let watchdog = stats.spawn_watchdog(
+    ///     Duration::from_secs(30),
+    ///     Duration::from_secs(1),
+    ///     move |tree| logger.log_in_flight(tree),
+    /// );
+    /// # }
+    /// ```
+    ///
+    /// The in-flight call trees are checked every `interval`. The watchdog
+    /// runs with the default subscriber at the time of this call, so that
+    /// [InFlightTree::partial_call_tree] works inside `on_overlong`. It
+    /// only sees call trees if tracking is enabled with
+    /// [CallTreeCollectorBuilder::track_in_flight](crate::CallTreeCollectorBuilder::track_in_flight).
+    ///
+    /// The thread is stopped when the returned [Watchdog] is dropped. Not
+    /// available on `wasm32`, which has no threads.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn spawn_watchdog<F>(
+        &self,
+        max_alive: Duration,
+        interval: Duration,
+        on_overlong: F,
+    ) -> Watchdog
+    where
+        F: Fn(&InFlightTree) + Send + 'static,
+    {
+        let stats = self.clone();
+        let dispatch = tracing::dispatcher::get_default(tracing::Dispatch::clone);
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::Builder::new()
+            .name("reqray-watchdog".to_string())
+            .spawn(move || {
+                tracing::dispatcher::with_default(&dispatch, || {
+                    let mut reported = HashSet::new();
+                    while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval)
+                    {
+                        let in_flight = stats.in_flight();
+                        reported.retain(|tree_id| {
+                            in_flight.iter().any(|tree| tree.entry.tree_id == *tree_id)
+                        });
+                        for tree in in_flight.iter().filter(|tree| tree.alive >= max_alive) {
+                            if reported.insert(tree.entry.tree_id) {
+                                on_overlong(tree);
+                            }
+                        }
+                    }
+                })
+            })
+            .expect("could not spawn reqray watchdog thread");
+        Watchdog {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+
+    /// Removes a finished call tree.
+    pub(crate) fn deregister(&self, entry: &InFlightEntry) {
+        if let Some(in_flight) = &self.inner.in_flight {
+            in_flight
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .remove(&entry.tree_id);
+        }
+    }
+}
+
+impl fmt::Debug for CollectorStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CollectorStats")
+            .field("degraded_trees", &self.degraded_trees())
+            .field("abandoned_trees", &self.abandoned_trees())
+            .field("in_flight_trees", &self.in_flight_trees())
+            .finish()
+    }
+}
+
+/// A snapshot of a call tree whose root span has not been closed yet.
+#[derive(Debug, Clone)]
+pub struct InFlightTree {
+    entry: Arc<InFlightEntry>,
+    alive: Duration,
+    spans: usize,
+}
+
+impl InFlightTree {
+    /// The name of the root call path.
+    pub fn name(&self) -> &str {
+        &self.entry.name
+    }
+
+    /// The ID of the root span.
+    pub fn root_id(&self) -> &span::Id {
+        &self.entry.root
+    }
+
+    /// The time since the root span was created.
+    pub fn alive(&self) -> Duration {
+        self.alive
+    }
+
+    /// The number of spans collected for the call tree so far.
+    pub fn spans(&self) -> usize {
+        self.spans
+    }
+
+    /// A copy of the call tree collected so far, e.g. to log where a stuck
+    /// request is hanging.
+    ///
+    /// Timings are only folded into the call tree when spans are closed,
+    /// so call paths of spans that are still open show up without calls
+    /// or durations.
+    ///
+    /// The call tree is looked up in the [Registry] of the current default
+    /// subscriber. Returns `None` if there is no such registry or if the
+    /// call tree has finished in the meantime.
+    pub fn partial_call_tree(&self) -> Option<CallPathPool> {
+        tracing::dispatcher::get_default(|dispatch| {
+            let registry = dispatch.downcast_ref::<Registry>()?;
+            let span = registry.span(&self.entry.root)?;
+            let extensions = span.extensions();
+            match extensions.get::<Arc<InFlightEntry>>() {
+                // The span ID might have been reused for another span.
+                Some(entry) if Arc::ptr_eq(entry, &self.entry) => {}
+                _ => return None,
+            }
+            extensions.get::<PendingCallTree>()?.snapshot()
+        })
+    }
+}
+
+/// A background thread reporting overlong in-flight call trees, see
+/// [CollectorStats::spawn_watchdog].
+///
+/// Dropping the watchdog stops the thread.
+pub struct Watchdog {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        // Disconnecting wakes up the thread.
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{mpsc, Arc},
+        time::Duration,
+    };
+
+    use quanta::{Clock, Mock};
+    use tracing::info_span;
+    use tracing_subscriber::prelude::*;
+
+    use crate::{internal::test::FinishedCallTreeStore, CallTreeCollectorBuilder, CollectorStats};
+
+    fn with_stats(track_in_flight: bool, call: impl FnOnce(Arc<Mock>, CollectorStats)) {
+        let (clock, mock) = Clock::mock();
+        let collector = CallTreeCollectorBuilder::default()
+            .clock(clock)
+            .track_in_flight(track_in_flight)
+            .build_with_collector(FinishedCallTreeStore::default());
+        let stats = collector.stats();
+        let subscriber = tracing_subscriber::registry().with(collector);
+        tracing::subscriber::with_default(subscriber, || call(mock, stats));
+    }
+
+    #[test]
+    fn in_flight_trees() {
+        with_stats(true, |mock, stats| {
+            assert_eq!(stats.in_flight_trees(), 0);
+            let request = info_span!("request");
+            mock.increment(10);
+            let _enter = request.enter();
+            let fetch = info_span!("fetch");
+            mock.increment(5);
+
+            let in_flight = stats.in_flight();
+            assert_eq!(in_flight.len(), 1);
+            assert_eq!(in_flight[0].name(), "request");
+            assert_eq!(in_flight[0].spans(), 2);
+            assert_eq!(in_flight[0].alive(), Duration::from_nanos(15));
+            let partial = in_flight[0].partial_call_tree().unwrap();
+            let child = *partial.root().children().next().unwrap();
+            assert_eq!(partial[child].name(), "fetch");
+
+            drop(fetch);
+            drop(_enter);
+            drop(request);
+            assert_eq!(stats.in_flight_trees(), 0);
+            assert!(in_flight[0].partial_call_tree().is_none());
+        });
+    }
+
+    #[test]
+    fn in_flight_trees_untracked() {
+        with_stats(false, |_mock, stats| {
+            let _request = info_span!("request");
+            assert_eq!(stats.in_flight_trees(), 0);
+            assert!(stats.in_flight().is_empty());
+        });
+    }
+
+    #[test]
+    fn watchdog_reports_overlong_trees_once() {
+        with_stats(true, |mock, stats| {
+            let (sender, receiver) = mpsc::channel();
+            let watchdog = stats.spawn_watchdog(
+                Duration::from_nanos(100),
+                Duration::from_millis(1),
+                move |tree| {
+                    let partial = tree
+                        .partial_call_tree()
+                        .map(|pool| pool.root().name().to_string());
+                    sender.send((tree.name().to_string(), partial)).unwrap();
+                },
+            );
+
+            let _fast = info_span!("fast");
+            mock.increment(50);
+            let _young = info_span!("young");
+            mock.increment(60);
+            // Only `_fast` has been alive long enough.
+            let reported = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(reported, ("fast".to_string(), Some("fast".to_string())));
+            std::thread::sleep(Duration::from_millis(20));
+            assert!(receiver.try_recv().is_err());
+
+            drop(watchdog);
+        });
+    }
+}
@@ -0,0 +1,52 @@
+//! The synchronization primitives of the state that the collector and the
+//! aggregator share between threads.
+//!
+//! They are the ones of [loom](https://docs.rs/loom) if compiled with
+//! `--cfg reqray_loom`, so that the loom tests can check all interleavings
+//! of the threads:
+//!
+//! ```text
+//! RUSTFLAGS="--cfg reqray_loom" cargo test --release --lib loom_
+//! ```
+//!
+//! Other tests fail with this cfg because loom primitives only work inside
+//! of `loom::model`.
+
+#[cfg(not(reqray_loom))]
+pub(crate) use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+#[cfg(reqray_loom)]
+pub(crate) use loom::sync::{Arc, Mutex, MutexGuard};
+
+/// Locks `mutex`, also if another thread panicked while holding it, so
+/// that one panicking thread does not stop the collection on all others.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// A weak reference to the value of `arc`.
+#[cfg(not(reqray_loom))]
+pub(crate) fn downgrade<T>(arc: &Arc<T>) -> Weak<T> {
+    Arc::downgrade(arc)
+}
+
+/// A weak reference to the value of `arc`.
+#[cfg(reqray_loom)]
+pub(crate) fn downgrade<T>(arc: &Arc<T>) -> Weak<T> {
+    Weak(arc.clone())
+}
+
+/// loom has no weak references, so this one keeps the value alive. This
+/// only changes when values are freed, not who gets to take them.
+#[cfg(reqray_loom)]
+pub(crate) struct Weak<T>(Arc<T>);
+
+#[cfg(reqray_loom)]
+impl<T> Weak<T> {
+    pub(crate) fn upgrade(&self) -> Option<Arc<T>> {
+        Some(self.0.clone())
+    }
+}
@@ -0,0 +1,148 @@
+//! Keep the spans of spawned tasks and threads in the call tree of the
+//! code that spawned them, independent of the async runtime.
+//!
+//! A spawned task runs on whatever thread the runtime picks. Unless it
+//! carries both the current span and the current subscriber along, its
+//! spans either start a new call tree or are not collected at all, so
+//! that the call tree of a request silently fragments. This happens with
+//! all runtimes, e.g. tokio, async-std, smol or glommio.
+//!
+//! [in_current_xray](XrayFutureExt::in_current_xray) attaches both to a
+//! future before it is spawned:
+//!
+//! ```
+//! use reqray::task::XrayFutureExt;
+//!
+//! # async_std::task::block_on(async {
+//! let _request = tracing::info_span!("request").entered();
+//! let handle = async_std::task::spawn(
+//!     async {
+//!         tracing::info_span!("spawned").in_scope(|| {});
+//!     }
+//!     .in_current_xray(),
+//! );
+//! handle.await;
+//! # });
+//! ```
+//!
+//! Use [in_current_xray] for closures passed to `std::thread::spawn` or
+//! `spawn_blocking`.
+
+use std::future::Future;
+
+use tracing::{
+    instrument::{Instrumented, WithDispatch},
+    Dispatch, Instrument, Span,
+};
+
+/// Extends all futures with [in_current_xray](Self::in_current_xray).
+pub trait XrayFutureExt: Future + Sized {
+    /// Runs the future in the current span and with the current subscriber,
+    /// so that its spans are part of the current call tree no matter on
+    /// which thread it is polled.
+    fn in_current_xray(self) -> WithDispatch<Instrumented<Self>> {
+        use tracing::instrument::WithSubscriber;
+
+        self.in_current_span().with_current_subscriber()
+    }
+}
+
+impl<F: Future> XrayFutureExt for F {}
+
+/// Wraps `f` so that it runs in the current span and with the current
+/// subscriber, like [XrayFutureExt::in_current_xray] for futures.
+///
+/// ```
+/// let _request = tracing::info_span!("request").entered();
+/// std::thread::spawn(reqray::task::in_current_xray(|| {
+///     tracing::info_span!("spawned").in_scope(|| {});
+/// }))
+/// .join()
+/// .unwrap();
+/// ```
+pub fn in_current_xray<F, R>(f: F) -> impl FnOnce() -> R
+where
+    F: FnOnce() -> R,
+{
+    let span = Span::current();
+    let dispatch = tracing::dispatcher::get_default(Dispatch::clone);
+    move || tracing::dispatcher::with_default(&dispatch, || span.in_scope(f))
+}
+
+#[cfg(test)]
+mod test {
+    use std::future::Future;
+
+    use super::{in_current_xray, XrayFutureExt};
+    use crate::{test_util::capture, CallPathPool};
+
+    /// The names of all call paths, in depth-first order.
+    fn call_paths(call_trees: &[CallPathPool]) -> Vec<String> {
+        call_trees
+            .iter()
+            .flat_map(|pool| pool.flatten("/").map(|(path, _)| path))
+            .collect()
+    }
+
+    async fn spawned() {
+        tracing::info_span!("spawned").in_scope(|| {});
+    }
+
+    /// Calls `spawn` inside a request span and waits for the spawned task.
+    async fn request<H: Future>(spawn: impl FnOnce() -> H) {
+        let handle = {
+            let _request = tracing::info_span!("request").entered();
+            spawn()
+        };
+        handle.await;
+    }
+
+    #[test]
+    fn tokio_multi_thread() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .build()
+            .unwrap();
+        let call_trees = capture(|| {
+            runtime.block_on(request(|| tokio::spawn(spawned().in_current_xray())));
+        });
+        assert_eq!(call_paths(&call_trees), vec!["request", "request/spawned"]);
+
+        // Without the subscriber, the spans of the task are lost.
+        let call_trees = capture(|| {
+            runtime.block_on(request(|| tokio::spawn(spawned())));
+        });
+        assert_eq!(call_paths(&call_trees), vec!["request"]);
+    }
+
+    #[test]
+    fn async_std() {
+        let call_trees = capture(|| {
+            async_std::task::block_on(request(|| {
+                async_std::task::spawn(spawned().in_current_xray())
+            }));
+        });
+        assert_eq!(call_paths(&call_trees), vec!["request", "request/spawned"]);
+    }
+
+    #[test]
+    fn smol() {
+        let call_trees = capture(|| {
+            smol::block_on(request(|| smol::spawn(spawned().in_current_xray())));
+        });
+        assert_eq!(call_paths(&call_trees), vec!["request", "request/spawned"]);
+    }
+
+    #[test]
+    fn threads() {
+        let call_trees = capture(|| {
+            let _request = tracing::info_span!("request").entered();
+            std::thread::spawn(in_current_xray(|| {
+                tracing::info_span!("spawned").in_scope(|| {});
+            }))
+            .join()
+            .unwrap();
+        });
+        assert_eq!(call_paths(&call_trees), vec!["request", "request/spawned"]);
+    }
+}
@@ -0,0 +1,169 @@
+//! Collect the call trees of code under test, e.g. to assert on call
+//! counts or on the shape of a call tree.
+//!
+//! Enable it with the `test_util` feature:
+//!
+//! ```toml
+//! [dev-dependencies]
+//! reqray = { version = "0.4", features = ["test_util"] }
+//! ```
+//!
+//! The functions in this module install a [CallTreeCollector] as the
+//! default subscriber of the current thread while running the given code,
+//! and return all call trees that finished in the meantime:
+//!
+//! ```
+//! use reqray::test_util::capture_mocked;
+//!
+//! let call_trees = capture_mocked(|mock| {
+//!     tracing::info_span!("request").in_scope(|| mock.increment(1_000));
+//! });
+//! assert_eq!(call_trees.len(), 1);
+//! assert_eq!(call_trees[0].root().name(), "request");
+//! assert_eq!(call_trees[0].root().sum_with_children().as_nanos(), 1_000);
+//! ```
+//!
+//! Call trees whose root span is still alive at the end are not returned.
+//! Spans that are created on other threads are not captured either, since
+//! the subscriber is only installed for the current thread.
+//!
+//! [CallTreeCollector]: crate::CallTreeCollector
+
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use quanta::Clock;
+pub use quanta::Mock;
+use tracing_subscriber::prelude::*;
+
+use crate::{
+    processors::block_on, CallPathPool, CallTreeCollectorBuilder, FinishedCallTreeProcessor,
+};
+
+/// Runs `call` and returns all call trees finished in the meantime,
+/// measured with the real clock.
+pub fn capture(call: impl FnOnce()) -> Vec<CallPathPool> {
+    capture_with(CallTreeCollectorBuilder::default(), call)
+}
+
+/// Like [capture] but with a mock clock which only advances when `call`
+/// increments it, so that durations are deterministic.
+pub fn capture_mocked(call: impl FnOnce(&Mock)) -> Vec<CallPathPool> {
+    let (clock, mock) = Clock::mock();
+    capture_with(
+        CallTreeCollectorBuilder::default()
+            .clock(clock)
+            .verify_clock(false),
+        || call(&mock),
+    )
+}
+
+/// Like [capture] but drives the future returned by `make_future` to
+/// completion on the current thread.
+///
+/// The future is created after the collector is installed, so that spans
+/// created along with the future, e.g. by `instrument`, are captured.
+///
+/// This is a minimal executor which only supports runtime independent
+/// futures. For futures which need a specific runtime, e.g. tokio timers,
+/// use [capture] with the `block_on` of that runtime instead:
+///
+/// ```
+/// # use reqray::test_util::capture;
+/// let runtime = tokio::runtime::Builder::new_current_thread()
+///     .enable_all()
+///     .build()
+///     .unwrap();
+/// let call_trees = capture(|| {
+///     runtime.block_on(async {
+///         let _request = tracing::info_span!("request").entered();
+///         tokio::task::yield_now().await;
+///     })
+/// });
+/// assert_eq!(call_trees.len(), 1);
+/// ```
+pub fn capture_async<F>(make_future: impl FnOnce() -> F) -> Vec<CallPathPool>
+where
+    F: Future<Output = ()>,
+{
+    capture(|| block_on(make_future()))
+}
+
+/// Like [capture] but with a custom collector configuration.
+///
+/// The processor of the collector is replaced so that the call trees can
+/// be returned.
+pub fn capture_with(builder: CallTreeCollectorBuilder, call: impl FnOnce()) -> Vec<CallPathPool> {
+    let store = CallTreeStore::default();
+    let collector = builder.build_with_collector(store.clone());
+    tracing::subscriber::with_default(tracing_subscriber::registry().with(collector), call);
+    let mut call_trees = store.0.lock().unwrap();
+    std::mem::take(&mut *call_trees)
+}
+
+/// Collects all finished call trees.
+#[derive(Clone, Default)]
+struct CallTreeStore(Arc<Mutex<Vec<CallPathPool>>>);
+
+impl FinishedCallTreeProcessor for CallTreeStore {
+    fn process_finished_call(&self, pool: CallPathPool) {
+        self.0.lock().unwrap().push(pool);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tracing_futures::Instrument;
+
+    use super::{capture, capture_async, capture_mocked, capture_with};
+    use crate::CallTreeCollectorBuilder;
+
+    #[test]
+    fn captures_finished_call_trees() {
+        let call_trees = capture_mocked(|mock| {
+            tracing::info_span!("first").in_scope(|| {
+                tracing::info_span!("child").in_scope(|| mock.increment(10));
+            });
+            tracing::info_span!("second").in_scope(|| mock.increment(20));
+            // Never closed.
+            std::mem::forget(tracing::info_span!("leaked"));
+        });
+        let roots = call_trees
+            .iter()
+            .map(|call_tree| {
+                let root = call_tree.root();
+                (root.name().to_string(), root.sum_with_children().as_nanos())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            roots,
+            vec![("first".to_string(), 10), ("second".to_string(), 20)]
+        );
+    }
+
+    #[test]
+    fn captures_async() {
+        let call_trees = capture_async(|| {
+            async {
+                let (tx, rx) = futures::channel::oneshot::channel();
+                std::thread::spawn(move || tx.send(()).unwrap());
+                rx.await.unwrap();
+            }
+            .instrument(tracing::info_span!("waiting"))
+        });
+        assert_eq!(call_trees.len(), 1);
+        assert_eq!(call_trees[0].root().name(), "waiting");
+    }
+
+    #[test]
+    fn captures_with_builder() {
+        let call_trees = capture_with(
+            CallTreeCollectorBuilder::default().span_name_mapper(|name| name.to_uppercase()),
+            || tracing::info_span!("request").in_scope(|| {}),
+        );
+        assert_eq!(call_trees[0].root().name(), "REQUEST");
+        assert!(capture(|| {}).is_empty());
+    }
+}
@@ -0,0 +1,288 @@
+//! Write call tree tables directly to a writer instead of logging them,
+//! e.g. to keep the x-rays in a dedicated file apart from the application
+//! logs.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    clock::system_time_now, display::LoggingCallTreeCollector, CallPathPool,
+    FinishedCallTreeProcessor,
+};
+
+/// A [FinishedCallTreeProcessor] which writes every call tree as a table
+/// to a writer, e.g. a file, stderr or an in-memory buffer, see
+/// [LoggingCallTreeCollectorBuilder::build_with_writer](crate::display::LoggingCallTreeCollectorBuilder::build_with_writer).
+///
+/// Every call tree starts with a header line prefixed with the UTC time
+/// at which it was written:
+///
+/// ```text
+/// 2022-02-06T20:01:57.103747Z Call summary of request@examples/nested.rs:51
+/// ```
+pub struct WriterCallTreeCollector<W: Write> {
+    config: LoggingCallTreeCollector,
+    writer: Mutex<W>,
+    timestamps: bool,
+}
+
+impl<W: Write> WriterCallTreeCollector<W> {
+    pub(crate) fn new(config: LoggingCallTreeCollector, writer: W) -> Self {
+        WriterCallTreeCollector {
+            config,
+            writer: Mutex::new(writer),
+            timestamps: true,
+        }
+    }
+
+    /// Whether to prefix headers with the current time. Enabled by
+    /// default.
+    pub fn with_timestamps(mut self, timestamps: bool) -> Self {
+        self.timestamps = timestamps;
+        self
+    }
+
+    /// Returns the writer, e.g. an in-memory buffer.
+    pub fn into_writer(self) -> W {
+        match self.writer.into_inner() {
+            Ok(writer) => writer,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl<W: Write> FinishedCallTreeProcessor for WriterCallTreeCollector<W> {
+    fn process_finished_call(&self, pool: CallPathPool) {
+        self.write(&pool)
+    }
+
+    fn process_shared_call(&self, pool: Arc<CallPathPool>) {
+        self.write(&pool)
+    }
+}
+
+impl<W: Write> WriterCallTreeCollector<W> {
+    fn write(&self, pool: &CallPathPool) {
+        let suppressed = match self.config.admit(pool) {
+            Some(suppressed) => suppressed,
+            None => return,
+        };
+        // Formatted up front, so that a call tree is written at once and
+        // not split up by a file rotation.
+        let mut out = String::new();
+        if self.timestamps {
+            out.push_str(&utc_timestamp(system_time_now()));
+            out.push(' ');
+        }
+        out.push_str(&self.config.header(pool, suppressed));
+        out.push_str(&format!("\n\n{}\n", self.config.table(pool)));
+
+        let mut writer = match self.writer.lock() {
+            Ok(writer) => writer,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let result = writer
+            .write_all(out.as_bytes())
+            .and_then(|_| writer.flush());
+        if let Err(e) = result {
+            tracing::warn!("Could not write call tree: {}", e);
+        }
+    }
+}
+
+/// Formats `time` like `2022-02-06T20:01:57.103747Z`.
+fn utc_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+
+    // The civil date from the days since 1970-01-01, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3_600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_micros()
+    )
+}
+
+/// A file which is rotated once it would grow beyond a maximum size,
+/// e.g. for a [WriterCallTreeCollector].
+///
+/// When rotating `xray.log`, it is renamed to `xray.log.1`, an existing
+/// `xray.log.1` to `xray.log.2` and so on. Only `max_files` rotated files
+/// are kept.
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    /// Appends to the file at `path`, creating it if necessary.
+    pub fn open(path: impl AsRef<Path>, max_bytes: u64, max_files: usize) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            max_bytes,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotated_path(&self, idx: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", idx));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let oldest = self.rotated_path(self.max_files);
+            if oldest.exists() {
+                fs::remove_file(oldest)?;
+            }
+            for idx in (1..self.max_files).rev() {
+                let rotated = self.rotated_path(idx);
+                if rotated.exists() {
+                    fs::rename(rotated, self.rotated_path(idx + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    /// Rotates the file before writing `buf` if it would grow too large.
+    /// `buf` is always written completely to the same file.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::Write,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use super::{utc_timestamp, RotatingFile};
+    use crate::{
+        display::LoggingCallTreeCollectorBuilder,
+        internal::test::{collect_call_trees, compound_call},
+        FinishedCallTreeProcessor,
+    };
+
+    #[test]
+    fn write_tables() {
+        let call_trees = collect_call_trees(|mock| compound_call(&mock));
+        let collector = LoggingCallTreeCollectorBuilder::default()
+            .left_margin(0)
+            .build_with_writer(Vec::new())
+            .with_timestamps(false);
+        for call_tree in call_trees {
+            collector.process_finished_call(call_tree);
+        }
+        let out = String::from_utf8(collector.into_writer()).unwrap();
+        let (header, table) = out.split_once("\n\n").unwrap();
+        assert!(header.starts_with("Call summary of compound_call@src/internal.rs:"));
+        assert_eq!(
+            table,
+            indoc::indoc! {r#"
+                    # calls │   ∑ alive ms │    ∑ busy ms │ ∑ own busy ms │ span tree
+                ────────────┼──────────────┼──────────────┼────────────-──┼───────────────────────
+                      0 001 ┊        0.001 ┊        0.001 ┊         0.001 ┊ ┬ compound_call
+                      0 003 ┊        0.000 ┊        0.000 ┊         0.000 ┊ ╰─ one_ns
+
+            "#}
+        );
+    }
+
+    #[test]
+    fn timestamps() {
+        let time = |secs, micros: u32| UNIX_EPOCH + Duration::new(secs, micros * 1_000);
+        assert_eq!(utc_timestamp(time(0, 0)), "1970-01-01T00:00:00.000000Z");
+        assert_eq!(
+            utc_timestamp(time(1_644_177_717, 103_747)),
+            "2022-02-06T20:01:57.103747Z"
+        );
+        assert_eq!(
+            utc_timestamp(time(951_827_696, 1)),
+            "2000-02-29T12:34:56.000001Z"
+        );
+    }
+
+    #[test]
+    fn rotate_files() {
+        let dir = std::env::temp_dir().join(format!("reqray-rotate-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("xray.log");
+
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        for tree in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(tree.as_bytes()).unwrap();
+        }
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("xray.log"), "fourth\n");
+        assert_eq!(read("xray.log.1"), "third\n");
+        assert_eq!(read("xray.log.2"), "second\n");
+        assert!(!dir.join("xray.log.3").exists());
+
+        // Appends after reopening.
+        let mut file = RotatingFile::open(&path, 100, 2).unwrap();
+        file.write_all(b"fifth\n").unwrap();
+        assert_eq!(read("xray.log"), "fourth\nfifth\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}